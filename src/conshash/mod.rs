@@ -1,5 +1,5 @@
 use futures::prelude::*;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
@@ -38,6 +38,7 @@ pub enum CHError {
 struct LookupTables {
     nodes: Vec<u64>,
     addrs: HashMap<u64, String>,
+    zones: HashMap<u64, String>,
 }
 
 pub struct ConsistentHashing {
@@ -61,6 +62,7 @@ impl ConsistentHashing {
             tables: RwLock::new(LookupTables {
                 nodes: Vec::new(),
                 addrs: HashMap::new(),
+                zones: HashMap::new(),
             }),
             membership: membership_client.clone(),
             weight_sm_client: WeightSMClient::new(id, &raft_client),
@@ -133,6 +135,20 @@ impl ConsistentHashing {
                 return Err(CHError::WatchError(res));
             }
         }
+        {
+            // zones are only used for placement, nodes stay the same
+            let ch = ch.clone();
+            let res = membership_client
+                .on_member_metadata_changed(move |(member, version)| {
+                    let ch = ch.clone();
+                    metadata_changed(ch, member, version).boxed()
+                })
+                .await;
+            if let Ok(Ok(_)) = res {
+            } else {
+                return Err(CHError::WatchError(res));
+            }
+        }
         Ok(ch)
     }
     pub async fn new(
@@ -230,6 +246,62 @@ impl ConsistentHashing {
     {
         self.get_server_id(hash_bytes(serialize(obj).as_slice()))
     }
+    pub fn zone_of(&self, server_id: u64) -> Option<String> {
+        self.tables.read().zones.get(&server_id).cloned()
+    }
+    // Up to `count` distinct servers for replicas of the hash, the first one is the same as
+    // `get_server_id`. Servers in zones not picked yet go first, servers without a zone count
+    // as zones of their own.
+    pub fn get_server_ids_across_zones(&self, hash: u64, count: usize) -> Vec<u64> {
+        let lookup_table = self.tables.read();
+        let nodes = &lookup_table.nodes;
+        let mut picked = Vec::with_capacity(count);
+        if nodes.is_empty() {
+            return picked;
+        }
+        let start = self.jump_hash(nodes.len(), hash);
+        let ring = || (0..nodes.len()).map(|i| nodes[(start + i) % nodes.len()]);
+        let mut zones = HashSet::new();
+        for server_id in ring() {
+            if picked.len() >= count {
+                return picked;
+            }
+            if picked.contains(&server_id) {
+                continue;
+            }
+            match lookup_table.zones.get(&server_id) {
+                Some(zone) if zones.contains(zone) => continue,
+                Some(zone) => {
+                    zones.insert(zone.clone());
+                }
+                None => {}
+            }
+            picked.push(server_id);
+        }
+        // not enough zones, fill with the rest
+        for server_id in ring() {
+            if picked.len() >= count {
+                break;
+            }
+            if !picked.contains(&server_id) {
+                picked.push(server_id);
+            }
+        }
+        picked
+    }
+    pub fn get_servers_across_zones(&self, hash: u64, count: usize) -> Vec<String> {
+        self.get_server_ids_across_zones(hash, count)
+            .into_iter()
+            .map(|id| self.to_server_name(id))
+            .collect()
+    }
+    pub fn get_servers_across_zones_by_string(
+        &self,
+        string: &String,
+        count: usize,
+    ) -> Vec<String> {
+        self.get_servers_across_zones(hash_str(string), count)
+    }
     pub fn rand_server(&self) -> Option<String> {
         let rand = rand::random::<u64>();
         self.get_server(rand)
@@ -312,8 +384,12 @@ impl ConsistentHashing {
                         let factor_sum: u32 = factors.values().sum();
                         let mut lookup_table = self.tables.write();
                         lookup_table.nodes = Vec::with_capacity(factor_sum as usize);
+                        lookup_table.zones.clear();
                         for member in members.iter() {
                             lookup_table.addrs.insert(member.id, member.address.clone());
+                            if let Some(zone) = member.zone() {
+                                lookup_table.zones.insert(member.id, zone.to_string());
+                            }
                         }
                         for (server_id, weight) in factors.into_iter() {
                             for _ in 0..weight {
@@ -378,18 +454,33 @@ async fn server_changed(ch: Arc<ConsistentHashing>, member: Member, action: Acti
     }
 }
 
+async fn metadata_changed(ch: Arc<ConsistentHashing>, member: Member, version: u64) {
+    debug!(
+        "Detected member metadata change, member {:?}, version {}",
+        member, version
+    );
+    if !ch.tables.read().addrs.contains_key(&member.id) {
+        return;
+    }
+    if ch.version.load(Ordering::Relaxed) <= version {
+        if let Err(e) = ch.init_table().await {
+            error!("Cannot reinit table {:?}", e);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::conshash::weights::Weights;
     use crate::conshash::ConsistentHashing;
-    use crate::membership::client::ObserverClient;
+    use crate::membership::client::{ObserverClient, ZONE};
     use crate::membership::member::MemberService;
     use crate::membership::server::Membership;
     use crate::raft::client::RaftClient;
     use crate::raft::{Options, RaftService, Storage};
     use crate::rpc::Server;
     use crate::utils::time::async_wait_secs;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
     use std::sync::atomic::*;
     use std::sync::Arc;
 
@@ -619,4 +710,84 @@ mod test {
         assert_eq!(ch3_server_node_changes_count.load(Ordering::Relaxed), 0);
         info!("Membership tests all done !");
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn zones() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2060");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: 0,
+        });
+        let server = Server::new(&addr);
+        let _membership = Membership::new(&server, &raft_service).await;
+        server.register_service(0, &raft_service).await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Weights::new(&raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr.clone()], 0).await.unwrap();
+        let observer_client = Arc::new(ObserverClient::new(&raft_client));
+        RaftClient::prepare_subscription(&server).await;
+
+        let group = String::from("zones_group");
+        observer_client.new_group(&group).await.unwrap().unwrap();
+        let zone = |z: &str| {
+            let mut metadata = BTreeMap::new();
+            metadata.insert(String::from(ZONE), String::from(z));
+            metadata
+        };
+        let servers = vec![
+            (String::from("zone_server1"), zone("a")),
+            (String::from("zone_server2"), zone("a")),
+            (String::from("zone_server3"), zone("b")),
+        ];
+        let mut member_svrs = vec![];
+        for (name, metadata) in &servers {
+            let svr = MemberService::new_with_metadata(name, metadata, &raft_client).await;
+            svr.join_group(&group).await.unwrap();
+            member_svrs.push(svr);
+        }
+        let ch = ConsistentHashing::new(&group, &raft_client, &observer_client)
+            .await
+            .unwrap();
+        for (name, _) in &servers {
+            ch.set_weight(name, 1).await.unwrap();
+        }
+        ch.init_table().await.unwrap();
+        assert_eq!(
+            ch.zone_of(member_svrs[2].get_server_id()),
+            Some(String::from("b"))
+        );
+
+        for i in 0..1000 {
+            let k = format!("k - {}", i);
+            let replicas = ch.get_servers_across_zones_by_string(&k, 2);
+            assert_eq!(replicas.len(), 2);
+            assert_eq!(replicas[0], ch.get_server_by_string(&k).unwrap());
+            // one of each zone
+            assert!(replicas.contains(&servers[2].0));
+            // more replicas than zones still get distinct servers
+            let mut all = ch.get_servers_across_zones_by_string(&k, 5);
+            all.sort();
+            let names: Vec<_> = servers.iter().map(|(name, _)| name.clone()).collect();
+            assert_eq!(all, names);
+        }
+
+        // Moving server 1 to zone b leaves server 2 alone in zone a
+        member_svrs[0].update_metadata(&zone("b")).await.unwrap();
+        async_wait_secs().await;
+        assert_eq!(
+            ch.zone_of(member_svrs[0].get_server_id()),
+            Some(String::from("b"))
+        );
+        for i in 0..1000 {
+            let k = format!("k - {}", i);
+            let replicas = ch.get_servers_across_zones_by_string(&k, 2);
+            assert!(replicas.contains(&servers[1].0));
+        }
+    }
 }
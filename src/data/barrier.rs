@@ -0,0 +1,459 @@
+// Cyclic barriers and countdown latches
+
+use super::{command, offline_members};
+use crate::raft::client::{Lagged, RaftClient, SubscriptionError};
+use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::{RaftMsg, RaftService};
+use crate::utils::serde::{deserialize, serialize};
+use bifrost_plugins::hash_ident;
+use futures::{stream, FutureExt, StreamExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_BARRIER) as u64;
+
+pub const REMOVED: u32 = 1;
+
+static WATCH_INTERVAL_MS: u64 = 500;
+// Waiters check the barrier in this interval in case notifications were lost
+static POLL_MS: u64 = 2_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BarrierState {
+    pub generation: u64,
+    pub parties: u64,
+    pub arrived: Vec<u64>,
+}
+
+raft_state_machine! {
+    def cmd enter(name: String, member: u64, parties: u64) -> (u64, bool);
+    def cmd leave(name: String, member: u64) -> bool;
+    def cmd remove_participant(member: u64) -> u64;
+    def qry barrier(name: String) -> Option<BarrierState>;
+    def cmd set_latch(name: String, count: u64) -> bool;
+    def cmd count_down(name: String) -> Option<u64>;
+    def qry latch(name: String) -> Option<u64>;
+    def sub on_released(name: String) -> u64;
+    def sub on_opened(name: String);
+    def sub on_removed(name: String, member: u64) -> u64;
+}
+
+// Members waiting in barriers, watched by the leader
+struct Participants {
+    members: Mutex<HashSet<u64>>,
+    closed: AtomicBool,
+}
+
+pub struct Barriers {
+    pub barriers: HashMap<String, BarrierState>,
+    pub latches: HashMap<String, u64>,
+    pub id: u64,
+    participants: Arc<Participants>,
+    callback: Option<SMCallback>,
+}
+
+impl Drop for Barriers {
+    fn drop(&mut self) {
+        self.participants.closed.store(true, Ordering::Relaxed)
+    }
+}
+
+impl Barriers {
+    fn track(&self) {
+        let mut members = self.participants.members.lock();
+        members.clear();
+        for barrier in self.barriers.values() {
+            members.extend(barrier.arrived.iter());
+        }
+    }
+}
+
+impl StateMachineCmds for Barriers {
+    // Generation entered and whether this entry released the barrier
+    fn enter(&mut self, name: String, member: u64, parties: u64) -> BoxFuture<(u64, bool)> {
+        async move {
+            let barrier = self
+                .barriers
+                .entry(name.clone())
+                .or_insert_with(|| BarrierState {
+                    generation: 0,
+                    parties,
+                    arrived: vec![],
+                });
+            let generation = barrier.generation;
+            if !barrier.arrived.contains(&member) {
+                barrier.arrived.push(member);
+            }
+            if (barrier.arrived.len() as u64) < barrier.parties {
+                self.track();
+                return (generation, false);
+            }
+            barrier.generation += 1;
+            barrier.arrived.clear();
+            self.track();
+            let msg = commands::on_released::new(&name);
+            cb_notify(&self.callback, msg, || generation).await;
+            (generation, true)
+        }
+        .boxed()
+    }
+    fn leave(&mut self, name: String, member: u64) -> BoxFuture<bool> {
+        let left = match self.barriers.get_mut(&name) {
+            Some(barrier) => {
+                let len = barrier.arrived.len();
+                barrier.arrived.retain(|m| *m != member);
+                barrier.arrived.len() != len
+            }
+            None => false,
+        };
+        self.track();
+        future::ready(left).boxed()
+    }
+    // Number of barriers the member left, its waits are notified by `on_removed`
+    fn remove_participant(&mut self, member: u64) -> BoxFuture<u64> {
+        async move {
+            let mut removed = vec![];
+            for (name, barrier) in self.barriers.iter_mut() {
+                let len = barrier.arrived.len();
+                barrier.arrived.retain(|m| *m != member);
+                if barrier.arrived.len() != len {
+                    debug!("Participant {} removed from barrier {}", member, name);
+                    removed.push((name.clone(), barrier.generation));
+                }
+            }
+            self.track();
+            for (name, generation) in &removed {
+                let msg = commands::on_removed::new(name, &member);
+                cb_notify(&self.callback, msg, || *generation).await;
+            }
+            removed.len() as u64
+        }
+        .boxed()
+    }
+    fn barrier(&self, name: String) -> BoxFuture<Option<BarrierState>> {
+        future::ready(self.barriers.get(&name).cloned()).boxed()
+    }
+    // False when the latch is not open yet
+    fn set_latch(&mut self, name: String, count: u64) -> BoxFuture<bool> {
+        let set = match self.latches.get(&name) {
+            Some(remaining) if *remaining > 0 => false,
+            _ => {
+                self.latches.insert(name, count);
+                true
+            }
+        };
+        future::ready(set).boxed()
+    }
+    // Remaining count, None for no such latch
+    fn count_down(&mut self, name: String) -> BoxFuture<Option<u64>> {
+        async move {
+            let remaining = match self.latches.get_mut(&name) {
+                Some(remaining) if *remaining > 0 => {
+                    *remaining -= 1;
+                    *remaining
+                }
+                Some(_) => return Some(0),
+                None => return None,
+            };
+            if remaining == 0 {
+                cb_notify(&self.callback, commands::on_opened::new(&name), || ()).await;
+            }
+            Some(remaining)
+        }
+        .boxed()
+    }
+    fn latch(&self, name: String) -> BoxFuture<Option<u64>> {
+        future::ready(self.latches.get(&name).cloned()).boxed()
+    }
+}
+
+impl StateMachineCtl for Barriers {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(serialize(&(&self.barriers, &self.latches)))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        match deserialize(data.as_slice()) {
+            Some((barriers, latches)) => {
+                self.barriers = barriers;
+                self.latches = latches;
+                self.track();
+            }
+            None => error!("Cannot decode snapshot of barriers {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl Barriers {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+        let participants = Arc::new(Participants {
+            members: Mutex::new(HashSet::new()),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(watch_participants(
+            id,
+            raft_service.clone(),
+            participants.clone(),
+        ));
+        raft_service
+            .register_state_machine(Box::new(Barriers {
+                barriers: HashMap::new(),
+                latches: HashMap::new(),
+                id,
+                participants,
+                callback: Some(SMCallback::new(id, raft_service.clone()).await),
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
+    }
+}
+
+// Leader issues commands to remove offline members from barriers
+async fn watch_participants(
+    sm_id: u64,
+    raft_service: Arc<RaftService>,
+    participants: Arc<Participants>,
+) {
+    while !participants.closed.load(Ordering::Relaxed) {
+        sleep(Duration::from_millis(WATCH_INTERVAL_MS)).await;
+        if !raft_service.is_leader() {
+            continue;
+        }
+        let members = participants.members.lock().clone();
+        if members.is_empty() {
+            continue;
+        }
+        for member in offline_members(&raft_service).await.intersection(&members) {
+            let (fn_id, _, data) = commands::remove_participant::new(member).encode();
+            command(&raft_service, sm_id, fn_id, data).await;
+        }
+    }
+    debug!("Barrier participant watcher for {} exiting", sm_id);
+}
+
+enum Wake {
+    Released(Result<u64, Lagged>),
+    Removed(Result<u64, Lagged>),
+}
+
+// Client of a participant, usually the member id from `MemberService::get_server_id`
+pub struct BarrierClient {
+    sm: client::SMClient,
+    sm_id: u64,
+    member: u64,
+}
+
+impl BarrierClient {
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>, member: u64) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            sm_id,
+            member,
+        }
+    }
+    fn removed(&self, name: &str) -> ExecError {
+        ExecError::StateMachineError {
+            sm_id: self.sm_id,
+            fn_id: hash_ident!(enter) as u64,
+            code: REMOVED,
+            message: format!("Participant {} removed from barrier {}", self.member, name),
+        }
+    }
+    // Enter the barrier and wait for `parties` participants, returns the generation released.
+    // Fails with `REMOVED` when the participant went offline before the release
+    pub async fn wait(
+        &self,
+        name: &str,
+        parties: u64,
+    ) -> Result<Result<u64, SubscriptionError>, ExecError> {
+        let name = name.to_string();
+        // Subscribe before entering so neither the release nor the removal can be missed
+        let released = match self.sm.streams(16).on_released(&name).await? {
+            Ok(stream) => stream,
+            Err(e) => return Ok(Err(e)),
+        };
+        let removed = match self.sm.streams(1).on_removed(&name, &self.member).await? {
+            Ok(stream) => stream,
+            Err(e) => return Ok(Err(e)),
+        };
+        let (generation, done) = self.sm.enter(&name, &self.member, &parties).await?;
+        if done {
+            return Ok(Ok(generation));
+        }
+        let mut wakes = stream::select(released.map(Wake::Released), removed.map(Wake::Removed));
+        loop {
+            match timeout(Duration::from_millis(POLL_MS), wakes.next()).await {
+                Ok(Some(Wake::Released(Ok(released)))) if released >= generation => {
+                    return Ok(Ok(released))
+                }
+                Ok(Some(Wake::Removed(Ok(removed)))) if removed == generation => {
+                    return Err(self.removed(&name))
+                }
+                Ok(Some(Wake::Released(Ok(_)))) | Ok(Some(Wake::Removed(Ok(_)))) => continue,
+                Ok(None) => return Ok(Err(SubscriptionError::RemoteError)),
+                // Notifications were dropped or lost, check the barrier instead
+                Ok(Some(_)) | Err(_) => {}
+            }
+            match self.sm.barrier(&name).await? {
+                Some(state) if state.generation > generation => return Ok(Ok(generation)),
+                Some(state) if !state.arrived.contains(&self.member) => {
+                    return Err(self.removed(&name))
+                }
+                _ => {}
+            }
+        }
+    }
+    // Leave the barrier before released
+    pub async fn leave(&self, name: &str) -> Result<bool, ExecError> {
+        self.sm.leave(&name.to_string(), &self.member).await
+    }
+    pub async fn barrier(&self, name: &str) -> Result<Option<BarrierState>, ExecError> {
+        self.sm.barrier(&name.to_string()).await
+    }
+    // Set the count of the latch when it is absent or open
+    pub async fn set_latch(&self, name: &str, count: u64) -> Result<bool, ExecError> {
+        self.sm.set_latch(&name.to_string(), &count).await
+    }
+    pub async fn count_down(&self, name: &str) -> Result<Option<u64>, ExecError> {
+        self.sm.count_down(&name.to_string()).await
+    }
+    pub async fn latch(&self, name: &str) -> Result<Option<u64>, ExecError> {
+        self.sm.latch(&name.to_string()).await
+    }
+    // Wait until the latch is counted down to zero
+    pub async fn wait_latch(&self, name: &str) -> Result<Result<(), SubscriptionError>, ExecError> {
+        let name = name.to_string();
+        let mut opened = match self.sm.streams(1).on_opened(&name).await? {
+            Ok(stream) => stream,
+            Err(e) => return Ok(Err(e)),
+        };
+        if self.sm.latch(&name).await? == Some(0) {
+            return Ok(Ok(()));
+        }
+        // Lagged also means the latch was opened
+        Ok(opened
+            .next()
+            .await
+            .map(|_| ())
+            .ok_or(SubscriptionError::RemoteError))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::membership::server::Membership;
+    use crate::membership::DEFAULT_SERVICE_ID as MEMBERSHIP_SERVICE_ID;
+    use crate::raft::{Options, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+    use bifrost_hasher::hash_str;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn barriers() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2050");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Membership::new(&server, &raft_service).await;
+        Barriers::new(&raft_service).await;
+        async_wait_secs().await;
+
+        RaftClient::prepare_subscription(&server).await;
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let sm = client::SMClient::new(DEFAULT_SERVICE_ID, &raft_client);
+        let phase = String::from("phase");
+
+        assert_eq!(sm.enter(&phase, &1, &3).await.unwrap(), (0, false));
+        assert_eq!(sm.enter(&phase, &2, &3).await.unwrap(), (0, false));
+        assert_eq!(sm.enter(&phase, &2, &3).await.unwrap(), (0, false));
+        // Offline participant does not count
+        assert_eq!(sm.remove_participant(&2).await.unwrap(), 1);
+        assert!(!sm.leave(&phase, &2).await.unwrap());
+        assert_eq!(sm.enter(&phase, &3, &3).await.unwrap(), (0, false));
+        assert_eq!(sm.enter(&phase, &4, &3).await.unwrap(), (0, true));
+        let state = sm.barrier(&phase).await.unwrap().unwrap();
+        assert_eq!((state.generation, state.arrived.len()), (1, 0));
+        assert_eq!(sm.enter(&phase, &1, &3).await.unwrap(), (1, false));
+        assert!(sm.leave(&phase, &1).await.unwrap());
+
+        let done = String::from("done");
+        assert_eq!(sm.count_down(&done).await.unwrap(), None);
+        assert!(sm.set_latch(&done, &2).await.unwrap());
+        assert!(!sm.set_latch(&done, &5).await.unwrap());
+        assert_eq!(sm.count_down(&done).await.unwrap(), Some(1));
+        assert_eq!(sm.count_down(&done).await.unwrap(), Some(0));
+        assert_eq!(sm.count_down(&done).await.unwrap(), Some(0));
+        assert_eq!(sm.latch(&done).await.unwrap(), Some(0));
+        assert!(sm.set_latch(&done, &1).await.unwrap());
+
+        // Waiters are released by the notification from the last participant
+        let a = BarrierClient::new(DEFAULT_SERVICE_ID, &raft_client, 1);
+        let b = BarrierClient::new(DEFAULT_SERVICE_ID, &raft_client, 2);
+        let client = raft_client.clone();
+        let waiting = tokio::spawn(async move {
+            let c = BarrierClient::new(DEFAULT_SERVICE_ID, &client, 3);
+            c.wait("sync", 3).await
+        });
+        async_wait_secs().await;
+        let waiting_a = tokio::spawn(async move { a.wait("sync", 3).await });
+        async_wait_secs().await;
+        assert_eq!(b.barrier("sync").await.unwrap().unwrap().arrived.len(), 2);
+        assert_eq!(b.wait("sync", 3).await.unwrap().unwrap(), 0);
+        assert_eq!(waiting.await.unwrap().unwrap().unwrap(), 0);
+        assert_eq!(waiting_a.await.unwrap().unwrap().unwrap(), 0);
+
+        // Latch waiters are woken when opened, and return at once when it is open
+        assert!(b.set_latch("ready", 2).await.unwrap());
+        let client = raft_client.clone();
+        let opening = tokio::spawn(async move {
+            let c = BarrierClient::new(DEFAULT_SERVICE_ID, &client, 3);
+            c.wait_latch("ready").await
+        });
+        assert_eq!(b.count_down("ready").await.unwrap(), Some(1));
+        async_wait_secs().await;
+        assert_eq!(b.count_down("ready").await.unwrap(), Some(0));
+        opening.await.unwrap().unwrap().unwrap();
+        b.wait_latch("ready").await.unwrap().unwrap();
+
+        // Removed by the leader when gone offline, the member never sends heartbeats
+        let member_addr = String::from("127.0.0.1:2067");
+        let (fn_id, _, data) = crate::membership::raft::commands::join::new(&member_addr).encode();
+        command(&raft_service, MEMBERSHIP_SERVICE_ID, fn_id, data).await;
+        let client = raft_client.clone();
+        let stale = tokio::spawn(async move {
+            let c = BarrierClient::new(DEFAULT_SERVICE_ID, &client, hash_str(&member_addr));
+            c.wait("stale", 2).await
+        });
+        let stale = timeout(Duration::from_secs(30), stale).await.unwrap();
+        match stale.unwrap() {
+            Err(ExecError::StateMachineError { code: REMOVED, .. }) => {}
+            other => panic!("Expect removed, got {:?}", other),
+        }
+        let state = b.barrier("stale").await.unwrap().unwrap();
+        assert!(state.arrived.is_empty());
+    }
+}
@@ -0,0 +1,258 @@
+// Replicated named counters and id sequences allocated in blocks
+
+use crate::raft::client::RaftClient;
+use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::RaftService;
+use crate::utils::serde::{deserialize, serialize};
+use async_std::sync::Mutex;
+use bifrost_plugins::hash_ident;
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_COUNTER) as u64;
+
+pub const OVERFLOW: u32 = 1;
+
+raft_state_machine! {
+    def cmd add(name: String, delta: i64) -> i64 | ExecError;
+    def cmd compare_and_set(name: String, expected: i64, value: i64) -> Result<(), i64>;
+    def cmd reset(name: String) -> i64;
+    def qry get(name: String) -> i64;
+    def cmd allocate(sequence: String, count: u64) -> (u64, u64) | ExecError;
+}
+
+pub struct Counters {
+    pub counters: HashMap<String, i64>,
+    // Next id of sequences, ids start from 1
+    pub sequences: HashMap<String, u64>,
+    pub id: u64,
+}
+
+fn overflow(name: &String) -> ExecError {
+    ExecError::state_machine(OVERFLOW, format!("{} overflowed", name))
+}
+
+impl Counters {
+    fn value(&self, name: &String) -> i64 {
+        self.counters.get(name).cloned().unwrap_or(0)
+    }
+    fn set(&mut self, name: String, value: i64) {
+        // Counters are zero when absent
+        if value == 0 {
+            self.counters.remove(&name);
+        } else {
+            self.counters.insert(name, value);
+        }
+    }
+}
+
+impl StateMachineCmds for Counters {
+    fn add(&mut self, name: String, delta: i64) -> BoxFuture<Result<i64, ExecError>> {
+        let res = match self.value(&name).checked_add(delta) {
+            Some(value) => {
+                self.set(name, value);
+                Ok(value)
+            }
+            None => Err(overflow(&name)),
+        };
+        future::ready(res).boxed()
+    }
+    fn compare_and_set(
+        &mut self,
+        name: String,
+        expected: i64,
+        value: i64,
+    ) -> BoxFuture<Result<(), i64>> {
+        let current = self.value(&name);
+        if current != expected {
+            return future::ready(Err(current)).boxed();
+        }
+        self.set(name, value);
+        future::ready(Ok(())).boxed()
+    }
+    fn reset(&mut self, name: String) -> BoxFuture<i64> {
+        future::ready(self.counters.remove(&name).unwrap_or(0)).boxed()
+    }
+    fn get(&self, name: String) -> BoxFuture<i64> {
+        future::ready(self.value(&name)).boxed()
+    }
+    fn allocate(
+        &mut self,
+        sequence: String,
+        count: u64,
+    ) -> BoxFuture<Result<(u64, u64), ExecError>> {
+        let start = self.sequences.get(&sequence).cloned().unwrap_or(1);
+        let res = match start.checked_add(count) {
+            Some(end) => {
+                self.sequences.insert(sequence, end);
+                Ok((start, end))
+            }
+            None => Err(overflow(&sequence)),
+        };
+        future::ready(res).boxed()
+    }
+}
+
+impl StateMachineCtl for Counters {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(serialize(&(&self.counters, &self.sequences)))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        match deserialize(data.as_slice()) {
+            Some((counters, sequences)) => {
+                self.counters = counters;
+                self.sequences = sequences;
+            }
+            None => error!("Cannot decode snapshot of counters {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl Counters {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+        raft_service
+            .register_state_machine(Box::new(Counters {
+                counters: HashMap::new(),
+                sequences: HashMap::new(),
+                id,
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
+    }
+}
+
+// Client for one counter
+pub struct CounterClient {
+    sm: client::SMClient,
+    name: String,
+}
+
+impl CounterClient {
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>, name: &str) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            name: name.to_string(),
+        }
+    }
+    // Value after added
+    pub async fn add(&self, delta: i64) -> Result<i64, ExecError> {
+        self.sm.add(&self.name, &delta).await
+    }
+    pub async fn incr(&self) -> Result<i64, ExecError> {
+        self.add(1).await
+    }
+    pub async fn get(&self) -> Result<i64, ExecError> {
+        self.sm.get(&self.name).await
+    }
+    // Returns the current value when it is not `expected`
+    pub async fn compare_and_set(
+        &self,
+        expected: i64,
+        value: i64,
+    ) -> Result<Result<(), i64>, ExecError> {
+        self.sm.compare_and_set(&self.name, &expected, &value).await
+    }
+    // Set to zero, returns the value before
+    pub async fn reset(&self) -> Result<i64, ExecError> {
+        self.sm.reset(&self.name).await
+    }
+}
+
+// Allocates unique ids from a sequence, a block of `block_size` ids is taken at a time
+pub struct IdAllocator {
+    sm: client::SMClient,
+    sequence: String,
+    block_size: u64,
+    // Next id and end of the block, exclusive
+    block: Mutex<(u64, u64)>,
+}
+
+impl IdAllocator {
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>, sequence: &str, block_size: u64) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            sequence: sequence.to_string(),
+            block_size: block_size.max(1),
+            block: Mutex::new((0, 0)),
+        }
+    }
+    pub async fn next_id(&self) -> Result<u64, ExecError> {
+        let mut block = self.block.lock().await;
+        if block.0 >= block.1 {
+            *block = self.allocate(self.block_size).await?;
+        }
+        let id = block.0;
+        block.0 += 1;
+        Ok(id)
+    }
+    // Take ids from `start` inclusive to `end` exclusive, bypassing the local block
+    pub async fn allocate(&self, count: u64) -> Result<(u64, u64), ExecError> {
+        self.sm.allocate(&self.sequence, &count).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raft::{Options, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+    use std::collections::HashSet;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn counters() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2047");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Counters::new(&raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let counter = CounterClient::new(DEFAULT_SERVICE_ID, &raft_client, "hits");
+        assert_eq!(counter.get().await.unwrap(), 0);
+        assert_eq!(counter.incr().await.unwrap(), 1);
+        assert_eq!(counter.add(9).await.unwrap(), 10);
+        assert_eq!(counter.compare_and_set(1, 5).await.unwrap(), Err(10));
+        assert_eq!(counter.compare_and_set(10, 5).await.unwrap(), Ok(()));
+        assert_eq!(counter.add(-6).await.unwrap(), -1);
+        match counter.add(i64::MIN).await {
+            Err(ExecError::StateMachineError { code: OVERFLOW, .. }) => {}
+            other => panic!("Expect overflow, got {:?}", other),
+        }
+        assert_eq!(counter.get().await.unwrap(), -1);
+        assert_eq!(counter.reset().await.unwrap(), -1);
+        assert_eq!(counter.get().await.unwrap(), 0);
+
+        let a = IdAllocator::new(DEFAULT_SERVICE_ID, &raft_client, "records", 10);
+        let b = IdAllocator::new(DEFAULT_SERVICE_ID, &raft_client, "records", 10);
+        let mut ids = HashSet::new();
+        for _ in 0..25 {
+            assert!(ids.insert(a.next_id().await.unwrap()));
+            assert!(ids.insert(b.next_id().await.unwrap()));
+        }
+        assert_eq!(ids.len(), 50);
+        // a and b took blocks of 10 ids in turn
+        assert_eq!(a.next_id().await.unwrap(), 46);
+        assert_eq!(a.allocate(5).await.unwrap(), (61, 66));
+    }
+}
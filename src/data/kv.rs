@@ -0,0 +1,577 @@
+// Revisioned key value store with compaction and watches from a revision
+
+use crate::raft::client::{Lagged, RaftClient, SubscriptionError, SubscriptionStream};
+use crate::raft::state_machine::callback::pattern::ArgFilter;
+use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::RaftService;
+use crate::utils::serde::{deserialize, serialize};
+use bifrost_plugins::hash_ident;
+use futures::stream::Stream;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_KV) as u64;
+
+pub const COMPACTED: u32 = 1;
+pub const FUTURE_REVISION: u32 = 2;
+
+static HISTORY_PAGE: u64 = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyValue {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    // Revision of the last write to the key
+    pub mod_revision: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub revision: u64,
+    pub key: Vec<u8>,
+    // None for deleted
+    pub value: Option<Vec<u8>>,
+}
+
+// Revision 0 in reads stands for the current revision
+raft_state_machine! {
+    def cmd put(key: Vec<u8>, value: Vec<u8>) -> u64;
+    def cmd delete(key: Vec<u8>) -> Option<u64>;
+    def cmd compare_and_put(key: Vec<u8>, mod_revision: u64, value: Option<Vec<u8>>) -> Result<u64, u64>;
+    def cmd compact(revision: u64) -> () | ExecError;
+    def qry get(key: Vec<u8>, revision: u64) -> Option<KeyValue> | ExecError;
+    def qry range(start: Option<Vec<u8>>, end: Option<Vec<u8>>, revision: u64, limit: u64) -> Vec<KeyValue> | ExecError;
+    def qry changes(key: Option<Vec<u8>>, from_revision: u64, limit: u64) -> Vec<Event> | ExecError;
+    def qry revisions() -> (u64, u64);
+    def sub on_changed(key: Vec<u8>) -> Event;
+}
+
+type Versions = BTreeMap<u64, Option<Vec<u8>>>;
+
+pub struct KvStore {
+    // Values of keys by revision, None for deleted
+    pub keys: BTreeMap<Vec<u8>, Versions>,
+    pub events: BTreeMap<u64, Event>,
+    pub revision: u64,
+    // Revisions before are compacted
+    pub compacted: u64,
+    pub id: u64,
+    callback: Option<SMCallback>,
+}
+
+fn value_at(versions: &Versions, revision: u64) -> Option<(u64, &Vec<u8>)> {
+    versions
+        .range(..=revision)
+        .next_back()
+        .and_then(|(rev, value)| value.as_ref().map(|v| (*rev, v)))
+}
+
+fn key_value(key: &Vec<u8>, (mod_revision, value): (u64, &Vec<u8>)) -> KeyValue {
+    KeyValue {
+        key: key.clone(),
+        value: value.clone(),
+        mod_revision,
+    }
+}
+
+impl KvStore {
+    fn read_revision(&self, revision: u64) -> Result<u64, ExecError> {
+        let revision = if revision == 0 {
+            self.revision
+        } else {
+            revision
+        };
+        if revision > self.revision {
+            Err(ExecError::state_machine(
+                FUTURE_REVISION,
+                format!("Revision {} is ahead of {}", revision, self.revision),
+            ))
+        } else if revision < self.compacted {
+            Err(ExecError::state_machine(
+                COMPACTED,
+                format!("Revision {} compacted to {}", revision, self.compacted),
+            ))
+        } else {
+            Ok(revision)
+        }
+    }
+    fn mod_revision(&self, key: &Vec<u8>) -> u64 {
+        self.keys
+            .get(key)
+            .and_then(|versions| value_at(versions, self.revision))
+            .map(|(rev, _)| rev)
+            .unwrap_or(0)
+    }
+    async fn write(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) -> u64 {
+        self.revision += 1;
+        let revision = self.revision;
+        self.keys
+            .entry(key.clone())
+            .or_insert_with(BTreeMap::new)
+            .insert(revision, value.clone());
+        let event = Event {
+            revision,
+            key,
+            value,
+        };
+        self.events.insert(revision, event.clone());
+        let msg = commands::on_changed::new(&event.key);
+        cb_notify(&self.callback, msg, || event).await;
+        revision
+    }
+}
+
+impl StateMachineCmds for KvStore {
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> BoxFuture<u64> {
+        self.write(key, Some(value)).boxed()
+    }
+    fn delete(&mut self, key: Vec<u8>) -> BoxFuture<Option<u64>> {
+        async move {
+            if self.mod_revision(&key) == 0 {
+                return None;
+            }
+            Some(self.write(key, None).await)
+        }
+        .boxed()
+    }
+    // Write only when the key was last written at `mod_revision`, 0 for absent keys
+    fn compare_and_put(
+        &mut self,
+        key: Vec<u8>,
+        mod_revision: u64,
+        value: Option<Vec<u8>>,
+    ) -> BoxFuture<Result<u64, u64>> {
+        async move {
+            let current = self.mod_revision(&key);
+            if current != mod_revision {
+                return Err(current);
+            }
+            if current == 0 && value.is_none() {
+                return Ok(self.revision);
+            }
+            Ok(self.write(key, value).await)
+        }
+        .boxed()
+    }
+    fn compact(&mut self, revision: u64) -> BoxFuture<Result<(), ExecError>> {
+        let revision = match self.read_revision(revision) {
+            Ok(revision) => revision,
+            // Compacting to compacted revisions is no-op
+            Err(ExecError::StateMachineError {
+                code: COMPACTED, ..
+            }) => return future::ready(Ok(())).boxed(),
+            Err(e) => return future::ready(Err(e)).boxed(),
+        };
+        // Keep the value of each key at the revision and all versions after
+        self.keys.retain(|_, versions| {
+            if let Some(base) = versions.range(..=revision).next_back().map(|(r, _)| *r) {
+                *versions = versions.split_off(&base);
+                if let Some(None) = versions.get(&base) {
+                    versions.remove(&base);
+                }
+            }
+            !versions.is_empty()
+        });
+        self.events = self.events.split_off(&revision);
+        self.compacted = revision;
+        future::ready(Ok(())).boxed()
+    }
+    fn get(&self, key: Vec<u8>, revision: u64) -> BoxFuture<Result<Option<KeyValue>, ExecError>> {
+        let res = self.read_revision(revision).map(|revision| {
+            self.keys
+                .get(&key)
+                .and_then(|versions| value_at(versions, revision))
+                .map(|v| key_value(&key, v))
+        });
+        future::ready(res).boxed()
+    }
+    fn range(
+        &self,
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        revision: u64,
+        limit: u64,
+    ) -> BoxFuture<Result<Vec<KeyValue>, ExecError>> {
+        let revision = match self.read_revision(revision) {
+            Ok(revision) => revision,
+            Err(e) => return future::ready(Err(e)).boxed(),
+        };
+        let start = start.map(Included).unwrap_or(Unbounded);
+        let end = end.map(Excluded).unwrap_or(Unbounded);
+        if let (Included(s), Excluded(e)) = (&start, &end) {
+            if s >= e {
+                return future::ready(Ok(vec![])).boxed();
+            }
+        }
+        let kvs = self
+            .keys
+            .range((start, end))
+            .filter_map(|(key, versions)| value_at(versions, revision).map(|v| key_value(key, v)))
+            .take(limit as usize)
+            .collect();
+        future::ready(Ok(kvs)).boxed()
+    }
+    // Events from the revision in order, of one key or all keys
+    fn changes(
+        &self,
+        key: Option<Vec<u8>>,
+        from_revision: u64,
+        limit: u64,
+    ) -> BoxFuture<Result<Vec<Event>, ExecError>> {
+        if from_revision < self.compacted {
+            return future::ready(Err(ExecError::state_machine(
+                COMPACTED,
+                format!("Revision {} compacted to {}", from_revision, self.compacted),
+            )))
+            .boxed();
+        }
+        let events = self
+            .events
+            .range(from_revision..)
+            .map(|(_, event)| event)
+            .filter(|event| key.as_ref().map(|k| k == &event.key).unwrap_or(true))
+            .take(limit as usize)
+            .cloned()
+            .collect();
+        future::ready(Ok(events)).boxed()
+    }
+    // Current and compacted revision
+    fn revisions(&self) -> BoxFuture<(u64, u64)> {
+        future::ready((self.revision, self.compacted)).boxed()
+    }
+}
+
+impl StateMachineCtl for KvStore {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(serialize(&(
+            &self.keys,
+            &self.events,
+            self.revision,
+            self.compacted,
+        )))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        match deserialize(data.as_slice()) {
+            Some((keys, events, revision, compacted)) => {
+                self.keys = keys;
+                self.events = events;
+                self.revision = revision;
+                self.compacted = compacted;
+            }
+            None => error!("Cannot decode snapshot of kv store {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl KvStore {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+        raft_service
+            .register_state_machine(Box::new(KvStore {
+                keys: BTreeMap::new(),
+                events: BTreeMap::new(),
+                revision: 0,
+                compacted: 0,
+                id,
+                callback: Some(SMCallback::new(id, raft_service.clone()).await),
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
+    }
+}
+
+// Events replayed from history followed by live events. Live events dropped for the stream
+// being full are reported as `Lagged`, watch again from the last revision seen to catch up
+pub struct Watch<S = SubscriptionStream<Event>> {
+    replay: VecDeque<Event>,
+    // Live events up to this revision were replayed
+    replayed: u64,
+    live: S,
+}
+
+impl<S> Watch<S> {
+    pub fn new(replay: Vec<Event>, from_revision: u64, live: S) -> Self {
+        let replayed = replay
+            .last()
+            .map(|event| event.revision)
+            .unwrap_or(from_revision.saturating_sub(1));
+        Self {
+            replay: replay.into(),
+            replayed,
+            live,
+        }
+    }
+}
+
+impl<S: Stream<Item = Result<Event, Lagged>> + Unpin> Stream for Watch<S> {
+    type Item = Result<Event, Lagged>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(event) = this.replay.pop_front() {
+            return Poll::Ready(Some(Ok(event)));
+        }
+        loop {
+            match Pin::new(&mut this.live).poll_next(cx) {
+                Poll::Ready(Some(Ok(event))) if event.revision <= this.replayed => continue,
+                res => return res,
+            }
+        }
+    }
+}
+
+pub struct KvClient {
+    sm: client::SMClient,
+}
+
+impl KvClient {
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+        }
+    }
+    // Revision of the write
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<u64, ExecError> {
+        self.sm.put(&key.to_vec(), &value.to_vec()).await
+    }
+    pub async fn delete(&self, key: &[u8]) -> Result<Option<u64>, ExecError> {
+        self.sm.delete(&key.to_vec()).await
+    }
+    // Put, or delete with None, when the key was last written at `mod_revision`, 0 for absent.
+    // Returns the current mod revision of the key when it was not
+    pub async fn compare_and_put(
+        &self,
+        key: &[u8],
+        mod_revision: u64,
+        value: Option<&[u8]>,
+    ) -> Result<Result<u64, u64>, ExecError> {
+        let value = value.map(|v| v.to_vec());
+        self.sm
+            .compare_and_put(&key.to_vec(), &mod_revision, &value)
+            .await
+    }
+    pub async fn compact(&self, revision: u64) -> Result<(), ExecError> {
+        self.sm.compact(&revision).await
+    }
+    pub async fn get(&self, key: &[u8]) -> Result<Option<KeyValue>, ExecError> {
+        self.get_at(key, 0).await
+    }
+    pub async fn get_at(&self, key: &[u8], revision: u64) -> Result<Option<KeyValue>, ExecError> {
+        self.sm.get(&key.to_vec(), &revision).await
+    }
+    pub async fn range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        limit: u64,
+    ) -> Result<Vec<KeyValue>, ExecError> {
+        self.range_at(start, end, 0, limit).await
+    }
+    // Keys from `start` inclusive to `end` exclusive at the revision
+    pub async fn range_at(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        revision: u64,
+        limit: u64,
+    ) -> Result<Vec<KeyValue>, ExecError> {
+        let start = start.map(|k| k.to_vec());
+        let end = end.map(|k| k.to_vec());
+        self.sm.range(&start, &end, &revision, &limit).await
+    }
+    // Current and compacted revision
+    pub async fn revisions(&self) -> Result<(u64, u64), ExecError> {
+        self.sm.revisions().await
+    }
+    // All events from the revision, of one key or all keys
+    pub async fn history(
+        &self,
+        key: Option<&[u8]>,
+        from_revision: u64,
+    ) -> Result<Vec<Event>, ExecError> {
+        let key = key.map(|k| k.to_vec());
+        let mut events: Vec<Event> = vec![];
+        let mut from = from_revision;
+        loop {
+            let page = self.sm.changes(&key, &from, &HISTORY_PAGE).await?;
+            let full = page.len() as u64 == HISTORY_PAGE;
+            events.extend(page);
+            match events.last() {
+                Some(last) if full => from = last.revision + 1,
+                _ => return Ok(events),
+            }
+        }
+    }
+    // Watch one key or all keys, replaying events from the revision, 0 for new events only.
+    // Events are dropped when more than `capacity` are waiting to be taken.
+    pub async fn watch(
+        &self,
+        key: Option<&[u8]>,
+        from_revision: u64,
+        capacity: usize,
+    ) -> Result<Result<Watch, SubscriptionError>, ExecError> {
+        // Subscribe before reading history so no event falls in between
+        let streams = self.sm.streams(capacity);
+        let live = match key {
+            Some(key) => streams.on_changed(&key.to_vec()).await?,
+            None => {
+                streams
+                    .subscribe_pattern(commands::on_changed::pattern(&ArgFilter::Any))
+                    .await?
+            }
+        };
+        let live = match live {
+            Ok(live) => live,
+            Err(e) => return Ok(Err(e)),
+        };
+        let replay = if from_revision > 0 {
+            self.history(key, from_revision).await?
+        } else {
+            vec![]
+        };
+        Ok(Ok(Watch::new(replay, from_revision, live)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raft::{Options, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+    use futures::StreamExt;
+
+    fn event(revision: u64, key: &[u8], value: Option<&[u8]>) -> Event {
+        Event {
+            revision,
+            key: key.to_vec(),
+            value: value.map(|v| v.to_vec()),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watch_replay() {
+        let replay = vec![event(3, b"a", Some(b"1")), event(4, b"a", None)];
+        // Live events overlap with replayed history
+        let live = futures::stream::iter(vec![
+            Ok(event(4, b"a", None)),
+            Err(Lagged(1)),
+            Ok(event(6, b"a", Some(b"2"))),
+        ]);
+        let events: Vec<_> = Watch::new(replay, 3, live).collect().await;
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| e.as_ref().map(|e| e.revision).map_err(|l| *l))
+                .collect::<Vec<_>>(),
+            vec![Ok(3), Ok(4), Err(Lagged(1)), Ok(6)]
+        );
+        let live = futures::stream::iter(vec![Ok(event(1, b"a", None)), Ok(event(2, b"a", None))]);
+        let events: Vec<_> = Watch::new(vec![], 2, live).collect().await;
+        assert_eq!(events, vec![Ok(event(2, b"a", None))]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn kv() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2048");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        KvStore::new(&raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let kv = KvClient::new(DEFAULT_SERVICE_ID, &raft_client);
+        let values = |kvs: Vec<KeyValue>| {
+            kvs.into_iter()
+                .map(|kv| (kv.key, kv.value))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(kv.put(b"a", b"1").await.unwrap(), 1);
+        assert_eq!(kv.put(b"b", b"2").await.unwrap(), 2);
+        assert_eq!(kv.put(b"a", b"3").await.unwrap(), 3);
+        assert_eq!(kv.delete(b"b").await.unwrap(), Some(4));
+        assert_eq!(kv.delete(b"b").await.unwrap(), None);
+        assert_eq!(kv.revisions().await.unwrap(), (4, 0));
+
+        assert_eq!(kv.get(b"a").await.unwrap().unwrap().mod_revision, 3);
+        assert_eq!(kv.get_at(b"a", 1).await.unwrap().unwrap().value, b"1");
+        assert_eq!(kv.get_at(b"b", 4).await.unwrap(), None);
+        assert_eq!(
+            values(kv.range_at(None, None, 2, 10).await.unwrap()),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec())
+            ]
+        );
+        assert_eq!(
+            values(kv.range(None, None, 10).await.unwrap()),
+            vec![(b"a".to_vec(), b"3".to_vec())]
+        );
+        match kv.get_at(b"a", 5).await {
+            Err(ExecError::StateMachineError {
+                code: FUTURE_REVISION,
+                ..
+            }) => {}
+            other => panic!("Expect future revision error, got {:?}", other),
+        }
+
+        assert_eq!(
+            kv.compare_and_put(b"a", 1, Some(b"4")).await.unwrap(),
+            Err(3)
+        );
+        assert_eq!(
+            kv.compare_and_put(b"a", 3, Some(b"4")).await.unwrap(),
+            Ok(5)
+        );
+        assert_eq!(
+            kv.compare_and_put(b"c", 0, Some(b"5")).await.unwrap(),
+            Ok(6)
+        );
+
+        assert_eq!(
+            kv.history(Some(b"a"), 2).await.unwrap(),
+            vec![event(3, b"a", Some(b"3")), event(5, b"a", Some(b"4"))]
+        );
+        assert_eq!(kv.history(None, 4).await.unwrap().len(), 3);
+
+        kv.compact(4).await.unwrap();
+        assert_eq!(kv.revisions().await.unwrap(), (6, 4));
+        match kv.get_at(b"a", 3).await {
+            Err(ExecError::StateMachineError {
+                code: COMPACTED, ..
+            }) => {}
+            other => panic!("Expect compacted error, got {:?}", other),
+        }
+        assert!(kv.history(None, 3).await.is_err());
+        assert_eq!(kv.get_at(b"a", 4).await.unwrap().unwrap().value, b"3");
+        assert_eq!(kv.get_at(b"b", 4).await.unwrap(), None);
+        assert_eq!(kv.range_at(None, None, 4, 10).await.unwrap().len(), 1);
+        assert_eq!(kv.history(None, 4).await.unwrap().len(), 3);
+        kv.compact(2).await.unwrap();
+        assert_eq!(kv.revisions().await.unwrap(), (6, 4));
+    }
+}
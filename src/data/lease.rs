@@ -0,0 +1,442 @@
+// Leases with TTL, attached keys are deleted together with releasing the lease
+
+use super::command;
+use crate::raft::client::{RaftClient, SubscriptionError, SubscriptionReceipt};
+use crate::raft::state_machine::callback::pattern::ArgFilter;
+use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::commands::cascade_;
+use crate::raft::state_machine::master::{ExecError, SubCommand, MASTER_SM_ID};
+use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::{ClientQryResponse, LogEntry, RaftMsg, RaftService, Service as raft_svr_trait};
+use crate::utils::serde::{deserialize, serialize};
+use crate::utils::time::get_time;
+use bifrost_plugins::hash_ident;
+use futures::FutureExt;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_LEASE) as u64;
+
+static WATCH_INTERVAL_MS: u64 = 200;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LeaseInfo {
+    pub id: u64,
+    pub ttl_ms: u64,
+    pub version: u64,
+    // Commands deleting the attached keys
+    pub keys: Vec<SubCommand>,
+}
+
+raft_state_machine! {
+    def cmd grant(ttl_ms: u64) -> u64;
+    def cmd keep_alive(lease: u64) -> bool;
+    def cmd attach(lease: u64, key: SubCommand) -> bool;
+    def cmd detach(lease: u64, key: SubCommand) -> bool;
+    def cmd release_(lease: u64, version: u64) -> bool;
+    def qry lease(lease: u64) -> Option<LeaseInfo>;
+    def qry leases() -> Vec<u64>;
+    def sub on_released(lease: u64) -> Vec<SubCommand>;
+}
+
+struct Deadlines {
+    deadlines: Mutex<HashMap<u64, (u64, i64)>>,
+    closed: AtomicBool,
+}
+
+pub struct Leases {
+    pub leases: HashMap<u64, LeaseInfo>,
+    pub next_id: u64,
+    pub id: u64,
+    deadlines: Arc<Deadlines>,
+    callback: Option<SMCallback>,
+}
+
+impl Drop for Leases {
+    fn drop(&mut self) {
+        self.deadlines.closed.store(true, Ordering::Relaxed)
+    }
+}
+
+impl Leases {
+    fn touch(&self, lease: &LeaseInfo) {
+        if lease.ttl_ms == 0 {
+            return;
+        }
+        self.deadlines
+            .deadlines
+            .lock()
+            .insert(lease.id, (lease.ttl_ms, get_time() + lease.ttl_ms as i64));
+    }
+    fn update<F>(&mut self, lease: u64, f: F) -> bool
+    where
+        F: FnOnce(&mut LeaseInfo) -> bool,
+    {
+        match self.leases.get_mut(&lease) {
+            Some(info) => {
+                let changed = f(info);
+                if changed {
+                    info.version += 1;
+                }
+                changed
+            }
+            None => false,
+        }
+    }
+}
+
+impl StateMachineCmds for Leases {
+    fn grant(&mut self, ttl_ms: u64) -> BoxFuture<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let lease = LeaseInfo {
+            id,
+            ttl_ms,
+            version: 0,
+            keys: vec![],
+        };
+        self.touch(&lease);
+        self.leases.insert(id, lease);
+        future::ready(id).boxed()
+    }
+    fn keep_alive(&mut self, lease: u64) -> BoxFuture<bool> {
+        let alive = self.update(lease, |_| true);
+        if let Some(info) = self.leases.get(&lease) {
+            self.touch(info);
+        }
+        future::ready(alive).boxed()
+    }
+    fn attach(&mut self, lease: u64, key: SubCommand) -> BoxFuture<bool> {
+        if key.is_internal() {
+            return future::ready(false).boxed();
+        }
+        let attached = self.update(lease, |info| {
+            if !info.keys.contains(&key) {
+                info.keys.push(key);
+            }
+            true
+        });
+        future::ready(attached).boxed()
+    }
+    fn detach(&mut self, lease: u64, key: SubCommand) -> BoxFuture<bool> {
+        let detached = self.update(lease, |info| {
+            let len = info.keys.len();
+            info.keys.retain(|k| k != &key);
+            info.keys.len() != len
+        });
+        future::ready(detached).boxed()
+    }
+    // Guard of the cascade deleting attached keys
+    fn release_(&mut self, lease: u64, version: u64) -> BoxFuture<bool> {
+        async move {
+            match self.leases.get(&lease) {
+                Some(info) if info.version == version => {}
+                _ => return false,
+            }
+            let info = self.leases.remove(&lease).unwrap();
+            self.deadlines.deadlines.lock().remove(&lease);
+            debug!("Lease {} released with {} keys", lease, info.keys.len());
+            let msg = commands::on_released::new(&lease);
+            cb_notify(&self.callback, msg, || info.keys).await;
+            true
+        }
+        .boxed()
+    }
+    fn lease(&self, lease: u64) -> BoxFuture<Option<LeaseInfo>> {
+        future::ready(self.leases.get(&lease).cloned()).boxed()
+    }
+    fn leases(&self) -> BoxFuture<Vec<u64>> {
+        future::ready(self.leases.keys().cloned().collect()).boxed()
+    }
+}
+
+impl StateMachineCtl for Leases {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(serialize(&(&self.leases, self.next_id)))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        match deserialize(data.as_slice()) {
+            Some((leases, next_id)) => {
+                self.leases = leases;
+                self.next_id = next_id;
+                self.deadlines.deadlines.lock().clear();
+                for lease in self.leases.values() {
+                    self.touch(lease);
+                }
+            }
+            None => error!("Cannot decode snapshot of leases {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl Leases {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+        let deadlines = Arc::new(Deadlines {
+            deadlines: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(watch_deadlines(id, raft_service.clone(), deadlines.clone()));
+        raft_service
+            .register_state_machine(Box::new(Leases {
+                leases: HashMap::new(),
+                next_id: 1,
+                id,
+                deadlines,
+                callback: Some(SMCallback::new(id, raft_service.clone()).await),
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
+    }
+}
+
+// Master command releasing the lease and deleting its keys, if the lease is still at the version
+fn release_cascade(sm_id: u64, lease: &LeaseInfo) -> cascade_ {
+    let guard = SubCommand::new(sm_id, commands::release_::new(&lease.id, &lease.version));
+    cascade_::new(&guard, &lease.keys)
+}
+
+// Leader issues releases of leases not kept alive in TTL
+async fn watch_deadlines(sm_id: u64, raft_service: Arc<RaftService>, deadlines: Arc<Deadlines>) {
+    let mut was_leader = false;
+    while !deadlines.closed.load(Ordering::Relaxed) {
+        sleep(Duration::from_millis(WATCH_INTERVAL_MS)).await;
+        if !raft_service.is_leader() {
+            was_leader = false;
+            continue;
+        }
+        let now = get_time();
+        let expired: Vec<_> = {
+            let mut deadlines = deadlines.deadlines.lock();
+            if !was_leader {
+                for (ttl_ms, deadline) in deadlines.values_mut() {
+                    *deadline = now + *ttl_ms as i64;
+                }
+            }
+            deadlines
+                .iter()
+                .filter(|(_, (_, deadline))| *deadline <= now)
+                .map(|(lease, _)| *lease)
+                .collect()
+        };
+        was_leader = true;
+        for lease in expired {
+            let (fn_id, _, data) = commands::lease::new(&lease).encode();
+            let res = raft_service
+                .c_query(LogEntry {
+                    id: 0,
+                    term: 0,
+                    sm_id,
+                    fn_id,
+                    data,
+                    sm_version: 0,
+                    time: 0,
+                })
+                .await;
+            if let ClientQryResponse::Success { data: Ok(data), .. } = res {
+                if let Some(info) = commands::lease::decode_return(&data).flatten() {
+                    debug!("Lease {} expired", lease);
+                    let (fn_id, _, data) = release_cascade(sm_id, &info).encode();
+                    command(&raft_service, MASTER_SM_ID, fn_id, data).await;
+                }
+            }
+        }
+    }
+    debug!("Lease deadline watcher for {} exiting", sm_id);
+}
+
+pub struct LeaseClient {
+    sm: client::SMClient,
+    sm_id: u64,
+    raft_client: Arc<RaftClient>,
+}
+
+impl LeaseClient {
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            sm_id,
+            raft_client: client.clone(),
+        }
+    }
+    // Lease id, leases with zero TTL are only released by revoke
+    pub async fn grant(&self, ttl_ms: u64) -> Result<u64, ExecError> {
+        self.sm.grant(&ttl_ms).await
+    }
+    // False when the lease is gone
+    pub async fn keep_alive(&self, lease: u64) -> Result<bool, ExecError> {
+        self.sm.keep_alive(&lease).await
+    }
+    // Attach a key by the command deleting it from its state machine,
+    // e.g. `map::commands::remove::new(&key)` for keys of `data::map`.
+    // Commands to the master or config state machines are rejected with `FnNotFound`
+    pub async fn attach<R, M: RaftMsg<R>>(
+        &self,
+        lease: u64,
+        sm_id: u64,
+        delete: M,
+    ) -> Result<bool, ExecError> {
+        let key = SubCommand::new(sm_id, delete);
+        if key.is_internal() {
+            return Err(ExecError::FnNotFound);
+        }
+        self.sm.attach(&lease, &key).await
+    }
+    pub async fn detach<R, M: RaftMsg<R>>(
+        &self,
+        lease: u64,
+        sm_id: u64,
+        delete: M,
+    ) -> Result<bool, ExecError> {
+        self.sm
+            .detach(&lease, &SubCommand::new(sm_id, delete))
+            .await
+    }
+    // Release the lease now and delete its keys
+    pub async fn revoke(&self, lease: u64) -> Result<bool, ExecError> {
+        loop {
+            let info = match self.sm.lease(&lease).await? {
+                Some(info) => info,
+                None => return Ok(false),
+            };
+            let cascade = release_cascade(self.sm_id, &info);
+            if self.raft_client.execute(MASTER_SM_ID, cascade).await? {
+                return Ok(true);
+            }
+            // Changed since read, try again with the new version
+        }
+    }
+    pub async fn lease(&self, lease: u64) -> Result<Option<LeaseInfo>, ExecError> {
+        self.sm.lease(&lease).await
+    }
+    pub async fn leases(&self) -> Result<Vec<u64>, ExecError> {
+        self.sm.leases().await
+    }
+    // Commands deleted the keys, when the lease is revoked or expired
+    pub async fn on_released<F>(
+        &self,
+        lease: u64,
+        f: F,
+    ) -> Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>
+    where
+        F: Fn(Vec<SubCommand>) -> BoxFuture<'static, ()> + 'static + Send + Sync,
+    {
+        self.sm.on_released(f, &lease).await
+    }
+    pub async fn on_any_released<F>(
+        &self,
+        f: F,
+    ) -> Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>
+    where
+        F: Fn(Vec<SubCommand>) -> BoxFuture<'static, ()> + 'static + Send + Sync,
+    {
+        let pattern = commands::on_released::pattern(&ArgFilter::Any);
+        self.sm.subscribe_pattern(pattern, f).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data::map::{self, Map, MapClient};
+    use crate::raft::state_machine::configs::commands::unsubscribe as conf_unsubscribe;
+    use crate::raft::state_machine::configs::CONFIG_SM_ID;
+    use crate::raft::{Options, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::{async_wait, async_wait_secs};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn leases() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2049");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Leases::new(&raft_service).await;
+        Map::new(&raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let leases = LeaseClient::new(DEFAULT_SERVICE_ID, &raft_client);
+        let map = MapClient::<String, u32>::new(map::DEFAULT_SERVICE_ID, &raft_client);
+        let key = |k: &str| k.to_string();
+        let attach = |lease: u64, k: &str| {
+            let delete = map::commands::remove::new(&map::MapKey::encode_key(&key(k)));
+            leases.attach(lease, map::DEFAULT_SERVICE_ID, delete)
+        };
+
+        // Kept alive beyond TTL, then expired with its keys
+        let expiring = leases.grant(1000).await.unwrap();
+        map.put(&key("a"), &1).await.unwrap();
+        map.put(&key("b"), &2).await.unwrap();
+        assert!(attach(expiring, "a").await.unwrap());
+        assert!(attach(expiring, "b").await.unwrap());
+        for _ in 0..4 {
+            async_wait(Duration::from_millis(500)).await;
+            assert!(leases.keep_alive(expiring).await.unwrap());
+        }
+        assert_eq!(map.get(&key("a")).await.unwrap(), Some(1));
+        let info = leases.lease(expiring).await.unwrap().unwrap();
+        assert_eq!((info.keys.len(), info.version), (2, 6));
+        async_wait_secs().await;
+        assert_eq!(leases.lease(expiring).await.unwrap(), None);
+        assert!(!leases.keep_alive(expiring).await.unwrap());
+        assert_eq!(map.get(&key("a")).await.unwrap(), None);
+        assert_eq!(map.get(&key("b")).await.unwrap(), None);
+
+        // Revoked, detached keys are kept
+        let revoking = leases.grant(0).await.unwrap();
+        map.put(&key("c"), &3).await.unwrap();
+        map.put(&key("d"), &4).await.unwrap();
+        assert!(attach(revoking, "c").await.unwrap());
+        assert!(attach(revoking, "d").await.unwrap());
+        let delete = map::commands::remove::new(&map::MapKey::encode_key(&key("d")));
+        assert!(leases
+            .detach(revoking, map::DEFAULT_SERVICE_ID, delete)
+            .await
+            .unwrap());
+        assert_eq!(leases.leases().await.unwrap(), vec![revoking]);
+        assert!(leases.revoke(revoking).await.unwrap());
+        assert!(!leases.revoke(revoking).await.unwrap());
+        assert_eq!(map.get(&key("c")).await.unwrap(), None);
+        assert_eq!(map.get(&key("d")).await.unwrap(), Some(4));
+        assert!(leases.leases().await.unwrap().is_empty());
+
+        // Keys of internal state machines are rejected, by the client and when replicated
+        let guarded = leases.grant(0).await.unwrap();
+        let unsubscribe = conf_unsubscribe::new(&0);
+        match leases.attach(guarded, CONFIG_SM_ID, unsubscribe).await {
+            Err(ExecError::FnNotFound) => {}
+            res => panic!("{:?}", res),
+        }
+        let internal = SubCommand::new(CONFIG_SM_ID, conf_unsubscribe::new(&0));
+        assert!(!leases.sm.attach(&guarded, &internal).await.unwrap());
+        assert!(leases
+            .lease(guarded)
+            .await
+            .unwrap()
+            .unwrap()
+            .keys
+            .is_empty());
+    }
+}
@@ -0,0 +1,454 @@
+// Replicated locks with fencing tokens, released by TTL or when the holder goes offline
+
+use super::{command, offline_members};
+use crate::raft::client::{RaftClient, SubscriptionError};
+use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::{RaftMsg, RaftService};
+use crate::utils::serde::{deserialize, serialize};
+use crate::utils::time::get_time;
+use bifrost_plugins::hash_ident;
+use futures::{FutureExt, StreamExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_LOCK) as u64;
+
+static WATCH_INTERVAL_MS: u64 = 500;
+// Waiters check the lock in this interval in case the hand over notification was lost
+static LOCK_POLL_MS: u64 = 2_000;
+
+raft_state_machine! {
+    def cmd try_lock(name: String, holder: u64, ttl_ms: u64) -> Option<u64>;
+    def cmd lock(name: String, holder: u64, ttl_ms: u64) -> Option<u64>;
+    def cmd unlock(name: String, token: u64) -> bool;
+    def cmd renew(name: String, token: u64) -> bool;
+    def cmd expire_(name: String, token: u64);
+    def cmd release_holder_(holder: u64);
+    def qry holder(name: String) -> Option<(u64, u64)>;
+    def sub on_acquired(name: String, holder: u64) -> u64;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Waiter {
+    pub holder: u64,
+    pub ttl_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LockState {
+    pub holder: u64,
+    pub token: u64,
+    pub ttl_ms: u64,
+    pub waiters: VecDeque<Waiter>,
+}
+
+struct Lease {
+    holder: u64,
+    token: u64,
+    ttl_ms: u64,
+    deadline: i64,
+}
+
+struct Leases {
+    leases: Mutex<HashMap<String, Lease>>,
+    closed: AtomicBool,
+}
+
+pub struct Locks {
+    pub locks: HashMap<String, LockState>,
+    pub next_token: u64,
+    pub id: u64,
+    leases: Arc<Leases>,
+    callback: Option<SMCallback>,
+}
+
+impl Drop for Locks {
+    fn drop(&mut self) {
+        self.leases.closed.store(true, Ordering::Relaxed)
+    }
+}
+
+impl Lease {
+    fn new(state: &LockState) -> Self {
+        Self {
+            holder: state.holder,
+            token: state.token,
+            ttl_ms: state.ttl_ms,
+            deadline: get_time() + state.ttl_ms as i64,
+        }
+    }
+    fn expired(&self, now: i64) -> bool {
+        // Zero TTL locks are only released by unlock or the holder went offline
+        self.ttl_ms > 0 && self.deadline <= now
+    }
+}
+
+impl Locks {
+    fn grant(&mut self, name: &String, holder: u64, ttl_ms: u64, waiters: VecDeque<Waiter>) -> u64 {
+        let token = self.next_token;
+        self.next_token += 1;
+        let state = LockState {
+            holder,
+            token,
+            ttl_ms,
+            waiters,
+        };
+        self.leases
+            .leases
+            .lock()
+            .insert(name.clone(), Lease::new(&state));
+        self.locks.insert(name.clone(), state);
+        token
+    }
+    fn acquire(&mut self, name: String, holder: u64, ttl_ms: u64, wait: bool) -> Option<u64> {
+        match self.locks.get_mut(&name) {
+            None => Some(self.grant(&name, holder, ttl_ms, VecDeque::new())),
+            // Reentrant for the same holder, with a new token to fence writes of the last one
+            Some(state) if state.holder == holder => {
+                state.token = self.next_token;
+                self.next_token += 1;
+                self.leases.leases.lock().insert(name, Lease::new(state));
+                Some(state.token)
+            }
+            Some(state) => {
+                if wait && !state.waiters.iter().any(|w| w.holder == holder) {
+                    state.waiters.push_back(Waiter { holder, ttl_ms });
+                }
+                None
+            }
+        }
+    }
+    // Release the lock and hand it over to the first waiter
+    async fn release(&mut self, name: String) {
+        let mut waiters = match self.locks.remove(&name) {
+            Some(state) => state.waiters,
+            None => return,
+        };
+        match waiters.pop_front() {
+            Some(waiter) => {
+                let token = self.grant(&name, waiter.holder, waiter.ttl_ms, waiters);
+                let msg = commands::on_acquired::new(&name, &waiter.holder);
+                cb_notify(&self.callback, msg, || token).await;
+            }
+            None => {
+                self.leases.leases.lock().remove(&name);
+            }
+        }
+    }
+    fn token_matches(&self, name: &String, token: u64) -> bool {
+        self.locks
+            .get(name)
+            .map(|state| state.token == token)
+            .unwrap_or(false)
+    }
+    fn reset_leases(&self) {
+        let mut leases = self.leases.leases.lock();
+        leases.clear();
+        for (name, state) in &self.locks {
+            leases.insert(name.clone(), Lease::new(state));
+        }
+    }
+}
+
+impl StateMachineCmds for Locks {
+    fn try_lock(&mut self, name: String, holder: u64, ttl_ms: u64) -> BoxFuture<Option<u64>> {
+        future::ready(self.acquire(name, holder, ttl_ms, false)).boxed()
+    }
+    fn lock(&mut self, name: String, holder: u64, ttl_ms: u64) -> BoxFuture<Option<u64>> {
+        future::ready(self.acquire(name, holder, ttl_ms, true)).boxed()
+    }
+    fn unlock(&mut self, name: String, token: u64) -> BoxFuture<bool> {
+        async move {
+            if !self.token_matches(&name, token) {
+                return false;
+            }
+            self.release(name).await;
+            true
+        }
+        .boxed()
+    }
+    fn renew(&mut self, name: String, token: u64) -> BoxFuture<bool> {
+        if !self.token_matches(&name, token) {
+            return future::ready(false).boxed();
+        }
+        if let Some(lease) = self.leases.leases.lock().get_mut(&name) {
+            lease.deadline = get_time() + lease.ttl_ms as i64;
+        }
+        future::ready(true).boxed()
+    }
+    fn expire_(&mut self, name: String, token: u64) -> BoxFuture<()> {
+        async move {
+            if self.token_matches(&name, token) {
+                debug!("Lock {} with token {} expired", name, token);
+                self.release(name).await;
+            }
+        }
+        .boxed()
+    }
+    fn release_holder_(&mut self, holder: u64) -> BoxFuture<()> {
+        async move {
+            let mut held = vec![];
+            for (name, state) in self.locks.iter_mut() {
+                state.waiters.retain(|w| w.holder != holder);
+                if state.holder == holder {
+                    held.push(name.clone());
+                }
+            }
+            // Tokens are handed out in order of names, not in the order of the hash map that
+            // differs among replicas
+            held.sort();
+            for name in held {
+                debug!("Releasing lock {} of offline holder {}", name, holder);
+                self.release(name).await;
+            }
+        }
+        .boxed()
+    }
+    fn holder(&self, name: String) -> BoxFuture<Option<(u64, u64)>> {
+        future::ready(self.locks.get(&name).map(|s| (s.holder, s.token))).boxed()
+    }
+}
+
+impl StateMachineCtl for Locks {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(serialize(&(&self.locks, self.next_token)))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        match deserialize(data.as_slice()) {
+            Some((locks, next_token)) => {
+                self.locks = locks;
+                self.next_token = next_token;
+                self.reset_leases();
+            }
+            None => error!("Cannot decode snapshot of locks {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl Locks {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+        let leases = Arc::new(Leases {
+            leases: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(watch_leases(id, raft_service.clone(), leases.clone()));
+        raft_service
+            .register_state_machine(Box::new(Locks {
+                locks: HashMap::new(),
+                next_token: 1,
+                id,
+                leases,
+                callback: Some(SMCallback::new(id, raft_service.clone()).await),
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
+    }
+}
+
+// Leader issues commands to release expired locks and locks of offline members
+async fn watch_leases(sm_id: u64, raft_service: Arc<RaftService>, leases: Arc<Leases>) {
+    let mut was_leader = false;
+    while !leases.closed.load(Ordering::Relaxed) {
+        sleep(Duration::from_millis(WATCH_INTERVAL_MS)).await;
+        if !raft_service.is_leader() {
+            was_leader = false;
+            continue;
+        }
+        let now = get_time();
+        let (expired, holders) = {
+            let mut leases = leases.leases.lock();
+            if !was_leader {
+                for lease in leases.values_mut() {
+                    lease.deadline = now + lease.ttl_ms as i64;
+                }
+            }
+            let expired: Vec<_> = leases
+                .iter()
+                .filter(|(_, lease)| lease.expired(now))
+                .map(|(name, lease)| (name.clone(), lease.token))
+                .collect();
+            let holders: HashSet<_> = leases.values().map(|lease| lease.holder).collect();
+            (expired, holders)
+        };
+        was_leader = true;
+        for (name, token) in expired {
+            let (fn_id, _, data) = commands::expire_::new(&name, &token).encode();
+            command(&raft_service, sm_id, fn_id, data).await;
+        }
+        if holders.is_empty() {
+            continue;
+        }
+        for holder in offline_members(&raft_service).await.intersection(&holders) {
+            let (fn_id, _, data) = commands::release_holder_::new(holder).encode();
+            command(&raft_service, sm_id, fn_id, data).await;
+        }
+    }
+    debug!("Lock lease watcher for {} exiting", sm_id);
+}
+
+// Lock client for a holder, usually the member id from `MemberService::get_server_id`
+pub struct LockClient {
+    sm: client::SMClient,
+    holder: u64,
+    ttl_ms: u64,
+}
+
+impl LockClient {
+    // Locks expire after `ttl_ms` without renewal, zero for no expiration
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>, holder: u64, ttl_ms: u64) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            holder,
+            ttl_ms,
+        }
+    }
+    // Fencing token when acquired
+    pub async fn try_lock(&self, name: &str) -> Result<Option<u64>, ExecError> {
+        self.sm
+            .try_lock(&name.to_string(), &self.holder, &self.ttl_ms)
+            .await
+    }
+    // Wait in the queue of the lock until acquired
+    pub async fn lock(&self, name: &str) -> Result<Result<u64, SubscriptionError>, ExecError> {
+        let name = name.to_string();
+        // Subscribe before queueing so the hand over cannot be missed
+        let mut acquired = match self.sm.streams(1).on_acquired(&name, &self.holder).await? {
+            Ok(stream) => stream,
+            Err(e) => return Ok(Err(e)),
+        };
+        if let Some(token) = self.sm.lock(&name, &self.holder, &self.ttl_ms).await? {
+            return Ok(Ok(token));
+        }
+        loop {
+            match timeout(Duration::from_millis(LOCK_POLL_MS), acquired.next()).await {
+                Ok(Some(Ok(token))) => return Ok(Ok(token)),
+                Ok(None) => return Ok(Err(SubscriptionError::RemoteError)),
+                Ok(Some(Err(_))) | Err(_) => {}
+            }
+            // The notification may be lost, e.g. across leader changes. Queue again when free
+            match self.sm.holder(&name).await? {
+                Some((holder, token)) if holder == self.holder => return Ok(Ok(token)),
+                Some(_) => {}
+                None => {
+                    if let Some(token) = self.sm.lock(&name, &self.holder, &self.ttl_ms).await? {
+                        return Ok(Ok(token));
+                    }
+                }
+            }
+        }
+    }
+    pub async fn unlock(&self, name: &str, token: u64) -> Result<bool, ExecError> {
+        self.sm.unlock(&name.to_string(), &token).await
+    }
+    pub async fn renew(&self, name: &str, token: u64) -> Result<bool, ExecError> {
+        self.sm.renew(&name.to_string(), &token).await
+    }
+    // Current holder and fencing token
+    pub async fn holder(&self, name: &str) -> Result<Option<(u64, u64)>, ExecError> {
+        self.sm.holder(&name.to_string()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::membership::server::Membership;
+    use crate::membership::DEFAULT_SERVICE_ID as MEMBERSHIP_SERVICE_ID;
+    use crate::raft::{Options, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+    use bifrost_hasher::hash_str;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn locks() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2041");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Membership::new(&server, &raft_service).await;
+        Locks::new(&raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let a = LockClient::new(DEFAULT_SERVICE_ID, &raft_client, 1, 0);
+        let b = LockClient::new(DEFAULT_SERVICE_ID, &raft_client, 2, 0);
+
+        let t1 = a.try_lock("x").await.unwrap().unwrap();
+        assert_eq!(b.try_lock("x").await.unwrap(), None);
+        let t1 = {
+            let reentered = a.try_lock("x").await.unwrap().unwrap();
+            assert!(reentered > t1);
+            assert!(!a.renew("x", t1).await.unwrap());
+            reentered
+        };
+        // Queue b, unlock hands the lock over with a new token
+        assert_eq!(b.sm.lock(&"x".to_string(), &2, &0).await.unwrap(), None);
+        assert!(a.unlock("x", t1).await.unwrap());
+        let (holder, t2) = a.holder("x").await.unwrap().unwrap();
+        assert_eq!(holder, 2);
+        assert!(t2 > t1);
+        assert!(!a.unlock("x", t1).await.unwrap());
+        assert!(!b.renew("x", t1).await.unwrap());
+        assert!(b.renew("x", t2).await.unwrap());
+        assert!(b.unlock("x", t2).await.unwrap());
+        assert_eq!(a.holder("x").await.unwrap(), None);
+
+        // Released when TTL passed without renewal
+        let c = LockClient::new(DEFAULT_SERVICE_ID, &raft_client, 3, 1000);
+        let t3 = c.try_lock("y").await.unwrap().unwrap();
+        assert!(t3 > t2);
+        async_wait_secs().await;
+        assert_eq!(c.holder("y").await.unwrap(), None);
+        assert!(!c.renew("y", t3).await.unwrap());
+
+        // Released when the holder went offline, the member never sends heartbeats
+        let member_addr = String::from("127.0.0.1:2042");
+        let (fn_id, _, data) = crate::membership::raft::commands::join::new(&member_addr).encode();
+        command(&raft_service, MEMBERSHIP_SERVICE_ID, fn_id, data).await;
+        let d = LockClient::new(DEFAULT_SERVICE_ID, &raft_client, hash_str(&member_addr), 0);
+        let t4 = d.try_lock("z").await.unwrap().unwrap();
+        assert!(t4 > t3);
+        assert!(d.try_lock("w").await.unwrap().unwrap() > t4);
+        // Both are handed over to a in order of names
+        assert_eq!(a.sm.lock(&"z".to_string(), &1, &0).await.unwrap(), None);
+        assert_eq!(a.sm.lock(&"w".to_string(), &1, &0).await.unwrap(), None);
+        let mut released = false;
+        for _ in 0..10 {
+            async_wait_secs().await;
+            if d.holder("z").await.unwrap().map(|(holder, _)| holder) == Some(1) {
+                released = true;
+                break;
+            }
+        }
+        assert!(released);
+        let (w_holder, w_token) = a.holder("w").await.unwrap().unwrap();
+        let (_, z_token) = a.holder("z").await.unwrap().unwrap();
+        assert_eq!(w_holder, 1);
+        assert!(w_token > t4);
+        assert_eq!(z_token, w_token + 1);
+    }
+}
@@ -0,0 +1,538 @@
+// Replicated ordered map, typed keys are encoded with `MapKey` to keep their order
+
+use crate::raft::client::{RaftClient, SubscriptionError, SubscriptionReceipt};
+use crate::raft::state_machine::callback::pattern::ArgFilter;
+use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::RaftService;
+use crate::utils::serde::{deserialize, serialize};
+use bifrost_plugins::hash_ident;
+use futures::FutureExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::sync::Arc;
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_MAP) as u64;
+
+pub type Entry = (Vec<u8>, Vec<u8>);
+//                  (key, old value, new value)
+pub type Change = (Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+raft_state_machine! {
+    def qry get(key: Vec<u8>) -> Option<Vec<u8>>;
+    def qry get_batch(keys: Vec<Vec<u8>>) -> Vec<Option<Vec<u8>>>;
+    def qry range(start: Option<Vec<u8>>, end: Option<Vec<u8>>, limit: u64) -> Vec<Entry>;
+    def qry len() -> u64;
+    def cmd put(key: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>>;
+    def cmd put_batch(entries: Vec<Entry>);
+    def cmd remove(key: Vec<u8>) -> Option<Vec<u8>>;
+    def cmd compare_and_swap(key: Vec<u8>, expected: Option<Vec<u8>>, value: Option<Vec<u8>>) -> Result<(), Option<Vec<u8>>>;
+    def sub on_changed(key: Vec<u8>) -> Change;
+}
+
+pub struct Map {
+    pub map: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub id: u64,
+    callback: Option<SMCallback>,
+}
+
+impl Map {
+    async fn changed(&self, key: Vec<u8>, old: Option<Vec<u8>>, new: Option<Vec<u8>>) {
+        if old != new {
+            let msg = commands::on_changed::new(&key);
+            cb_notify(&self.callback, msg, || (key, old, new)).await;
+        }
+    }
+    fn set(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        match value {
+            Some(value) => self.map.insert(key, value),
+            None => self.map.remove(&key),
+        }
+    }
+}
+
+impl StateMachineCmds for Map {
+    fn get(&self, key: Vec<u8>) -> BoxFuture<Option<Vec<u8>>> {
+        future::ready(self.map.get(&key).cloned()).boxed()
+    }
+    fn get_batch(&self, keys: Vec<Vec<u8>>) -> BoxFuture<Vec<Option<Vec<u8>>>> {
+        future::ready(keys.iter().map(|key| self.map.get(key).cloned()).collect()).boxed()
+    }
+    fn range(
+        &self,
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        limit: u64,
+    ) -> BoxFuture<Vec<Entry>> {
+        let start = start.map(Included).unwrap_or(Unbounded);
+        let end = end.map(Excluded).unwrap_or(Unbounded);
+        if let (Included(s), Excluded(e)) = (&start, &end) {
+            if s >= e {
+                return future::ready(vec![]).boxed();
+            }
+        }
+        future::ready(
+            self.map
+                .range((start, end))
+                .take(limit as usize)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        )
+        .boxed()
+    }
+    fn len(&self) -> BoxFuture<u64> {
+        future::ready(self.map.len() as u64).boxed()
+    }
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> BoxFuture<Option<Vec<u8>>> {
+        async move {
+            let old = self.map.insert(key.clone(), value.clone());
+            self.changed(key, old.clone(), Some(value)).await;
+            old
+        }
+        .boxed()
+    }
+    fn put_batch(&mut self, entries: Vec<Entry>) -> BoxFuture<()> {
+        async move {
+            for (key, value) in entries {
+                let old = self.map.insert(key.clone(), value.clone());
+                self.changed(key, old, Some(value)).await;
+            }
+        }
+        .boxed()
+    }
+    fn remove(&mut self, key: Vec<u8>) -> BoxFuture<Option<Vec<u8>>> {
+        async move {
+            let old = self.map.remove(&key);
+            self.changed(key, old.clone(), None).await;
+            old
+        }
+        .boxed()
+    }
+    fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        value: Option<Vec<u8>>,
+    ) -> BoxFuture<Result<(), Option<Vec<u8>>>> {
+        async move {
+            let current = self.map.get(&key).cloned();
+            if current != expected {
+                return Err(current);
+            }
+            self.set(key.clone(), value.clone());
+            self.changed(key, current, value).await;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+impl StateMachineCtl for Map {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(serialize(&self.map))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        match deserialize(data.as_slice()) {
+            Some(map) => self.map = map,
+            None => error!("Cannot decode snapshot of map {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl Map {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+        raft_service
+            .register_state_machine(Box::new(Map {
+                map: BTreeMap::new(),
+                id,
+                callback: Some(SMCallback::new(id, raft_service.clone()).await),
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
+    }
+}
+
+// Keys encoded into bytes in the same order as the keys
+pub trait MapKey: Sized {
+    fn encode_key(&self) -> Vec<u8>;
+    fn decode_key(data: &[u8]) -> Option<Self>;
+}
+
+macro_rules! unsigned_map_key {
+    ($($t:ty),*) => {$(
+        impl MapKey for $t {
+            fn encode_key(&self) -> Vec<u8> {
+                self.to_be_bytes().to_vec()
+            }
+            fn decode_key(data: &[u8]) -> Option<Self> {
+                let mut bytes = [0u8; std::mem::size_of::<$t>()];
+                if data.len() != bytes.len() {
+                    return None;
+                }
+                bytes.copy_from_slice(data);
+                Some(<$t>::from_be_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+// Flip the sign bit so negative numbers come first
+macro_rules! signed_map_key {
+    ($($t:ty => $u:ty),*) => {$(
+        impl MapKey for $t {
+            fn encode_key(&self) -> Vec<u8> {
+                ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_key()
+            }
+            fn decode_key(data: &[u8]) -> Option<Self> {
+                <$u>::decode_key(data).map(|u| (u ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*};
+}
+
+unsigned_map_key!(u8, u16, u32, u64);
+signed_map_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64);
+
+impl MapKey for String {
+    fn encode_key(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+    fn decode_key(data: &[u8]) -> Option<Self> {
+        String::from_utf8(data.to_vec()).ok()
+    }
+}
+
+impl MapKey for Vec<u8> {
+    fn encode_key(&self) -> Vec<u8> {
+        self.clone()
+    }
+    fn decode_key(data: &[u8]) -> Option<Self> {
+        Some(data.to_vec())
+    }
+}
+
+// Map client with typed keys and values. Entries not matching the types, like those written
+// by clients of other types, fail with `CannotDecode` of the function reading them
+pub struct MapClient<K, V> {
+    sm: client::SMClient,
+    sm_id: u64,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+fn decode_value<V: DeserializeOwned>(
+    sm_id: u64,
+    fn_id: u64,
+    data: Vec<u8>,
+) -> Result<V, ExecError> {
+    deserialize(data.as_slice()).ok_or(ExecError::CannotDecode { sm_id, fn_id })
+}
+
+fn decode_key<K: MapKey>(sm_id: u64, fn_id: u64, data: Vec<u8>) -> Result<K, ExecError> {
+    K::decode_key(data.as_slice()).ok_or(ExecError::CannotDecode { sm_id, fn_id })
+}
+
+impl<K, V> MapClient<K, V>
+where
+    K: MapKey + Send + 'static,
+    V: Serialize + DeserializeOwned + Send + 'static,
+{
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            sm_id,
+            _types: PhantomData,
+        }
+    }
+    fn decode_value(&self, fn_id: u64, data: Option<Vec<u8>>) -> Result<Option<V>, ExecError> {
+        data.map(|v| decode_value(self.sm_id, fn_id, v)).transpose()
+    }
+    pub async fn get(&self, key: &K) -> Result<Option<V>, ExecError> {
+        let value = self.sm.get(&key.encode_key()).await?;
+        self.decode_value(hash_ident!(get) as u64, value)
+    }
+    pub async fn get_batch(&self, keys: &[K]) -> Result<Vec<Option<V>>, ExecError> {
+        let keys = keys.iter().map(MapKey::encode_key).collect();
+        let values = self.sm.get_batch(&keys).await?;
+        values
+            .into_iter()
+            .map(|v| self.decode_value(hash_ident!(get_batch) as u64, v))
+            .collect()
+    }
+    // Entries from `start` inclusive to `end` exclusive, in order of keys
+    pub async fn range(
+        &self,
+        start: Option<&K>,
+        end: Option<&K>,
+        limit: u64,
+    ) -> Result<Vec<(K, V)>, ExecError> {
+        let start = start.map(MapKey::encode_key);
+        let end = end.map(MapKey::encode_key);
+        let entries = self.sm.range(&start, &end, &limit).await?;
+        let fn_id = hash_ident!(range) as u64;
+        entries
+            .into_iter()
+            .map(|(k, v)| {
+                Ok((
+                    decode_key(self.sm_id, fn_id, k)?,
+                    decode_value(self.sm_id, fn_id, v)?,
+                ))
+            })
+            .collect()
+    }
+    pub async fn len(&self) -> Result<u64, ExecError> {
+        self.sm.len().await
+    }
+    pub async fn put(&self, key: &K, value: &V) -> Result<Option<V>, ExecError> {
+        let old = self.sm.put(&key.encode_key(), &serialize(value)).await?;
+        self.decode_value(hash_ident!(put) as u64, old)
+    }
+    pub async fn put_batch(&self, entries: &[(K, V)]) -> Result<(), ExecError> {
+        let entries = entries
+            .iter()
+            .map(|(k, v)| (k.encode_key(), serialize(v)))
+            .collect();
+        self.sm.put_batch(&entries).await
+    }
+    pub async fn remove(&self, key: &K) -> Result<Option<V>, ExecError> {
+        let old = self.sm.remove(&key.encode_key()).await?;
+        self.decode_value(hash_ident!(remove) as u64, old)
+    }
+    // Set the value, or remove it with None, only when current value is `expected`.
+    // Returns the current value when it is not
+    pub async fn compare_and_swap(
+        &self,
+        key: &K,
+        expected: Option<&V>,
+        value: Option<&V>,
+    ) -> Result<Result<(), Option<V>>, ExecError> {
+        let expected = expected.map(serialize);
+        let value = value.map(serialize);
+        let res = self
+            .sm
+            .compare_and_swap(&key.encode_key(), &expected, &value)
+            .await?;
+        match res {
+            Ok(()) => Ok(Ok(())),
+            Err(current) => Ok(Err(
+                self.decode_value(hash_ident!(compare_and_swap) as u64, current)?
+            )),
+        }
+    }
+    // Changes of the key as (key, old value, new value). Changes not matching the types are
+    // logged and skipped
+    pub async fn on_changed<F>(
+        &self,
+        key: &K,
+        f: F,
+    ) -> Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>
+    where
+        F: Fn((K, Option<V>, Option<V>)) -> BoxFuture<'static, ()> + 'static + Send + Sync,
+    {
+        let sm_id = self.sm_id;
+        self.sm
+            .on_changed(
+                move |change| with_change(sm_id, change, &f),
+                &key.encode_key(),
+            )
+            .await
+    }
+    pub async fn on_any_changed<F>(
+        &self,
+        f: F,
+    ) -> Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>
+    where
+        F: Fn((K, Option<V>, Option<V>)) -> BoxFuture<'static, ()> + 'static + Send + Sync,
+    {
+        let sm_id = self.sm_id;
+        let pattern = commands::on_changed::pattern(&ArgFilter::Any);
+        self.sm
+            .subscribe_pattern(pattern, move |change| with_change(sm_id, change, &f))
+            .await
+    }
+}
+
+fn decode_change<K, V>(
+    sm_id: u64,
+    (key, old, new): Change,
+) -> Result<(K, Option<V>, Option<V>), ExecError>
+where
+    K: MapKey,
+    V: DeserializeOwned,
+{
+    let fn_id = hash_ident!(on_changed) as u64;
+    Ok((
+        decode_key(sm_id, fn_id, key)?,
+        old.map(|v| decode_value(sm_id, fn_id, v)).transpose()?,
+        new.map(|v| decode_value(sm_id, fn_id, v)).transpose()?,
+    ))
+}
+
+fn with_change<K, V, F>(sm_id: u64, change: Change, f: &F) -> BoxFuture<'static, ()>
+where
+    K: MapKey,
+    V: DeserializeOwned,
+    F: Fn((K, Option<V>, Option<V>)) -> BoxFuture<'static, ()>,
+{
+    match decode_change(sm_id, change) {
+        Ok(change) => f(change),
+        Err(e) => {
+            warn!("Skipped map change of state machine {}, {:?}", sm_id, e);
+            future::ready(()).boxed()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raft::state_machine::callback::client::SubscriptionService;
+    use crate::raft::state_machine::configs::commands::subscribe as conf_subscribe;
+    use crate::raft::state_machine::configs::CONFIG_SM_ID;
+    use crate::raft::{Options, RaftMsg, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+    use bifrost_hasher::hash_bytes;
+
+    #[test]
+    fn key_order() {
+        let mut nums = vec![-300i64, -1, 0, 1, 2, 255, 256, i64::MIN, i64::MAX];
+        let mut keys: Vec<_> = nums.iter().map(MapKey::encode_key).collect();
+        nums.sort();
+        keys.sort();
+        let decoded: Vec<i64> = keys
+            .into_iter()
+            .map(|k| decode_key(0, 0, k).unwrap())
+            .collect();
+        assert_eq!(decoded, nums);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn map() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2039");
+        let subscriber_addr = String::from("127.0.0.1:2040");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Map::new(&raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let map = MapClient::<i32, String>::new(DEFAULT_SERVICE_ID, &raft_client);
+
+        // Subscribe to key 1 with a local subscription service
+        let subscriber = Server::new(&subscriber_addr);
+        Server::listen_and_resume(&subscriber).await;
+        let sub_service = SubscriptionService::initialize(&subscriber).await;
+        let (fn_id, _, pattern_data) = commands::on_changed::new(&1i32.encode_key()).encode();
+        let key = (
+            RAFT_SERVICE_ID,
+            DEFAULT_SERVICE_ID,
+            fn_id,
+            hash_bytes(&pattern_data),
+        );
+        let changes = Arc::new(parking_lot::Mutex::new(vec![]));
+        let changes_clone = changes.clone();
+        sub_service.subs.write().await.insert(
+            key,
+            vec![(
+                Box::new(move |data: Vec<u8>| {
+                    let change = commands::on_changed::decode_return(&data).unwrap();
+                    changes_clone
+                        .lock()
+                        .push(decode_change::<i32, String>(0, change).unwrap());
+                    future::ready(()).boxed()
+                }),
+                0,
+            )],
+        );
+        raft_client
+            .execute(
+                CONFIG_SM_ID,
+                conf_subscribe::new(&key, &subscriber_addr, &sub_service.session_id),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+        let a = String::from("a");
+        let b = String::from("b");
+        assert_eq!(map.put(&1, &a).await.unwrap(), None);
+        assert_eq!(map.get(&1).await.unwrap(), Some(a.clone()));
+        assert_eq!(map.put(&1, &b).await.unwrap(), Some(a.clone()));
+        assert_eq!(
+            map.compare_and_swap(&1, Some(&a), None).await.unwrap(),
+            Err(Some(b.clone()))
+        );
+        assert_eq!(
+            map.compare_and_swap(&1, Some(&b), None).await.unwrap(),
+            Ok(())
+        );
+        assert_eq!(map.get(&1).await.unwrap(), None);
+
+        let entries: Vec<_> = (-5..5).map(|i| (i, i.to_string())).collect();
+        map.put_batch(&entries).await.unwrap();
+        assert_eq!(map.len().await.unwrap(), 10);
+        assert_eq!(
+            map.get_batch(&[-5, 10]).await.unwrap(),
+            vec![Some(String::from("-5")), None]
+        );
+        assert_eq!(
+            map.range(Some(&-2), Some(&2), 10).await.unwrap(),
+            entries[3..7].to_vec()
+        );
+        assert_eq!(
+            map.range(None, None, 3).await.unwrap(),
+            entries[..3].to_vec()
+        );
+        assert_eq!(map.remove(&-5).await.unwrap(), Some(String::from("-5")));
+        assert_eq!(map.len().await.unwrap(), 9);
+
+        async_wait_secs().await;
+        // Delivered concurrently, the order may vary
+        let mut changes = changes.lock().clone();
+        changes.sort();
+        assert_eq!(
+            changes,
+            vec![
+                (1, None, Some(String::from("1"))),
+                (1, None, Some(a.clone())),
+                (1, Some(a), Some(b.clone())),
+                (1, Some(b), None),
+            ]
+        );
+
+        // Value written by a client of another type
+        let numbers = MapClient::<i32, u64>::new(DEFAULT_SERVICE_ID, &raft_client);
+        numbers.put(&100, &7).await.unwrap();
+        match map.get(&100).await {
+            Err(ExecError::CannotDecode { sm_id, .. }) => assert_eq!(sm_id, DEFAULT_SERVICE_ID),
+            other => panic!("Expect decode failure, got {:?}", other),
+        }
+        match map.range(Some(&100), None, 1).await {
+            Err(ExecError::CannotDecode { .. }) => {}
+            other => panic!("Expect decode failure, got {:?}", other),
+        }
+        assert_eq!(numbers.get(&100).await.unwrap(), Some(7));
+    }
+}
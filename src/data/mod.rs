@@ -0,0 +1,96 @@
+// Built-in replicated data structures as raft state machines
+
+use crate::membership::raft::commands::{all_members, group_members};
+use crate::membership::DEFAULT_SERVICE_ID as MEMBERSHIP_SERVICE_ID;
+use crate::raft::{ClientQryResponse, LogEntry, RaftMsg, RaftService, Service as raft_svr_trait};
+use bifrost_hasher::hash_str;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+pub mod barrier;
+pub mod counter;
+pub mod kv;
+pub mod lease;
+pub mod lock;
+pub mod map;
+pub mod queue;
+pub mod rate_limit;
+pub mod scheduler;
+pub mod semaphore;
+pub mod set;
+pub mod sorted_set;
+pub mod topic;
+
+// Commands issued by the leader itself, e.g. to release expired leases
+pub(crate) async fn command(
+    raft_service: &Arc<RaftService>,
+    sm_id: u64,
+    fn_id: u64,
+    data: Vec<u8>,
+) {
+    raft_service
+        .c_command(LogEntry {
+            id: 0,
+            term: 0,
+            sm_id,
+            fn_id,
+            data,
+            sm_version: 0,
+            time: 0,
+        })
+        .await;
+}
+
+// Members known to the membership state machine as offline, empty when it is not registered
+pub(crate) async fn offline_members(raft_service: &Arc<RaftService>) -> HashSet<u64> {
+    let (fn_id, _, data) = all_members::new(&false).encode();
+    let res = raft_service
+        .c_query(LogEntry {
+            id: 0,
+            term: 0,
+            sm_id: MEMBERSHIP_SERVICE_ID,
+            fn_id,
+            data,
+            sm_version: 0,
+            time: 0,
+        })
+        .await;
+    match res {
+        ClientQryResponse::Success { data: Ok(data), .. } => {
+            match all_members::decode_return(&data) {
+                Some((members, _)) => members
+                    .into_iter()
+                    .filter(|member| !member.online)
+                    .map(|member| member.id)
+                    .collect(),
+                None => HashSet::new(),
+            }
+        }
+        _ => HashSet::new(),
+    }
+}
+
+// Online members of the group, empty when the group or the membership state machine is absent
+pub(crate) async fn online_group_members(raft_service: &Arc<RaftService>, group: &str) -> Vec<u64> {
+    let (fn_id, _, data) = group_members::new(&hash_str(group), &true).encode();
+    let res = raft_service
+        .c_query(LogEntry {
+            id: 0,
+            term: 0,
+            sm_id: MEMBERSHIP_SERVICE_ID,
+            fn_id,
+            data,
+            sm_version: 0,
+            time: 0,
+        })
+        .await;
+    match res {
+        ClientQryResponse::Success { data: Ok(data), .. } => {
+            match group_members::decode_return(&data).flatten() {
+                Some((members, _)) => members.into_iter().map(|member| member.id).collect(),
+                None => vec![],
+            }
+        }
+        _ => vec![],
+    }
+}
@@ -0,0 +1,506 @@
+// Replicated FIFO work queues with visibility timeouts and dead letter queues
+
+use super::command;
+use crate::raft::client::{RaftClient, SubscriptionError, SubscriptionReceipt};
+use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::{RaftMsg, RaftService};
+use crate::utils::serde::{deserialize, serialize};
+use crate::utils::time::get_time;
+use bifrost_plugins::hash_ident;
+use futures::{FutureExt, StreamExt};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_QUEUE) as u64;
+pub static DEFAULT_MAX_DELIVERIES: u32 = 5;
+pub static DEAD_LETTER_SUFFIX: &str = ".dead";
+
+static WATCH_INTERVAL_MS: u64 = 200;
+
+raft_state_machine! {
+    def cmd enqueue(queue: String, data: Vec<u8>) -> u64;
+    def cmd enqueue_batch(queue: String, items: Vec<Vec<u8>>) -> Vec<u64>;
+    def cmd dequeue(queue: String, visibility_ms: u64) -> Option<Delivery>;
+    def cmd ack(queue: String, receipt: u64) -> bool;
+    def cmd nack(queue: String, receipt: u64) -> bool;
+    def cmd expire_(queue: String, receipt: u64);
+    def qry len(queue: String) -> u64;
+    def qry in_flight(queue: String) -> u64;
+    def sub on_ready(queue: String) -> u64;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    pub id: u64,
+    pub data: Vec<u8>,
+    pub deliveries: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Delivery {
+    pub receipt: u64,
+    pub id: u64,
+    pub data: Vec<u8>,
+    // Including this delivery
+    pub deliveries: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct QueueState {
+    pub ready: VecDeque<Message>,
+    // Receipt to message and visibility timeout
+    pub in_flight: HashMap<u64, (Message, u64)>,
+}
+
+struct Visibilities {
+    deadlines: Mutex<HashMap<(String, u64), (u64, i64)>>,
+    closed: AtomicBool,
+}
+
+pub struct Queues {
+    pub queues: HashMap<String, QueueState>,
+    pub next_id: u64,
+    pub max_deliveries: u32,
+    pub id: u64,
+    visibilities: Arc<Visibilities>,
+    callback: Option<SMCallback>,
+}
+
+impl Drop for Queues {
+    fn drop(&mut self) {
+        self.visibilities.closed.store(true, Ordering::Relaxed)
+    }
+}
+
+pub fn dead_letter_queue(queue: &str) -> String {
+    format!("{}{}", queue, DEAD_LETTER_SUFFIX)
+}
+
+impl Queues {
+    fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+    fn push(&mut self, queue: &String, data: Vec<u8>) -> u64 {
+        let id = self.next_id();
+        self.queues
+            .entry(queue.clone())
+            .or_insert_with(Default::default)
+            .ready
+            .push_back(Message {
+                id,
+                data,
+                deliveries: 0,
+            });
+        id
+    }
+    async fn ready(&self, queue: &String) {
+        let len = self.queues.get(queue).map(|q| q.ready.len()).unwrap_or(0) as u64;
+        if len > 0 {
+            cb_notify(&self.callback, commands::on_ready::new(queue), || len).await;
+        }
+    }
+    fn take_in_flight(&mut self, queue: &String, receipt: u64) -> Option<Message> {
+        self.visibilities
+            .deadlines
+            .lock()
+            .remove(&(queue.clone(), receipt));
+        self.queues
+            .get_mut(queue)
+            .and_then(|q| q.in_flight.remove(&receipt))
+            .map(|(msg, _)| msg)
+    }
+    // Back to the head of the queue for redelivery, or to the dead letter queue
+    async fn redeliver(&mut self, queue: String, receipt: u64) -> bool {
+        let mut msg = match self.take_in_flight(&queue, receipt) {
+            Some(msg) => msg,
+            None => return false,
+        };
+        if msg.deliveries >= self.max_deliveries {
+            let dead_queue = dead_letter_queue(&queue);
+            debug!(
+                "Message {} delivered {} times, moving to {}",
+                msg.id, msg.deliveries, dead_queue
+            );
+            msg.deliveries = 0;
+            self.queues
+                .entry(dead_queue.clone())
+                .or_insert_with(Default::default)
+                .ready
+                .push_back(msg);
+            self.ready(&dead_queue).await;
+        } else {
+            if let Some(q) = self.queues.get_mut(&queue) {
+                q.ready.push_front(msg);
+            }
+            self.ready(&queue).await;
+        }
+        true
+    }
+    fn reset_visibilities(&self) {
+        let now = get_time();
+        let mut deadlines = self.visibilities.deadlines.lock();
+        deadlines.clear();
+        for (name, queue) in &self.queues {
+            for (receipt, (_, visibility_ms)) in &queue.in_flight {
+                if *visibility_ms == 0 {
+                    continue;
+                }
+                deadlines.insert(
+                    (name.clone(), *receipt),
+                    (*visibility_ms, now + *visibility_ms as i64),
+                );
+            }
+        }
+    }
+}
+
+impl StateMachineCmds for Queues {
+    fn enqueue(&mut self, queue: String, data: Vec<u8>) -> BoxFuture<u64> {
+        async move {
+            let id = self.push(&queue, data);
+            self.ready(&queue).await;
+            id
+        }
+        .boxed()
+    }
+    fn enqueue_batch(&mut self, queue: String, items: Vec<Vec<u8>>) -> BoxFuture<Vec<u64>> {
+        async move {
+            let ids = items
+                .into_iter()
+                .map(|data| self.push(&queue, data))
+                .collect();
+            self.ready(&queue).await;
+            ids
+        }
+        .boxed()
+    }
+    fn dequeue(&mut self, queue: String, visibility_ms: u64) -> BoxFuture<Option<Delivery>> {
+        let receipt = self.next_id;
+        let delivery = match self.queues.get_mut(&queue) {
+            Some(q) => q.ready.pop_front().map(|mut msg| {
+                msg.deliveries += 1;
+                let delivery = Delivery {
+                    receipt,
+                    id: msg.id,
+                    data: msg.data.clone(),
+                    deliveries: msg.deliveries,
+                };
+                q.in_flight.insert(receipt, (msg, visibility_ms));
+                delivery
+            }),
+            None => None,
+        };
+        if delivery.is_some() {
+            self.next_id += 1;
+            if visibility_ms > 0 {
+                self.visibilities.deadlines.lock().insert(
+                    (queue, receipt),
+                    (visibility_ms, get_time() + visibility_ms as i64),
+                );
+            }
+        }
+        future::ready(delivery).boxed()
+    }
+    fn ack(&mut self, queue: String, receipt: u64) -> BoxFuture<bool> {
+        future::ready(self.take_in_flight(&queue, receipt).is_some()).boxed()
+    }
+    fn nack(&mut self, queue: String, receipt: u64) -> BoxFuture<bool> {
+        self.redeliver(queue, receipt).boxed()
+    }
+    fn expire_(&mut self, queue: String, receipt: u64) -> BoxFuture<()> {
+        async move {
+            if self.redeliver(queue, receipt).await {
+                debug!("Visibility timeout of receipt {} passed", receipt);
+            }
+        }
+        .boxed()
+    }
+    fn len(&self, queue: String) -> BoxFuture<u64> {
+        future::ready(self.queues.get(&queue).map(|q| q.ready.len()).unwrap_or(0) as u64).boxed()
+    }
+    fn in_flight(&self, queue: String) -> BoxFuture<u64> {
+        future::ready(
+            self.queues
+                .get(&queue)
+                .map(|q| q.in_flight.len())
+                .unwrap_or(0) as u64,
+        )
+        .boxed()
+    }
+}
+
+impl StateMachineCtl for Queues {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(serialize(&(&self.queues, self.next_id)))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        match deserialize(data.as_slice()) {
+            Some((queues, next_id)) => {
+                self.queues = queues;
+                self.next_id = next_id;
+                self.reset_visibilities();
+            }
+            None => error!("Cannot decode snapshot of queues {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl Queues {
+    pub async fn new_with_id(id: u64, max_deliveries: u32, raft_service: &Arc<RaftService>) {
+        let visibilities = Arc::new(Visibilities {
+            deadlines: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(watch_visibilities(
+            id,
+            raft_service.clone(),
+            visibilities.clone(),
+        ));
+        raft_service
+            .register_state_machine(Box::new(Queues {
+                queues: HashMap::new(),
+                next_id: 1,
+                max_deliveries,
+                id,
+                visibilities,
+                callback: Some(SMCallback::new(id, raft_service.clone()).await),
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, DEFAULT_MAX_DELIVERIES, raft_service).await
+    }
+}
+
+// Leader issues commands to redeliver messages whose visibility timeout passed
+async fn watch_visibilities(sm_id: u64, raft_service: Arc<RaftService>, vis: Arc<Visibilities>) {
+    let mut was_leader = false;
+    while !vis.closed.load(Ordering::Relaxed) {
+        sleep(Duration::from_millis(WATCH_INTERVAL_MS)).await;
+        if !raft_service.is_leader() {
+            was_leader = false;
+            continue;
+        }
+        let now = get_time();
+        let expired: Vec<_> = {
+            let mut deadlines = vis.deadlines.lock();
+            if !was_leader {
+                for (visibility_ms, deadline) in deadlines.values_mut() {
+                    *deadline = now + *visibility_ms as i64;
+                }
+            }
+            deadlines
+                .iter()
+                .filter(|(_, (_, deadline))| *deadline <= now)
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
+        was_leader = true;
+        for (queue, receipt) in expired {
+            let (fn_id, _, data) = commands::expire_::new(&queue, &receipt).encode();
+            command(&raft_service, sm_id, fn_id, data).await;
+        }
+    }
+    debug!("Queue visibility watcher for {} exiting", sm_id);
+}
+
+// Typed client for one queue. Items not matching the type fail with `CannotDecode`
+pub struct QueueClient<T> {
+    sm: client::SMClient,
+    sm_id: u64,
+    queue: String,
+    _type: PhantomData<fn() -> T>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypedDelivery<T> {
+    pub receipt: u64,
+    pub id: u64,
+    pub item: T,
+    pub deliveries: u32,
+}
+
+fn decode_delivery<T: DeserializeOwned>(
+    sm_id: u64,
+    fn_id: u64,
+    delivery: Delivery,
+) -> Result<TypedDelivery<T>, ExecError> {
+    let item =
+        deserialize(delivery.data.as_slice()).ok_or(ExecError::CannotDecode { sm_id, fn_id })?;
+    Ok(TypedDelivery {
+        receipt: delivery.receipt,
+        id: delivery.id,
+        item,
+        deliveries: delivery.deliveries,
+    })
+}
+
+impl<T> QueueClient<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>, queue: &str) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            sm_id,
+            queue: queue.to_string(),
+            _type: PhantomData,
+        }
+    }
+    pub async fn enqueue(&self, item: &T) -> Result<u64, ExecError> {
+        self.sm.enqueue(&self.queue, &serialize(item)).await
+    }
+    pub async fn enqueue_batch(&self, items: &[T]) -> Result<Vec<u64>, ExecError> {
+        let items = items.iter().map(serialize).collect();
+        self.sm.enqueue_batch(&self.queue, &items).await
+    }
+    // Message is redelivered when not acked in `visibility_ms`, zero to wait for ack forever
+    pub async fn dequeue(&self, visibility_ms: u64) -> Result<Option<TypedDelivery<T>>, ExecError> {
+        let delivery = self.sm.dequeue(&self.queue, &visibility_ms).await?;
+        let fn_id = hash_ident!(dequeue) as u64;
+        delivery
+            .map(|d| decode_delivery(self.sm_id, fn_id, d))
+            .transpose()
+    }
+    // Wait for messages to be ready when the queue is empty
+    pub async fn dequeue_wait(
+        &self,
+        visibility_ms: u64,
+    ) -> Result<Result<TypedDelivery<T>, SubscriptionError>, ExecError> {
+        // Subscribe before checking the queue so new messages cannot be missed
+        let mut ready = match self.sm.streams(1).on_ready(&self.queue).await? {
+            Ok(stream) => stream,
+            Err(e) => return Ok(Err(e)),
+        };
+        loop {
+            if let Some(delivery) = self.dequeue(visibility_ms).await? {
+                return Ok(Ok(delivery));
+            }
+            // Other consumers may take the message first, check again after every notification
+            if ready.next().await.is_none() {
+                return Ok(Err(SubscriptionError::RemoteError));
+            }
+        }
+    }
+    pub async fn ack(&self, receipt: u64) -> Result<bool, ExecError> {
+        self.sm.ack(&self.queue, &receipt).await
+    }
+    pub async fn nack(&self, receipt: u64) -> Result<bool, ExecError> {
+        self.sm.nack(&self.queue, &receipt).await
+    }
+    pub async fn len(&self) -> Result<u64, ExecError> {
+        self.sm.len(&self.queue).await
+    }
+    pub async fn in_flight(&self) -> Result<u64, ExecError> {
+        self.sm.in_flight(&self.queue).await
+    }
+    // Number of ready messages, when messages are enqueued or redelivered
+    pub async fn on_ready<F>(
+        &self,
+        f: F,
+    ) -> Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>
+    where
+        F: Fn(u64) -> BoxFuture<'static, ()> + 'static + Send + Sync,
+    {
+        self.sm.on_ready(f, &self.queue).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raft::{Options, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queue() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2043");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Queues::new_with_id(DEFAULT_SERVICE_ID, 2, &raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let jobs = QueueClient::<String>::new(DEFAULT_SERVICE_ID, &raft_client, "jobs");
+        let dead = QueueClient::<String>::new(
+            DEFAULT_SERVICE_ID,
+            &raft_client,
+            &dead_letter_queue("jobs"),
+        );
+        let job = |i: u32| format!("job-{}", i);
+
+        jobs.enqueue(&job(1)).await.unwrap();
+        jobs.enqueue_batch(&[job(2), job(3)]).await.unwrap();
+        assert_eq!(jobs.len().await.unwrap(), 3);
+
+        let first = jobs.dequeue(0).await.unwrap().unwrap();
+        assert_eq!(first.item, job(1));
+        assert_eq!(first.deliveries, 1);
+        assert_eq!(jobs.in_flight().await.unwrap(), 1);
+        // Nacked message is delivered again before the others
+        assert!(jobs.nack(first.receipt).await.unwrap());
+        assert!(!jobs.ack(first.receipt).await.unwrap());
+        let again = jobs.dequeue(0).await.unwrap().unwrap();
+        assert_eq!((again.id, again.deliveries), (first.id, 2));
+        assert_ne!(again.receipt, first.receipt);
+        // Delivered max times, goes to the dead letter queue
+        assert!(jobs.nack(again.receipt).await.unwrap());
+        assert_eq!(jobs.len().await.unwrap(), 2);
+        let dead_letter = dead.dequeue(0).await.unwrap().unwrap();
+        assert_eq!((dead_letter.item, dead_letter.deliveries), (job(1), 1));
+        assert!(dead.ack(dead_letter.receipt).await.unwrap());
+
+        let second = jobs.dequeue(0).await.unwrap().unwrap();
+        assert_eq!(second.item, job(2));
+        assert!(jobs.ack(second.receipt).await.unwrap());
+        assert!(!jobs.ack(second.receipt).await.unwrap());
+
+        // Redelivered after visibility timeout
+        let third = jobs.dequeue(500).await.unwrap().unwrap();
+        assert_eq!(third.item, job(3));
+        assert_eq!(jobs.len().await.unwrap(), 0);
+        assert_eq!(jobs.dequeue(0).await.unwrap(), None);
+        async_wait_secs().await;
+        assert_eq!(jobs.in_flight().await.unwrap(), 0);
+        assert!(!jobs.ack(third.receipt).await.unwrap());
+        let third = jobs.dequeue(0).await.unwrap().unwrap();
+        assert_eq!((third.item, third.deliveries), (job(3), 2));
+        assert!(jobs.ack(third.receipt).await.unwrap());
+
+        // Items of other types fail to decode instead of panicking
+        let numbers = QueueClient::<u64>::new(DEFAULT_SERVICE_ID, &raft_client, "jobs");
+        numbers.enqueue(&7).await.unwrap();
+        match jobs.dequeue(0).await {
+            Err(ExecError::CannotDecode { sm_id, .. }) => assert_eq!(sm_id, DEFAULT_SERVICE_ID),
+            other => panic!("Expect decode failure, got {:?}", other),
+        }
+    }
+}
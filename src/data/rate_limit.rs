@@ -0,0 +1,469 @@
+// Cluster wide token bucket rate limiters with FIFO waiters
+
+use super::{command, offline_members};
+use crate::raft::client::{RaftClient, SubscriptionError};
+use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::{applied_time, StateMachineCtl};
+use crate::raft::{RaftMsg, RaftService};
+use crate::utils::serde::{deserialize, serialize};
+use crate::utils::time::get_time;
+use bifrost_plugins::hash_ident;
+use futures::{FutureExt, StreamExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_RATE_LIMIT) as u64;
+
+pub const NOT_FOUND: u32 = 1;
+pub const EXCEEDS_CAPACITY: u32 = 2;
+
+static WATCH_INTERVAL_MS: u64 = 200;
+
+raft_state_machine! {
+    def cmd create(name: String, capacity: u64, rate: u64) -> bool;
+    def cmd try_acquire(name: String, member: u64, count: u64) -> bool | ExecError;
+    def cmd acquire(name: String, member: u64, count: u64) -> bool | ExecError;
+    def cmd cancel(name: String, member: u64) -> bool;
+    def cmd grant_();
+    def cmd release_member_(member: u64);
+    def qry tokens(name: String) -> Option<u64>;
+    def sub on_acquired(name: String, member: u64) -> u64;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Waiter {
+    pub member: u64,
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bucket {
+    pub capacity: u64,
+    // Tokens added per second
+    pub rate: u64,
+    // Tokens in thousandths, to keep the fractions of refills
+    pub millis: u64,
+    pub waiters: VecDeque<Waiter>,
+    // Leader clock of the last refill, 0 before the first command carrying the time
+    #[serde(default)]
+    pub refilled_at: i64,
+}
+
+// Waiting members and when the first waiter can be granted, watched by the leader
+struct Demand {
+    members: Mutex<HashSet<u64>>,
+    due: AtomicI64,
+    closed: AtomicBool,
+}
+
+pub struct RateLimiters {
+    pub buckets: HashMap<String, Bucket>,
+    pub id: u64,
+    demand: Arc<Demand>,
+    callback: Option<SMCallback>,
+}
+
+impl Drop for RateLimiters {
+    fn drop(&mut self) {
+        self.demand.closed.store(true, Ordering::Relaxed)
+    }
+}
+
+impl Bucket {
+    fn tokens(&self) -> u64 {
+        self.millis / 1000
+    }
+    fn millis_at(&self, now: i64) -> u64 {
+        let elapsed_ms = match self.refilled_at {
+            0 => 0,
+            refilled_at => (now - refilled_at).max(0) as u64,
+        };
+        let added = self.rate.saturating_mul(elapsed_ms);
+        let capacity = self.capacity.saturating_mul(1000);
+        self.millis.saturating_add(added).min(capacity)
+    }
+    // Clocks of leaders may disagree, refills never go back in time
+    fn refill(&mut self, now: i64) {
+        if now > self.refilled_at {
+            self.millis = self.millis_at(now);
+            self.refilled_at = now;
+        }
+    }
+    // Leader clock when the first waiter can be granted
+    fn due(&self) -> Option<i64> {
+        let waiter = self.waiters.front()?;
+        let missing = waiter
+            .count
+            .saturating_mul(1000)
+            .saturating_sub(self.millis);
+        if missing == 0 {
+            return Some(self.refilled_at);
+        }
+        if self.rate == 0 {
+            return None;
+        }
+        let wait_ms = (missing + self.rate - 1) / self.rate;
+        Some(self.refilled_at.saturating_add(wait_ms as i64))
+    }
+    fn take(&mut self, count: u64) -> bool {
+        if count > self.tokens() {
+            return false;
+        }
+        self.millis -= count * 1000;
+        true
+    }
+}
+
+impl RateLimiters {
+    fn take(
+        &mut self,
+        name: String,
+        member: u64,
+        count: u64,
+        wait: bool,
+    ) -> Result<bool, ExecError> {
+        let bucket = match self.buckets.get_mut(&name) {
+            Some(bucket) => bucket,
+            None => {
+                return Err(ExecError::state_machine(
+                    NOT_FOUND,
+                    format!("Rate limiter {} not found", name),
+                ))
+            }
+        };
+        if count > bucket.capacity {
+            return Err(ExecError::state_machine(
+                EXCEEDS_CAPACITY,
+                format!("Rate limiter {} holds {} tokens", name, bucket.capacity),
+            ));
+        }
+        bucket.refill(applied_time());
+        // Queued acquirers go first
+        if bucket.waiters.is_empty() && bucket.take(count) {
+            return Ok(true);
+        }
+        if wait && !bucket.waiters.iter().any(|w| w.member == member) {
+            bucket.waiters.push_back(Waiter { member, count });
+        }
+        Ok(false)
+    }
+    // Hand tokens over to waiters in order
+    async fn grant(&mut self) {
+        let now = applied_time();
+        let mut granted = vec![];
+        for (name, bucket) in self.buckets.iter_mut() {
+            if bucket.waiters.is_empty() {
+                continue;
+            }
+            bucket.refill(now);
+            while let Some(waiter) = bucket.waiters.front() {
+                if !bucket.take(waiter.count) {
+                    break;
+                }
+                granted.push((name.clone(), bucket.waiters.pop_front().unwrap()));
+            }
+        }
+        for (name, waiter) in granted {
+            let msg = commands::on_acquired::new(&name, &waiter.member);
+            cb_notify(&self.callback, msg, || waiter.count).await;
+        }
+    }
+    fn track(&self) {
+        let mut members = self.demand.members.lock();
+        members.clear();
+        for bucket in self.buckets.values() {
+            members.extend(bucket.waiters.iter().map(|w| w.member));
+        }
+        let due = self
+            .buckets
+            .values()
+            .filter_map(|bucket| bucket.due())
+            .min();
+        self.demand
+            .due
+            .store(due.unwrap_or(i64::MAX), Ordering::Relaxed);
+    }
+}
+
+impl StateMachineCmds for RateLimiters {
+    // Buckets start full, false when the rate limiter exists
+    fn create(&mut self, name: String, capacity: u64, rate: u64) -> BoxFuture<bool> {
+        if self.buckets.contains_key(&name) {
+            return future::ready(false).boxed();
+        }
+        let bucket = Bucket {
+            capacity,
+            rate,
+            millis: capacity.saturating_mul(1000),
+            waiters: VecDeque::new(),
+            refilled_at: applied_time(),
+        };
+        self.buckets.insert(name, bucket);
+        future::ready(true).boxed()
+    }
+    fn try_acquire(
+        &mut self,
+        name: String,
+        member: u64,
+        count: u64,
+    ) -> BoxFuture<Result<bool, ExecError>> {
+        let res = self.take(name, member, count, false);
+        self.track();
+        future::ready(res).boxed()
+    }
+    fn acquire(
+        &mut self,
+        name: String,
+        member: u64,
+        count: u64,
+    ) -> BoxFuture<Result<bool, ExecError>> {
+        let res = self.take(name, member, count, true);
+        self.track();
+        future::ready(res).boxed()
+    }
+    // False when the member is not waiting, e.g. the tokens have been granted
+    fn cancel(&mut self, name: String, member: u64) -> BoxFuture<bool> {
+        async move {
+            let cancelled = match self.buckets.get_mut(&name) {
+                Some(bucket) => {
+                    let len = bucket.waiters.len();
+                    bucket.waiters.retain(|w| w.member != member);
+                    bucket.waiters.len() != len
+                }
+                None => false,
+            };
+            // Waiters behind may fit now
+            self.grant().await;
+            self.track();
+            cancelled
+        }
+        .boxed()
+    }
+    fn grant_(&mut self) -> BoxFuture<()> {
+        async move {
+            self.grant().await;
+            self.track();
+        }
+        .boxed()
+    }
+    fn release_member_(&mut self, member: u64) -> BoxFuture<()> {
+        async move {
+            for (name, bucket) in self.buckets.iter_mut() {
+                let len = bucket.waiters.len();
+                bucket.waiters.retain(|w| w.member != member);
+                if bucket.waiters.len() != len {
+                    debug!(
+                        "Dropped acquisition of offline member {} on {}",
+                        member, name
+                    );
+                }
+            }
+            self.grant().await;
+            self.track();
+        }
+        .boxed()
+    }
+    // Estimated by the clock of the server answering, refills are only recorded by commands
+    fn tokens(&self, name: String) -> BoxFuture<Option<u64>> {
+        let now = get_time();
+        let tokens = self.buckets.get(&name).map(|b| b.millis_at(now) / 1000);
+        future::ready(tokens).boxed()
+    }
+}
+
+impl StateMachineCtl for RateLimiters {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(serialize(&self.buckets))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        match deserialize(data.as_slice()) {
+            Some(buckets) => {
+                self.buckets = buckets;
+                self.track();
+            }
+            None => error!("Cannot decode snapshot of rate limiters {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl RateLimiters {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+        let demand = Arc::new(Demand {
+            members: Mutex::new(HashSet::new()),
+            due: AtomicI64::new(i64::MAX),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(watch_demand(id, raft_service.clone(), demand.clone()));
+        raft_service
+            .register_state_machine(Box::new(RateLimiters {
+                buckets: HashMap::new(),
+                id,
+                demand,
+                callback: Some(SMCallback::new(id, raft_service.clone()).await),
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
+    }
+}
+
+// Leader grants waiters when they are due and drops acquisitions of offline members.
+// Nothing is logged while no one waits
+async fn watch_demand(sm_id: u64, raft_service: Arc<RaftService>, demand: Arc<Demand>) {
+    while !demand.closed.load(Ordering::Relaxed) {
+        sleep(Duration::from_millis(WATCH_INTERVAL_MS)).await;
+        if !raft_service.is_leader() {
+            continue;
+        }
+        if demand.due.load(Ordering::Relaxed) <= get_time() {
+            let (fn_id, _, data) = commands::grant_::new().encode();
+            command(&raft_service, sm_id, fn_id, data).await;
+        }
+        let members = demand.members.lock().clone();
+        if members.is_empty() {
+            continue;
+        }
+        for member in offline_members(&raft_service).await.intersection(&members) {
+            let (fn_id, _, data) = commands::release_member_::new(member).encode();
+            command(&raft_service, sm_id, fn_id, data).await;
+        }
+    }
+    debug!("Rate limiter watcher for {} exiting", sm_id);
+}
+
+// Rate limiter client for a member, usually the member id from `MemberService::get_server_id`
+pub struct RateLimiterClient {
+    sm: client::SMClient,
+    member: u64,
+}
+
+impl RateLimiterClient {
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>, member: u64) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            member,
+        }
+    }
+    // Bucket of `capacity` tokens refilled by `rate` tokens per second
+    pub async fn create(&self, name: &str, capacity: u64, rate: u64) -> Result<bool, ExecError> {
+        self.sm.create(&name.to_string(), &capacity, &rate).await
+    }
+    pub async fn try_acquire(&self, name: &str, count: u64) -> Result<bool, ExecError> {
+        self.sm
+            .try_acquire(&name.to_string(), &self.member, &count)
+            .await
+    }
+    // Wait in the queue of the bucket for at most `wait`, false when timed out
+    pub async fn acquire(
+        &self,
+        name: &str,
+        count: u64,
+        wait: Duration,
+    ) -> Result<Result<bool, SubscriptionError>, ExecError> {
+        let name = name.to_string();
+        // Subscribe before queueing so the refill cannot be missed
+        let mut acquired = match self.sm.streams(1).on_acquired(&name, &self.member).await? {
+            Ok(stream) => stream,
+            Err(e) => return Ok(Err(e)),
+        };
+        if self.sm.acquire(&name, &self.member, &count).await? {
+            return Ok(Ok(true));
+        }
+        let res = timeout(wait, acquired.next()).await;
+        if let Ok(Some(_)) = res {
+            return Ok(Ok(true));
+        }
+        // Granted when no longer waiting
+        let granted = !self.sm.cancel(&name, &self.member).await?;
+        match res {
+            Ok(None) if !granted => Ok(Err(SubscriptionError::RemoteError)),
+            _ => Ok(Ok(granted)),
+        }
+    }
+    // Whole tokens in the bucket
+    pub async fn tokens(&self, name: &str) -> Result<Option<u64>, ExecError> {
+        self.sm.tokens(&name.to_string()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raft::{Options, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rate_limiters() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2053");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        RateLimiters::new(&raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let a = RateLimiterClient::new(DEFAULT_SERVICE_ID, &raft_client, 1);
+        let b = RateLimiterClient::new(DEFAULT_SERVICE_ID, &raft_client, 2);
+
+        match a.try_acquire("api", 1).await {
+            Err(ExecError::StateMachineError {
+                code: NOT_FOUND, ..
+            }) => {}
+            other => panic!("Expect not found, got {:?}", other),
+        }
+        assert!(a.create("api", 5, 10).await.unwrap());
+        assert!(!a.create("api", 1, 1).await.unwrap());
+        match a.try_acquire("api", 6).await {
+            Err(ExecError::StateMachineError {
+                code: EXCEEDS_CAPACITY,
+                ..
+            }) => {}
+            other => panic!("Expect exceeds capacity, got {:?}", other),
+        }
+        assert_eq!(a.tokens("api").await.unwrap(), Some(5));
+        assert!(a.try_acquire("api", 5).await.unwrap());
+        assert!(!b.try_acquire("api", 1).await.unwrap());
+        // Refilled by 10 tokens per second up to the capacity
+        async_wait_secs().await;
+        assert_eq!(a.tokens("api").await.unwrap(), Some(5));
+
+        // Refills are not logged while no one waits
+        let last_log_id = raft_service.last_log_id().await;
+        async_wait_secs().await;
+        assert_eq!(raft_service.last_log_id().await, last_log_id);
+
+        // Queued b is granted when due
+        let api = String::from("api");
+        assert!(a.try_acquire("api", 5).await.unwrap());
+        assert!(!b.sm.acquire(&api, &2, &4).await.unwrap());
+        async_wait_secs().await;
+        assert!(!b.sm.cancel(&api, &2).await.unwrap());
+        assert!(a.tokens("api").await.unwrap().unwrap() >= 1);
+        let last_log_id = raft_service.last_log_id().await;
+        async_wait_secs().await;
+        assert_eq!(raft_service.last_log_id().await, last_log_id);
+    }
+}
@@ -0,0 +1,582 @@
+// Replicated job scheduler with cron triggers and retries
+
+use super::{command, offline_members, online_group_members};
+use crate::raft::client::{RaftClient, SubscriptionError, SubscriptionStream};
+use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::{RaftMsg, RaftService};
+use crate::utils::cron::Cron;
+use crate::utils::serde::{deserialize, serialize};
+use crate::utils::time::get_time;
+use bifrost_plugins::hash_ident;
+use futures::FutureExt;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_SCHEDULER) as u64;
+
+pub const INVALID_TRIGGER: u32 = 1;
+
+static WATCH_INTERVAL_MS: u64 = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    // Milliseconds since epoch
+    At(i64),
+    Cron(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // Including the first attempt
+    pub max_attempts: u32,
+    pub backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff_ms: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JobSpec {
+    pub name: String,
+    pub trigger: Trigger,
+    // Membership group of members to run the job
+    pub group: String,
+    pub payload: Vec<u8>,
+    pub retry: RetryPolicy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    pub id: u64,
+    pub member: u64,
+    pub attempt: u32,
+    pub assigned_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Retry {
+    pub attempt: u32,
+    pub at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub id: u64,
+    pub spec: JobSpec,
+    // None when the trigger will not fire again
+    pub next_fire: Option<i64>,
+    // Runs to retry go before the next fire
+    pub retry: Option<Retry>,
+    pub run: Option<Run>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pub job: u64,
+    pub run: u64,
+    pub attempt: u32,
+    pub name: String,
+    pub payload: Vec<u8>,
+}
+
+raft_state_machine! {
+    def cmd schedule(spec: JobSpec, now: i64) -> u64 | ExecError;
+    def cmd unschedule(job: u64) -> bool;
+    def cmd assign_(job: u64, member: u64, now: i64) -> bool;
+    def cmd complete(job: u64, run: u64) -> bool;
+    def cmd fail(job: u64, run: u64, now: i64) -> bool;
+    def cmd release_member_(member: u64);
+    def qry job(job: u64) -> Option<Job>;
+    def qry jobs() -> Vec<Job>;
+    def qry assigned(member: u64) -> Vec<Assignment>;
+    def sub on_assigned(member: u64) -> Assignment;
+}
+
+// Group, due time and assignee of jobs, watched by the leader
+struct Entry {
+    group: String,
+    due: Option<i64>,
+    member: Option<u64>,
+}
+
+struct Agenda {
+    entries: Mutex<HashMap<u64, Entry>>,
+    closed: AtomicBool,
+}
+
+pub struct Scheduler {
+    pub jobs: BTreeMap<u64, Job>,
+    pub next_id: u64,
+    pub id: u64,
+    agenda: Arc<Agenda>,
+    callback: Option<SMCallback>,
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.agenda.closed.store(true, Ordering::Relaxed)
+    }
+}
+
+fn next_fire(trigger: &Trigger, now: i64) -> Result<Option<i64>, String> {
+    match trigger {
+        Trigger::At(at) => Ok(Some(*at)),
+        Trigger::Cron(expr) => Ok(Cron::parse(expr)?.next_after(now)),
+    }
+}
+
+impl Job {
+    // Time and attempt of the next run
+    fn due(&self) -> Option<(i64, u32)> {
+        if self.run.is_some() {
+            return None;
+        }
+        match &self.retry {
+            Some(retry) => Some((retry.at, retry.attempt)),
+            None => self.next_fire.map(|at| (at, 1)),
+        }
+    }
+    fn assignment(&self, run: &Run) -> Assignment {
+        Assignment {
+            job: self.id,
+            run: run.id,
+            attempt: run.attempt,
+            name: self.spec.name.clone(),
+            payload: self.spec.payload.clone(),
+        }
+    }
+    fn run_matches(&self, run: u64) -> bool {
+        self.run.as_ref().map(|r| r.id == run).unwrap_or(false)
+    }
+}
+
+impl Scheduler {
+    // Drop jobs with nothing more to run
+    fn settle(&mut self, job: u64) {
+        let done = match self.jobs.get(&job) {
+            Some(job) => job.run.is_none() && job.due().is_none(),
+            None => false,
+        };
+        if done {
+            self.jobs.remove(&job);
+        }
+        self.track();
+    }
+    fn track(&self) {
+        let mut entries = self.agenda.entries.lock();
+        entries.clear();
+        for (id, job) in &self.jobs {
+            let entry = Entry {
+                group: job.spec.group.clone(),
+                due: job.due().map(|(at, _)| at),
+                member: job.run.as_ref().map(|run| run.member),
+            };
+            entries.insert(*id, entry);
+        }
+    }
+}
+
+impl StateMachineCmds for Scheduler {
+    fn schedule(&mut self, spec: JobSpec, now: i64) -> BoxFuture<Result<u64, ExecError>> {
+        let next_fire = match next_fire(&spec.trigger, now) {
+            Ok(Some(next_fire)) => next_fire,
+            Ok(None) => {
+                return future::ready(Err(ExecError::state_machine(
+                    INVALID_TRIGGER,
+                    format!("Trigger of {} never fires", spec.name),
+                )))
+                .boxed()
+            }
+            Err(e) => {
+                return future::ready(Err(ExecError::state_machine(INVALID_TRIGGER, e))).boxed()
+            }
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        let job = Job {
+            id,
+            spec,
+            next_fire: Some(next_fire),
+            retry: None,
+            run: None,
+        };
+        self.jobs.insert(id, job);
+        self.track();
+        future::ready(Ok(id)).boxed()
+    }
+    // Runs in progress are dropped, acks of them are ignored
+    fn unschedule(&mut self, job: u64) -> BoxFuture<bool> {
+        let removed = self.jobs.remove(&job).is_some();
+        self.track();
+        future::ready(removed).boxed()
+    }
+    fn assign_(&mut self, job: u64, member: u64, now: i64) -> BoxFuture<bool> {
+        async move {
+            let job = match self.jobs.get_mut(&job) {
+                Some(job) => job,
+                None => return false,
+            };
+            let attempt = match job.due() {
+                Some((at, attempt)) if at <= now => attempt,
+                _ => return false,
+            };
+            if job.retry.take().is_none() {
+                // Fires missed while the job was running or without a leader are skipped
+                job.next_fire = match &job.spec.trigger {
+                    Trigger::At(_) => None,
+                    trigger => next_fire(trigger, now).unwrap_or(None),
+                };
+            }
+            let run = Run {
+                id: self.next_id,
+                member,
+                attempt,
+                assigned_at: now,
+            };
+            self.next_id += 1;
+            let assignment = job.assignment(&run);
+            job.run = Some(run);
+            self.track();
+            let msg = commands::on_assigned::new(&member);
+            cb_notify(&self.callback, msg, || assignment).await;
+            true
+        }
+        .boxed()
+    }
+    fn complete(&mut self, job: u64, run: u64) -> BoxFuture<bool> {
+        let completed = match self.jobs.get_mut(&job) {
+            Some(state) if state.run_matches(run) => {
+                state.run = None;
+                true
+            }
+            _ => false,
+        };
+        self.settle(job);
+        future::ready(completed).boxed()
+    }
+    fn fail(&mut self, job: u64, run: u64, now: i64) -> BoxFuture<bool> {
+        let failed = match self.jobs.get_mut(&job) {
+            Some(state) if state.run_matches(run) => {
+                let attempt = state.run.take().unwrap().attempt;
+                let retry = &state.spec.retry;
+                if attempt < retry.max_attempts {
+                    state.retry = Some(Retry {
+                        attempt: attempt + 1,
+                        at: now + retry.backoff_ms as i64,
+                    });
+                } else {
+                    debug!("Job {} failed after {} attempts", state.spec.name, attempt);
+                }
+                true
+            }
+            _ => false,
+        };
+        self.settle(job);
+        future::ready(failed).boxed()
+    }
+    fn release_member_(&mut self, member: u64) -> BoxFuture<()> {
+        for job in self.jobs.values_mut() {
+            if let Some(run) = &job.run {
+                if run.member == member {
+                    debug!(
+                        "Reassigning job {} of offline member {}",
+                        job.spec.name, member
+                    );
+                    job.retry = Some(Retry {
+                        attempt: run.attempt,
+                        at: run.assigned_at,
+                    });
+                    job.run = None;
+                }
+            }
+        }
+        self.track();
+        future::ready(()).boxed()
+    }
+    fn job(&self, job: u64) -> BoxFuture<Option<Job>> {
+        future::ready(self.jobs.get(&job).cloned()).boxed()
+    }
+    fn jobs(&self) -> BoxFuture<Vec<Job>> {
+        future::ready(self.jobs.values().cloned().collect()).boxed()
+    }
+    // Runs in progress assigned to the member
+    fn assigned(&self, member: u64) -> BoxFuture<Vec<Assignment>> {
+        let assignments = self
+            .jobs
+            .values()
+            .filter_map(|job| {
+                job.run
+                    .as_ref()
+                    .filter(|run| run.member == member)
+                    .map(|run| job.assignment(run))
+            })
+            .collect();
+        future::ready(assignments).boxed()
+    }
+}
+
+impl StateMachineCtl for Scheduler {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(serialize(&(&self.jobs, self.next_id)))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        match deserialize(data.as_slice()) {
+            Some((jobs, next_id)) => {
+                self.jobs = jobs;
+                self.next_id = next_id;
+                self.track();
+            }
+            None => error!("Cannot decode snapshot of scheduler {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl Scheduler {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+        let agenda = Arc::new(Agenda {
+            entries: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(watch_agenda(id, raft_service.clone(), agenda.clone()));
+        raft_service
+            .register_state_machine(Box::new(Scheduler {
+                jobs: BTreeMap::new(),
+                next_id: 1,
+                id,
+                agenda,
+                callback: Some(SMCallback::new(id, raft_service.clone()).await),
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
+    }
+}
+
+// Leader reassigns runs of offline members and assigns due jobs
+async fn watch_agenda(sm_id: u64, raft_service: Arc<RaftService>, agenda: Arc<Agenda>) {
+    while !agenda.closed.load(Ordering::Relaxed) {
+        sleep(Duration::from_millis(WATCH_INTERVAL_MS)).await;
+        if !raft_service.is_leader() {
+            continue;
+        }
+        let now = get_time();
+        let (due, mut running) = {
+            let entries = agenda.entries.lock();
+            let mut running: HashMap<u64, usize> = HashMap::new();
+            for member in entries.values().filter_map(|entry| entry.member) {
+                *running.entry(member).or_insert(0) += 1;
+            }
+            let due: Vec<_> = entries
+                .iter()
+                .filter(|(_, entry)| entry.due.map(|at| at <= now).unwrap_or(false))
+                .map(|(id, entry)| (*id, entry.group.clone()))
+                .collect();
+            (due, running)
+        };
+        if !running.is_empty() {
+            for member in offline_members(&raft_service).await {
+                if running.remove(&member).is_some() {
+                    let (fn_id, _, data) = commands::release_member_::new(&member).encode();
+                    command(&raft_service, sm_id, fn_id, data).await;
+                }
+            }
+        }
+        let mut groups: HashMap<String, Vec<u64>> = HashMap::new();
+        for (job, group) in due {
+            if !groups.contains_key(&group) {
+                let members = online_group_members(&raft_service, &group).await;
+                groups.insert(group.clone(), members);
+            }
+            let member = groups[&group]
+                .iter()
+                .min_by_key(|member| (running.get(member).cloned().unwrap_or(0), **member))
+                .cloned();
+            if let Some(member) = member {
+                *running.entry(member).or_insert(0) += 1;
+                let (fn_id, _, data) = commands::assign_::new(&job, &member, &now).encode();
+                command(&raft_service, sm_id, fn_id, data).await;
+            }
+        }
+    }
+    debug!("Scheduler watcher for {} exiting", sm_id);
+}
+
+// Scheduler client for a member, usually the member id from `MemberService::get_server_id`
+pub struct SchedulerClient {
+    sm: client::SMClient,
+    member: u64,
+}
+
+impl SchedulerClient {
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>, member: u64) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            member,
+        }
+    }
+    // Id of the job
+    pub async fn schedule(&self, spec: JobSpec) -> Result<u64, ExecError> {
+        self.sm.schedule(&spec, &get_time()).await
+    }
+    pub async fn unschedule(&self, job: u64) -> Result<bool, ExecError> {
+        self.sm.unschedule(&job).await
+    }
+    pub async fn job(&self, job: u64) -> Result<Option<Job>, ExecError> {
+        self.sm.job(&job).await
+    }
+    pub async fn jobs(&self) -> Result<Vec<Job>, ExecError> {
+        self.sm.jobs().await
+    }
+    // Runs assigned to this member before subscribing to assignments
+    pub async fn assigned(&self) -> Result<Vec<Assignment>, ExecError> {
+        self.sm.assigned(&self.member).await
+    }
+    // Runs assigned to this member from now on
+    pub async fn assignments(
+        &self,
+        capacity: usize,
+    ) -> Result<Result<SubscriptionStream<Assignment>, SubscriptionError>, ExecError> {
+        self.sm.streams(capacity).on_assigned(&self.member).await
+    }
+    // False when the run is no longer assigned, e.g. reassigned or unscheduled
+    pub async fn complete(&self, assignment: &Assignment) -> Result<bool, ExecError> {
+        self.sm.complete(&assignment.job, &assignment.run).await
+    }
+    pub async fn fail(&self, assignment: &Assignment) -> Result<bool, ExecError> {
+        self.sm
+            .fail(&assignment.job, &assignment.run, &get_time())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::membership::member::MemberService;
+    use crate::membership::raft::commands::{join, join_group};
+    use crate::membership::server::Membership;
+    use crate::membership::DEFAULT_SERVICE_ID as MEMBERSHIP_SERVICE_ID;
+    use crate::raft::{Options, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+    use bifrost_hasher::hash_str;
+
+    // The member never sends heartbeats, it stays online for a while after joined
+    async fn join_worker(raft_service: &Arc<RaftService>, address: &str) -> u64 {
+        let (fn_id, _, data) = join::new(&address.to_string()).encode();
+        command(raft_service, MEMBERSHIP_SERVICE_ID, fn_id, data).await;
+        let id = hash_str(address);
+        let (fn_id, _, data) = join_group::new(&String::from("workers"), &id).encode();
+        command(raft_service, MEMBERSHIP_SERVICE_ID, fn_id, data).await;
+        id
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn scheduler() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2055");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Membership::new(&server, &raft_service).await;
+        Scheduler::new(&raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let a = join_worker(&raft_service, "127.0.0.1:2056").await;
+        let worker_a = SchedulerClient::new(DEFAULT_SERVICE_ID, &raft_client, a);
+        let mut spec = JobSpec {
+            name: String::from("report"),
+            trigger: Trigger::Cron(String::from("0 0 30 2 *")),
+            group: String::from("workers"),
+            payload: b"data".to_vec(),
+            retry: RetryPolicy {
+                max_attempts: 2,
+                backoff_ms: 0,
+            },
+        };
+        match worker_a.schedule(spec.clone()).await {
+            Err(ExecError::StateMachineError {
+                code: INVALID_TRIGGER,
+                ..
+            }) => {}
+            other => panic!("Expect invalid trigger, got {:?}", other),
+        }
+        spec.trigger = Trigger::At(get_time());
+        let job = worker_a.schedule(spec).await.unwrap();
+        async_wait_secs().await;
+        let assigned_a = worker_a.assigned().await.unwrap();
+        assert_eq!(assigned_a.len(), 1);
+        assert_eq!((assigned_a[0].job, assigned_a[0].attempt), (job, 1));
+        assert_eq!(worker_a.job(job).await.unwrap().unwrap().next_fire, None);
+
+        // Reassigned when the assignee went offline, b keeps sending heartbeats
+        let member_b = MemberService::new(&String::from("127.0.0.1:2057"), &raft_client).await;
+        member_b.join_group(&String::from("workers")).await.unwrap();
+        let worker_b =
+            SchedulerClient::new(DEFAULT_SERVICE_ID, &raft_client, member_b.get_server_id());
+        let mut assigned = vec![];
+        for _ in 0..10 {
+            async_wait_secs().await;
+            assigned = worker_b.assigned().await.unwrap();
+            if !assigned.is_empty() {
+                break;
+            }
+        }
+        assert_eq!((assigned[0].job, assigned[0].attempt), (job, 1));
+        assert!(worker_a.assigned().await.unwrap().is_empty());
+        assert!(!worker_a.complete(&assigned_a[0]).await.unwrap());
+
+        // Retried by the policy, then dropped
+        assert!(worker_b.fail(&assigned[0]).await.unwrap());
+        async_wait_secs().await;
+        let assigned = worker_b.assigned().await.unwrap();
+        assert_eq!((assigned[0].job, assigned[0].attempt), (job, 2));
+        assert!(worker_b.fail(&assigned[0]).await.unwrap());
+        assert_eq!(worker_b.job(job).await.unwrap(), None);
+
+        let spec = JobSpec {
+            name: String::from("tick"),
+            trigger: Trigger::Cron(String::from("*/5 * * * *")),
+            group: String::from("workers"),
+            payload: vec![],
+            retry: RetryPolicy::default(),
+        };
+        let now = get_time();
+        let job = worker_b.schedule(spec).await.unwrap();
+        let next_fire = worker_b.job(job).await.unwrap().unwrap().next_fire.unwrap();
+        assert!(next_fire > now && next_fire <= now + 300_000);
+        assert_eq!(next_fire % 300_000, 0);
+        assert_eq!(worker_b.jobs().await.unwrap().len(), 1);
+        assert!(worker_b.unschedule(job).await.unwrap());
+        assert!(worker_b.jobs().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,464 @@
+// Replicated counting semaphores with FIFO waiters
+
+use super::{command, offline_members};
+use crate::raft::client::{RaftClient, SubscriptionError};
+use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::{RaftMsg, RaftService};
+use crate::utils::serde::{deserialize, serialize};
+use bifrost_plugins::hash_ident;
+use futures::{FutureExt, StreamExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_SEMAPHORE) as u64;
+
+pub const NOT_FOUND: u32 = 1;
+pub const EXCEEDS_PERMITS: u32 = 2;
+
+static WATCH_INTERVAL_MS: u64 = 500;
+
+raft_state_machine! {
+    def cmd create(name: String, permits: u64) -> bool;
+    def cmd try_acquire(name: String, member: u64, count: u64) -> bool | ExecError;
+    def cmd acquire(name: String, member: u64, count: u64) -> bool | ExecError;
+    def cmd cancel(name: String, member: u64) -> bool;
+    def cmd release(name: String, member: u64, count: u64) -> u64;
+    def cmd release_member_(member: u64);
+    def qry available(name: String) -> Option<u64>;
+    def qry held(name: String, member: u64) -> u64;
+    def sub on_acquired(name: String, member: u64) -> u64;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Waiter {
+    pub member: u64,
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SemaphoreState {
+    pub permits: u64,
+    pub available: u64,
+    // Permits held by members
+    pub holders: HashMap<u64, u64>,
+    pub waiters: VecDeque<Waiter>,
+}
+
+// Members holding or waiting for permits, watched by the leader
+struct Participants {
+    members: Mutex<HashSet<u64>>,
+    closed: AtomicBool,
+}
+
+pub struct Semaphores {
+    pub semaphores: HashMap<String, SemaphoreState>,
+    pub id: u64,
+    participants: Arc<Participants>,
+    callback: Option<SMCallback>,
+}
+
+impl Drop for Semaphores {
+    fn drop(&mut self) {
+        self.participants.closed.store(true, Ordering::Relaxed)
+    }
+}
+
+fn not_found(name: &String) -> ExecError {
+    ExecError::state_machine(NOT_FOUND, format!("Semaphore {} not found", name))
+}
+
+impl Semaphores {
+    fn take(
+        &mut self,
+        name: String,
+        member: u64,
+        count: u64,
+        wait: bool,
+    ) -> Result<bool, ExecError> {
+        let state = match self.semaphores.get_mut(&name) {
+            Some(state) => state,
+            None => return Err(not_found(&name)),
+        };
+        if count > state.permits {
+            return Err(ExecError::state_machine(
+                EXCEEDS_PERMITS,
+                format!("Semaphore {} has {} permits", name, state.permits),
+            ));
+        }
+        // Queued acquirers go first
+        if state.waiters.is_empty() && state.available >= count {
+            state.available -= count;
+            *state.holders.entry(member).or_insert(0) += count;
+        } else {
+            if wait && !state.waiters.iter().any(|w| w.member == member) {
+                state.waiters.push_back(Waiter { member, count });
+            }
+            return Ok(false);
+        }
+        Ok(true)
+    }
+    // Hand permits over to waiters in order
+    async fn grant(&mut self, name: &String) {
+        let mut granted = vec![];
+        if let Some(state) = self.semaphores.get_mut(name) {
+            while let Some(waiter) = state.waiters.front() {
+                if waiter.count > state.available {
+                    break;
+                }
+                let waiter = state.waiters.pop_front().unwrap();
+                state.available -= waiter.count;
+                *state.holders.entry(waiter.member).or_insert(0) += waiter.count;
+                granted.push(waiter);
+            }
+        }
+        for waiter in granted {
+            let msg = commands::on_acquired::new(name, &waiter.member);
+            cb_notify(&self.callback, msg, || waiter.count).await;
+        }
+    }
+    fn give_back(state: &mut SemaphoreState, member: u64, count: u64) -> u64 {
+        let held = state.holders.get(&member).cloned().unwrap_or(0);
+        let released = held.min(count);
+        if released == held {
+            state.holders.remove(&member);
+        } else {
+            state.holders.insert(member, held - released);
+        }
+        state.available += released;
+        released
+    }
+    fn track(&self) {
+        let mut members = self.participants.members.lock();
+        members.clear();
+        for state in self.semaphores.values() {
+            members.extend(state.holders.keys());
+            members.extend(state.waiters.iter().map(|w| w.member));
+        }
+    }
+}
+
+impl StateMachineCmds for Semaphores {
+    fn create(&mut self, name: String, permits: u64) -> BoxFuture<bool> {
+        if self.semaphores.contains_key(&name) {
+            return future::ready(false).boxed();
+        }
+        let state = SemaphoreState {
+            permits,
+            available: permits,
+            holders: HashMap::new(),
+            waiters: VecDeque::new(),
+        };
+        self.semaphores.insert(name, state);
+        future::ready(true).boxed()
+    }
+    fn try_acquire(
+        &mut self,
+        name: String,
+        member: u64,
+        count: u64,
+    ) -> BoxFuture<Result<bool, ExecError>> {
+        let res = self.take(name, member, count, false);
+        self.track();
+        future::ready(res).boxed()
+    }
+    fn acquire(
+        &mut self,
+        name: String,
+        member: u64,
+        count: u64,
+    ) -> BoxFuture<Result<bool, ExecError>> {
+        let res = self.take(name, member, count, true);
+        self.track();
+        future::ready(res).boxed()
+    }
+    // False when the member is not waiting, e.g. the permits have been granted
+    fn cancel(&mut self, name: String, member: u64) -> BoxFuture<bool> {
+        async move {
+            let cancelled = match self.semaphores.get_mut(&name) {
+                Some(state) => {
+                    let len = state.waiters.len();
+                    state.waiters.retain(|w| w.member != member);
+                    state.waiters.len() != len
+                }
+                None => false,
+            };
+            // Waiters behind may fit now
+            self.grant(&name).await;
+            self.track();
+            cancelled
+        }
+        .boxed()
+    }
+    // Number of permits released, at most the number held by the member
+    fn release(&mut self, name: String, member: u64, count: u64) -> BoxFuture<u64> {
+        async move {
+            let released = match self.semaphores.get_mut(&name) {
+                Some(state) => Self::give_back(state, member, count),
+                None => return 0,
+            };
+            self.grant(&name).await;
+            self.track();
+            released
+        }
+        .boxed()
+    }
+    fn release_member_(&mut self, member: u64) -> BoxFuture<()> {
+        async move {
+            let mut names = vec![];
+            for (name, state) in self.semaphores.iter_mut() {
+                state.waiters.retain(|w| w.member != member);
+                let released = Self::give_back(state, member, u64::MAX);
+                if released > 0 {
+                    debug!(
+                        "Reclaimed {} permits of semaphore {} from offline member {}",
+                        released, name, member
+                    );
+                }
+                names.push(name.clone());
+            }
+            for name in names {
+                self.grant(&name).await;
+            }
+            self.track();
+        }
+        .boxed()
+    }
+    fn available(&self, name: String) -> BoxFuture<Option<u64>> {
+        future::ready(self.semaphores.get(&name).map(|s| s.available)).boxed()
+    }
+    fn held(&self, name: String, member: u64) -> BoxFuture<u64> {
+        let held = self
+            .semaphores
+            .get(&name)
+            .and_then(|s| s.holders.get(&member).cloned())
+            .unwrap_or(0);
+        future::ready(held).boxed()
+    }
+}
+
+impl StateMachineCtl for Semaphores {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(serialize(&self.semaphores))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        match deserialize(data.as_slice()) {
+            Some(semaphores) => {
+                self.semaphores = semaphores;
+                self.track();
+            }
+            None => error!("Cannot decode snapshot of semaphores {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl Semaphores {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+        let participants = Arc::new(Participants {
+            members: Mutex::new(HashSet::new()),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(watch_participants(
+            id,
+            raft_service.clone(),
+            participants.clone(),
+        ));
+        raft_service
+            .register_state_machine(Box::new(Semaphores {
+                semaphores: HashMap::new(),
+                id,
+                participants,
+                callback: Some(SMCallback::new(id, raft_service.clone()).await),
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
+    }
+}
+
+// Leader issues commands to reclaim permits of offline members
+async fn watch_participants(
+    sm_id: u64,
+    raft_service: Arc<RaftService>,
+    participants: Arc<Participants>,
+) {
+    while !participants.closed.load(Ordering::Relaxed) {
+        sleep(Duration::from_millis(WATCH_INTERVAL_MS)).await;
+        if !raft_service.is_leader() {
+            continue;
+        }
+        let members = participants.members.lock().clone();
+        if members.is_empty() {
+            continue;
+        }
+        for member in offline_members(&raft_service).await.intersection(&members) {
+            let (fn_id, _, data) = commands::release_member_::new(member).encode();
+            command(&raft_service, sm_id, fn_id, data).await;
+        }
+    }
+    debug!("Semaphore participant watcher for {} exiting", sm_id);
+}
+
+// Semaphore client for a member, usually the member id from `MemberService::get_server_id`
+pub struct SemaphoreClient {
+    sm: client::SMClient,
+    member: u64,
+}
+
+impl SemaphoreClient {
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>, member: u64) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            member,
+        }
+    }
+    // False when the semaphore exists
+    pub async fn create(&self, name: &str, permits: u64) -> Result<bool, ExecError> {
+        self.sm.create(&name.to_string(), &permits).await
+    }
+    pub async fn try_acquire(&self, name: &str, count: u64) -> Result<bool, ExecError> {
+        self.sm
+            .try_acquire(&name.to_string(), &self.member, &count)
+            .await
+    }
+    // Wait in the queue of the semaphore for at most `wait`, false when timed out
+    pub async fn acquire(
+        &self,
+        name: &str,
+        count: u64,
+        wait: Duration,
+    ) -> Result<Result<bool, SubscriptionError>, ExecError> {
+        let name = name.to_string();
+        // Subscribe before queueing so the hand over cannot be missed
+        let mut acquired = match self.sm.streams(1).on_acquired(&name, &self.member).await? {
+            Ok(stream) => stream,
+            Err(e) => return Ok(Err(e)),
+        };
+        if self.sm.acquire(&name, &self.member, &count).await? {
+            return Ok(Ok(true));
+        }
+        let res = timeout(wait, acquired.next()).await;
+        if let Ok(Some(_)) = res {
+            return Ok(Ok(true));
+        }
+        // Granted when no longer waiting
+        let granted = !self.sm.cancel(&name, &self.member).await?;
+        match res {
+            Ok(None) if !granted => Ok(Err(SubscriptionError::RemoteError)),
+            _ => Ok(Ok(granted)),
+        }
+    }
+    // Number of permits released
+    pub async fn release(&self, name: &str, count: u64) -> Result<u64, ExecError> {
+        self.sm
+            .release(&name.to_string(), &self.member, &count)
+            .await
+    }
+    pub async fn available(&self, name: &str) -> Result<Option<u64>, ExecError> {
+        self.sm.available(&name.to_string()).await
+    }
+    // Permits held by this member
+    pub async fn held(&self, name: &str) -> Result<u64, ExecError> {
+        self.sm.held(&name.to_string(), &self.member).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::membership::server::Membership;
+    use crate::membership::DEFAULT_SERVICE_ID as MEMBERSHIP_SERVICE_ID;
+    use crate::raft::{Options, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+    use bifrost_hasher::hash_str;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn semaphores() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2051");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Membership::new(&server, &raft_service).await;
+        Semaphores::new(&raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let a = SemaphoreClient::new(DEFAULT_SERVICE_ID, &raft_client, 1);
+        let b = SemaphoreClient::new(DEFAULT_SERVICE_ID, &raft_client, 2);
+
+        match a.try_acquire("pool", 1).await {
+            Err(ExecError::StateMachineError {
+                code: NOT_FOUND, ..
+            }) => {}
+            other => panic!("Expect not found, got {:?}", other),
+        }
+        assert!(a.create("pool", 3).await.unwrap());
+        assert!(!a.create("pool", 5).await.unwrap());
+        match a.try_acquire("pool", 4).await {
+            Err(ExecError::StateMachineError {
+                code: EXCEEDS_PERMITS,
+                ..
+            }) => {}
+            other => panic!("Expect exceeds permits, got {:?}", other),
+        }
+        assert!(a.try_acquire("pool", 2).await.unwrap());
+        assert!(!b.try_acquire("pool", 2).await.unwrap());
+        // Queue b, release hands the permits over
+        let pool = String::from("pool");
+        assert!(!b.sm.acquire(&pool, &2, &2).await.unwrap());
+        assert_eq!(a.available("pool").await.unwrap(), Some(1));
+        assert_eq!(a.release("pool", 5).await.unwrap(), 2);
+        assert_eq!(b.held("pool").await.unwrap(), 2);
+        assert_eq!(a.available("pool").await.unwrap(), Some(1));
+        assert!(!b.sm.cancel(&pool, &2).await.unwrap());
+        // Queued acquirers go first
+        assert!(!a.sm.acquire(&pool, &1, &3).await.unwrap());
+        assert!(!b.try_acquire("pool", 1).await.unwrap());
+        assert!(a.sm.cancel(&pool, &1).await.unwrap());
+        assert!(b.try_acquire("pool", 1).await.unwrap());
+        assert_eq!(b.release("pool", 3).await.unwrap(), 3);
+        assert_eq!(a.available("pool").await.unwrap(), Some(3));
+
+        // Reclaimed when the member went offline, the member never sends heartbeats
+        let member_addr = String::from("127.0.0.1:2052");
+        let (fn_id, _, data) = crate::membership::raft::commands::join::new(&member_addr).encode();
+        command(&raft_service, MEMBERSHIP_SERVICE_ID, fn_id, data).await;
+        let c = SemaphoreClient::new(DEFAULT_SERVICE_ID, &raft_client, hash_str(&member_addr));
+        assert!(c.try_acquire("pool", 3).await.unwrap());
+        assert!(!a.sm.acquire(&pool, &1, &1).await.unwrap());
+        let mut reclaimed = false;
+        for _ in 0..10 {
+            async_wait_secs().await;
+            if c.held("pool").await.unwrap() == 0 {
+                reclaimed = true;
+                break;
+            }
+        }
+        assert!(reclaimed);
+        assert_eq!(a.held("pool").await.unwrap(), 1);
+        assert_eq!(a.available("pool").await.unwrap(), Some(2));
+    }
+}
@@ -0,0 +1,441 @@
+// Replicated named sets with set algebra over sets of the same state machine
+
+use crate::raft::client::{RaftClient, SubscriptionError, SubscriptionReceipt};
+use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::RaftService;
+use crate::utils::serde::{deserialize, serialize};
+use bifrost_plugins::hash_ident;
+use futures::FutureExt;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_SET) as u64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SetChange<T> {
+    Added(T),
+    Removed(T),
+}
+
+raft_state_machine! {
+    def cmd add(set: String, member: Vec<u8>) -> bool;
+    def cmd add_all(set: String, members: Vec<Vec<u8>>) -> u64;
+    def cmd remove(set: String, member: Vec<u8>) -> bool;
+    def cmd clear(set: String) -> u64;
+    def qry contains(set: String, member: Vec<u8>) -> bool;
+    def qry members(set: String) -> Vec<Vec<u8>>;
+    def qry len(set: String) -> u64;
+    def qry union(sets: Vec<String>) -> Vec<Vec<u8>>;
+    def qry intersection(sets: Vec<String>) -> Vec<Vec<u8>>;
+    def qry difference(set: String, others: Vec<String>) -> Vec<Vec<u8>>;
+    def sub on_changed(set: String) -> SetChange<Vec<u8>>;
+}
+
+pub struct Sets {
+    pub sets: HashMap<String, BTreeSet<Vec<u8>>>,
+    pub id: u64,
+    callback: Option<SMCallback>,
+}
+
+impl Sets {
+    async fn changed(&self, set: &String, change: SetChange<Vec<u8>>) {
+        cb_notify(&self.callback, commands::on_changed::new(set), || change).await;
+    }
+    async fn insert(&mut self, set: &String, member: Vec<u8>) -> bool {
+        let added = self
+            .sets
+            .entry(set.clone())
+            .or_insert_with(BTreeSet::new)
+            .insert(member.clone());
+        if added {
+            self.changed(set, SetChange::Added(member)).await;
+        }
+        added
+    }
+    fn get(&self, set: &String) -> Option<&BTreeSet<Vec<u8>>> {
+        self.sets.get(set)
+    }
+}
+
+impl StateMachineCmds for Sets {
+    fn add(&mut self, set: String, member: Vec<u8>) -> BoxFuture<bool> {
+        async move { self.insert(&set, member).await }.boxed()
+    }
+    fn add_all(&mut self, set: String, members: Vec<Vec<u8>>) -> BoxFuture<u64> {
+        async move {
+            let mut added = 0;
+            for member in members {
+                if self.insert(&set, member).await {
+                    added += 1;
+                }
+            }
+            added
+        }
+        .boxed()
+    }
+    fn remove(&mut self, set: String, member: Vec<u8>) -> BoxFuture<bool> {
+        async move {
+            let removed = match self.sets.get_mut(&set) {
+                Some(members) => {
+                    let removed = members.remove(&member);
+                    if members.is_empty() {
+                        self.sets.remove(&set);
+                    }
+                    removed
+                }
+                None => false,
+            };
+            if removed {
+                self.changed(&set, SetChange::Removed(member)).await;
+            }
+            removed
+        }
+        .boxed()
+    }
+    fn clear(&mut self, set: String) -> BoxFuture<u64> {
+        async move {
+            let members = self.sets.remove(&set).unwrap_or_default();
+            let len = members.len() as u64;
+            for member in members {
+                self.changed(&set, SetChange::Removed(member)).await;
+            }
+            len
+        }
+        .boxed()
+    }
+    fn contains(&self, set: String, member: Vec<u8>) -> BoxFuture<bool> {
+        future::ready(self.get(&set).map(|s| s.contains(&member)).unwrap_or(false)).boxed()
+    }
+    fn members(&self, set: String) -> BoxFuture<Vec<Vec<u8>>> {
+        future::ready(
+            self.get(&set)
+                .map(|s| s.iter().cloned().collect())
+                .unwrap_or_default(),
+        )
+        .boxed()
+    }
+    fn len(&self, set: String) -> BoxFuture<u64> {
+        future::ready(self.get(&set).map(|s| s.len()).unwrap_or(0) as u64).boxed()
+    }
+    fn union(&self, sets: Vec<String>) -> BoxFuture<Vec<Vec<u8>>> {
+        let union: BTreeSet<_> = sets
+            .iter()
+            .filter_map(|set| self.get(set))
+            .flat_map(|s| s.iter().cloned())
+            .collect();
+        future::ready(union.into_iter().collect()).boxed()
+    }
+    fn intersection(&self, sets: Vec<String>) -> BoxFuture<Vec<Vec<u8>>> {
+        let res = match sets.split_first() {
+            Some((first, rest)) => self
+                .get(first)
+                .map(|first| {
+                    first
+                        .iter()
+                        .filter(|m| {
+                            rest.iter()
+                                .all(|set| self.get(set).map(|s| s.contains(*m)).unwrap_or(false))
+                        })
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default(),
+            None => vec![],
+        };
+        future::ready(res).boxed()
+    }
+    fn difference(&self, set: String, others: Vec<String>) -> BoxFuture<Vec<Vec<u8>>> {
+        let res = self
+            .get(&set)
+            .map(|members| {
+                members
+                    .iter()
+                    .filter(|m| {
+                        !others
+                            .iter()
+                            .any(|other| self.get(other).map(|s| s.contains(*m)).unwrap_or(false))
+                    })
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        future::ready(res).boxed()
+    }
+}
+
+impl StateMachineCtl for Sets {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(serialize(&self.sets))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        match deserialize(data.as_slice()) {
+            Some(sets) => self.sets = sets,
+            None => error!("Cannot decode snapshot of sets {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl Sets {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+        raft_service
+            .register_state_machine(Box::new(Sets {
+                sets: HashMap::new(),
+                id,
+                callback: Some(SMCallback::new(id, raft_service.clone()).await),
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
+    }
+}
+
+// Typed client for one set. Members not matching the type fail with `CannotDecode`
+pub struct SetClient<T> {
+    sm: client::SMClient,
+    sm_id: u64,
+    set: String,
+    _type: PhantomData<fn() -> T>,
+}
+
+fn decode_member<T: DeserializeOwned>(
+    sm_id: u64,
+    fn_id: u64,
+    data: Vec<u8>,
+) -> Result<T, ExecError> {
+    deserialize(data.as_slice()).ok_or(ExecError::CannotDecode { sm_id, fn_id })
+}
+
+fn decode_members<T: DeserializeOwned>(
+    sm_id: u64,
+    fn_id: u64,
+    members: Vec<Vec<u8>>,
+) -> Result<Vec<T>, ExecError> {
+    members
+        .into_iter()
+        .map(|m| decode_member(sm_id, fn_id, m))
+        .collect()
+}
+
+fn decode_change<T: DeserializeOwned>(
+    sm_id: u64,
+    change: SetChange<Vec<u8>>,
+) -> Result<SetChange<T>, ExecError> {
+    let fn_id = hash_ident!(on_changed) as u64;
+    Ok(match change {
+        SetChange::Added(m) => SetChange::Added(decode_member(sm_id, fn_id, m)?),
+        SetChange::Removed(m) => SetChange::Removed(decode_member(sm_id, fn_id, m)?),
+    })
+}
+
+fn set_names(set: &String, others: &[&str]) -> Vec<String> {
+    let mut sets = vec![set.clone()];
+    sets.extend(others.iter().map(|s| s.to_string()));
+    sets
+}
+
+impl<T> SetClient<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>, set: &str) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            sm_id,
+            set: set.to_string(),
+            _type: PhantomData,
+        }
+    }
+    pub async fn add(&self, member: &T) -> Result<bool, ExecError> {
+        self.sm.add(&self.set, &serialize(member)).await
+    }
+    // Number of members not in the set before
+    pub async fn add_all(&self, members: &[T]) -> Result<u64, ExecError> {
+        let members = members.iter().map(serialize).collect();
+        self.sm.add_all(&self.set, &members).await
+    }
+    pub async fn remove(&self, member: &T) -> Result<bool, ExecError> {
+        self.sm.remove(&self.set, &serialize(member)).await
+    }
+    pub async fn clear(&self) -> Result<u64, ExecError> {
+        self.sm.clear(&self.set).await
+    }
+    pub async fn contains(&self, member: &T) -> Result<bool, ExecError> {
+        self.sm.contains(&self.set, &serialize(member)).await
+    }
+    // Members in order of their serialized form
+    pub async fn members(&self) -> Result<Vec<T>, ExecError> {
+        let members = self.sm.members(&self.set).await?;
+        decode_members(self.sm_id, hash_ident!(members) as u64, members)
+    }
+    pub async fn len(&self) -> Result<u64, ExecError> {
+        self.sm.len(&self.set).await
+    }
+    // Set algebra of this set with other sets of the same member type
+    pub async fn union(&self, others: &[&str]) -> Result<Vec<T>, ExecError> {
+        let sets = set_names(&self.set, others);
+        let members = self.sm.union(&sets).await?;
+        decode_members(self.sm_id, hash_ident!(union) as u64, members)
+    }
+    pub async fn intersection(&self, others: &[&str]) -> Result<Vec<T>, ExecError> {
+        let sets = set_names(&self.set, others);
+        let members = self.sm.intersection(&sets).await?;
+        decode_members(self.sm_id, hash_ident!(intersection) as u64, members)
+    }
+    pub async fn difference(&self, others: &[&str]) -> Result<Vec<T>, ExecError> {
+        let others = others.iter().map(|s| s.to_string()).collect();
+        let members = self.sm.difference(&self.set, &others).await?;
+        decode_members(self.sm_id, hash_ident!(difference) as u64, members)
+    }
+    pub async fn on_changed<F>(
+        &self,
+        f: F,
+    ) -> Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>
+    where
+        F: Fn(SetChange<T>) -> BoxFuture<'static, ()> + 'static + Send + Sync,
+    {
+        let sm_id = self.sm_id;
+        self.sm
+            .on_changed(
+                move |change| match decode_change(sm_id, change) {
+                    Ok(change) => f(change),
+                    Err(e) => {
+                        warn!("Skipped set change of state machine {}, {:?}", sm_id, e);
+                        future::ready(()).boxed()
+                    }
+                },
+                &self.set,
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raft::state_machine::callback::client::SubscriptionService;
+    use crate::raft::state_machine::configs::commands::subscribe as conf_subscribe;
+    use crate::raft::state_machine::configs::CONFIG_SM_ID;
+    use crate::raft::{Options, RaftMsg, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+    use bifrost_hasher::hash_bytes;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sets() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2044");
+        let subscriber_addr = String::from("127.0.0.1:2045");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Sets::new(&raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let a = SetClient::<u32>::new(DEFAULT_SERVICE_ID, &raft_client, "a");
+        let b = SetClient::<u32>::new(DEFAULT_SERVICE_ID, &raft_client, "b");
+
+        // Subscribe to changes of set a with a local subscription service
+        let subscriber = Server::new(&subscriber_addr);
+        Server::listen_and_resume(&subscriber).await;
+        let sub_service = SubscriptionService::initialize(&subscriber).await;
+        let (fn_id, _, pattern_data) = commands::on_changed::new(&String::from("a")).encode();
+        let key = (
+            RAFT_SERVICE_ID,
+            DEFAULT_SERVICE_ID,
+            fn_id,
+            hash_bytes(&pattern_data),
+        );
+        let changes = Arc::new(parking_lot::Mutex::new(vec![]));
+        let changes_clone = changes.clone();
+        sub_service.subs.write().await.insert(
+            key,
+            vec![(
+                Box::new(move |data: Vec<u8>| {
+                    let change = commands::on_changed::decode_return(&data).unwrap();
+                    changes_clone
+                        .lock()
+                        .push(decode_change::<u32>(DEFAULT_SERVICE_ID, change).unwrap());
+                    future::ready(()).boxed()
+                }),
+                0,
+            )],
+        );
+        raft_client
+            .execute(
+                CONFIG_SM_ID,
+                conf_subscribe::new(&key, &subscriber_addr, &sub_service.session_id),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(a.add(&1).await.unwrap());
+        assert!(!a.add(&1).await.unwrap());
+        assert_eq!(a.add_all(&[1, 2, 3]).await.unwrap(), 2);
+        assert_eq!(b.add_all(&[2, 3, 4]).await.unwrap(), 3);
+        assert!(a.contains(&2).await.unwrap());
+        assert!(!a.contains(&4).await.unwrap());
+        assert_eq!(a.len().await.unwrap(), 3);
+
+        let sorted = |mut v: Vec<u32>| {
+            v.sort();
+            v
+        };
+        assert_eq!(sorted(a.union(&["b"]).await.unwrap()), vec![1, 2, 3, 4]);
+        assert_eq!(sorted(a.intersection(&["b"]).await.unwrap()), vec![2, 3]);
+        assert_eq!(a.difference(&["b"]).await.unwrap(), vec![1]);
+        assert_eq!(
+            a.intersection(&["b", "none"]).await.unwrap(),
+            Vec::<u32>::new()
+        );
+        assert_eq!(a.difference(&["none"]).await.unwrap().len(), 3);
+
+        // Members of other types fail to decode instead of panicking
+        let names = SetClient::<String>::new(DEFAULT_SERVICE_ID, &raft_client, "names");
+        assert!(names.add(&String::from("x")).await.unwrap());
+        match a.union(&["names"]).await {
+            Err(ExecError::CannotDecode { sm_id, .. }) => assert_eq!(sm_id, DEFAULT_SERVICE_ID),
+            other => panic!("Expect decode failure, got {:?}", other),
+        }
+
+        assert!(a.remove(&1).await.unwrap());
+        assert!(!a.remove(&1).await.unwrap());
+        assert_eq!(sorted(a.members().await.unwrap()), vec![2, 3]);
+        assert_eq!(b.clear().await.unwrap(), 3);
+        assert_eq!(b.len().await.unwrap(), 0);
+
+        async_wait_secs().await;
+        let mut changes = changes.lock().clone();
+        changes.sort();
+        assert_eq!(
+            changes,
+            vec![
+                SetChange::Added(1),
+                SetChange::Added(2),
+                SetChange::Added(3),
+                SetChange::Removed(1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,474 @@
+// Replicated named sorted sets, ordered by score then by member
+
+use crate::raft::client::{RaftClient, SubscriptionError, SubscriptionReceipt};
+use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::StateMachineCtl;
+use crate::raft::RaftService;
+use crate::utils::serde::{deserialize, serialize};
+use bifrost_plugins::hash_ident;
+use futures::FutureExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::marker::PhantomData;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::sync::Arc;
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_SORTED_SET) as u64;
+
+pub type Scored = (Vec<u8>, f64);
+//                  (member, old score, new score)
+pub type Change = (Vec<u8>, Option<f64>, Option<f64>);
+
+raft_state_machine! {
+    def cmd add(set: String, member: Vec<u8>, score: f64) -> Option<f64>;
+    def cmd incr(set: String, member: Vec<u8>, delta: f64) -> f64;
+    def cmd remove(set: String, member: Vec<u8>) -> Option<f64>;
+    def cmd clear(set: String) -> u64;
+    def qry score(set: String, member: Vec<u8>) -> Option<f64>;
+    def qry rank(set: String, member: Vec<u8>, reverse: bool) -> Option<u64>;
+    def qry range_by_rank(set: String, start: u64, limit: u64, reverse: bool) -> Vec<Scored>;
+    def qry range_by_score(set: String, min: f64, max: f64, limit: u64) -> Vec<Scored>;
+    def qry len(set: String) -> u64;
+    def sub on_changed(set: String) -> Change;
+}
+
+// Order preserving bits of a score, for the ordered index
+fn score_key(score: f64) -> u64 {
+    let bits = score.to_bits();
+    if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | (1 << 63)
+    }
+}
+
+#[derive(Default)]
+pub struct ScoredSet {
+    scores: HashMap<Vec<u8>, f64>,
+    index: BTreeSet<(u64, Vec<u8>)>,
+}
+
+impl ScoredSet {
+    fn insert(&mut self, member: Vec<u8>, score: f64) -> Option<f64> {
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.index.remove(&(score_key(old), member.clone()));
+        }
+        self.index.insert((score_key(score), member));
+        old
+    }
+    fn remove(&mut self, member: &Vec<u8>) -> Option<f64> {
+        let old = self.scores.remove(member);
+        if let Some(old) = old {
+            self.index.remove(&(score_key(old), member.clone()));
+        }
+        old
+    }
+    fn scored(&self, (_, member): &(u64, Vec<u8>)) -> Scored {
+        (member.clone(), self.scores[member])
+    }
+}
+
+pub struct SortedSets {
+    pub sets: HashMap<String, ScoredSet>,
+    pub id: u64,
+    callback: Option<SMCallback>,
+}
+
+impl SortedSets {
+    async fn changed(&self, set: &String, member: Vec<u8>, old: Option<f64>, new: Option<f64>) {
+        if old != new {
+            let msg = commands::on_changed::new(set);
+            cb_notify(&self.callback, msg, || (member, old, new)).await;
+        }
+    }
+    async fn set_score(&mut self, set: &String, member: Vec<u8>, score: f64) -> Option<f64> {
+        let old = self
+            .sets
+            .entry(set.clone())
+            .or_insert_with(Default::default)
+            .insert(member.clone(), score);
+        self.changed(set, member, old, Some(score)).await;
+        old
+    }
+}
+
+impl StateMachineCmds for SortedSets {
+    fn add(&mut self, set: String, member: Vec<u8>, score: f64) -> BoxFuture<Option<f64>> {
+        async move { self.set_score(&set, member, score).await }.boxed()
+    }
+    fn incr(&mut self, set: String, member: Vec<u8>, delta: f64) -> BoxFuture<f64> {
+        async move {
+            let score = self
+                .sets
+                .get(&set)
+                .and_then(|s| s.scores.get(&member))
+                .cloned()
+                .unwrap_or(0.0)
+                + delta;
+            self.set_score(&set, member, score).await;
+            score
+        }
+        .boxed()
+    }
+    fn remove(&mut self, set: String, member: Vec<u8>) -> BoxFuture<Option<f64>> {
+        async move {
+            let old = match self.sets.get_mut(&set) {
+                Some(s) => {
+                    let old = s.remove(&member);
+                    if s.scores.is_empty() {
+                        self.sets.remove(&set);
+                    }
+                    old
+                }
+                None => None,
+            };
+            self.changed(&set, member, old, None).await;
+            old
+        }
+        .boxed()
+    }
+    fn clear(&mut self, set: String) -> BoxFuture<u64> {
+        async move {
+            let removed = self.sets.remove(&set).unwrap_or_default();
+            let len = removed.scores.len() as u64;
+            // Notify in order of members, not in the order of the hash map that differs
+            // among replicas
+            let mut scores: Vec<_> = removed.scores.into_iter().collect();
+            scores.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (member, score) in scores {
+                self.changed(&set, member, Some(score), None).await;
+            }
+            len
+        }
+        .boxed()
+    }
+    fn score(&self, set: String, member: Vec<u8>) -> BoxFuture<Option<f64>> {
+        future::ready(
+            self.sets
+                .get(&set)
+                .and_then(|s| s.scores.get(&member))
+                .cloned(),
+        )
+        .boxed()
+    }
+    fn rank(&self, set: String, member: Vec<u8>, reverse: bool) -> BoxFuture<Option<u64>> {
+        let rank = self.sets.get(&set).and_then(|s| {
+            let score = s.scores.get(&member)?;
+            let entry = (score_key(*score), member);
+            let lower = s.index.range(..&entry).count();
+            Some(if reverse {
+                s.index.len() - lower - 1
+            } else {
+                lower
+            } as u64)
+        });
+        future::ready(rank).boxed()
+    }
+    fn range_by_rank(
+        &self,
+        set: String,
+        start: u64,
+        limit: u64,
+        reverse: bool,
+    ) -> BoxFuture<Vec<Scored>> {
+        let res = match self.sets.get(&set) {
+            Some(s) if reverse => s
+                .index
+                .iter()
+                .rev()
+                .skip(start as usize)
+                .take(limit as usize)
+                .map(|e| s.scored(e))
+                .collect(),
+            Some(s) => s
+                .index
+                .iter()
+                .skip(start as usize)
+                .take(limit as usize)
+                .map(|e| s.scored(e))
+                .collect(),
+            None => vec![],
+        };
+        future::ready(res).boxed()
+    }
+    // Scores from `min` to `max`, both inclusive
+    fn range_by_score(
+        &self,
+        set: String,
+        min: f64,
+        max: f64,
+        limit: u64,
+    ) -> BoxFuture<Vec<Scored>> {
+        let res = match self.sets.get(&set) {
+            Some(s) if min <= max => s
+                .index
+                .range((
+                    Included((score_key(min), vec![])),
+                    match score_key(max).checked_add(1) {
+                        Some(upper) => Excluded((upper, vec![])),
+                        None => Unbounded,
+                    },
+                ))
+                .take(limit as usize)
+                .map(|e| s.scored(e))
+                .collect(),
+            _ => vec![],
+        };
+        future::ready(res).boxed()
+    }
+    fn len(&self, set: String) -> BoxFuture<u64> {
+        future::ready(self.sets.get(&set).map(|s| s.scores.len()).unwrap_or(0) as u64).boxed()
+    }
+}
+
+impl StateMachineCtl for SortedSets {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        let scores: HashMap<_, _> = self.sets.iter().map(|(k, s)| (k, &s.scores)).collect();
+        Some(serialize(&scores))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        let sets: Option<HashMap<String, HashMap<Vec<u8>, f64>>> = deserialize(data.as_slice());
+        match sets {
+            // Rebuild the index from scores
+            Some(sets) => {
+                self.sets = sets
+                    .into_iter()
+                    .map(|(name, scores)| {
+                        let mut set = ScoredSet::default();
+                        for (member, score) in scores {
+                            set.insert(member, score);
+                        }
+                        (name, set)
+                    })
+                    .collect()
+            }
+            None => error!("Cannot decode snapshot of sorted sets {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl SortedSets {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+        raft_service
+            .register_state_machine(Box::new(SortedSets {
+                sets: HashMap::new(),
+                id,
+                callback: Some(SMCallback::new(id, raft_service.clone()).await),
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
+    }
+}
+
+// Typed client for one sorted set. Members not matching the type fail with `CannotDecode`
+pub struct SortedSetClient<T> {
+    sm: client::SMClient,
+    sm_id: u64,
+    set: String,
+    _type: PhantomData<fn() -> T>,
+}
+
+fn decode_member<T: DeserializeOwned>(
+    sm_id: u64,
+    fn_id: u64,
+    data: Vec<u8>,
+) -> Result<T, ExecError> {
+    deserialize(data.as_slice()).ok_or(ExecError::CannotDecode { sm_id, fn_id })
+}
+
+fn decode_scored<T: DeserializeOwned>(
+    sm_id: u64,
+    fn_id: u64,
+    entries: Vec<Scored>,
+) -> Result<Vec<(T, f64)>, ExecError> {
+    entries
+        .into_iter()
+        .map(|(member, score)| Ok((decode_member(sm_id, fn_id, member)?, score)))
+        .collect()
+}
+
+impl<T> SortedSetClient<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>, set: &str) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            sm_id,
+            set: set.to_string(),
+            _type: PhantomData,
+        }
+    }
+    // Set the score of the member, returns the old score
+    pub async fn add(&self, member: &T, score: f64) -> Result<Option<f64>, ExecError> {
+        self.sm.add(&self.set, &serialize(member), &score).await
+    }
+    // Add to the score of the member, from 0 for new members
+    pub async fn incr(&self, member: &T, delta: f64) -> Result<f64, ExecError> {
+        self.sm.incr(&self.set, &serialize(member), &delta).await
+    }
+    pub async fn remove(&self, member: &T) -> Result<Option<f64>, ExecError> {
+        self.sm.remove(&self.set, &serialize(member)).await
+    }
+    pub async fn clear(&self) -> Result<u64, ExecError> {
+        self.sm.clear(&self.set).await
+    }
+    pub async fn score(&self, member: &T) -> Result<Option<f64>, ExecError> {
+        self.sm.score(&self.set, &serialize(member)).await
+    }
+    pub async fn rank(&self, member: &T) -> Result<Option<u64>, ExecError> {
+        self.sm.rank(&self.set, &serialize(member), &false).await
+    }
+    pub async fn rev_rank(&self, member: &T) -> Result<Option<u64>, ExecError> {
+        self.sm.rank(&self.set, &serialize(member), &true).await
+    }
+    pub async fn range_by_rank(&self, start: u64, limit: u64) -> Result<Vec<(T, f64)>, ExecError> {
+        let entries = self
+            .sm
+            .range_by_rank(&self.set, &start, &limit, &false)
+            .await?;
+        decode_scored(self.sm_id, hash_ident!(range_by_rank) as u64, entries)
+    }
+    // Highest scores first, for leaderboards
+    pub async fn top(&self, start: u64, limit: u64) -> Result<Vec<(T, f64)>, ExecError> {
+        let entries = self
+            .sm
+            .range_by_rank(&self.set, &start, &limit, &true)
+            .await?;
+        decode_scored(self.sm_id, hash_ident!(range_by_rank) as u64, entries)
+    }
+    pub async fn range_by_score(
+        &self,
+        min: f64,
+        max: f64,
+        limit: u64,
+    ) -> Result<Vec<(T, f64)>, ExecError> {
+        let entries = self
+            .sm
+            .range_by_score(&self.set, &min, &max, &limit)
+            .await?;
+        decode_scored(self.sm_id, hash_ident!(range_by_score) as u64, entries)
+    }
+    pub async fn len(&self) -> Result<u64, ExecError> {
+        self.sm.len(&self.set).await
+    }
+    // Score changes as (member, old score, new score). Members not matching the type are
+    // logged and skipped
+    pub async fn on_changed<F>(
+        &self,
+        f: F,
+    ) -> Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>
+    where
+        F: Fn((T, Option<f64>, Option<f64>)) -> BoxFuture<'static, ()> + 'static + Send + Sync,
+    {
+        let sm_id = self.sm_id;
+        let fn_id = hash_ident!(on_changed) as u64;
+        self.sm
+            .on_changed(
+                move |(member, old, new)| match decode_member(sm_id, fn_id, member) {
+                    Ok(member) => f((member, old, new)),
+                    Err(e) => {
+                        warn!(
+                            "Skipped sorted set change of state machine {}, {:?}",
+                            sm_id, e
+                        );
+                        future::ready(()).boxed()
+                    }
+                },
+                &self.set,
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raft::{Options, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+
+    #[test]
+    fn score_order() {
+        let scores = [f64::MIN, -100.0, -1.5, 0.0, 1.0, 2.5, f64::MAX];
+        for pair in scores.windows(2) {
+            assert!(score_key(pair[0]) < score_key(pair[1]));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sorted_sets() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2046");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        SortedSets::new(&raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let board = SortedSetClient::<String>::new(DEFAULT_SERVICE_ID, &raft_client, "board");
+        let name = |s: &str| s.to_string();
+
+        assert_eq!(board.add(&name("a"), 10.0).await.unwrap(), None);
+        assert_eq!(board.add(&name("b"), -5.0).await.unwrap(), None);
+        assert_eq!(board.add(&name("c"), 20.0).await.unwrap(), None);
+        assert_eq!(board.add(&name("a"), 30.0).await.unwrap(), Some(10.0));
+        assert_eq!(board.incr(&name("d"), 15.0).await.unwrap(), 15.0);
+        assert_eq!(board.incr(&name("d"), 1.5).await.unwrap(), 16.5);
+        assert_eq!(board.len().await.unwrap(), 4);
+        assert_eq!(board.score(&name("a")).await.unwrap(), Some(30.0));
+
+        assert_eq!(board.rank(&name("b")).await.unwrap(), Some(0));
+        assert_eq!(board.rank(&name("a")).await.unwrap(), Some(3));
+        assert_eq!(board.rev_rank(&name("a")).await.unwrap(), Some(0));
+        assert_eq!(board.rank(&name("none")).await.unwrap(), None);
+        assert_eq!(
+            board.top(0, 2).await.unwrap(),
+            vec![(name("a"), 30.0), (name("c"), 20.0)]
+        );
+        assert_eq!(
+            board.range_by_rank(1, 2).await.unwrap(),
+            vec![(name("d"), 16.5), (name("c"), 20.0)]
+        );
+        assert_eq!(
+            board.range_by_score(-5.0, 20.0, 10).await.unwrap(),
+            vec![(name("b"), -5.0), (name("d"), 16.5), (name("c"), 20.0)]
+        );
+        assert_eq!(board.range_by_score(20.0, -5.0, 10).await.unwrap(), vec![]);
+
+        // Members of other types fail to decode instead of panicking
+        let numbers = SortedSetClient::<u64>::new(DEFAULT_SERVICE_ID, &raft_client, "numbers");
+        assert_eq!(numbers.add(&7, 1.0).await.unwrap(), None);
+        let misread = SortedSetClient::<String>::new(DEFAULT_SERVICE_ID, &raft_client, "numbers");
+        match misread.top(0, 10).await {
+            Err(ExecError::CannotDecode { sm_id, .. }) => assert_eq!(sm_id, DEFAULT_SERVICE_ID),
+            other => panic!("Expect decode failure, got {:?}", other),
+        }
+
+        assert_eq!(board.remove(&name("c")).await.unwrap(), Some(20.0));
+        assert_eq!(board.remove(&name("c")).await.unwrap(), None);
+        assert_eq!(board.rev_rank(&name("d")).await.unwrap(), Some(1));
+        assert_eq!(board.clear().await.unwrap(), 3);
+        assert_eq!(board.len().await.unwrap(), 0);
+    }
+}
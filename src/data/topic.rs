@@ -0,0 +1,587 @@
+// Replicated append only topic logs with retention and consumer group offsets
+
+use super::command;
+use crate::raft::client::{Lagged, RaftClient, SubscriptionError, SubscriptionStream};
+use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
+use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::{applied_time, StateMachineCtl};
+use crate::raft::{RaftMsg, RaftService};
+use crate::utils::serde::{deserialize, serialize};
+use crate::utils::time::get_time;
+use bifrost_plugins::hash_ident;
+use futures::stream::Stream;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_DATA_TOPIC) as u64;
+
+pub const NOT_FOUND: u32 = 1;
+pub const OFFSET_OUT_OF_RANGE: u32 = 2;
+
+static WATCH_INTERVAL_MS: u64 = 1000;
+static READ_PAGE: u64 = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TopicConfig {
+    // Zero for no limit
+    pub max_records: u64,
+    pub max_age_ms: u64,
+    // Keep only the last record of each key
+    pub compact: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub offset: u64,
+    pub key: Option<Vec<u8>>,
+    pub data: Vec<u8>,
+    pub timestamp: i64,
+}
+
+raft_state_machine! {
+    def cmd create(topic: String, config: TopicConfig) -> bool;
+    def cmd delete(topic: String) -> bool;
+    def cmd append(topic: String, key: Option<Vec<u8>>, data: Vec<u8>, timestamp: i64) -> u64 | ExecError;
+    def cmd commit(topic: String, group: String, offset: u64) -> () | ExecError;
+    def cmd expire_(now: i64);
+    def qry read(topic: String, from: u64, limit: u64) -> Vec<Record> | ExecError;
+    def qry offsets(topic: String) -> (u64, u64) | ExecError;
+    def qry committed(topic: String, group: String) -> Option<u64> | ExecError;
+    def sub on_appended(topic: String) -> Record;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopicState {
+    pub config: TopicConfig,
+    pub records: BTreeMap<u64, Record>,
+    // Offset of the last record of keys, for compaction
+    pub keys: HashMap<Vec<u8>, u64>,
+    pub next_offset: u64,
+    // Next offset to read by consumer groups
+    pub groups: HashMap<String, u64>,
+}
+
+struct Retention {
+    aging: AtomicBool,
+    closed: AtomicBool,
+}
+
+pub struct Topics {
+    pub topics: HashMap<String, TopicState>,
+    pub id: u64,
+    retention: Arc<Retention>,
+    callback: Option<SMCallback>,
+}
+
+impl Drop for Topics {
+    fn drop(&mut self) {
+        self.retention.closed.store(true, Ordering::Relaxed)
+    }
+}
+
+fn not_found(topic: &String) -> ExecError {
+    ExecError::state_machine(NOT_FOUND, format!("Topic {} not found", topic))
+}
+
+impl TopicState {
+    fn start_offset(&self) -> u64 {
+        self.records
+            .keys()
+            .next()
+            .cloned()
+            .unwrap_or(self.next_offset)
+    }
+    fn remove(&mut self, offset: u64) {
+        if let Some(record) = self.records.remove(&offset) {
+            if let Some(key) = record.key {
+                if self.keys.get(&key) == Some(&offset) {
+                    self.keys.remove(&key);
+                }
+            }
+        }
+    }
+    fn trim(&mut self) {
+        let max_records = self.config.max_records;
+        while max_records > 0 && self.records.len() as u64 > max_records {
+            let first = self.start_offset();
+            self.remove(first);
+        }
+    }
+    fn expire(&mut self, now: i64) {
+        let max_age_ms = self.config.max_age_ms;
+        if max_age_ms == 0 {
+            return;
+        }
+        // Records are expired in order of offsets like a log
+        while let Some(record) = self.records.values().next() {
+            if record.timestamp + max_age_ms as i64 > now {
+                break;
+            }
+            let first = record.offset;
+            self.remove(first);
+        }
+    }
+}
+
+impl Topics {
+    fn track(&self) {
+        let aging = self
+            .topics
+            .values()
+            .any(|topic| topic.config.max_age_ms > 0 && !topic.records.is_empty());
+        self.retention.aging.store(aging, Ordering::Relaxed);
+    }
+}
+
+impl StateMachineCmds for Topics {
+    // False when the topic exists
+    fn create(&mut self, topic: String, config: TopicConfig) -> BoxFuture<bool> {
+        if self.topics.contains_key(&topic) {
+            return future::ready(false).boxed();
+        }
+        let state = TopicState {
+            config,
+            records: BTreeMap::new(),
+            keys: HashMap::new(),
+            next_offset: 0,
+            groups: HashMap::new(),
+        };
+        self.topics.insert(topic, state);
+        future::ready(true).boxed()
+    }
+    fn delete(&mut self, topic: String) -> BoxFuture<bool> {
+        let deleted = self.topics.remove(&topic).is_some();
+        self.track();
+        future::ready(deleted).boxed()
+    }
+    fn append(
+        &mut self,
+        topic: String,
+        key: Option<Vec<u8>>,
+        data: Vec<u8>,
+        timestamp: i64,
+    ) -> BoxFuture<Result<u64, ExecError>> {
+        async move {
+            let state = match self.topics.get_mut(&topic) {
+                Some(state) => state,
+                None => return Err(not_found(&topic)),
+            };
+            let offset = state.next_offset;
+            state.next_offset += 1;
+            if let Some(key) = &key {
+                if state.config.compact {
+                    if let Some(last) = state.keys.insert(key.clone(), offset) {
+                        state.records.remove(&last);
+                    }
+                }
+            }
+            // Producer clocks may run ahead and hold back expiry of every later record,
+            // they are only used for entries logged without the leader clock
+            let timestamp = match applied_time() {
+                0 => timestamp,
+                now => now,
+            };
+            let record = Record {
+                offset,
+                key,
+                data,
+                timestamp,
+            };
+            state.records.insert(offset, record.clone());
+            state.trim();
+            self.track();
+            let msg = commands::on_appended::new(&topic);
+            cb_notify(&self.callback, msg, || record).await;
+            Ok(offset)
+        }
+        .boxed()
+    }
+    fn commit(
+        &mut self,
+        topic: String,
+        group: String,
+        offset: u64,
+    ) -> BoxFuture<Result<(), ExecError>> {
+        let res = match self.topics.get_mut(&topic) {
+            Some(state) if offset > state.next_offset => Err(ExecError::state_machine(
+                OFFSET_OUT_OF_RANGE,
+                format!("Offset {} is ahead of {}", offset, state.next_offset),
+            )),
+            Some(state) => {
+                state.groups.insert(group, offset);
+                Ok(())
+            }
+            None => Err(not_found(&topic)),
+        };
+        future::ready(res).boxed()
+    }
+    fn expire_(&mut self, now: i64) -> BoxFuture<()> {
+        for state in self.topics.values_mut() {
+            state.expire(now);
+        }
+        self.track();
+        future::ready(()).boxed()
+    }
+    // Records from the offset in order, offsets of dropped records are skipped
+    fn read(
+        &self,
+        topic: String,
+        from: u64,
+        limit: u64,
+    ) -> BoxFuture<Result<Vec<Record>, ExecError>> {
+        let res = self
+            .topics
+            .get(&topic)
+            .ok_or_else(|| not_found(&topic))
+            .map(|state| {
+                state
+                    .records
+                    .range(from..)
+                    .take(limit as usize)
+                    .map(|(_, record)| record.clone())
+                    .collect()
+            });
+        future::ready(res).boxed()
+    }
+    // First retained offset and the offset of the next record
+    fn offsets(&self, topic: String) -> BoxFuture<Result<(u64, u64), ExecError>> {
+        let res = self
+            .topics
+            .get(&topic)
+            .ok_or_else(|| not_found(&topic))
+            .map(|state| (state.start_offset(), state.next_offset));
+        future::ready(res).boxed()
+    }
+    fn committed(&self, topic: String, group: String) -> BoxFuture<Result<Option<u64>, ExecError>> {
+        let res = self
+            .topics
+            .get(&topic)
+            .ok_or_else(|| not_found(&topic))
+            .map(|state| state.groups.get(&group).cloned());
+        future::ready(res).boxed()
+    }
+}
+
+impl StateMachineCtl for Topics {
+    raft_sm_complete!();
+    fn id(&self) -> u64 {
+        self.id
+    }
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(serialize(&self.topics))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        match deserialize(data.as_slice()) {
+            Some(topics) => {
+                self.topics = topics;
+                self.track();
+            }
+            None => error!("Cannot decode snapshot of topics {}", self.id),
+        }
+        future::ready(()).boxed()
+    }
+}
+
+impl Topics {
+    pub async fn new_with_id(id: u64, raft_service: &Arc<RaftService>) {
+        let retention = Arc::new(Retention {
+            aging: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(watch_retention(id, raft_service.clone(), retention.clone()));
+        raft_service
+            .register_state_machine(Box::new(Topics {
+                topics: HashMap::new(),
+                id,
+                retention,
+                callback: Some(SMCallback::new(id, raft_service.clone()).await),
+            }))
+            .await
+    }
+    pub async fn new(raft_service: &Arc<RaftService>) {
+        Self::new_with_id(DEFAULT_SERVICE_ID, raft_service).await
+    }
+}
+
+// Leader issues commands to expire records of topics with max age
+async fn watch_retention(sm_id: u64, raft_service: Arc<RaftService>, retention: Arc<Retention>) {
+    while !retention.closed.load(Ordering::Relaxed) {
+        sleep(Duration::from_millis(WATCH_INTERVAL_MS)).await;
+        if !raft_service.is_leader() || !retention.aging.load(Ordering::Relaxed) {
+            continue;
+        }
+        let (fn_id, _, data) = commands::expire_::new(&get_time()).encode();
+        command(&raft_service, sm_id, fn_id, data).await;
+    }
+    debug!("Topic retention watcher for {} exiting", sm_id);
+}
+
+// Records read from the log followed by live records. Live records dropped for the stream
+// being full are reported as `Lagged`, read or tail again from the next offset to catch up
+pub struct Tail<S = SubscriptionStream<Record>> {
+    replay: VecDeque<Record>,
+    // Live records before this offset were replayed
+    next_offset: u64,
+    live: S,
+}
+
+impl<S> Tail<S> {
+    pub fn new(replay: Vec<Record>, from: u64, live: S) -> Self {
+        let next_offset = replay
+            .last()
+            .map(|record| record.offset + 1)
+            .unwrap_or(from);
+        Self {
+            replay: replay.into(),
+            next_offset,
+            live,
+        }
+    }
+}
+
+impl<S: Stream<Item = Result<Record, Lagged>> + Unpin> Stream for Tail<S> {
+    type Item = Result<Record, Lagged>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(record) = this.replay.pop_front() {
+            return Poll::Ready(Some(Ok(record)));
+        }
+        loop {
+            match Pin::new(&mut this.live).poll_next(cx) {
+                Poll::Ready(Some(Ok(record))) if record.offset < this.next_offset => continue,
+                res => return res,
+            }
+        }
+    }
+}
+
+// Client for one topic
+pub struct TopicClient {
+    sm: client::SMClient,
+    topic: String,
+}
+
+impl TopicClient {
+    pub fn new(sm_id: u64, client: &Arc<RaftClient>, topic: &str) -> Self {
+        Self {
+            sm: client::SMClient::new(sm_id, client),
+            topic: topic.to_string(),
+        }
+    }
+    // False when the topic exists
+    pub async fn create(&self, config: TopicConfig) -> Result<bool, ExecError> {
+        self.sm.create(&self.topic, &config).await
+    }
+    pub async fn delete(&self) -> Result<bool, ExecError> {
+        self.sm.delete(&self.topic).await
+    }
+    // Offset of the record
+    pub async fn append(&self, data: &[u8]) -> Result<u64, ExecError> {
+        self.append_record(None, data).await
+    }
+    // Appended records replace earlier records of the key in compacted topics
+    pub async fn append_keyed(&self, key: &[u8], data: &[u8]) -> Result<u64, ExecError> {
+        self.append_record(Some(key), data).await
+    }
+    async fn append_record(&self, key: Option<&[u8]>, data: &[u8]) -> Result<u64, ExecError> {
+        let key = key.map(|k| k.to_vec());
+        self.sm
+            .append(&self.topic, &key, &data.to_vec(), &get_time())
+            .await
+    }
+    pub async fn read(&self, from: u64, limit: u64) -> Result<Vec<Record>, ExecError> {
+        self.sm.read(&self.topic, &from, &limit).await
+    }
+    // First retained offset and the offset of the next record
+    pub async fn offsets(&self) -> Result<(u64, u64), ExecError> {
+        self.sm.offsets(&self.topic).await
+    }
+    // Commit the next offset to read by the group
+    pub async fn commit(&self, group: &str, offset: u64) -> Result<(), ExecError> {
+        self.sm
+            .commit(&self.topic, &group.to_string(), &offset)
+            .await
+    }
+    pub async fn committed(&self, group: &str) -> Result<Option<u64>, ExecError> {
+        self.sm.committed(&self.topic, &group.to_string()).await
+    }
+    // All records retained from the offset
+    pub async fn read_all(&self, from: u64) -> Result<Vec<Record>, ExecError> {
+        let mut records: Vec<Record> = vec![];
+        let mut from = from;
+        loop {
+            let page = self.read(from, READ_PAGE).await?;
+            let full = page.len() as u64 == READ_PAGE;
+            records.extend(page);
+            match records.last() {
+                Some(last) if full => from = last.offset + 1,
+                _ => return Ok(records),
+            }
+        }
+    }
+    // Records from the offset followed by records appended later.
+    // Records are dropped when more than `capacity` are waiting to be taken.
+    pub async fn follow(
+        &self,
+        from: u64,
+        capacity: usize,
+    ) -> Result<Result<Tail, SubscriptionError>, ExecError> {
+        // Subscribe before reading so no record falls in between
+        let live = match self.sm.streams(capacity).on_appended(&self.topic).await? {
+            Ok(live) => live,
+            Err(e) => return Ok(Err(e)),
+        };
+        let replay = self.read_all(from).await?;
+        Ok(Ok(Tail::new(replay, from, live)))
+    }
+    // Follow from the offset committed by the group, or the first retained offset
+    pub async fn follow_group(
+        &self,
+        group: &str,
+        capacity: usize,
+    ) -> Result<Result<Tail, SubscriptionError>, ExecError> {
+        let from = match self.committed(group).await? {
+            Some(offset) => offset,
+            None => self.offsets().await?.0,
+        };
+        self.follow(from, capacity).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raft::{Options, Storage, DEFAULT_SERVICE_ID as RAFT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+    use futures::StreamExt;
+
+    fn record(offset: u64, data: &[u8]) -> Record {
+        Record {
+            offset,
+            key: None,
+            data: data.to_vec(),
+            timestamp: 0,
+        }
+    }
+
+    fn offsets(records: &Vec<Record>) -> Vec<u64> {
+        records.iter().map(|r| r.offset).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tail_replay() {
+        let replay = vec![record(2, b"a"), record(3, b"b")];
+        // Live records overlap with replayed records
+        let live = futures::stream::iter(vec![Ok(record(3, b"b")), Ok(record(4, b"c"))]);
+        let records: Vec<_> = Tail::new(replay, 2, live)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(offsets(&records), vec![2, 3, 4]);
+        let live = futures::stream::iter(vec![
+            Ok(record(0, b"a")),
+            Ok(record(1, b"b")),
+            Err(Lagged(2)),
+        ]);
+        let records: Vec<_> = Tail::new(vec![], 1, live).collect().await;
+        assert_eq!(records, vec![Ok(record(1, b"b")), Err(Lagged(2))]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn topics() {
+        let _ = env_logger::try_init();
+        let addr = String::from("127.0.0.1:2054");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: RAFT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(RAFT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Topics::new(&raft_service).await;
+        async_wait_secs().await;
+
+        let raft_client = RaftClient::new(&vec![addr], RAFT_SERVICE_ID).await.unwrap();
+        let events = TopicClient::new(DEFAULT_SERVICE_ID, &raft_client, "events");
+        match events.append(b"a").await {
+            Err(ExecError::StateMachineError {
+                code: NOT_FOUND, ..
+            }) => {}
+            other => panic!("Expect not found, got {:?}", other),
+        }
+        let config = TopicConfig {
+            max_records: 3,
+            ..TopicConfig::default()
+        };
+        assert!(events.create(config.clone()).await.unwrap());
+        assert!(!events.create(config).await.unwrap());
+        for (i, data) in [b"a", b"b", b"c", b"d"].iter().enumerate() {
+            assert_eq!(events.append(*data).await.unwrap(), i as u64);
+        }
+        // Bounded to 3 records
+        assert_eq!(events.offsets().await.unwrap(), (1, 4));
+        assert_eq!(offsets(&events.read_all(0).await.unwrap()), vec![1, 2, 3]);
+        assert_eq!(events.read(2, 1).await.unwrap()[0].data, b"c");
+
+        assert_eq!(events.committed("g").await.unwrap(), None);
+        events.commit("g", 3).await.unwrap();
+        assert_eq!(events.committed("g").await.unwrap(), Some(3));
+        match events.commit("g", 5).await {
+            Err(ExecError::StateMachineError {
+                code: OFFSET_OUT_OF_RANGE,
+                ..
+            }) => {}
+            other => panic!("Expect offset out of range, got {:?}", other),
+        }
+
+        // Compacted topics keep the last record of keys
+        let states = TopicClient::new(DEFAULT_SERVICE_ID, &raft_client, "states");
+        let config = TopicConfig {
+            compact: true,
+            ..TopicConfig::default()
+        };
+        assert!(states.create(config).await.unwrap());
+        states.append_keyed(b"x", b"1").await.unwrap();
+        states.append_keyed(b"y", b"1").await.unwrap();
+        states.append_keyed(b"x", b"2").await.unwrap();
+        states.append(b"none").await.unwrap();
+        let records = states.read_all(0).await.unwrap();
+        assert_eq!(offsets(&records), vec![1, 2, 3]);
+        assert_eq!(records[1].data, b"2");
+
+        // Expired by the leader
+        let recent = TopicClient::new(DEFAULT_SERVICE_ID, &raft_client, "recent");
+        let config = TopicConfig {
+            max_age_ms: 1000,
+            ..TopicConfig::default()
+        };
+        assert!(recent.create(config).await.unwrap());
+        recent.append(b"old").await.unwrap();
+        // Producer clock ahead of the leader
+        let future = get_time() + 3_600_000;
+        let (topic, data) = (String::from("recent"), b"ahead".to_vec());
+        recent
+            .sm
+            .append(&topic, &None, &data, &future)
+            .await
+            .unwrap();
+        async_wait_secs().await;
+        assert_eq!(recent.offsets().await.unwrap(), (2, 2));
+        assert!(recent.read_all(0).await.unwrap().is_empty());
+        assert!(recent.delete().await.unwrap());
+        assert!(recent.offsets().await.is_err());
+    }
+}
@@ -21,6 +21,7 @@ pub mod rpc;
 #[macro_use]
 pub mod raft;
 pub mod conshash;
+pub mod data;
 pub mod membership;
 pub mod vector_clock;
 
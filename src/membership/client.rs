@@ -1,17 +1,44 @@
 use crate::membership::raft::client::SMClient;
+use crate::membership::registry::{
+    Health, ServiceEndpoint, ServiceEvent, ServiceFilter, ServiceInstance,
+};
 use crate::membership::DEFAULT_SERVICE_ID;
 use crate::raft::client::{RaftClient, SubscriptionError, SubscriptionReceipt};
 use crate::raft::state_machine::master::ExecError;
 use bifrost_hasher::hash_str;
 use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
+// Well known metadata keys
+pub static ZONE: &str = "zone";
+pub static RACK: &str = "rack";
+pub static VERSION: &str = "version";
+pub static CAPACITY: &str = "capacity";
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Member {
     pub id: u64,
     pub address: String,
     pub online: bool,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Member {
+    pub fn zone(&self) -> Option<&str> {
+        self.metadata.get(ZONE).map(|z| z.as_str())
+    }
+    pub fn rack(&self) -> Option<&str> {
+        self.metadata.get(RACK).map(|r| r.as_str())
+    }
+    pub fn capacity(&self) -> Option<u64> {
+        self.metadata.get(CAPACITY).and_then(|c| c.parse().ok())
+    }
+    // Tags are metadata entries, the member has to carry all of them
+    pub fn has_tags(&self, tags: &BTreeMap<String, String>) -> bool {
+        tags.iter().all(|(k, v)| self.metadata.get(k) == Some(v))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -33,6 +60,27 @@ impl MemberClient {
     pub async fn leave_group(&self, group: &String) -> Result<bool, ExecError> {
         self.sm_client.leave_group(&hash_str(group), &self.id).await
     }
+    pub async fn update_metadata(
+        &self,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<bool, ExecError> {
+        self.sm_client.update_metadata(&self.id, metadata).await
+    }
+    pub async fn register_service(&self, endpoint: &ServiceEndpoint) -> Result<bool, ExecError> {
+        self.sm_client.register_service(&self.id, endpoint).await
+    }
+    pub async fn deregister_service(&self, name: &String) -> Result<bool, ExecError> {
+        self.sm_client.deregister_service(&self.id, name).await
+    }
+    pub async fn set_service_health(
+        &self,
+        name: &String,
+        health: Health,
+    ) -> Result<bool, ExecError> {
+        self.sm_client
+            .set_service_health(&self.id, name, &health)
+            .await
+    }
 }
 
 pub struct ObserverClient {
@@ -71,6 +119,16 @@ impl ObserverClient {
             .group_members(&hash_str(group), &online_only)
             .await
     }
+    pub async fn group_members_by_tags(
+        &self,
+        group: &String,
+        tags: &BTreeMap<String, String>,
+        online_only: bool,
+    ) -> Result<Option<(Vec<Member>, u64)>, ExecError> {
+        self.sm_client
+            .group_members_by_tags(&hash_str(group), tags, &online_only)
+            .await
+    }
     pub async fn all_members(&self, online_only: bool) -> Result<(Vec<Member>, u64), ExecError> {
         self.sm_client.all_members(&online_only).await
     }
@@ -158,6 +216,15 @@ impl ObserverClient {
     {
         self.sm_client.on_any_member_left(f).await
     }
+    pub async fn on_member_metadata_changed<F>(
+        &self,
+        f: F,
+    ) -> Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>
+    where
+        F: Fn((Member, u64)) -> BoxFuture<'static, ()> + 'static + Send + Sync,
+    {
+        self.sm_client.on_member_metadata_changed(f).await
+    }
     pub async fn on_group_leader_changed<F>(
         &self,
         f: F,
@@ -173,4 +240,21 @@ impl ObserverClient {
             .on_group_leader_changed(f, &hash_str(group))
             .await
     }
+    pub async fn resolve(
+        &self,
+        name: &String,
+        filter: &ServiceFilter,
+    ) -> Result<(Vec<ServiceInstance>, u64), ExecError> {
+        self.sm_client.resolve_service(name, filter).await
+    }
+    pub async fn on_service_changed<F>(
+        &self,
+        f: F,
+        name: &String,
+    ) -> Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>
+    where
+        F: Fn((ServiceEvent, u64)) -> BoxFuture<'static, ()> + 'static + Send + Sync,
+    {
+        self.sm_client.on_service_changed(f, name).await
+    }
 }
@@ -1,8 +1,10 @@
 use super::client::{MemberClient, ObserverClient};
 use super::heartbeat_rpc::*;
 use super::raft::client::SMClient;
+use super::registry::{Health, ServiceEndpoint};
 use bifrost_hasher::hash_str;
 use futures::prelude::*;
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::time;
@@ -23,6 +25,13 @@ pub struct MemberService {
 
 impl MemberService {
     pub async fn new(server_address: &String, raft_client: &Arc<RaftClient>) -> Arc<MemberService> {
+        Self::new_with_metadata(server_address, &BTreeMap::new(), raft_client).await
+    }
+    pub async fn new_with_metadata(
+        server_address: &String,
+        metadata: &BTreeMap<String, String>,
+        raft_client: &Arc<RaftClient>,
+    ) -> Arc<MemberService> {
         let server_id = hash_str(server_address);
         let sm_client = Arc::new(SMClient::new(DEFAULT_SERVICE_ID, &raft_client));
         let service = Arc::new(MemberService {
@@ -35,7 +44,9 @@ impl MemberService {
             closed: AtomicBool::new(false),
             id: server_id,
         });
-        let _join_res = sm_client.join(&server_address).await;
+        let _join_res = sm_client
+            .join_with_metadata(&server_address, metadata)
+            .await;
         let service_clone = service.clone();
         tokio::spawn(async move {
             while !service_clone.closed.load(Ordering::Relaxed) {
@@ -64,6 +75,25 @@ impl MemberService {
     pub async fn leave_group(&self, group: &String) -> Result<bool, ExecError> {
         self.member_client.leave_group(group).await
     }
+    pub async fn update_metadata(
+        &self,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<bool, ExecError> {
+        self.member_client.update_metadata(metadata).await
+    }
+    pub async fn register_service(&self, endpoint: &ServiceEndpoint) -> Result<bool, ExecError> {
+        self.member_client.register_service(endpoint).await
+    }
+    pub async fn deregister_service(&self, name: &String) -> Result<bool, ExecError> {
+        self.member_client.deregister_service(name).await
+    }
+    pub async fn set_service_health(
+        &self,
+        name: &String,
+        health: Health,
+    ) -> Result<bool, ExecError> {
+        self.member_client.set_service_health(name, health).await
+    }
     pub fn client(&self) -> ObserverClient {
         ObserverClient::new_from_sm(&self.sm_client)
     }
@@ -2,10 +2,15 @@
 
 pub mod client;
 pub mod member;
+pub mod registry;
 pub mod server;
 
 use crate::membership::client::Member as ClientMember;
+use crate::membership::registry::{
+    Health, ServiceEndpoint, ServiceEvent, ServiceFilter, ServiceInstance,
+};
 use bifrost_plugins::hash_ident;
+use std::collections::BTreeMap;
 
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_MEMBERSHIP_SERVICE) as u64;
 
@@ -31,6 +36,15 @@ pub mod raft {
         def sub on_group_member_left(group: u64) -> (ClientMember, u64); //
         def sub on_any_member_left() -> (ClientMember, u64); //
         def sub on_group_leader_changed(group: u64) -> (Option<ClientMember>, Option<ClientMember>, u64);
+        def cmd register_service(id: u64, endpoint: ServiceEndpoint) -> bool;
+        def cmd deregister_service(id: u64, name: String) -> bool;
+        def cmd set_service_health(id: u64, name: String, health: Health) -> bool;
+        def qry resolve_service(name: String, filter: ServiceFilter) -> (Vec<ServiceInstance>, u64);
+        def sub on_service_changed(name: String) -> (ServiceEvent, u64);
+        def cmd join_with_metadata(address: String, metadata: BTreeMap<String, String>) -> Option<u64>;
+        def cmd update_metadata(id: u64, metadata: BTreeMap<String, String>) -> bool;
+        def qry group_members_by_tags(group: u64, tags: BTreeMap<String, String>, online_only: bool) -> Option<(Vec<ClientMember>, u64)>;
+        def sub on_member_metadata_changed() -> (ClientMember, u64);
     }
 }
 
@@ -45,14 +59,17 @@ mod heartbeat_rpc {
 
 #[cfg(test)]
 mod test {
-    use crate::membership::client::ObserverClient;
+    use crate::membership::client::{ObserverClient, CAPACITY, ZONE};
     use crate::membership::member::MemberService;
+    use crate::membership::registry::{Health, ServiceEndpoint, ServiceFilter};
     use crate::membership::server::Membership;
     use crate::raft::client::RaftClient;
     use crate::raft::{Options, RaftService, Storage, DEFAULT_SERVICE_ID};
-    use crate::rpc::Server;
+    use crate::rpc::{ClientPool, Server};
     use crate::utils::time::async_wait_secs;
+    use bifrost_hasher::hash_str;
     use futures::prelude::*;
+    use std::collections::BTreeMap;
     use std::sync::atomic::*;
     use std::sync::Arc;
 
@@ -558,4 +575,289 @@ mod test {
         assert_eq!(group_member_online_count.load(Ordering::Relaxed), 0);
         assert_eq!(group_member_offline_count.load(Ordering::Relaxed), 1);
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn service_registry() {
+        let _ = env_logger::builder().format_timestamp(None).try_init();
+        let addr = String::from("127.0.0.1:2058");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: DEFAULT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(DEFAULT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Membership::new(&server, &raft_service).await;
+        let raft_client = RaftClient::new(&vec![addr.clone()], DEFAULT_SERVICE_ID)
+            .await
+            .unwrap();
+        let client = ObserverClient::new(&raft_client);
+
+        let group = String::from("registry_group");
+        let name = String::from("storage");
+        let member_a = MemberService::new(&String::from("registry_a"), &raft_client).await;
+        let member_b = MemberService::new(&String::from("registry_b"), &raft_client).await;
+        member_a.join_group(&group).await.unwrap();
+
+        let mut endpoint_a = ServiceEndpoint::new(&name, &addr, DEFAULT_SERVICE_ID);
+        endpoint_a.version = String::from("v1");
+        endpoint_a
+            .metadata
+            .insert(String::from("zone"), String::from("east"));
+        let mut endpoint_b = ServiceEndpoint::new(&name, &addr, DEFAULT_SERVICE_ID);
+        endpoint_b.version = String::from("v2");
+        assert!(member_a.register_service(&endpoint_a).await.unwrap());
+        assert!(member_b.register_service(&endpoint_b).await.unwrap());
+
+        let resolve = |filter: ServiceFilter| {
+            let client = &client;
+            let name = &name;
+            async move { client.resolve(name, &filter).await.unwrap().0 }
+        };
+        assert_eq!(resolve(ServiceFilter::default()).await.len(), 2);
+        assert!(resolve(ServiceFilter::default())
+            .await
+            .iter()
+            .all(|i| i.member.online));
+        let v2 = resolve(ServiceFilter {
+            version: Some(String::from("v2")),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(v2.len(), 1);
+        assert_eq!(v2[0].member.id, member_b.get_server_id());
+        let mut metadata = BTreeMap::new();
+        metadata.insert(String::from("zone"), String::from("east"));
+        let east = resolve(ServiceFilter {
+            metadata,
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(east.len(), 1);
+        assert_eq!(east[0].member.id, member_a.get_server_id());
+        let grouped = resolve(ServiceFilter {
+            group: Some(group.clone()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(grouped.len(), 1);
+        assert_eq!(grouped[0].member.id, member_a.get_server_id());
+
+        assert!(member_a
+            .set_service_health(&name, Health::Unhealthy)
+            .await
+            .unwrap());
+        let healthy = resolve(ServiceFilter {
+            healthy_only: true,
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(healthy.len(), 1);
+        assert_eq!(healthy[0].member.id, member_b.get_server_id());
+
+        // Resolved endpoints connect through the pool
+        let pool = ClientPool::new();
+        let rpc_client = healthy[0].rpc_client(&pool).await.unwrap();
+        assert_eq!(rpc_client.server_id, hash_str(&addr));
+
+        assert!(member_b.deregister_service(&name).await.unwrap());
+        assert!(!member_b.deregister_service(&name).await.unwrap());
+        assert!(!member_b
+            .set_service_health(&name, Health::Healthy)
+            .await
+            .unwrap());
+        assert_eq!(resolve(ServiceFilter::default()).await.len(), 1);
+        member_a.leave().await.unwrap();
+        assert_eq!(resolve(ServiceFilter::default()).await.len(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn metadata() {
+        let _ = env_logger::builder().format_timestamp(None).try_init();
+        let addr = String::from("127.0.0.1:2059");
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: DEFAULT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(DEFAULT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service.bootstrap().await;
+        Membership::new(&server, &raft_service).await;
+        async_wait_secs().await;
+        let raft_client = RaftClient::new(&vec![addr.clone()], DEFAULT_SERVICE_ID)
+            .await
+            .unwrap();
+        let client = ObserverClient::new(&raft_client);
+        RaftClient::prepare_subscription(&server).await;
+
+        let changed_count = Arc::new(AtomicUsize::new(0));
+        let changed_count_clone = changed_count.clone();
+        client
+            .on_member_metadata_changed(move |(member, _)| {
+                assert_eq!(member.zone(), Some("west"));
+                changed_count_clone.fetch_add(1, Ordering::Relaxed);
+                future::ready(()).boxed()
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let group = String::from("metadata_group");
+        let mut metadata_a = BTreeMap::new();
+        metadata_a.insert(String::from(ZONE), String::from("east"));
+        metadata_a.insert(String::from(CAPACITY), String::from("8"));
+        let mut metadata_b = BTreeMap::new();
+        metadata_b.insert(String::from(ZONE), String::from("west"));
+        let member_a = MemberService::new_with_metadata(
+            &String::from("metadata_a"),
+            &metadata_a,
+            &raft_client,
+        )
+        .await;
+        let member_b = MemberService::new_with_metadata(
+            &String::from("metadata_b"),
+            &metadata_b,
+            &raft_client,
+        )
+        .await;
+        member_a.join_group(&group).await.unwrap();
+        member_b.join_group(&group).await.unwrap();
+
+        let (members, _) = client.group_members(&group, true).await.unwrap().unwrap();
+        assert_eq!(members.len(), 2);
+        let a = members
+            .iter()
+            .find(|m| m.id == member_a.get_server_id())
+            .unwrap();
+        assert_eq!(a.metadata, metadata_a);
+        assert_eq!(a.zone(), Some("east"));
+        assert_eq!(a.capacity(), Some(8));
+        let (all, _) = client.all_members(true).await.unwrap();
+        assert!(all
+            .iter()
+            .any(|m| m.id == member_b.get_server_id() && m.metadata == metadata_b));
+
+        let mut east = BTreeMap::new();
+        east.insert(String::from(ZONE), String::from("east"));
+        let tagged = |tags: BTreeMap<String, String>| {
+            let client = &client;
+            let group = &group;
+            async move {
+                client
+                    .group_members_by_tags(group, &tags, false)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .0
+                    .into_iter()
+                    .map(|m| m.id)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(tagged(east.clone()).await, vec![member_a.get_server_id()]);
+        assert_eq!(tagged(BTreeMap::new()).await.len(), 2);
+        assert!(client
+            .group_members_by_tags(&String::from("no_group"), &east, false)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Moving member a to the west zone
+        let mut metadata_a = metadata_a.clone();
+        metadata_a.insert(String::from(ZONE), String::from("west"));
+        assert!(member_a.update_metadata(&metadata_a).await.unwrap());
+        // Unchanged metadata is not notified
+        assert!(member_a.update_metadata(&metadata_a).await.unwrap());
+        assert!(tagged(east).await.is_empty());
+        let mut west = BTreeMap::new();
+        west.insert(String::from(ZONE), String::from("west"));
+        assert_eq!(tagged(west).await.len(), 2);
+
+        // Joining again replaces the metadata of the member
+        metadata_a.insert(String::from(CAPACITY), String::from("16"));
+        let rejoined = MemberService::new_with_metadata(
+            &String::from("metadata_a"),
+            &metadata_a,
+            &raft_client,
+        )
+        .await;
+        rejoined.close();
+        let (all, _) = client.all_members(true).await.unwrap();
+        let a = all
+            .iter()
+            .find(|m| m.id == member_a.get_server_id())
+            .unwrap();
+        assert_eq!(a.capacity(), Some(16));
+
+        async_wait_secs().await;
+        assert_eq!(changed_count.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn snapshot() {
+        let _ = env_logger::builder().format_timestamp(None).try_init();
+        let node = |addr: &str| {
+            let addr = addr.to_string();
+            async move {
+                let raft_service = RaftService::new(Options {
+                    storage: Storage::default(),
+                    address: addr.clone(),
+                    service_id: DEFAULT_SERVICE_ID,
+                });
+                let server = Server::new(&addr);
+                server
+                    .register_service(DEFAULT_SERVICE_ID, &raft_service)
+                    .await;
+                Server::listen_and_resume(&server).await;
+                RaftService::start(&raft_service).await;
+                raft_service.bootstrap().await;
+                Membership::new(&server, &raft_service).await;
+                async_wait_secs().await;
+                RaftClient::new(&vec![addr], DEFAULT_SERVICE_ID)
+                    .await
+                    .unwrap()
+            }
+        };
+        let path = std::env::temp_dir().join("bifrost_membership_snapshot_test.bak");
+        let origin = node("127.0.0.1:2068").await;
+        let group = String::from("snapshot_group");
+        let name = String::from("snapshot_service");
+        let mut metadata = BTreeMap::new();
+        metadata.insert(String::from(ZONE), String::from("east"));
+        let member =
+            MemberService::new_with_metadata(&String::from("snapshot_a"), &metadata, &origin).await;
+        member.join_group(&group).await.unwrap();
+        let endpoint = ServiceEndpoint::new(&name, &String::from("snapshot_a"), 1);
+        assert!(member.register_service(&endpoint).await.unwrap());
+        member.close();
+        origin.backup(&path).await.unwrap();
+
+        let restored = node("127.0.0.1:2069").await;
+        restored.restore(&path).await.unwrap();
+        let client = ObserverClient::new(&restored);
+        let (members, _) = client.group_members(&group, false).await.unwrap().unwrap();
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].id, member.get_server_id());
+        assert_eq!(members[0].metadata, metadata);
+        assert!(members[0].online);
+        let leader = client.group_leader(&group).await.unwrap().unwrap().0;
+        assert_eq!(leader.map(|m| m.id), Some(member.get_server_id()));
+        let (instances, _) = client
+            .resolve(&name, &ServiceFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].endpoint.address, endpoint.address);
+        let _ = std::fs::remove_file(&path);
+    }
 }
@@ -0,0 +1,92 @@
+// Service endpoints advertised by members, resolved by name through the membership state machine.
+// Endpoints go away with the member, and are reported again when the member goes on or offline.
+
+use super::client::Member;
+use crate::rpc::{ClientPool, RPCClient};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServiceEndpoint {
+    pub name: String,
+    // Address of the rpc server, e.g. `host:port`
+    pub address: String,
+    // Id of the rpc service at the address
+    pub service_id: u64,
+    pub version: String,
+    pub metadata: BTreeMap<String, String>,
+    pub health: Health,
+}
+
+impl ServiceEndpoint {
+    pub fn new(name: &str, address: &str, service_id: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            address: address.to_string(),
+            service_id,
+            version: String::new(),
+            metadata: BTreeMap::new(),
+            health: Health::Healthy,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceInstance {
+    pub member: Member,
+    pub endpoint: ServiceEndpoint,
+}
+
+impl ServiceInstance {
+    // Client of the endpoint address, calls should go to `endpoint.service_id`
+    pub async fn rpc_client(&self, pool: &ClientPool) -> io::Result<Arc<RPCClient>> {
+        pool.get(&self.endpoint.address).await
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ServiceEvent {
+    // Registered, health changed, or the member went on or offline
+    Updated(ServiceInstance),
+    // Deregistered or the member left
+    Removed(ServiceInstance),
+}
+
+// Default filter matches all endpoints of the name
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ServiceFilter {
+    // Members of the group only
+    pub group: Option<String>,
+    pub version: Option<String>,
+    // Endpoints with all of the metadata
+    pub metadata: BTreeMap<String, String>,
+    // Exclude degraded and unhealthy endpoints
+    pub healthy_only: bool,
+    pub online_only: bool,
+}
+
+impl ServiceFilter {
+    // Group is left to the membership state machine
+    pub fn matches(&self, member: &Member, endpoint: &ServiceEndpoint) -> bool {
+        (!self.online_only || member.online)
+            && (!self.healthy_only || endpoint.health == Health::Healthy)
+            && self
+                .version
+                .as_ref()
+                .map(|v| v == &endpoint.version)
+                .unwrap_or(true)
+            && self
+                .metadata
+                .iter()
+                .all(|(k, v)| endpoint.metadata.get(k) == Some(v))
+    }
+}
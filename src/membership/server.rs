@@ -2,17 +2,22 @@ use super::heartbeat_rpc::*;
 use super::raft::*;
 use super::*;
 use crate::membership::client::Member as ClientMember;
+use crate::membership::registry::{
+    Health, ServiceEndpoint, ServiceEvent, ServiceFilter, ServiceInstance,
+};
 use crate::raft::state_machine::callback::server::{notify as cb_notify, SMCallback};
 use crate::raft::state_machine::StateMachineCtl;
 use crate::raft::{LogEntry, RaftMsg, RaftService, Service as raft_svr_trait};
 use crate::rpc::Server;
+use crate::utils::serde::{deserialize, serialize};
 use crate::utils::time;
 use async_std::sync::*;
 use bifrost_hasher::hash_str;
 use futures::prelude::future::*;
 use futures::prelude::*;
 use futures::stream::FuturesUnordered;
-use std::collections::{BTreeSet, HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::future::Future;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
@@ -61,6 +66,8 @@ impl HeartbeatService {
                 sm_id: DEFAULT_SERVICE_ID,
                 fn_id,
                 data,
+                sm_version: 0,
+                time: 0,
             })
             .await;
     }
@@ -77,13 +84,16 @@ impl HeartbeatService {
 }
 dispatch_rpc_service_functions!(HeartbeatService);
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct Member {
     pub id: u64,
     pub address: String,
     pub groups: HashSet<u64>,
+    pub services: BTreeMap<String, ServiceEndpoint>,
+    pub metadata: BTreeMap<String, String>,
 }
 
+#[derive(Serialize, Deserialize)]
 struct MemberGroup {
     members: BTreeSet<u64>,
     leader: Option<u64>,
@@ -93,6 +103,8 @@ pub struct Membership {
     heartbeat: Arc<HeartbeatService>,
     groups: HashMap<u64, MemberGroup>,
     members: HashMap<u64, Member>,
+    // Online flags as applied, the heartbeat status of leader may run ahead of them
+    online: parking_lot::Mutex<HashMap<u64, bool>>,
     callback: Option<SMCallback>,
     version: u64,
 }
@@ -168,6 +180,7 @@ impl Membership {
             heartbeat: service.clone(),
             groups: HashMap::new(),
             members: HashMap::new(),
+            online: parking_lot::Mutex::new(HashMap::new()),
             callback: None,
             version: 0,
         };
@@ -184,8 +197,54 @@ impl Membership {
             id,
             address: member.address.clone(),
             online: stat_map.get(&id).unwrap().online,
+            metadata: member.metadata.clone(),
         }
     }
+    async fn join_member(
+        &mut self,
+        address: String,
+        metadata: Option<BTreeMap<String, String>>,
+    ) -> Option<u64> {
+        self.version += 1;
+        let id = hash_str(&address);
+        if let Some(member) = self.members.get_mut(&id) {
+            match metadata {
+                Some(metadata) if member.metadata != metadata => member.metadata = metadata,
+                _ => return None,
+            }
+            self.notify_for_metadata_changed(id).await;
+            return None;
+        }
+        {
+            let current_time = time::get_time();
+            let mut stat_map = self.heartbeat.status.write().await;
+            let mut stat = stat_map.entry(id).or_insert_with(|| HBStatus {
+                online: true,
+                last_updated: current_time,
+            });
+            stat.online = true;
+            stat.last_updated = current_time;
+        }
+        self.online.lock().insert(id, true);
+        self.members.insert(
+            id,
+            Member {
+                id,
+                address,
+                groups: HashSet::new(),
+                services: BTreeMap::new(),
+                metadata: metadata.unwrap_or_default(),
+            },
+        );
+        let composed_client_member = self.compose_client_member(id).await;
+        cb_notify(
+            &self.callback,
+            commands::on_any_member_joined::new(),
+            || (composed_client_member, self.version),
+        )
+        .await;
+        Some(id)
+    }
     async fn init_callback(&mut self, raft_service: &Arc<RaftService>) {
         self.callback = Some(SMCallback::new(self.id(), raft_service.clone()).await);
     }
@@ -209,6 +268,7 @@ impl Membership {
                 .await;
             }
         }
+        self.notify_for_member_services(id, false).await;
     }
     async fn notify_for_member_offline(&self, id: u64) {
         debug!("Notifying member {} offline", id);
@@ -230,6 +290,7 @@ impl Membership {
                 .await;
             }
         }
+        self.notify_for_member_services(id, false).await;
     }
     async fn notify_for_member_left(&self, id: u64) {
         debug!("Notifying member {} left", id);
@@ -245,6 +306,47 @@ impl Membership {
                     .await
             }
         }
+        self.notify_for_member_services(id, true).await;
+    }
+    async fn notify_for_metadata_changed(&self, id: u64) {
+        let client_member = self.compose_client_member(id).await;
+        cb_notify(
+            &self.callback,
+            commands::on_member_metadata_changed::new(),
+            || (client_member, self.version),
+        )
+        .await;
+    }
+    async fn notify_for_service(&self, id: u64, name: &str, removed: bool) {
+        let endpoint = match self.members.get(&id).and_then(|m| m.services.get(name)) {
+            Some(endpoint) => endpoint.clone(),
+            None => return,
+        };
+        let instance = ServiceInstance {
+            member: self.compose_client_member(id).await,
+            endpoint,
+        };
+        let event = if removed {
+            ServiceEvent::Removed(instance)
+        } else {
+            ServiceEvent::Updated(instance)
+        };
+        let version = self.version;
+        cb_notify(
+            &self.callback,
+            commands::on_service_changed::new(&name.to_string()),
+            || (event, version),
+        )
+        .await;
+    }
+    async fn notify_for_member_services(&self, id: u64, removed: bool) {
+        let names: Vec<_> = match self.members.get(&id) {
+            Some(member) => member.services.keys().cloned().collect(),
+            None => return,
+        };
+        for name in names {
+            self.notify_for_service(id, &name, removed).await;
+        }
     }
     async fn notify_for_group_member_left(&self, group: u64, member: &ClientMember) {
         debug!("Notifying member {:?} left group {}", member, group);
@@ -392,6 +494,17 @@ impl StateMachineCmds for Membership {
                         stat.online = false;
                     }
                 }
+                let mut online_map = self.online.lock();
+                for id in &online {
+                    if let Some(member_online) = online_map.get_mut(id) {
+                        *member_online = true;
+                    }
+                }
+                for id in &offline {
+                    if let Some(member_online) = online_map.get_mut(id) {
+                        *member_online = false;
+                    }
+                }
             }
             for id in online {
                 self.notify_for_member_online(id).await;
@@ -405,42 +518,15 @@ impl StateMachineCmds for Membership {
         .boxed()
     }
     fn join(&mut self, address: String) -> BoxFuture<Option<u64>> {
-        async move {
-            self.version += 1;
-            let id = hash_str(&address);
-            let mut joined = false;
-            {
-                let mut stat_map = self.heartbeat.status.write().await;
-                self.members.entry(id).or_insert_with(|| {
-                    let current_time = time::get_time();
-                    let mut stat = stat_map.entry(id).or_insert_with(|| HBStatus {
-                        online: true,
-                        last_updated: current_time,
-                    });
-                    stat.online = true;
-                    stat.last_updated = current_time;
-                    joined = true;
-                    Member {
-                        id,
-                        address: address.clone(),
-                        groups: HashSet::new(),
-                    }
-                });
-            }
-            if joined {
-                let composed_client_member = self.compose_client_member(id).await;
-                cb_notify(
-                    &self.callback,
-                    commands::on_any_member_joined::new(),
-                    || (composed_client_member, self.version),
-                )
-                .await;
-                Some(id)
-            } else {
-                None
-            }
-        }
-        .boxed()
+        self.join_member(address, None).boxed()
+    }
+    // Members joined again keep their state, with the metadata replaced
+    fn join_with_metadata(
+        &mut self,
+        address: String,
+        metadata: BTreeMap<String, String>,
+    ) -> BoxFuture<Option<u64>> {
+        self.join_member(address, Some(metadata)).boxed()
     }
     fn leave(&mut self, id: u64) -> BoxFuture<bool> {
         async move {
@@ -465,6 +551,7 @@ impl StateMachineCmds for Membership {
                 let mut stat_map = self.heartbeat.status.write().await;
                 stat_map.remove(&id);
             }
+            self.online.lock().remove(&id);
             self.members.remove(&id);
             true
         }
@@ -609,18 +696,175 @@ impl StateMachineCmds for Membership {
         }
         .boxed()
     }
+    fn register_service(&mut self, id: u64, endpoint: ServiceEndpoint) -> BoxFuture<bool> {
+        async move {
+            let name = endpoint.name.clone();
+            match self.members.get_mut(&id) {
+                Some(member) => {
+                    member.services.insert(name.clone(), endpoint);
+                }
+                None => return false,
+            }
+            self.version += 1;
+            self.notify_for_service(id, &name, false).await;
+            true
+        }
+        .boxed()
+    }
+    fn deregister_service(&mut self, id: u64, name: String) -> BoxFuture<bool> {
+        async move {
+            if !self
+                .members
+                .get(&id)
+                .map(|m| m.services.contains_key(&name))
+                .unwrap_or(false)
+            {
+                return false;
+            }
+            self.version += 1;
+            // notify before removal so the event carries the endpoint
+            self.notify_for_service(id, &name, true).await;
+            self.members.get_mut(&id).unwrap().services.remove(&name);
+            true
+        }
+        .boxed()
+    }
+    fn set_service_health(&mut self, id: u64, name: String, health: Health) -> BoxFuture<bool> {
+        async move {
+            match self
+                .members
+                .get_mut(&id)
+                .and_then(|m| m.services.get_mut(&name))
+            {
+                Some(endpoint) if endpoint.health != health => endpoint.health = health,
+                Some(_) => return true,
+                None => return false,
+            }
+            self.version += 1;
+            self.notify_for_service(id, &name, false).await;
+            true
+        }
+        .boxed()
+    }
+    fn resolve_service(
+        &self,
+        name: String,
+        filter: ServiceFilter,
+    ) -> BoxFuture<(Vec<ServiceInstance>, u64)> {
+        async move {
+            let group = filter.group.as_ref().map(|g| hash_str(g));
+            let mut instances = vec![];
+            // members are in a hash map, sort for stable results
+            let mut ids: Vec<_> = self.members.keys().cloned().collect();
+            ids.sort();
+            for id in ids {
+                let member = &self.members[&id];
+                if group.map(|g| !member.groups.contains(&g)).unwrap_or(false) {
+                    continue;
+                }
+                if let Some(endpoint) = member.services.get(&name) {
+                    let client_member = self.compose_client_member(id).await;
+                    if filter.matches(&client_member, endpoint) {
+                        instances.push(ServiceInstance {
+                            member: client_member,
+                            endpoint: endpoint.clone(),
+                        });
+                    }
+                }
+            }
+            (instances, self.version)
+        }
+        .boxed()
+    }
+    fn update_metadata(&mut self, id: u64, metadata: BTreeMap<String, String>) -> BoxFuture<bool> {
+        async move {
+            match self.members.get_mut(&id) {
+                Some(member) if member.metadata != metadata => member.metadata = metadata,
+                Some(_) => return true,
+                None => return false,
+            }
+            self.version += 1;
+            self.notify_for_metadata_changed(id).await;
+            true
+        }
+        .boxed()
+    }
+    fn group_members_by_tags(
+        &self,
+        group: u64,
+        tags: BTreeMap<String, String>,
+        online_only: bool,
+    ) -> BoxFuture<Option<(Vec<ClientMember>, u64)>> {
+        async move {
+            let (members, version) = self.group_members(group, online_only).await?;
+            Some((
+                members
+                    .into_iter()
+                    .filter(|member| member.has_tags(&tags))
+                    .collect(),
+                version,
+            ))
+        }
+        .boxed()
+    }
 }
 impl StateMachineCtl for Membership {
     raft_sm_complete!();
     fn id(&self) -> u64 {
         DEFAULT_SERVICE_ID
     }
+    // Online states are kept with the members so recovered replicas agree on them
     fn snapshot(&self) -> Option<Vec<u8>> {
-        //Some(serialize!(&self.map))
-        None // TODO: Backup members
-    }
-    fn recover(&mut self, _: Vec<u8>) -> BoxFuture<()> {
-        future::ready(()).boxed()
-        //self.map = deserialize!(&data);
+        let online: HashMap<u64, bool> = self
+            .online
+            .lock()
+            .iter()
+            .filter(|(id, _)| self.members.contains_key(id))
+            .map(|(id, online)| (*id, *online))
+            .collect();
+        Some(serialize(&(
+            &self.members,
+            &self.groups,
+            &online,
+            self.version,
+        )))
+    }
+    fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+        async move {
+            let (members, groups, online, version): (
+                HashMap<u64, Member>,
+                HashMap<u64, MemberGroup>,
+                HashMap<u64, bool>,
+                u64,
+            ) = match deserialize(data.as_slice()) {
+                Some(snapshot) => snapshot,
+                None => {
+                    error!("Cannot decode snapshot of membership");
+                    return;
+                }
+            };
+            {
+                // Members get a full timeout to ping the leader, like on leadership transfer
+                let current_time = time::get_time();
+                let mut stat_map = self.heartbeat.status.write().await;
+                let mut online_map = self.online.lock();
+                online_map.clear();
+                for id in members.keys() {
+                    let online = online.get(id).cloned().unwrap_or(false);
+                    online_map.insert(*id, online);
+                    stat_map.insert(
+                        *id,
+                        HBStatus {
+                            online,
+                            last_updated: current_time,
+                        },
+                    );
+                }
+            }
+            self.members = members;
+            self.groups = groups;
+            self.version = version;
+        }
+        .boxed()
     }
 }
@@ -0,0 +1,287 @@
+// Point-in-time backup of all state machines
+//
+// File layout, all numbers are little endian:
+//   magic (8 bytes) | version (u32) | crc32 of body (u32) | body length (u64) | body
+// Body is the CBOR encoded `Backup`, independent from the build profile
+// Version 1 bodies carry items as (sm_id, data) pairs without schema version
+
+use crate::raft::state_machine::master::{ExecError, SnapshotDataItem, SnapshotDataItems};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+pub const BACKUP_MAGIC: &'static [u8; 8] = b"BIFROSTB";
+pub const BACKUP_VERSION: u32 = 2;
+const HEADER_LEN: usize = 8 + 4 + 4 + 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Backup {
+    pub cluster_id: u64,
+    pub log_id: u64,
+    pub log_term: u64,
+    pub items: SnapshotDataItems,
+}
+
+#[derive(Deserialize)]
+struct BackupV1 {
+    cluster_id: u64,
+    log_id: u64,
+    log_term: u64,
+    items: Vec<(u64, Vec<u8>)>,
+}
+
+impl From<BackupV1> for Backup {
+    fn from(v1: BackupV1) -> Self {
+        Backup {
+            cluster_id: v1.cluster_id,
+            log_id: v1.log_id,
+            log_term: v1.log_term,
+            items: v1
+                .items
+                .into_iter()
+                .map(|(sm_id, data)| SnapshotDataItem {
+                    sm_id,
+                    version: 0,
+                    data,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    IOError(io::Error),
+    ExecError(ExecError),
+    NotLeader,
+    BadMagic,
+    UnsupportedVersion(u32),
+    ChecksumMismatch,
+    CannotDecode,
+}
+
+impl Backup {
+    pub fn encode(&self) -> Vec<u8> {
+        let body = serde_cbor::to_vec(self).unwrap();
+        let mut data = Vec::with_capacity(HEADER_LEN + body.len());
+        data.extend_from_slice(BACKUP_MAGIC);
+        data.extend_from_slice(&BACKUP_VERSION.to_le_bytes());
+        data.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        data.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        data.extend_from_slice(&body);
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, BackupError> {
+        if data.len() < HEADER_LEN || &data[0..8] != BACKUP_MAGIC {
+            return Err(BackupError::BadMagic);
+        }
+        let mut u32_buf = [0u8; 4];
+        let mut u64_buf = [0u8; 8];
+        u32_buf.copy_from_slice(&data[8..12]);
+        let version = u32::from_le_bytes(u32_buf);
+        u32_buf.copy_from_slice(&data[12..16]);
+        let checksum = u32::from_le_bytes(u32_buf);
+        u64_buf.copy_from_slice(&data[16..24]);
+        let body_len = u64::from_le_bytes(u64_buf) as usize;
+        let body = &data[HEADER_LEN..];
+        if body.len() != body_len || crc32fast::hash(body) != checksum {
+            return Err(BackupError::ChecksumMismatch);
+        }
+        match version {
+            1 => serde_cbor::from_slice::<BackupV1>(body)
+                .map(Backup::from)
+                .map_err(|_| BackupError::CannotDecode),
+            BACKUP_VERSION => serde_cbor::from_slice(body).map_err(|_| BackupError::CannotDecode),
+            _ => Err(BackupError::UnsupportedVersion(version)),
+        }
+    }
+
+    // Written aside and renamed into place, a crash never leaves a torn backup at the path
+    pub async fn write_to<P: AsRef<Path>>(&self, path: P) -> Result<(), BackupError> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let written: io::Result<()> = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            file.write_all(&self.encode()).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp_path, path).await
+        }
+        .await;
+        written.map_err(BackupError::IOError)
+    }
+
+    pub async fn read_from<P: AsRef<Path>>(path: P) -> Result<Self, BackupError> {
+        let data = tokio::fs::read(path).await.map_err(BackupError::IOError)?;
+        Self::decode(data.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::raft::backup::{Backup, BackupError, BACKUP_MAGIC};
+    use crate::raft::client::RaftClient;
+    use crate::raft::state_machine::master::MasterStateMachine;
+    use crate::raft::state_machine::StateMachineCtl;
+    use crate::raft::{Options, RaftService, Storage, DEFAULT_SERVICE_ID};
+    use crate::rpc::Server;
+    use crate::utils::time::async_wait_secs;
+    use futures::FutureExt;
+
+    raft_state_machine! {
+        def cmd set(value: u64);
+        def qry get() -> u64;
+    }
+
+    struct Value {
+        value: u64,
+    }
+
+    impl StateMachineCmds for Value {
+        fn set(&mut self, value: u64) -> BoxFuture<()> {
+            self.value = value;
+            future::ready(()).boxed()
+        }
+        fn get(&self) -> BoxFuture<u64> {
+            future::ready(self.value).boxed()
+        }
+    }
+
+    impl StateMachineCtl for Value {
+        raft_sm_complete!();
+        fn id(&self) -> u64 {
+            20
+        }
+        fn snapshot(&self) -> Option<Vec<u8>> {
+            Some(crate::utils::serde::serialize(&self.value))
+        }
+        fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+            self.value = crate::utils::serde::deserialize(&data).unwrap();
+            future::ready(()).boxed()
+        }
+        fn schema_version(&self) -> u32 {
+            1
+        }
+        // Version 0 kept the value as u32
+        fn migrate_snapshot(&self, from_version: u32, data: Vec<u8>) -> Option<Vec<u8>> {
+            if from_version != 0 {
+                return None;
+            }
+            let value: u32 = crate::utils::serde::deserialize(&data)?;
+            Some(crate::utils::serde::serialize(&(value as u64)))
+        }
+    }
+
+    async fn single_node(addr: &str) -> std::sync::Arc<RaftClient> {
+        let addr = addr.to_string();
+        let raft_service = RaftService::new(Options {
+            storage: Storage::default(),
+            address: addr.clone(),
+            service_id: DEFAULT_SERVICE_ID,
+        });
+        let server = Server::new(&addr);
+        server
+            .register_service(DEFAULT_SERVICE_ID, &raft_service)
+            .await;
+        Server::listen_and_resume(&server).await;
+        RaftService::start(&raft_service).await;
+        raft_service
+            .register_state_machine(Box::new(Value { value: 0 }))
+            .await;
+        raft_service.bootstrap().await;
+        async_wait_secs().await;
+        RaftClient::new(&vec![addr], DEFAULT_SERVICE_ID)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn backup_and_restore() {
+        let _ = env_logger::try_init();
+        let path = std::env::temp_dir().join("bifrost_backup_test.bak");
+        let origin = single_node("127.0.0.1:2025").await;
+        let origin_sm = client::SMClient::new(20, &origin);
+        origin_sm.set(&42).await.unwrap();
+        let log_id = origin.backup(&path).await.unwrap();
+        assert!(log_id > 0);
+        assert!(!std::env::temp_dir()
+            .join("bifrost_backup_test.bak.tmp")
+            .exists());
+
+        let restored = single_node("127.0.0.1:2026").await;
+        let restored_sm = client::SMClient::new(20, &restored);
+        assert_eq!(restored_sm.get().await.unwrap(), 0);
+        assert_eq!(restored.restore(&path).await.unwrap(), log_id);
+        assert_eq!(restored_sm.get().await.unwrap(), 42);
+
+        let mut data = tokio::fs::read(&path).await.unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        match Backup::decode(&data) {
+            Err(BackupError::ChecksumMismatch) => {}
+            other => panic!("Corrupted backup should be rejected, got {:?}", other),
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restore_v1_backup() {
+        let _ = env_logger::try_init();
+        let path = std::env::temp_dir().join("bifrost_backup_v1_test.bak");
+        // Version 1 body, items are (sm_id, data) pairs of a version 0 state machine
+        let body = serde_cbor::to_vec(&serde_cbor::Value::Map(
+            vec![
+                ("cluster_id", serde_cbor::Value::Integer(0)),
+                ("log_id", serde_cbor::Value::Integer(1)),
+                ("log_term", serde_cbor::Value::Integer(1)),
+                (
+                    "items",
+                    serde_cbor::value::to_value(vec![(
+                        20u64,
+                        crate::utils::serde::serialize(&7u32),
+                    )])
+                    .unwrap(),
+                ),
+            ]
+            .into_iter()
+            .map(|(k, v)| (serde_cbor::Value::Text(k.to_string()), v))
+            .collect(),
+        ))
+        .unwrap();
+        let mut data = vec![];
+        data.extend_from_slice(BACKUP_MAGIC);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        data.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        data.extend_from_slice(&body);
+        tokio::fs::write(&path, data).await.unwrap();
+
+        let restored = single_node("127.0.0.1:2027").await;
+        let restored_sm = client::SMClient::new(20, &restored);
+        assert_eq!(restored.restore(&path).await.unwrap(), 1);
+        assert_eq!(restored_sm.get().await.unwrap(), 7);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn recover_legacy_master_snapshot() {
+        let _ = env_logger::try_init();
+        // Master snapshot taken before schema versions, items are (sm_id, data) pairs
+        let legacy = crate::utils::serde::serialize(&vec![
+            (20u64, crate::utils::serde::serialize(&7u32)),
+            (21u64, crate::utils::serde::serialize(&9u32)),
+        ]);
+        let mut master = MasterStateMachine::new(DEFAULT_SERVICE_ID);
+        master.register(Box::new(Value { value: 0 })).await;
+        master.recover(legacy).await;
+        let items = master.data_snapshot();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].sm_id, 20);
+        assert_eq!(
+            crate::utils::serde::deserialize::<u64>(&items[0].data),
+            Some(7)
+        );
+    }
+}
@@ -1,10 +1,14 @@
 use super::*;
+use crate::raft::backup::{Backup, BackupError};
 use crate::raft::state_machine::callback::client::SubscriptionService;
-use crate::raft::state_machine::callback::SubKey;
+use crate::raft::state_machine::callback::pattern::SubPattern;
+use crate::raft::state_machine::callback::{Notifications, SubKey};
 use crate::raft::state_machine::configs::commands::{
-    subscribe as conf_subscribe, unsubscribe as conf_unsubscribe,
+    resubscribe as conf_resubscribe, resubscribe_pattern as conf_resubscribe_pattern,
+    unsubscribe as conf_unsubscribe,
 };
-use crate::raft::state_machine::master::ExecError;
+use crate::raft::state_machine::master::commands::restore_ as master_restore;
+use crate::raft::state_machine::master::{ExecError, MASTER_SM_ID};
 use crate::raft::state_machine::StateMachineClient;
 use crate::rpc;
 use bifrost_hasher::{hash_bytes, hash_str};
@@ -13,8 +17,13 @@ use std::clone::Clone;
 use std::cmp::max;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::iter::FromIterator;
+use std::path::Path;
+use std::pin::Pin;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::time::sleep;
 
 const ORDERING: Ordering = Ordering::Relaxed;
@@ -54,11 +63,12 @@ pub struct RaftClient {
     last_log_id: AtomicU64,
     last_log_term: AtomicU64,
     service_id: u64,
+    this: Weak<RaftClient>, // for subscription renewal to resubscribe through this client
 }
 
 impl RaftClient {
     pub async fn new(servers: &Vec<String>, service_id: u64) -> Result<Arc<Self>, ClientError> {
-        let client = RaftClient {
+        let client = Arc::new_cyclic(|this| RaftClient {
             qry_meta: QryMeta {
                 pos: AtomicU64::new(rand::random::<u64>()),
             },
@@ -70,9 +80,10 @@ impl RaftClient {
             last_log_id: AtomicU64::new(0),
             last_log_term: AtomicU64::new(0),
             service_id,
-        };
+            this: this.clone(),
+        });
         client.update_info(servers).await?;
-        Ok(Arc::new(client))
+        Ok(client)
     }
     pub async fn prepare_subscription(server: &Arc<rpc::Server>) -> Option<()> {
         let mut callback = CALLBACK.write().await;
@@ -84,6 +95,16 @@ impl RaftClient {
             None
         };
     }
+    // Subscribe without a server, notifications are pushed through connections to the cluster
+    pub async fn prepare_push_subscription() -> Option<()> {
+        let mut callback = CALLBACK.write().await;
+        return if callback.is_none() {
+            *callback = Some(SubscriptionService::initialize_push());
+            Some(())
+        } else {
+            None
+        };
+    }
 
     async fn cluster_info<'a>(&'a self, servers: &Vec<String>) -> Option<ClientClusterInfo> {
         debug!("Getting server info for {:?}", servers);
@@ -258,7 +279,7 @@ impl RaftClient {
         };
         match response {
             Ok(data) => match data {
-                Ok(data) => Ok(M::decode_return(&data)),
+                Ok(data) => M::decode_return(&data).ok_or(ExecError::CannotDecode { sm_id, fn_id }),
                 Err(e) => Err(e),
             },
             Err(e) => Err(e),
@@ -306,17 +327,28 @@ impl RaftClient {
             Ok(c) => c,
             Err(e) => return Ok(Err(e)),
         };
+        let pattern = msg.pattern();
         let key = self.get_sub_key(sm_id, msg);
-        let wrapper_fn =
-            move |data: Vec<u8>| -> BoxFuture<'static, ()> { f(M::decode_return(&data)).boxed() };
+        let wrapper_fn = move |data: Vec<u8>| -> BoxFuture<'static, ()> {
+            match M::decode_return(&data) {
+                Some(res) => f(res).boxed(),
+                None => {
+                    warn!("Cannot decode notification for key {:?}", key);
+                    future::ready(()).boxed()
+                }
+            }
+        };
         let cluster_subs = self
-            .execute(
-                CONFIG_SM_ID,
-                conf_subscribe::new(&key, &callback.server_address, &callback.session_id),
-            )
+            .resubscribe_key(&callback, key, pattern.clone(), None, None)
             .await;
         match cluster_subs {
-            Ok(Ok(sub_id)) => {
+            Ok(Ok((sub_id, seq, _))) => {
+                // Notifications before the subscription will not be delivered
+                callback.last_seqs.write().await.entry(key).or_insert(seq);
+                if let Some(pattern) = pattern {
+                    callback.patterns.write().await.insert(key, pattern);
+                }
+                callback.add_client(self.service_id, &self.this).await;
                 let mut subs_map = callback.subs.write().await;
                 let subs_lst = subs_map.entry(key).or_insert_with(|| Vec::new());
                 let boxed_fn = Box::new(wrapper_fn);
@@ -328,6 +360,130 @@ impl RaftClient {
         }
     }
 
+    // Subscribe again for all local subscriptions to this cluster, with the last seen sequence.
+    // Notifications missed in between, like when the subscriber was unreachable or the cluster
+    // lost the subscription, will be replayed from the buffer. Returns number of replayed.
+    pub async fn resubscribe(&self) -> Result<Result<usize, SubscriptionError>, ExecError> {
+        let callback = match self.get_callback().await {
+            Ok(c) => c,
+            Err(e) => return Ok(Err(e)),
+        };
+        let local_subs: Vec<(SubKey, u64)> = callback
+            .subs
+            .read()
+            .await
+            .iter()
+            .filter(|(key, _)| key.0 == self.service_id)
+            .flat_map(|(key, subs)| subs.iter().map(move |(_, sub_id)| (*key, *sub_id)))
+            .collect();
+        callback.add_client(self.service_id, &self.this).await;
+        let mut replayed = 0;
+        for (key, sub_id) in local_subs {
+            let last_seq = callback.last_seqs.read().await.get(&key).cloned();
+            let pattern = callback.patterns.read().await.get(&key).cloned();
+            let cluster_sub = self
+                .resubscribe_key(&callback, key, pattern, Some(sub_id), last_seq)
+                .await?;
+            match cluster_sub {
+                Ok((new_sub_id, _, missed)) => {
+                    if new_sub_id != sub_id {
+                        debug!("Subscription {} is now {}", sub_id, new_sub_id);
+                        let mut subs_map = callback.subs.write().await;
+                        if let Some(subs_lst) = subs_map.get_mut(&key) {
+                            for sub in subs_lst.iter_mut().filter(|sub| sub.1 == sub_id) {
+                                sub.1 = new_sub_id;
+                            }
+                        }
+                    }
+                    replayed += missed.len();
+                    callback.deliver(key, missed).await;
+                }
+                Err(_) => return Ok(Err(SubscriptionError::RemoteError)),
+            }
+        }
+        Ok(Ok(replayed))
+    }
+
+    async fn resubscribe_key(
+        &self,
+        callback: &SubscriptionService,
+        key: SubKey,
+        pattern: Option<SubPattern>,
+        sub_id: Option<u64>,
+        last_seq: Option<u64>,
+    ) -> Result<Result<(u64, u64, Notifications), ()>, ExecError> {
+        callback.handshake_push(&self.servers().await).await;
+        let address = &callback.server_address;
+        let session_id = &callback.session_id;
+        match pattern {
+            Some(pattern) => {
+                self.execute(
+                    CONFIG_SM_ID,
+                    conf_resubscribe_pattern::new(
+                        &key, &pattern, address, session_id, &sub_id, &last_seq,
+                    ),
+                )
+                .await
+            }
+            None => {
+                self.execute(
+                    CONFIG_SM_ID,
+                    conf_resubscribe::new(&key, address, session_id, &sub_id, &last_seq),
+                )
+                .await
+            }
+        }
+    }
+
+    // Subscribe into a bounded stream. Notifications are dropped when the stream is full and the
+    // consumer receives `Lagged` with the number dropped, at where they were dropped.
+    // The subscription is removed when the stream is dropped.
+    pub async fn subscribe_stream<M, R>(
+        client: &Arc<Self>,
+        sm_id: u64,
+        msg: M,
+        capacity: usize,
+    ) -> Result<Result<SubscriptionStream<R>, SubscriptionError>, ExecError>
+    where
+        M: RaftMsg<R> + 'static,
+        R: 'static + Send,
+    {
+        let (sender, receiver) = mpsc::channel(max(capacity, 1));
+        let lagged = Arc::new(AtomicU64::new(0));
+        let missed = Arc::new(AtomicU64::new(0));
+        let lagged_ref = lagged.clone();
+        let missed_ref = missed.clone();
+        let f = move |item: R| -> BoxFuture<'static, ()> {
+            // Marker for notifications dropped before this one goes first
+            let dropped = missed_ref.swap(0, ORDERING);
+            if dropped > 0 {
+                if let Err(TrySendError::Full(_)) = sender.try_send(Err(Lagged(dropped))) {
+                    missed_ref.fetch_add(dropped, ORDERING);
+                }
+            }
+            let full = missed_ref.load(ORDERING) > 0
+                || match sender.try_send(Ok(item)) {
+                    Err(TrySendError::Full(_)) => true,
+                    _ => false,
+                };
+            if full {
+                missed_ref.fetch_add(1, ORDERING);
+                lagged_ref.fetch_add(1, ORDERING);
+            }
+            future::ready(()).boxed()
+        };
+        Ok(client
+            .subscribe(sm_id, msg, f)
+            .await?
+            .map(|receipt| SubscriptionStream {
+                client: client.clone(),
+                receipt,
+                receiver,
+                lagged,
+                missed,
+            }))
+    }
+
     pub async fn unsubscribe(
         &self,
         receipt: SubscriptionReceipt,
@@ -366,6 +522,62 @@ impl RaftClient {
         }
     }
 
+    // Write a consistent snapshot of all state machines, taken by the leader, into the file.
+    // Returns the log id the snapshot was taken at
+    pub async fn backup<P: AsRef<Path>>(&self, path: P) -> Result<u64, BackupError> {
+        let mut depth = 0;
+        loop {
+            if let Some((leader_id, client)) = self.current_leader_client().await {
+                match client.c_backup().await {
+                    Ok(Some(backup)) => {
+                        info!(
+                            "Writing backup of {} state machines at log {}, term {}",
+                            backup.items.len(),
+                            backup.log_id,
+                            backup.log_term
+                        );
+                        backup.write_to(path.as_ref()).await?;
+                        return Ok(backup.log_id);
+                    }
+                    Ok(None) => debug!("Backup request sent to non-leader {}", leader_id),
+                    Err(e) => debug!("Cannot get backup from {}, {:?}", leader_id, e),
+                }
+            }
+            if depth >= 5 {
+                return Err(BackupError::NotLeader);
+            }
+            depth += 1;
+            let servers = {
+                let members = self.members.read().await;
+                Vec::from_iter(members.id_map.values().cloned())
+            };
+            let _ = self.update_info(&servers).await;
+        }
+    }
+
+    // Seed state machines of this cluster from a backup file. Cluster configuration in the
+    // backup is ignored so the cluster keeps its own members and subscriptions.
+    // Returns the log id the backup was taken at
+    pub async fn restore<P: AsRef<Path>>(&self, path: P) -> Result<u64, BackupError> {
+        let backup = Backup::read_from(path).await?;
+        info!(
+            "Restoring {} state machines from cluster {} at log {}, term {}",
+            backup.items.len(),
+            backup.cluster_id,
+            backup.log_id,
+            backup.log_term
+        );
+        let items = backup
+            .items
+            .into_iter()
+            .filter(|item| item.sm_id != CONFIG_SM_ID)
+            .collect();
+        self.execute(MASTER_SM_ID, master_restore::new(&items))
+            .await
+            .map_err(BackupError::ExecError)?;
+        Ok(backup.log_id)
+    }
+
     async fn query(&self, sm_id: u64, fn_id: u64, data: Vec<u8>) -> Result<ExecResult, ExecError> {
         let mut depth = 0;
         loop {
@@ -478,7 +690,7 @@ impl RaftClient {
                                 return Ok(data);
                             }
                             Ok(ClientCmdResponse::NotLeader(new_leader_id)) => {
-                                if new_leader_id == 0 || leader_id == leader_id {
+                                if new_leader_id == 0 || new_leader_id == leader_id {
                                     debug!(
                                         "CLIENT: NOT LEADER, SUGGESTION NOT USEFUL, PROBE. GOT: {}",
                                         new_leader_id
@@ -531,6 +743,8 @@ impl RaftClient {
             sm_id,
             fn_id,
             data: data.clone(),
+            sm_version: 0,
+            time: 0,
         }
     }
     pub fn leader_id(&self) -> u64 {
@@ -546,6 +760,11 @@ impl RaftClient {
         }
     }
 
+    pub(crate) async fn servers(&self) -> Vec<String> {
+        let members = self.members.read().await;
+        Vec::from_iter(members.id_map.values().cloned())
+    }
+
     async fn current_leader_client(&self) -> Option<(u64, Client)> {
         {
             let leader_client = self.leader_client().await;
@@ -574,6 +793,65 @@ impl RaftClient {
     }
 }
 
+// Notifications were dropped because the stream was full, with the number dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged(pub u64);
+
+pub struct SubscriptionStream<R> {
+    client: Arc<RaftClient>,
+    receipt: SubscriptionReceipt,
+    receiver: mpsc::Receiver<Result<R, Lagged>>,
+    lagged: Arc<AtomicU64>,
+    missed: Arc<AtomicU64>, // dropped and not reported to the consumer yet
+}
+
+impl<R> SubscriptionStream<R> {
+    // Number of notifications dropped because the stream was full
+    pub fn lagged(&self) -> u64 {
+        self.lagged.load(ORDERING)
+    }
+    pub fn receipt(&self) -> SubscriptionReceipt {
+        self.receipt
+    }
+}
+
+impl<R> Stream for SubscriptionStream<R> {
+    type Item = Result<R, Lagged>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match this.receiver.poll_recv(cx) {
+            Poll::Pending => match this.missed.swap(0, ORDERING) {
+                0 => Poll::Pending,
+                dropped => Poll::Ready(Some(Err(Lagged(dropped)))),
+            },
+            res => res,
+        }
+    }
+}
+
+impl<R> Drop for SubscriptionStream<R> {
+    fn drop(&mut self) {
+        let client = self.client.clone();
+        let receipt = self.receipt;
+        match tokio::runtime::Handle::try_current() {
+            Ok(rt) => {
+                rt.spawn(async move {
+                    if let Err(e) = client.unsubscribe(receipt).await {
+                        warn!(
+                            "Cannot unsubscribe {:?} for dropped stream, {:?}",
+                            receipt, e
+                        );
+                    }
+                });
+            }
+            Err(_) => warn!(
+                "Cannot unsubscribe {:?} for dropped stream, no runtime",
+                receipt
+            ),
+        }
+    }
+}
+
 fn swap_when_greater(atomic: &AtomicU64, value: u64) {
     let mut orig_num = atomic.load(ORDERING);
     loop {
@@ -1,14 +1,17 @@
 // Now only offers log persistent
 
-use crate::raft::{LogEntry, LogsMap, Options, RaftMeta, Storage};
+use crate::raft::state_machine::configs::commands::force_members_;
+use crate::raft::state_machine::configs::CONFIG_SM_ID;
+use crate::raft::{LogEntry, LogsMap, Options, RaftMeta, RaftMsg, Storage};
 use async_std::sync::*;
 use serde::{Deserialize, Serialize};
 
 use std::fs::OpenOptions;
+use std::cmp::max;
 use std::io;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::ops::Bound::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tokio::fs::*;
 use tokio::io::*;
 
@@ -25,7 +28,11 @@ pub struct DiskOptions {
 pub struct StorageEntity {
     pub logs: Option<File>,
     pub snapshot: Option<File>,
-    pub last_term: u64,
+    // Id of the last log appended to the file
+    pub last_log_id: u64,
+    // 0 for node that have not been bootstrapped or joined any cluster
+    pub cluster_id: u64,
+    cluster_id_path: PathBuf,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -36,7 +43,109 @@ struct DiskLogEntry {
     log: LogEntry,
 }
 
+// Summary of the logs on disk, for picking the most up-to-date survivor before
+// `RaftService::force_reconfigure`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageInfo {
+    pub term: u64,
+    pub last_log_id: u64,
+    pub last_log_term: u64,
+    pub commit_index: u64,
+}
+
+fn log_path(options: &DiskOptions) -> PathBuf {
+    Path::new(&options.path).with_file_name("log.dat")
+}
+
+// A torn entry at the end of the file is ignored, it was never acknowledged
+fn read_log_entries(file: &mut std::fs::File) -> io::Result<Vec<DiskLogEntry>> {
+    let mut entries = vec![];
+    let mut len_buf = [0u8; 8];
+    loop {
+        if file.read_exact(&mut len_buf).is_err() {
+            break;
+        }
+        let len = u64::from_le_bytes(len_buf);
+        let mut data_buf = vec![0u8; len as usize];
+        if file.read_exact(&mut data_buf).is_err() {
+            break;
+        }
+        let entry = crate::utils::serde::deserialize::<DiskLogEntry>(data_buf.as_slice())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "corrupted raft log"))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn read_log_file(options: &DiskOptions) -> io::Result<Vec<DiskLogEntry>> {
+    if !options.append_logs {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "logs are not persisted with these options",
+        ));
+    }
+    read_log_entries(&mut std::fs::File::open(log_path(options))?)
+}
+
 impl StorageEntity {
+    // Storage must not be in use by a running node
+    pub fn inspect(options: &DiskOptions) -> io::Result<StorageInfo> {
+        let entries = read_log_file(options)?;
+        let mut info = StorageInfo {
+            term: 0,
+            last_log_id: 0,
+            last_log_term: 0,
+            commit_index: 0,
+        };
+        for entry in entries {
+            info.term = max(info.term, max(entry.term, entry.log.term));
+            info.commit_index = entry.commit_index;
+            if entry.log.id >= info.last_log_id {
+                info.last_log_id = entry.log.id;
+                info.last_log_term = entry.log.term;
+            }
+        }
+        Ok(info)
+    }
+
+    // Append a committed config entry replacing the members, with a term above every term
+    // in the logs and `min_term`. Returns the id and term of the entry.
+    pub fn force_members(
+        options: &DiskOptions,
+        members: &Vec<String>,
+        min_term: u64,
+    ) -> io::Result<(u64, u64)> {
+        if members.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "members cannot be empty",
+            ));
+        }
+        let info = Self::inspect(options)?;
+        let term = max(min_term, info.term + 1);
+        let (fn_id, _, data) = force_members_::new(members).encode();
+        let entry = DiskLogEntry {
+            term,
+            commit_index: info.last_log_id + 1,
+            last_applied: 0,
+            log: LogEntry {
+                id: info.last_log_id + 1,
+                term,
+                sm_id: CONFIG_SM_ID,
+                fn_id,
+                data,
+                sm_version: 0,
+                time: 0,
+            },
+        };
+        let entry_data = crate::utils::serde::serialize(&entry);
+        let mut log_file = OpenOptions::new().append(true).open(log_path(options))?;
+        log_file.write_all(&(entry_data.len() as u64).to_le_bytes())?;
+        log_file.write_all(entry_data.as_slice())?;
+        log_file.sync_all()?;
+        Ok((entry.log.id, term))
+    }
+
     pub fn new_with_options(
         opts: &Options,
         term: &mut u64,
@@ -48,39 +157,37 @@ impl StorageEntity {
             &Storage::DISK(ref options) => {
                 let base_path = Path::new(&options.path);
                 let _ = std::fs::create_dir_all(base_path);
-                let log_path = base_path.with_file_name("log.dat");
+                let log_path = log_path(options);
                 let snapshot_path = base_path.with_file_name("snapshot.dat");
+                let cluster_id_path = base_path.with_file_name("cluster.dat");
+                let cluster_id = match std::fs::read(cluster_id_path.as_path()) {
+                    Ok(data) if data.len() == 8 => {
+                        let mut id_buf = [0u8; 8];
+                        id_buf.copy_from_slice(data.as_slice());
+                        u64::from_le_bytes(id_buf)
+                    }
+                    _ => 0,
+                };
                 let mut open_opts = OpenOptions::new();
                 open_opts
                     .write(true)
                     .create(true)
                     .read(true)
                     .truncate(false);
+                let mut last_log_id = 0;
                 Some(Self {
                     logs: if options.append_logs {
                         let mut log_file = open_opts.open(log_path.as_path())?;
-                        let mut len_buf = [0u8; 8];
-                        let mut counter = 0;
-                        loop {
-                            if log_file.read_exact(&mut len_buf).is_err() {
-                                break;
-                            }
-                            let len = u64::from_le_bytes(len_buf);
-                            let mut data_buf = vec![0u8; len as usize];
-                            if log_file.read_exact(&mut data_buf).is_err() {
-                                break;
-                            }
-                            let entry = crate::utils::serde::deserialize::<DiskLogEntry>(
-                                data_buf.as_slice(),
-                            )
-                            .unwrap();
+                        let entries = read_log_entries(&mut log_file)?;
+                        debug!("Recovered {} raft logs", entries.len());
+                        for entry in entries {
                             *term = entry.term;
                             *commit_index = entry.commit_index;
-                            *last_applied = entry.last_applied;
+                            last_log_id = entry.log.id;
                             logs.insert(entry.log.id, entry.log);
-                            counter += 1;
                         }
-                        debug!("Recovered {} raft logs", counter);
+                        // State machines are empty, recovered logs are applied again on start
+                        *last_applied = 0;
                         Some(File::from_std(log_file))
                     } else {
                         None
@@ -90,25 +197,34 @@ impl StorageEntity {
                     } else {
                         None
                     },
-                    last_term: 0,
+                    last_log_id,
+                    cluster_id,
+                    cluster_id_path,
                 })
             }
             _ => None,
         })
     }
 
+    pub async fn persist_cluster_id(&mut self, cluster_id: u64) -> io::Result<()> {
+        tokio::fs::write(self.cluster_id_path.as_path(), cluster_id.to_le_bytes()).await?;
+        self.cluster_id = cluster_id;
+        debug!("Persisted cluster id {}", cluster_id);
+        Ok(())
+    }
+
     pub async fn append_logs<'a>(
         &mut self,
         meta: &'a RwLockWriteGuard<'a, RaftMeta>,
         logs: &'a RwLockWriteGuard<'a, LogsMap>,
     ) -> io::Result<()> {
         if let Some(f) = &mut self.logs {
-            let was_last_term = self.last_term;
+            let was_last_log_id = self.last_log_id;
             let mut counter = 0;
-            let mut terms_appended = vec![];
-            for (term, log) in logs.range((Excluded(self.last_term), Unbounded)) {
+            let mut ids_appended = vec![];
+            for (id, log) in logs.range((Excluded(self.last_log_id), Unbounded)) {
                 let entry = DiskLogEntry {
-                    term: *term,
+                    term: meta.term,
                     commit_index: meta.commit_index,
                     last_applied: meta.last_applied,
                     log: log.clone(),
@@ -116,15 +232,15 @@ impl StorageEntity {
                 let entry_data = crate::utils::serde::serialize(&entry);
                 f.write(&(entry_data.len() as u64).to_le_bytes()).await?;
                 f.write(entry_data.as_slice()).await?;
-                self.last_term = *term;
-                terms_appended.push(self.last_term);
+                self.last_log_id = *id;
+                ids_appended.push(self.last_log_id);
                 counter += 1;
             }
             if counter > 0 {
                 f.sync_all().await?;
                 debug!(
                     "Appended and persisted {} logs, was {}, appended {:?}",
-                    counter, was_last_term, terms_appended
+                    counter, was_last_log_id, ids_appended
                 );
             }
         }
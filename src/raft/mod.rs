@@ -1,4 +1,10 @@
-use self::state_machine::configs::commands::{del_member_, member_address, new_member_};
+use self::backup::Backup;
+use self::state_machine::callback::pattern::SubPattern;
+use self::state_machine::callback::server::Subscriptions;
+use self::state_machine::callback::SUBSCRIBER_RENEW_MS;
+use self::state_machine::configs::commands::{
+    del_member_, expire_subscribers_, member_address, new_member_,
+};
 use self::state_machine::configs::{RaftMember, CONFIG_SM_ID};
 use self::state_machine::master::{ExecError, ExecResult, MasterStateMachine, SubStateMachine};
 use self::state_machine::OpType;
@@ -26,6 +32,7 @@ use tokio::time::*;
 
 #[macro_use]
 pub mod state_machine;
+pub mod backup;
 pub mod client;
 pub mod disk;
 
@@ -33,7 +40,15 @@ pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_RAFT_DEFAULT_SERVICE) a
 
 pub trait RaftMsg<R>: Send + Sync {
     fn encode(self) -> (u64, OpType, Vec<u8>);
-    fn decode_return(data: &Vec<u8>) -> R;
+    // None when the data is not the return value of the function
+    fn decode_return(data: &Vec<u8>) -> Option<R>;
+    // Arguments from encoded data, serialized one by one for matching subscription patterns
+    fn args(_data: &Vec<u8>) -> Vec<Vec<u8>> {
+        vec![]
+    }
+    fn pattern(&self) -> Option<SubPattern> {
+        None
+    }
 }
 
 const CHECKER_MS: i64 = 50;
@@ -46,6 +61,12 @@ pub struct LogEntry {
     pub sm_id: u64,
     pub fn_id: u64,
     pub data: Vec<u8>,
+    // schema version of the state machine on the leader when the entry was appended
+    #[serde(default)]
+    pub sm_version: u32,
+    // leader clock when the entry was appended, see `state_machine::applied_time`
+    #[serde(default)]
+    pub time: i64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -73,6 +94,16 @@ pub struct ClientClusterInfo {
     last_log_id: u64,
     last_log_term: u64,
     leader_id: u64,
+    cluster_id: u64,
+}
+
+#[derive(Debug)]
+pub enum BootstrapError {
+    NotExpectedMember,
+    ClusterIdMismatch(u64, u64), // local/remote, expected
+    Timeout,
+    JoinFailed(ExecError),
+    IOError(io::Error),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -98,10 +129,13 @@ service! {
     rpc request_vote(term: u64, candidate_id: u64, last_log_id: u64, last_log_term: u64) -> ((u64, u64), bool); // term, voteGranted
     rpc install_snapshot(term: u64, leader_id: u64, last_included_index: u64, last_included_term: u64, data: Vec<u8>) -> u64;
     rpc c_command(entry: LogEntry) -> ClientCmdResponse;
+    rpc c_forward_command(entry: LogEntry) -> ClientCmdResponse;
     rpc c_query(entry: LogEntry) -> ClientQryResponse;
     rpc c_server_cluster_info() -> ClientClusterInfo;
     rpc c_put_offline() -> bool;
     rpc c_have_state_machine(id: u64) -> bool;
+    rpc c_backup() -> Option<Backup>;
+    rpc c_renew_subscriber(address: String, session_id: u64) -> Option<bool>;
     rpc c_ping();
 }
 
@@ -152,6 +186,7 @@ pub struct RaftMeta {
     commit_index: u64,
     last_applied: u64,
     leader_id: u64,
+    cluster_id: u64,
     storage: Option<Arc<Mutex<StorageEntity>>>,
 }
 
@@ -180,6 +215,7 @@ pub struct RaftService {
     pub options: Options,
     rt: runtime::Runtime,
     _is_leader: AtomicBool,
+    forward_commands: AtomicBool,
 }
 dispatch_rpc_service_functions!(RaftService);
 
@@ -217,6 +253,15 @@ async fn check_commit(meta: &mut RwLockWriteGuard<'_, RaftMeta>) {
     }
 }
 
+// Cluster id is derived from the expected initial members, so every node that
+// bootstraps with the same list will agree on it without communication
+pub fn cluster_id_of(members: &Vec<String>) -> u64 {
+    let mut members = members.clone();
+    members.sort();
+    members.dedup();
+    hash_str(&members.join(","))
+}
+
 fn is_majority(members: u64, granted: u64) -> bool {
     let required = members / 2 + 1;
     let majority = granted >= (required);
@@ -267,6 +312,7 @@ impl RaftService {
         .unwrap();
 
         let master_sm = MasterStateMachine::new(opts.service_id);
+        let cluster_id = storage_entity.as_ref().map(|e| e.cluster_id).unwrap_or(0);
 
         let server_obj = RaftService {
             meta: RwLock::new(RaftMeta {
@@ -280,6 +326,7 @@ impl RaftService {
                 commit_index,
                 last_applied,
                 leader_id: 0,
+                cluster_id,
                 storage: storage_entity.map(|e| Arc::new(Mutex::new(e))),
             }),
             id: server_id,
@@ -292,6 +339,7 @@ impl RaftService {
                 .build()
                 .unwrap(),
             _is_leader: AtomicBool::new(false),
+            forward_commands: AtomicBool::new(false),
         };
         Arc::new(server_obj)
     }
@@ -315,6 +363,30 @@ impl RaftService {
                 return false;
             }
         }
+        {
+            // Logs recovered from disk rebuild the state machines, which should be registered
+            // before start
+            let mut meta = server.meta.write().await;
+            if meta.commit_index > meta.last_applied {
+                info!(
+                    "Applying {} logs recovered from disk",
+                    meta.commit_index - meta.last_applied
+                );
+                // Restarted members wait for a leader or elect one with the recovered config
+                let term = meta.term;
+                server.become_follower(&mut meta, term, 0);
+            }
+            while meta.commit_index > meta.last_applied {
+                meta.last_applied += 1;
+                let last_applied = meta.last_applied;
+                let logs = meta.logs.read().await;
+                if let Some(entry) = logs.get(&last_applied) {
+                    if let Err(e) = commit_command(&meta, entry).await {
+                        warn!("Cannot apply recovered log {}, {:?}", last_applied, e);
+                    }
+                }
+            }
+        }
         let checker_ref = server.clone();
         server.rt.spawn(async {
             let server = checker_ref;
@@ -338,7 +410,9 @@ impl RaftService {
                             debug_assert!(meta.timeout > 100);
                             let timeout_time = meta.last_checked + meta.timeout;
                             let time_remains = timeout_time - current_time;
-                            if meta.vote_for == None && time_remains < 0 {
+                            // Voters and failed candidates time out as well, or elections
+                            // split among candidates would never be retried
+                            if time_remains < 0 {
                                 // TODO: in my test sometimes timeout_elapsed may go 1 for no reason, require investigation
                                 //Timeout, require election
                                 warn!(
@@ -400,6 +474,10 @@ impl RaftService {
                 }
             }
         });
+        let lease_ref = server.clone();
+        server.rt.spawn(async move {
+            lease_ref.watch_subscriber_leases().await;
+        });
         return true;
     }
     pub async fn new_server(opts: Options) -> (bool, Arc<RaftService>, Arc<Server>) {
@@ -437,9 +515,9 @@ impl RaftService {
         let meta = self.meta.read().await;
         debug!("Conservative bootstrap, checking storage");
         if let Some(storage) = &meta.storage {
-            debug!("There are storage, checking last term");
-            if storage.lock().await.last_term > 0 {
-                debug!("There are logged term, will probe and join or bootstrap");
+            debug!("There are storage, checking last log");
+            if storage.lock().await.last_log_id > 0 {
+                debug!("There are logs, will probe and join or bootstrap");
                 drop(meta);
                 self.probe_and_join(servers).await.unwrap();
             } else {
@@ -453,6 +531,123 @@ impl RaftService {
             self.probe_and_join(servers).await.unwrap();
         }
     }
+    // Bootstrap a new cluster with the expected initial members. Only the first member
+    // (in sorted order) will bootstrap, and only after a quorum of the expected members are
+    // reachable. Others will wait for the cluster to emerge and join it.
+    // Returns true if this node bootstrapped the cluster, false if it joined.
+    // The cluster cannot form while the first member is down, the others time out. Letting
+    // any member take over is not safe, members seeing different quorums in a partition would
+    // bootstrap two clusters. Start the first member again, or call `bootstrap` on exactly one
+    // member and `join` on the rest.
+    pub async fn bootstrap_with_members(
+        &self,
+        members: &Vec<String>,
+        wait: Duration,
+    ) -> Result<bool, BootstrapError> {
+        let mut expected = members.clone();
+        expected.sort();
+        expected.dedup();
+        if !expected.contains(&self.options.address) {
+            return Err(BootstrapError::NotExpectedMember);
+        }
+        let cluster_id = cluster_id_of(&expected);
+        let local_cluster_id = self.cluster_id().await;
+        if local_cluster_id != 0 && local_cluster_id != cluster_id {
+            error!(
+                "Refuse to bootstrap, storage belongs to cluster {}, expecting {}",
+                local_cluster_id, cluster_id
+            );
+            return Err(BootstrapError::ClusterIdMismatch(
+                local_cluster_id,
+                cluster_id,
+            ));
+        }
+        let is_seed = expected[0] == self.options.address;
+        let deadline = get_time() + wait.as_millis() as i64;
+        loop {
+            let infos = self.probe_cluster_info(&expected).await;
+            if let Some(info) = infos.iter().find(|info| info.leader_id != 0) {
+                if info.cluster_id != 0 && info.cluster_id != cluster_id {
+                    error!(
+                        "Refuse to join, found cluster {} but expecting {}",
+                        info.cluster_id, cluster_id
+                    );
+                    return Err(BootstrapError::ClusterIdMismatch(
+                        info.cluster_id,
+                        cluster_id,
+                    ));
+                }
+                debug!("Found running cluster {}, will join", cluster_id);
+                self.set_cluster_id(cluster_id)
+                    .await
+                    .map_err(BootstrapError::IOError)?;
+                return self
+                    .join(&expected)
+                    .await
+                    .map(|_| false)
+                    .map_err(BootstrapError::JoinFailed);
+            }
+            let reachable = infos.len() as u64 + 1;
+            if is_seed && is_majority(expected.len() as u64, reachable) {
+                info!(
+                    "Bootstrapping cluster {}, {} of {} expected members reachable",
+                    cluster_id,
+                    reachable,
+                    expected.len()
+                );
+                self.set_cluster_id(cluster_id)
+                    .await
+                    .map_err(BootstrapError::IOError)?;
+                self.bootstrap().await;
+                return Ok(true);
+            }
+            if get_time() >= deadline {
+                warn!(
+                    "Cannot bootstrap cluster {} in time, {} of {} expected members reachable, \
+                     seed member is {}",
+                    cluster_id,
+                    reachable,
+                    expected.len(),
+                    expected[0]
+                );
+                return Err(BootstrapError::Timeout);
+            }
+            sleep(Duration::from_millis(500)).await;
+        }
+    }
+    async fn probe_cluster_info(&self, servers: &Vec<String>) -> Vec<ClientClusterInfo> {
+        let service_id = self.options.service_id;
+        servers
+            .iter()
+            .filter(|addr| **addr != self.options.address)
+            .map(|addr| {
+                timeout(Duration::from_secs(2), async move {
+                    match crate::rpc::DEFAULT_CLIENT_POOL.get(addr).await {
+                        Ok(client) => ImmeServiceClient::c_server_cluster_info(service_id, &client)
+                            .await
+                            .ok(),
+                        Err(_) => None,
+                    }
+                })
+            })
+            .collect::<FuturesUnordered<_>>()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .filter_map(|r| r.ok().and_then(|info| info))
+            .collect()
+    }
+    pub async fn cluster_id(&self) -> u64 {
+        self.meta.read().await.cluster_id
+    }
+    pub async fn set_cluster_id(&self, cluster_id: u64) -> io::Result<()> {
+        let mut meta = self.write_meta().await;
+        if let Some(storage) = &meta.storage {
+            storage.lock().await.persist_cluster_id(cluster_id).await?;
+        }
+        meta.cluster_id = cluster_id;
+        Ok(())
+    }
     pub async fn join(&self, servers: &Vec<String>) -> Result<bool, ExecError> {
         debug!("Trying to join cluster with id {}", self.id);
         let client = RaftClient::new(servers, self.options.service_id).await;
@@ -521,6 +716,45 @@ impl RaftService {
         sm.clear_subs();
         return true;
     }
+    // Stop taking part in the cluster without leaving it, e.g. before `force_reconfigure` on
+    // its storage. The node stops electing and refuses raft requests.
+    pub async fn shutdown(&self) {
+        let mut meta = self.write_meta().await;
+        meta.membership = Membership::Offline;
+        self._is_leader.store(false, Relaxed);
+        warn!("Raft service {} shut down", self.options.address);
+    }
+    // Logs on disk of a node that is not running
+    pub fn inspect_storage(storage: &DiskOptions) -> io::Result<StorageInfo> {
+        StorageEntity::inspect(storage)
+    }
+    // UNSAFE: Only for disaster recovery when majority of the cluster is permanently lost.
+    // Offline operation on the disk storage of a stopped survivor, membership configuration
+    // will be overwritten without consensus by appending a committed config entry to its logs.
+    // Run it on exactly one survivor, the one with the most up-to-date logs according to
+    // `inspect_storage`, then start it and the other survivors with their storage unchanged.
+    // The entry takes a term above every term in its logs and `min_term`, so its logs win
+    // the election and overwrite divergent logs of the other survivors. Survivors that ran
+    // it on their own would write different entries at the same index and term.
+    // Entries that have not been committed in the old cluster may be committed by this, and
+    // committed entries that did not reach this survivor are lost.
+    // Returns the id and term of the appended config entry.
+    pub fn force_reconfigure(
+        storage: &DiskOptions,
+        members: &Vec<String>,
+        min_term: u64,
+    ) -> io::Result<(u64, u64)> {
+        error!(
+            "FORCE RECONFIGURE ON {}! Members will be overwritten to {:?} without consensus",
+            storage.path, members
+        );
+        let (log_id, term) = StorageEntity::force_members(storage, members, min_term)?;
+        error!(
+            "FORCE RECONFIGURE ON {} COMPLETED! Appended config at log {}, term {}",
+            storage.path, log_id, term
+        );
+        Ok((log_id, term))
+    }
     pub async fn cluster_info(&self) -> ClientClusterInfo {
         let meta = self.meta.read().await;
         let logs = meta.logs.read().await;
@@ -536,6 +770,7 @@ impl RaftService {
             last_log_id,
             last_log_term,
             leader_id: meta.leader_id,
+            cluster_id: meta.cluster_id,
         }
     }
     pub async fn num_members(&self) -> usize {
@@ -571,10 +806,63 @@ impl RaftService {
     pub fn get_server_id(&self) -> u64 {
         self.id
     }
+    // When enabled, commands received by a follower will be proxied to the leader it knows
+    // instead of replying `NotLeader` and let the client find the leader by itself
+    pub fn set_command_forwarding(&self, enabled: bool) {
+        self.forward_commands.store(enabled, Relaxed);
+    }
+    pub fn is_command_forwarding(&self) -> bool {
+        self.forward_commands.load(Relaxed)
+    }
+    async fn subscriptions(&self) -> Arc<parking_lot::RwLock<Subscriptions>> {
+        let meta = self.meta.read().await;
+        let sm = meta.state_machine.read().await;
+        sm.configs.subscriptions.clone()
+    }
+    // Leader removes subscribers that did not renew their leases in time
+    async fn watch_subscriber_leases(&self) {
+        let mut was_leader = false;
+        loop {
+            sleep(Duration::from_millis(SUBSCRIBER_RENEW_MS)).await;
+            if let Membership::Offline = self.meta.read().await.membership {
+                debug!("Subscriber lease watcher exiting");
+                break;
+            }
+            let is_leader = self.is_leader();
+            if !is_leader {
+                was_leader = false;
+                continue;
+            }
+            let expired = {
+                let subs = self.subscriptions().await;
+                let mut subs = subs.write();
+                if !was_leader {
+                    subs.reset_leases();
+                }
+                subs.expired_subscribers(get_time())
+            };
+            was_leader = true;
+            if expired.is_empty() {
+                continue;
+            }
+            warn!("Subscribers {:?} lease expired, removing", expired);
+            let (fn_id, _, data) = expire_subscribers_::new(&expired).encode();
+            self.c_command(LogEntry {
+                id: 0,
+                term: 0,
+                sm_id: CONFIG_SM_ID,
+                fn_id,
+                data,
+                sm_version: 0,
+                time: 0,
+            })
+            .await;
+        }
+    }
     pub async fn register_state_machine(&self, state_machine: SubStateMachine) {
         let meta = self.meta.read().await;
         let mut master_sm = meta.state_machine.write().await;
-        master_sm.register(state_machine);
+        master_sm.register(state_machine).await;
     }
     fn switch_membership(&self, meta: &mut RwLockWriteGuard<RaftMeta>, membership: Membership) {
         self.reset_last_checked(meta);
@@ -737,6 +1025,9 @@ impl RaftService {
             guard.last_updated = get_time();
         }
         meta.leader_id = self.id;
+        // Bootstrapped leaders did not vote for themselves, no other candidate of this term
+        // should get the vote
+        meta.vote_for = Some(self.id);
         self.switch_membership(meta, Membership::Leader(leader_meta));
     }
 
@@ -997,6 +1288,12 @@ impl RaftService {
         let new_log_term = meta.term;
         entry.term = new_log_term;
         entry.id = new_log_id;
+        entry.sm_version = meta
+            .state_machine
+            .read()
+            .await
+            .schema_version_of(entry.sm_id);
+        entry.time = get_time();
         logs.insert(entry.id, entry.clone());
         self.logs_post_processing(meta, logs).await.unwrap();
         (new_log_id, new_log_term)
@@ -1014,6 +1311,67 @@ impl RaftService {
         Ok(())
     }
 
+    async fn client_command(&self, entry: LogEntry, forward: bool) -> ClientCmdResponse {
+        let meta = self.write_meta().await;
+        let mut entry = entry;
+        if !is_leader(&meta) {
+            let leader_id = meta.leader_id;
+            if forward && leader_id != 0 && leader_id != self.id {
+                let leader_rpc = {
+                    let sm = meta.state_machine.read().await;
+                    sm.configs.members.get(&leader_id).map(|m| m.rpc.clone())
+                };
+                if let Some(leader_rpc) = leader_rpc {
+                    drop(meta);
+                    debug!(
+                        "Forwarding command from follower {} to leader {}",
+                        self.id, leader_id
+                    );
+                    // Leader will not forward it again, so there is no chance of loops
+                    // when nodes have different ideas on who is the leader
+                    return match leader_rpc.c_forward_command(entry).await {
+                        Ok(res) => res,
+                        Err(e) => {
+                            debug!("Cannot forward command to leader {}, {:?}", leader_id, e);
+                            ClientCmdResponse::NotLeader(leader_id)
+                        }
+                    };
+                }
+            }
+            debug!(
+                "Command sent to non-leader node, {}, should be {}",
+                self.id, meta.leader_id
+            );
+            return if meta.leader_id == self.id {
+                debug!("Found outdated leader id, will return 0");
+                ClientCmdResponse::NotLeader(0)
+            } else {
+                ClientCmdResponse::NotLeader(meta.leader_id)
+            };
+        }
+        let (new_log_id, new_log_term) = self.leader_append_log(&meta, &mut entry).await;
+        let data = match entry.sm_id {
+            // special treats for membership changes
+            CONFIG_SM_ID => Some(
+                self.try_sync_config_to_followers(meta, &entry, new_log_id)
+                    .await,
+            ),
+            _ => {
+                self.try_sync_log_to_followers(meta, &entry, new_log_id)
+                    .await
+            }
+        }; // Some for committed and None for not committed
+        if let Some(data) = data {
+            ClientCmdResponse::Success {
+                data,
+                last_log_id: new_log_id,
+                last_log_term: new_log_term,
+            }
+        } else {
+            ClientCmdResponse::NotCommitted
+        }
+    }
+
     async fn try_sync_log_to_followers<'a>(
         &'a self,
         mut meta: RwLockWriteGuard<'a, RaftMeta>,
@@ -1065,6 +1423,9 @@ impl Service for RaftService {
     ) -> BoxFuture<(u64, AppendEntriesResult)> {
         async move {
             let mut meta = self.write_meta().await;
+            if let Membership::Offline = meta.membership {
+                return (meta.term, AppendEntriesResult::TermOut(0));
+            }
             self.reset_last_checked(&mut meta);
             let term_ok = self.check_term(&mut meta, term, leader_id); // RI, 1
             let result = if term_ok {
@@ -1072,6 +1433,8 @@ impl Service for RaftService {
                     debug!("SWITCH FROM CANDIDATE BACK TO FOLLOWER {}", self.id);
                     self.become_follower(&mut meta, term, leader_id);
                 }
+                // Only the leader of this term sends entries, voters learn about it here
+                meta.leader_id = leader_id;
                 if prev_log_id > 0 {
                     check_commit(&mut meta).await;
                     let mut logs = meta.logs.write().await;
@@ -1136,41 +1499,57 @@ impl Service for RaftService {
     ) -> BoxFuture<((u64, u64), bool)> {
         async move {
             let mut meta = self.write_meta().await;
-            let vote_for = meta.vote_for;
+            if let Membership::Offline = meta.membership {
+                return ((0, 0), false);
+            }
             let mut vote_granted = false;
-            if term > meta.term {
+            let candidate_valid = meta
+                .state_machine
+                .read()
+                .await
+                .configs
+                .member_existed(candidate_id);
+            debug!(
+                "{} VOTE FOR: {}, valid: {}",
+                self.id, candidate_id, candidate_valid
+            );
+            if term > meta.term && candidate_valid {
+                // Newer term of a member, the vote of this node in older terms no longer counts
                 check_commit(&mut meta).await;
-                let logs = meta.logs.read().await;
-                let conf_sm = &meta.state_machine.read().await.configs;
-                let candidate_valid = conf_sm.member_existed(candidate_id);
+                self.become_follower(&mut meta, term, 0);
+            }
+            let vote_for = meta.vote_for;
+            if term < meta.term {
                 debug!(
-                    "{} VOTE FOR: {}, valid: {}",
-                    self.id, candidate_id, candidate_valid
+                    "{} VOTE FOR: {}, not granted due to term out",
+                    self.id, candidate_id
                 );
-                if (vote_for.is_none() || vote_for.unwrap() == candidate_id) && candidate_valid {
-                    let (last_id, last_term) = get_last_log_info!(self, logs);
-                    if last_log_id >= last_id && last_log_term >= last_term {
-                        vote_granted = true;
-                    } else {
-                        debug!(
-                            "{} VOTE FOR: {}, not granted due to log check",
-                            self.id, candidate_id
-                        );
-                    }
+            } else if !candidate_valid {
+                debug!(
+                    "{} VOTE FOR: {}, not granted, candidate not a member",
+                    self.id, candidate_id
+                );
+            } else if vote_for.is_some() && vote_for != Some(candidate_id) {
+                debug!(
+                    "{} VOTE FOR: {}, not granted, voted for {:?}",
+                    self.id, candidate_id, vote_for
+                );
+            } else {
+                let logs = meta.logs.read().await;
+                let (last_id, last_term) = get_last_log_info!(self, logs);
+                if last_log_id >= last_id && last_log_term >= last_term {
+                    vote_granted = true;
                 } else {
                     debug!(
-                        "{} VOTE FOR: {}, not granted, candidate valid: {}, voted for {:?}",
-                        self.id, candidate_id, candidate_valid, vote_for
+                        "{} VOTE FOR: {}, not granted due to log check",
+                        self.id, candidate_id
                     );
                 }
-            } else {
-                debug!(
-                    "{} VOTE FOR: {}, not granted due to term out",
-                    self.id, candidate_id
-                );
             }
             if vote_granted {
+                // At most one candidate gets the vote of this node in the term
                 meta.vote_for = Some(candidate_id);
+                self.reset_last_checked(&mut meta);
             }
             debug!(
                 "{} VOTE FOR: {}, granted: {}",
@@ -1191,11 +1570,14 @@ impl Service for RaftService {
     ) -> BoxFuture<u64> {
         async move {
             let mut meta = self.write_meta().await;
+            if let Membership::Offline = meta.membership {
+                return meta.term;
+            }
             let term_ok = self.check_term(&mut meta, term, leader_id);
             if term_ok {
                 check_commit(&mut meta).await;
             }
-            meta.state_machine.write().await.recover(data);
+            meta.state_machine.write().await.recover(data).await;
             meta.term = last_included_term;
             meta.commit_index = last_included_index;
             meta.last_applied = last_included_index;
@@ -1206,44 +1588,12 @@ impl Service for RaftService {
     }
 
     fn c_command(&self, entry: LogEntry) -> BoxFuture<ClientCmdResponse> {
-        async move {
-            let meta = self.write_meta().await;
-            let mut entry = entry;
-            if !is_leader(&meta) {
-                debug!(
-                    "Command sent to non-leader node, {}, should be {}",
-                    self.id, meta.leader_id
-                );
-                return if meta.leader_id == self.id {
-                    debug!("Found outdated leader id, will return 0");
-                    ClientCmdResponse::NotLeader(0)
-                } else {
-                    ClientCmdResponse::NotLeader(meta.leader_id)
-                };
-            }
-            let (new_log_id, new_log_term) = self.leader_append_log(&meta, &mut entry).await;
-            let data = match entry.sm_id {
-                // special treats for membership changes
-                CONFIG_SM_ID => Some(
-                    self.try_sync_config_to_followers(meta, &entry, new_log_id)
-                        .await,
-                ),
-                _ => {
-                    self.try_sync_log_to_followers(meta, &entry, new_log_id)
-                        .await
-                }
-            }; // Some for committed and None for not committed
-            if let Some(data) = data {
-                ClientCmdResponse::Success {
-                    data,
-                    last_log_id: new_log_id,
-                    last_log_term: new_log_term,
-                }
-            } else {
-                ClientCmdResponse::NotCommitted
-            }
-        }
-        .boxed()
+        self.client_command(entry, self.is_command_forwarding())
+            .boxed()
+    }
+
+    fn c_forward_command(&self, entry: LogEntry) -> BoxFuture<ClientCmdResponse> {
+        self.client_command(entry, false).boxed()
     }
 
     fn c_query(&self, entry: LogEntry) -> BoxFuture<ClientQryResponse> {
@@ -1288,6 +1638,52 @@ impl Service for RaftService {
         .boxed()
     }
 
+    fn c_backup(&self) -> BoxFuture<Option<Backup>> {
+        async move {
+            let meta = self.meta.read().await;
+            if let Membership::Leader(_) = meta.membership {
+                // commands are applied on leader as soon as they are committed. With meta lock
+                // held, state machines are consistent with the commit index
+                let log_id = meta.commit_index;
+                let log_term = meta
+                    .logs
+                    .read()
+                    .await
+                    .get(&log_id)
+                    .map(|entry| entry.term)
+                    .unwrap_or(0);
+                let items = meta.state_machine.read().await.data_snapshot();
+                debug!(
+                    "Backup {} state machines at log {}, term {}",
+                    items.len(),
+                    log_id,
+                    log_term
+                );
+                Some(Backup {
+                    cluster_id: meta.cluster_id,
+                    log_id,
+                    log_term,
+                    items,
+                })
+            } else {
+                None
+            }
+        }
+        .boxed()
+    }
+
+    fn c_renew_subscriber(&self, address: String, session_id: u64) -> BoxFuture<Option<bool>> {
+        async move {
+            if !self.is_leader() {
+                return None;
+            }
+            let subs = self.subscriptions().await;
+            let renewed = subs.write().renew(&address, session_id);
+            Some(renewed)
+        }
+        .boxed()
+    }
+
     fn c_ping(&self) -> BoxFuture<()> {
         future::ready(()).boxed()
     }
@@ -1311,10 +1707,17 @@ impl RaftStateMachine {
 mod test {
     use crate::raft::state_machine::master::ExecError;
     use crate::raft::state_machine::StateMachineCtl;
-    use crate::raft::{Options, RaftService, Storage, DEFAULT_SERVICE_ID};
+    use crate::raft::disk::DiskOptions;
+    use crate::raft::{
+        cluster_id_of, AppendEntriesResult, BootstrapError, Options, RaftService, Service,
+        Storage, DEFAULT_SERVICE_ID,
+    };
     use crate::rpc::Server;
     use crate::utils::time::async_wait_secs;
-    use futures::FutureExt;
+    use bifrost_hasher::hash_str;
+    use futures::stream::FuturesUnordered;
+    use futures::{FutureExt, StreamExt};
+    use std::time::Duration;
 
     #[tokio::test(flavor = "multi_thread")]
     async fn startup() {
@@ -1554,9 +1957,159 @@ mod test {
         assert_eq!(service5.leader_id().await, service1.id);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bootstrap_with_expected_members() {
+        let _ = env_logger::try_init();
+        let addresses: Vec<_> = vec!["127.0.0.1:2018", "127.0.0.1:2019", "127.0.0.1:2020"]
+            .into_iter()
+            .map(|addr| addr.to_string())
+            .collect();
+        // Members keep their cluster id on disk, one directory for each
+        let dir = std::env::temp_dir().join("bifrost_bootstrap_members_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let options = |i: usize| Options {
+            storage: Storage::DISK(DiskOptions {
+                path: dir.join(i.to_string()).join("raft").to_string_lossy().to_string(),
+                take_snapshots: false,
+                append_logs: true,
+                trim_logs: false,
+            }),
+            address: addresses[i].clone(),
+            service_id: DEFAULT_SERVICE_ID,
+        };
+        let mut services = vec![];
+        let mut servers = vec![];
+        for i in 0..addresses.len() {
+            let (success, service, server) = RaftService::new_server(options(i)).await;
+            assert!(success);
+            services.push(service);
+            servers.push(server);
+        }
+        let results = services
+            .iter()
+            .rev()
+            .map(|service| {
+                let service = service.clone();
+                let addresses = addresses.clone();
+                tokio::spawn(async move {
+                    service
+                        .bootstrap_with_members(&addresses, Duration::from_secs(30))
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect::<FuturesUnordered<_>>()
+            .collect::<Vec<_>>()
+            .await;
+        let bootstrapped = results.into_iter().filter(|r| *r.as_ref().unwrap()).count();
+        assert_eq!(bootstrapped, 1);
+        async_wait_secs().await;
+        let cluster_id = cluster_id_of(&addresses);
+        for service in &services {
+            assert_eq!(service.num_members().await, 3);
+            assert_eq!(service.leader_id().await, services[0].id);
+            assert_eq!(service.cluster_id().await, cluster_id);
+        }
+        let other_members = vec![addresses[0].clone(), "127.0.0.1:2021".to_string()];
+        match services[0]
+            .bootstrap_with_members(&other_members, Duration::from_secs(1))
+            .await
+        {
+            Err(BootstrapError::ClusterIdMismatch(local, _)) => assert_eq!(local, cluster_id),
+            other => panic!("Should refuse to bootstrap, got {:?}", other),
+        }
+
+        // Restarted member reloads the cluster id from its storage
+        services[2].shutdown().await;
+        let restarted = RaftService::new(options(2));
+        assert_eq!(restarted.cluster_id().await, cluster_id);
+        servers[2]
+            .register_service(DEFAULT_SERVICE_ID, &restarted)
+            .await;
+        assert!(RaftService::start(&restarted).await);
+        let other_members = vec![addresses[2].clone(), "127.0.0.1:2021".to_string()];
+        match restarted
+            .bootstrap_with_members(&other_members, Duration::from_secs(1))
+            .await
+        {
+            Err(BootstrapError::ClusterIdMismatch(local, _)) => assert_eq!(local, cluster_id),
+            other => panic!("Should refuse to bootstrap, got {:?}", other),
+        }
+        let bootstrapped = restarted
+            .bootstrap_with_members(&addresses, Duration::from_secs(30))
+            .await
+            .unwrap();
+        assert!(!bootstrapped);
+        // Logs not persisted as committed before the restart are replicated by the leader
+        let mut caught_up = false;
+        for _ in 0..30 {
+            async_wait_secs().await;
+            if restarted.num_members().await == 3
+                && restarted.leader_id().await == services[0].id
+            {
+                caught_up = true;
+                break;
+            }
+        }
+        assert!(caught_up);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn votes_once_per_term() {
+        let _ = env_logger::try_init();
+        let a_addr = String::from("127.0.0.1:2070");
+        let b_addr = String::from("127.0.0.1:2071");
+        let mut services = vec![];
+        for addr in &[&a_addr, &b_addr] {
+            let (success, service, _) = RaftService::new_server(Options {
+                storage: Storage::default(),
+                address: addr.to_string(),
+                service_id: DEFAULT_SERVICE_ID,
+            })
+            .await;
+            assert!(success);
+            services.push(service);
+        }
+        let (a, b) = (&services[0], &services[1]);
+        a.bootstrap().await;
+        assert!(b.join(&vec![a_addr.clone()]).await.unwrap());
+        async_wait_secs().await;
+        let term = a.read_meta().await.term;
+        let (last_id, last_term) = {
+            let meta = a.read_meta().await;
+            let logs = meta.logs.read().await;
+            logs.values().last().map(|log| (log.id, log.term)).unwrap()
+        };
+        let non_member = hash_str("127.0.0.1:9");
+
+        // Leader holds its own vote for the term
+        assert!(!a.request_vote(term, b.id, last_id, last_term).await.1);
+        // The vote in a newer term goes to the first candidate, asking again still gets it
+        let ((vote_term, _), granted) = a.request_vote(term + 1, b.id, last_id, last_term).await;
+        assert_eq!((vote_term, granted), (term + 1, true));
+        assert_eq!(a.leader_id().await, 0);
+        assert!(!a.request_vote(term + 1, a.id, last_id, last_term).await.1);
+        assert!(a.request_vote(term + 1, b.id, last_id, last_term).await.1);
+        // Non-members cannot move the term or take the vote
+        assert!(!a.request_vote(term + 5, non_member, last_id, last_term).await.1);
+        assert_eq!(a.read_meta().await.term, term + 1);
+        // Candidates with stale logs do not take the vote of the newer term
+        let ((vote_term, _), granted) = a.request_vote(term + 2, b.id, 0, 0).await;
+        assert_eq!((vote_term, granted), (term + 2, false));
+        assert!(a.request_vote(term + 2, a.id, last_id, last_term).await.1);
+        assert!(!a.request_vote(term + 1, b.id, last_id, last_term).await.1);
+
+        // Voters learn the leader of the term from its entries
+        let (_, result) = a.append_entries(term + 2, b.id, 0, 0, None, 0).await;
+        assert!(matches!(result, AppendEntriesResult::Ok));
+        assert_eq!(a.leader_id().await, b.id);
+    }
+
     mod state_machine {
         use super::*;
         use crate::raft::client::RaftClient;
+        use crate::raft::{AsyncServiceClient, ClientCmdResponse, LogEntry, OpType, RaftMsg};
         use crate::utils::time::async_wait;
         use futures::stream::FuturesUnordered;
         use std::sync::Arc;
@@ -1566,6 +2119,8 @@ mod test {
             def qry answer_to_the_universe(name: String) -> String;
             def qry get_shot() -> i32;
             def cmd take_a_shot(num: i32) -> i32;
+            def cmd take_shots_checked(num: i32) -> i32 | ExecError;
+            def cmd misfire();
         }
 
         struct SM {
@@ -1584,6 +2139,17 @@ mod test {
             fn get_shot(&self) -> BoxFuture<i32> {
                 future::ready(self.shots).boxed()
             }
+            fn take_shots_checked(&mut self, num: i32) -> BoxFuture<Result<i32, ExecError>> {
+                if num > self.shots {
+                    let message = format!("Only {} shots left", self.shots);
+                    return future::ready(Err(ExecError::state_machine(1, message))).boxed();
+                }
+                self.shots -= num;
+                future::ready(Ok(self.shots)).boxed()
+            }
+            fn misfire(&mut self) -> BoxFuture<()> {
+                panic!("Misfired")
+            }
         }
         impl StateMachineCtl for SM {
             raft_sm_complete!();
@@ -1635,6 +2201,242 @@ mod test {
             assert_eq!(sm_client.take_a_shot(&2).await.unwrap(), 8);
         }
 
+        #[tokio::test(flavor = "multi_thread")]
+        async fn state_machine_errors() {
+            let _ = env_logger::try_init();
+            let addr = String::from("127.0.0.1:2022");
+            let raft_service = RaftService::new(Options {
+                storage: Storage::default(),
+                address: addr.clone(),
+                service_id: DEFAULT_SERVICE_ID,
+            });
+            let server = Server::new(&addr);
+            server
+                .register_service(DEFAULT_SERVICE_ID, &raft_service)
+                .await;
+            Server::listen_and_resume(&server).await;
+            RaftService::start(&raft_service).await;
+            raft_service
+                .register_state_machine(Box::new(SM { shots: 10 }))
+                .await;
+            raft_service.bootstrap().await;
+            async_wait_secs().await;
+
+            let raft_client = RaftClient::new(&vec![addr.clone()], DEFAULT_SERVICE_ID)
+                .await
+                .unwrap();
+            let sm_client = client::SMClient::new(15, &raft_client);
+            assert_eq!(sm_client.take_shots_checked(&4).await.unwrap(), 6);
+            match sm_client.take_shots_checked(&7).await {
+                Err(ExecError::StateMachineError {
+                    sm_id: 15,
+                    fn_id,
+                    code: 1,
+                    ..
+                }) if fn_id == commands::take_shots_checked::new(&7).encode().0 => {}
+                other => panic!("Expect state machine error, got {:?}", other),
+            }
+            match sm_client.misfire().await {
+                Err(ExecError::Panicked { sm_id: 15, .. }) => {}
+                other => panic!("Expect panic caught, got {:?}", other),
+            }
+            let leader = crate::rpc::DEFAULT_CLIENT_POOL.get(&addr).await.unwrap();
+            let leader = AsyncServiceClient::new(DEFAULT_SERVICE_ID, &leader);
+            let (fn_id, _, _) = commands::take_a_shot::new(&1).encode();
+            let res = leader
+                .c_command(LogEntry {
+                    id: 0,
+                    term: 0,
+                    sm_id: 15,
+                    fn_id,
+                    data: b"not arguments".to_vec(),
+                    sm_version: 0,
+                    time: 0,
+                })
+                .await
+                .unwrap();
+            match res {
+                ClientCmdResponse::Success {
+                    data: Err(ExecError::CannotDecode { sm_id: 15, .. }),
+                    ..
+                } => {}
+                other => panic!("Expect decode failure, got {:?}", other),
+            }
+            // Return value the client cannot decode
+            struct ShotAsText;
+            impl RaftMsg<String> for ShotAsText {
+                fn encode(self) -> (u64, OpType, Vec<u8>) {
+                    commands::get_shot::new().encode()
+                }
+                fn decode_return(data: &Vec<u8>) -> Option<String> {
+                    crate::utils::serde::deserialize(data)
+                }
+            }
+            match raft_client.execute(15, ShotAsText).await {
+                Err(ExecError::CannotDecode { sm_id: 15, .. }) => {}
+                other => panic!("Expect client decode failure, got {:?}", other),
+            }
+            // Failures above did not stop the state machine from applying commands
+            assert_eq!(sm_client.take_a_shot(&2).await.unwrap(), 4);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn follower_command_forwarding() {
+            let _ = env_logger::try_init();
+            let addresses: Vec<_> = vec!["127.0.0.1:2015", "127.0.0.1:2016", "127.0.0.1:2017"]
+                .into_iter()
+                .map(|addr| addr.to_string())
+                .collect();
+            let mut raft_services = vec![];
+            for addr in &addresses {
+                let raft_service = RaftService::new(Options {
+                    storage: Storage::default(),
+                    address: addr.clone(),
+                    service_id: DEFAULT_SERVICE_ID,
+                });
+                let server = Server::new(addr);
+                server
+                    .register_service(DEFAULT_SERVICE_ID, &raft_service)
+                    .await;
+                Server::listen_and_resume(&server).await;
+                RaftService::start(&raft_service).await;
+                raft_service
+                    .register_state_machine(Box::new(SM { shots: 10 }))
+                    .await;
+                raft_service.set_command_forwarding(true);
+                raft_services.push(raft_service);
+            }
+            raft_services[0].bootstrap().await;
+            for i in 1..raft_services.len() {
+                raft_services[i].join(&addresses).await.unwrap();
+            }
+            async_wait(Duration::from_secs(2)).await;
+            let follower = crate::rpc::DEFAULT_CLIENT_POOL
+                .get(&addresses[2])
+                .await
+                .unwrap();
+            let follower = AsyncServiceClient::new(DEFAULT_SERVICE_ID, &follower);
+            let (fn_id, _, data) = commands::take_a_shot::new(&1).encode();
+            let res = follower
+                .c_command(LogEntry {
+                    id: 0,
+                    term: 0,
+                    sm_id: 15,
+                    fn_id,
+                    data,
+                    sm_version: 0,
+                    time: 0,
+                })
+                .await
+                .unwrap();
+            match res {
+                ClientCmdResponse::Success { data, .. } => {
+                    let shots: i32 = crate::utils::serde::deserialize(&data.unwrap()).unwrap();
+                    assert_eq!(shots, 9);
+                }
+                other => panic!("Command is not forwarded to leader, got {:?}", other),
+            }
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn force_reconfigure_survivors() {
+            let _ = env_logger::try_init();
+            let addresses: Vec<_> = (2061..2066)
+                .map(|port| format!("127.0.0.1:{}", port))
+                .collect();
+            let dir = std::env::temp_dir().join("bifrost_force_reconfigure_test");
+            let _ = std::fs::remove_dir_all(&dir);
+            let disk = DiskOptions {
+                path: dir.join("raft").to_string_lossy().to_string(),
+                take_snapshots: false,
+                append_logs: true,
+                trim_logs: false,
+            };
+            let options = |i: usize| Options {
+                storage: if i == 0 {
+                    Storage::DISK(disk.clone())
+                } else {
+                    Storage::default()
+                },
+                address: addresses[i].clone(),
+                service_id: DEFAULT_SERVICE_ID,
+            };
+            let mut servers = vec![];
+            let mut raft_services = vec![];
+            for i in 0..addresses.len() {
+                let raft_service = RaftService::new(options(i));
+                let server = Server::new(&addresses[i]);
+                server
+                    .register_service(DEFAULT_SERVICE_ID, &raft_service)
+                    .await;
+                Server::listen_and_resume(&server).await;
+                RaftService::start(&raft_service).await;
+                raft_service
+                    .register_state_machine(Box::new(SM { shots: 10 }))
+                    .await;
+                servers.push(server);
+                raft_services.push(raft_service);
+            }
+            raft_services[0].bootstrap().await;
+            for i in 1..raft_services.len() {
+                raft_services[i].join(&addresses).await.unwrap();
+            }
+            async_wait(Duration::from_secs(2)).await;
+            let raft_client = RaftClient::new(&addresses, DEFAULT_SERVICE_ID)
+                .await
+                .unwrap();
+            let sm_client = client::SMClient::new(15, &raft_client);
+            for _ in 0..3 {
+                sm_client.take_a_shot(&1).await.unwrap();
+            }
+            async_wait_secs().await;
+
+            // Majority is lost, the survivor with disk storage is stopped for the operation
+            for i in 2..raft_services.len() {
+                raft_services[i].shutdown().await;
+            }
+            raft_services[0].shutdown().await;
+            let info = RaftService::inspect_storage(&disk).unwrap();
+            assert!(info.last_log_id > 0);
+            let survivors = addresses[..2].to_vec();
+            assert!(RaftService::force_reconfigure(&disk, &vec![], 0).is_err());
+            let (log_id, term) = RaftService::force_reconfigure(&disk, &survivors, 0).unwrap();
+            assert_eq!(log_id, info.last_log_id + 1);
+            assert!(term > info.term);
+            let forced = RaftService::inspect_storage(&disk).unwrap();
+            assert_eq!((forced.last_log_id, forced.last_log_term), (log_id, term));
+
+            let restarted = RaftService::new(options(0));
+            restarted
+                .register_state_machine(Box::new(SM { shots: 10 }))
+                .await;
+            servers[0]
+                .register_service(DEFAULT_SERVICE_ID, &restarted)
+                .await;
+            RaftService::start(&restarted).await;
+            assert_eq!(restarted.num_members().await, 2);
+            // Election timeout is up to 30 seconds, votes may also be term out for once
+            let mut elected = false;
+            for _ in 0..90 {
+                async_wait(Duration::from_secs(1)).await;
+                let leader_id = restarted.leader_id().await;
+                if leader_id == restarted.id && raft_services[1].leader_id().await == leader_id {
+                    elected = true;
+                    break;
+                }
+            }
+            assert!(elected);
+            let raft_client = RaftClient::new(&survivors, DEFAULT_SERVICE_ID)
+                .await
+                .unwrap();
+            let sm_client = client::SMClient::new(15, &raft_client);
+            assert_eq!(sm_client.get_shot().await.unwrap(), 7);
+            assert_eq!(sm_client.take_a_shot(&1).await.unwrap(), 6);
+            async_wait_secs().await;
+            assert_eq!(raft_services[1].num_members().await, 2);
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+
         #[tokio::test(flavor = "multi_thread")]
         async fn multi_server_command() {
             let _ = env_logger::try_init();
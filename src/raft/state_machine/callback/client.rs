@@ -1,53 +1,206 @@
+use super::pattern::SubPattern;
 use super::*;
+use crate::raft::client::RaftClient;
+use crate::raft::AsyncServiceClient as RaftServiceClient;
+use crate::rpc::{self, DEFAULT_CLIENT_POOL};
 use crate::utils::time::get_time;
 use async_std::sync::*;
 use futures::future::BoxFuture;
 use futures::stream::FuturesUnordered;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+// Where to renew subscriber leases. Clusters of raft clients follow membership changes and
+// resubscribe when the cluster forgot the subscriber
+#[derive(Clone)]
+pub enum Cluster {
+    Servers(Vec<String>),
+    Client(Weak<RaftClient>),
+}
 
 trait SubFunc = Fn(Vec<u8>) -> BoxFuture<'static, ()>;
 trait BoxedSubFunc = SubFunc + Send + Sync;
 
 pub struct SubscriptionService {
     pub subs: RwLock<HashMap<SubKey, Vec<(Box<dyn BoxedSubFunc>, u64)>>>,
+    pub last_seqs: RwLock<HashMap<SubKey, u64>>,
+    pub patterns: RwLock<HashMap<SubKey, SubPattern>>, // for resubscribe
+    pub clusters: RwLock<Vec<(u64, Cluster)>>,         // (raft service id, cluster), for renewal
     pub server_address: String,
     pub session_id: u64,
 }
 
 impl Service for SubscriptionService {
-    fn notify(&self, key: SubKey, data: Vec<u8>) -> BoxFuture<()> {
-        debug!("Received notification for key {:?}", key);
-        async move {
-            let subs = self.subs.read().await;
-            if let Some(subs) = subs.get(&key) {
-                let subs = Pin::new(subs);
-                let futs: FuturesUnordered<_> = subs
-                    .iter()
-                    .map(|(fun, _)| {
-                        let fun_pinned = Pin::new(fun);
-                        fun_pinned(data.clone())
-                    })
-                    .collect();
-                // Spawn async task DETACHED with the function to avoid deadlocks inside raft state machine
-                tokio::spawn(async move {
-                    let _: Vec<_> = futs.collect().await;
-                });
-            }
-        }
-        .boxed()
+    fn notify(&self, key: SubKey, items: Notifications) -> BoxFuture<()> {
+        debug!("Received {} notifications for key {:?}", items.len(), key);
+        self.deliver(key, items).boxed()
     }
 }
 dispatch_rpc_service_functions!(SubscriptionService);
 
 impl SubscriptionService {
     pub async fn initialize(server: &Arc<Server>) -> Arc<SubscriptionService> {
-        let service = Arc::new(SubscriptionService {
+        let service = Self::new(server.address().clone());
+        server.register_service(DEFAULT_SERVICE_ID, &service).await;
+        Self::start_renewal(&service);
+        service
+    }
+
+    // Receive notifications through connections this process made to raft servers,
+    // for clients cannot run a server reachable by the cluster
+    pub fn initialize_push() -> Arc<SubscriptionService> {
+        let service = Self::new(push_address(rpc::push_client_id()));
+        rpc::register_push_service(DEFAULT_SERVICE_ID, &service);
+        Self::start_renewal(&service);
+        service
+    }
+
+    fn new(server_address: String) -> Arc<SubscriptionService> {
+        Arc::new(SubscriptionService {
             subs: RwLock::new(HashMap::new()),
-            server_address: server.address().clone(),
+            last_seqs: RwLock::new(HashMap::new()),
+            patterns: RwLock::new(HashMap::new()),
+            clusters: RwLock::new(Vec::new()),
+            server_address,
             session_id: get_time() as u64,
+        })
+    }
+
+    fn start_renewal(service: &Arc<SubscriptionService>) {
+        let service_ref = Arc::downgrade(service);
+        tokio::spawn(async move {
+            while let Some(service) = service_ref.upgrade() {
+                service.renew_leases().await;
+                drop(service);
+                tokio::time::sleep(Duration::from_millis(SUBSCRIBER_RENEW_MS)).await;
+            }
+            debug!("Subscription lease renewal stopped");
         });
-        server.register_service(DEFAULT_SERVICE_ID, &service).await;
-        service
+    }
+
+    pub async fn add_cluster(&self, raft_sid: u64, servers: Vec<String>) {
+        let mut clusters = self.clusters.write().await;
+        clusters.retain(|(sid, c)| !(*sid == raft_sid && matches!(c, Cluster::Servers(_))));
+        clusters.push((raft_sid, Cluster::Servers(servers)));
+    }
+
+    pub async fn add_client(&self, raft_sid: u64, client: &Weak<RaftClient>) {
+        let mut clusters = self.clusters.write().await;
+        let existed = clusters.iter().any(|(_, c)| match c {
+            Cluster::Client(c) => c.ptr_eq(client),
+            _ => false,
+        });
+        if !existed {
+            clusters.push((raft_sid, Cluster::Client(client.clone())));
+        }
+    }
+
+    // Push subscribers receive notifications through connections to the cluster, introduce this
+    // process on them
+    pub(crate) async fn handshake_push(&self, servers: &Vec<String>) {
+        if push_client_id(&self.server_address).is_none() {
+            return;
+        }
+        for server_addr in servers {
+            if let Ok(client) = DEFAULT_CLIENT_POOL.get(server_addr).await {
+                if let Err(e) = client.handshake_push().await {
+                    warn!("Cannot handshake for push with {}, {}", server_addr, e);
+                }
+            }
+        }
+    }
+
+    // Renew subscriber lease on leader of every cluster we have subscribed to
+    pub(crate) async fn renew_leases(&self) {
+        self.clusters.write().await.retain(|(_, c)| match c {
+            Cluster::Client(client) => client.strong_count() > 0,
+            _ => true,
+        });
+        let clusters = self.clusters.read().await.clone();
+        for (raft_sid, cluster) in clusters {
+            let (servers, client) = match cluster {
+                Cluster::Servers(servers) => (servers, None),
+                Cluster::Client(client) => match client.upgrade() {
+                    Some(client) => (client.servers().await, Some(client)),
+                    None => continue,
+                },
+            };
+            self.handshake_push(&servers).await;
+            let renewals: FuturesUnordered<_> = servers
+                .iter()
+                .map(|server_addr| async move {
+                    let client = DEFAULT_CLIENT_POOL.get(server_addr).await.ok()?;
+                    RaftServiceClient::new(raft_sid, &client)
+                        .c_renew_subscriber(self.server_address.clone(), self.session_id)
+                        .await
+                        .ok()?
+                })
+                .collect();
+            let renewals: Vec<_> = renewals.collect().await;
+            if renewals.contains(&Some(false)) {
+                warn!(
+                    "Subscriber {} is unknown to raft service {}, resubscribing",
+                    self.server_address, raft_sid
+                );
+                match client {
+                    Some(client) => match client.resubscribe().await {
+                        Ok(Ok(replayed)) => {
+                            info!("Resubscribed to {}, replayed {}", raft_sid, replayed)
+                        }
+                        res => warn!("Cannot resubscribe to {}, {:?}", raft_sid, res),
+                    },
+                    None => warn!("No raft client to resubscribe to {}", raft_sid),
+                }
+            } else if !renewals.contains(&Some(true)) {
+                debug!("Cannot find leader to renew lease for {}", raft_sid);
+            }
+        }
+    }
+
+    // Invoke subscribed functions with notifications that have not been seen, by sequence
+    pub async fn deliver(&self, key: SubKey, items: Notifications) {
+        let fresh: Vec<Vec<u8>> = {
+            let mut last_seqs = self.last_seqs.write().await;
+            let last_seq = last_seqs.entry(key).or_insert(0);
+            let mut fresh = vec![];
+            for (seq, data) in items {
+                if seq <= *last_seq {
+                    trace!("Skip duplicated notification {} for key {:?}", seq, key);
+                    continue;
+                }
+                if *last_seq > 0 && seq > *last_seq + 1 {
+                    warn!(
+                        "Missing notifications from {} to {} for key {:?}",
+                        *last_seq + 1,
+                        seq - 1,
+                        key
+                    );
+                }
+                *last_seq = seq;
+                fresh.push(data);
+            }
+            fresh
+        };
+        if fresh.is_empty() {
+            return;
+        }
+        let subs = self.subs.read().await;
+        if let Some(subs) = subs.get(&key) {
+            let subs = Pin::new(subs);
+            let futs: FuturesUnordered<_> = fresh
+                .iter()
+                .flat_map(|data| {
+                    subs.iter().map(move |(fun, _)| {
+                        let fun_pinned = Pin::new(fun);
+                        fun_pinned(data.clone())
+                    })
+                })
+                .collect();
+            // Spawn async task DETACHED with the function to avoid deadlocks inside raft state machine
+            tokio::spawn(async move {
+                let _: Vec<_> = futs.collect().await;
+            });
+        }
     }
 }
@@ -1,25 +1,55 @@
 use bifrost_plugins::hash_ident;
 
 pub mod client;
+pub mod pattern;
 pub mod server;
 //                (raft_sid, sm_id, fn_id, pattern_id)
 pub type SubKey = (u64, u64, u64, u64);
+//                    (seq, data)
+pub type Notifications = Vec<(u64, Vec<u8>)>;
+
+// Number of recent notifications kept for each key to replay
+pub const NOTIFICATION_BUFFER_SIZE: usize = 256;
+// Subscribers not renewed in this period will be removed by leader
+pub const SUBSCRIBER_LEASE_MS: i64 = 10_000;
+pub const SUBSCRIBER_RENEW_MS: u64 = 2_000;
+
+// Address of subscribers notified through connections they made to servers, followed by push id
+pub const PUSH_ADDRESS_PREFIX: &str = "push://";
 
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(BIFROST_RAFT_SM_CALLBACK_DEFAULT_SERVICE) as u64;
 
 service! {
-    rpc notify(key: SubKey, data: Vec<u8>);
+    rpc notify(key: SubKey, items: Notifications);
+}
+
+pub fn push_address(client_id: u64) -> String {
+    format!("{}{}", PUSH_ADDRESS_PREFIX, client_id)
+}
+
+pub fn push_client_id(address: &String) -> Option<u64> {
+    if address.starts_with(PUSH_ADDRESS_PREFIX) {
+        address[PUSH_ADDRESS_PREFIX.len()..].parse().ok()
+    } else {
+        None
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::raft::client::RaftClient;
+    use crate::raft::client::{Lagged, RaftClient, CALLBACK};
     use crate::raft::state_machine::callback::server::SMCallback;
+    use crate::raft::state_machine::configs::commands::{
+        expire_subscribers_ as conf_expire_subscribers_, unsubscribe as conf_unsubscribe,
+    };
+    use crate::raft::state_machine::configs::CONFIG_SM_ID;
     use crate::raft::state_machine::StateMachineCtl;
     use crate::raft::{Options, RaftService, Storage, DEFAULT_SERVICE_ID};
     use crate::rpc::Server;
     use crate::utils::time::async_wait_secs;
+    use bifrost_hasher::hash_str;
     use future::FutureExt;
+    use futures::StreamExt;
     use std::sync::atomic::*;
     use std::sync::Arc;
 
@@ -98,7 +128,7 @@ mod test {
         let sumer_clone = sumer.clone();
         let mut expected_sum = 0;
         RaftClient::prepare_subscription(&server).await;
-        sm_client
+        let (_, sub_id) = sm_client
             .on_trigged(move |res: u64| {
                 counter_clone.fetch_add(1, Ordering::Relaxed);
                 sumer_clone.fetch_add(res as usize, Ordering::Relaxed);
@@ -121,5 +151,456 @@ mod test {
 
         assert_eq!(counter.load(Ordering::Relaxed), loops);
         assert_eq!(sumer.load(Ordering::Relaxed), expected_sum);
+
+        // Cluster lost the subscription, missed notifications should be replayed once
+        raft_client
+            .execute(CONFIG_SM_ID, conf_unsubscribe::new(&sub_id))
+            .await
+            .unwrap();
+        for i in loops..loops + 3 {
+            expected_sum += i + 1;
+            sm_client.trigger().await.unwrap();
+        }
+        async_wait_secs().await;
+        assert_eq!(counter.load(Ordering::Relaxed), loops);
+        assert_eq!(raft_client.resubscribe().await.unwrap().unwrap(), 3);
+        assert_eq!(raft_client.resubscribe().await.unwrap().unwrap(), 0);
+        async_wait_secs().await;
+        assert_eq!(counter.load(Ordering::Relaxed), loops + 3);
+        assert_eq!(sumer.load(Ordering::Relaxed), expected_sum);
+
+        sm_client.trigger().await.unwrap();
+        async_wait_secs().await;
+        assert_eq!(counter.load(Ordering::Relaxed), loops + 4);
+
+        // Stream buffers at most 2 notifications, the rest are lagged
+        let mut stream = sm_client.streams(2).on_trigged().await.unwrap().unwrap();
+        for _ in 0..5 {
+            sm_client.trigger().await.unwrap();
+        }
+        async_wait_secs().await;
+        assert_eq!(stream.next().await.unwrap(), Ok(loops as u64 + 5));
+        assert_eq!(stream.next().await.unwrap(), Ok(loops as u64 + 6));
+        assert_eq!(stream.next().await.unwrap(), Err(Lagged(3)));
+        assert_eq!(stream.lagged(), 3);
+        let (key, _) = stream.receipt();
+        drop(stream);
+        async_wait_secs().await;
+        let callback = CALLBACK.read().await.clone().unwrap();
+        assert_eq!(callback.subs.read().await.get(&key).unwrap().len(), 1);
+
+        // Cluster expired the subscriber, lease renewal resubscribes. Renewed here, the renewal
+        // task belongs to the runtime of whichever test prepared the subscription service
+        let suber_id = hash_str(&callback.server_address);
+        raft_client
+            .execute(CONFIG_SM_ID, conf_expire_subscribers_::new(&vec![suber_id]))
+            .await
+            .unwrap();
+        callback.renew_leases().await;
+        sm_client.trigger().await.unwrap();
+        async_wait_secs().await;
+        assert_eq!(counter.load(Ordering::Relaxed), loops + 10);
+    }
+
+    mod failover {
+        use crate::raft::client::RaftClient;
+        use crate::raft::state_machine::callback::client::SubscriptionService;
+        use crate::raft::state_machine::callback::pattern::ArgFilter;
+        use crate::raft::state_machine::callback::server::{
+            notify as cb_notify, SMCallback, Subscriptions,
+        };
+        use crate::raft::state_machine::callback::{
+            push_address, Notifications, SubKey, DEFAULT_SERVICE_ID as CALLBACK_SERVICE_ID,
+            PUSH_ADDRESS_PREFIX, SUBSCRIBER_LEASE_MS, SUBSCRIBER_RENEW_MS,
+        };
+        use crate::raft::state_machine::configs::commands::{
+            resubscribe_pattern as conf_resubscribe_pattern, subscribe as conf_subscribe,
+        };
+        use crate::raft::state_machine::configs::CONFIG_SM_ID;
+        use crate::raft::state_machine::StateMachineCtl;
+        use crate::raft::{
+            Options, RaftMsg, RaftService, Service as RaftServiceTrait, Storage, DEFAULT_SERVICE_ID,
+        };
+        use crate::rpc::Server;
+        use crate::tcp::push::{push_id_of, HANDSHAKE_MSG_ID, PUSH_MSG_ID};
+        use crate::utils::time::async_wait;
+        use bifrost_hasher::hash_bytes;
+        use bytes::{Buf, BufMut, BytesMut};
+        use future::FutureExt;
+        use futures::{SinkExt, StreamExt};
+        use std::sync::atomic::*;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio::net::TcpStream;
+        use tokio::time::timeout;
+        use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+        pub struct Counter {
+            count: u64,
+            callback: Option<SMCallback>,
+        }
+
+        raft_state_machine! {
+            def cmd incr();
+            def cmd incr_group(group: u64);
+            def sub on_incr() -> u64;
+            def sub on_group_incr(group: u64) -> u64;
+        }
+
+        impl StateMachineCmds for Counter {
+            fn incr(&mut self) -> BoxFuture<()> {
+                self.count += 1;
+                let count = self.count;
+                async move { cb_notify(&self.callback, commands::on_incr::new(), || count).await }
+                    .boxed()
+            }
+            fn incr_group(&mut self, group: u64) -> BoxFuture<()> {
+                self.count += 1;
+                let msg = commands::on_group_incr::new(&group);
+                async move { cb_notify(&self.callback, msg, || group).await }.boxed()
+            }
+        }
+
+        impl StateMachineCtl for Counter {
+            raft_sm_complete!();
+            fn id(&self) -> u64 {
+                11
+            }
+            fn snapshot(&self) -> Option<Vec<u8>> {
+                Some(crate::utils::serde::serialize(&self.count))
+            }
+            fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
+                self.count = crate::utils::serde::deserialize(&data).unwrap();
+                future::ready(()).boxed()
+            }
+        }
+
+        async fn start_node(addr: &String) -> Arc<RaftService> {
+            let raft_service = RaftService::new(Options {
+                storage: Storage::default(),
+                address: addr.clone(),
+                service_id: DEFAULT_SERVICE_ID,
+            });
+            let server = Server::new(addr);
+            server
+                .register_service(DEFAULT_SERVICE_ID, &raft_service)
+                .await;
+            Server::listen_and_resume(&server).await;
+            RaftService::start(&raft_service).await;
+            let counter = Counter {
+                count: 0,
+                callback: Some(SMCallback::new(11, raft_service.clone()).await),
+            };
+            raft_service.register_state_machine(Box::new(counter)).await;
+            raft_service
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn subscriptions_survive_snapshot_and_failover() {
+            let _ = env_logger::try_init();
+            let leader_addr = String::from("127.0.0.1:2028");
+            let follower_addr = String::from("127.0.0.1:2029");
+            let subscriber_addr = String::from("127.0.0.1:2030");
+            let leader = start_node(&leader_addr).await;
+            leader.bootstrap().await;
+            async_wait(Duration::from_secs(1)).await;
+
+            // Subscribe with a local subscription service, not the global one from RaftClient
+            let subscriber = Server::new(&subscriber_addr);
+            Server::listen_and_resume(&subscriber).await;
+            let sub_service = SubscriptionService::initialize(&subscriber).await;
+            sub_service
+                .add_cluster(
+                    DEFAULT_SERVICE_ID,
+                    vec![leader_addr.clone(), follower_addr.clone()],
+                )
+                .await;
+            let (fn_id, _, pattern_data) = commands::on_incr::new().encode();
+            let key = (DEFAULT_SERVICE_ID, 11, fn_id, hash_bytes(&pattern_data));
+            let counter = Arc::new(AtomicU64::new(0));
+            let counter_clone = counter.clone();
+            sub_service.subs.write().await.insert(
+                key,
+                vec![(
+                    Box::new(move |data: Vec<u8>| {
+                        let count = commands::on_incr::decode_return(&data).unwrap();
+                        counter_clone.store(count, Ordering::Relaxed);
+                        future::ready(()).boxed()
+                    }),
+                    0,
+                )],
+            );
+            let leader_client = RaftClient::new(&vec![leader_addr.clone()], DEFAULT_SERVICE_ID)
+                .await
+                .unwrap();
+            leader_client
+                .execute(
+                    CONFIG_SM_ID,
+                    conf_subscribe::new(&key, &subscriber_addr, &sub_service.session_id),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            let sm_client = client::SMClient::new(11, &leader_client);
+            sm_client.incr().await.unwrap();
+            async_wait(Duration::from_secs(1)).await;
+            assert_eq!(counter.load(Ordering::Relaxed), 1);
+
+            // New node catches up by snapshot, logs before the snapshot will not be applied
+            let follower = start_node(&follower_addr).await;
+            let (snapshot, last_included, term) = {
+                let meta = leader.read_meta().await;
+                let snapshot = meta.state_machine.read().await.snapshot().unwrap();
+                (snapshot, meta.commit_index, meta.term)
+            };
+            follower
+                .install_snapshot(term, leader.id, last_included, term, snapshot)
+                .await;
+            follower.join(&vec![leader_addr.clone()]).await.unwrap();
+            async_wait(Duration::from_secs(2)).await;
+
+            // Fail over to the new node
+            assert!(leader.leave().await);
+            let mut waited = 0;
+            while !follower.is_leader() {
+                assert!(waited < 60, "New node did not become leader");
+                async_wait(Duration::from_secs(1)).await;
+                waited += 1;
+            }
+            let follower_client = RaftClient::new(&vec![follower_addr], DEFAULT_SERVICE_ID)
+                .await
+                .unwrap();
+            let sm_client = client::SMClient::new(11, &follower_client);
+            sm_client.incr().await.unwrap();
+            async_wait(Duration::from_secs(1)).await;
+            assert_eq!(counter.load(Ordering::Relaxed), 2);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn dead_subscribers_expire() {
+            let _ = env_logger::try_init();
+            let raft_addr = String::from("127.0.0.1:2031");
+            let live_addr = String::from("127.0.0.1:2032");
+            let dead_addr = String::from("127.0.0.1:2033");
+            let raft_service = start_node(&raft_addr).await;
+            raft_service.bootstrap().await;
+            async_wait(Duration::from_secs(1)).await;
+
+            let live = Server::new(&live_addr);
+            Server::listen_and_resume(&live).await;
+            let live_service = SubscriptionService::initialize(&live).await;
+            live_service
+                .add_cluster(DEFAULT_SERVICE_ID, vec![raft_addr.clone()])
+                .await;
+            // Subscriber that never renews its lease
+            let dead = Server::new(&dead_addr);
+            Server::listen_and_resume(&dead).await;
+
+            let raft_client = RaftClient::new(&vec![raft_addr], DEFAULT_SERVICE_ID)
+                .await
+                .unwrap();
+            let (fn_id, _, pattern_data) = commands::on_incr::new().encode();
+            let key = (DEFAULT_SERVICE_ID, 11, fn_id, hash_bytes(&pattern_data));
+            for (addr, session_id) in vec![(&live_addr, live_service.session_id), (&dead_addr, 0)] {
+                raft_client
+                    .execute(CONFIG_SM_ID, conf_subscribe::new(&key, addr, &session_id))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            }
+            let subs = raft_service.subscriptions().await;
+            assert!(subs.read().subscriber_exists(&dead_addr));
+
+            async_wait(Duration::from_millis(
+                SUBSCRIBER_LEASE_MS as u64 + SUBSCRIBER_RENEW_MS * 3,
+            ))
+            .await;
+            assert!(subs.read().subscriber_exists(&live_addr));
+            assert!(!subs.read().subscriber_exists(&dead_addr));
+        }
+
+        #[test]
+        fn unreachable_subscribers_are_kept() {
+            // Nothing listens on the address, replicas keep the subscriber anyway
+            let address = String::from("127.0.0.1:2070");
+            let key = (DEFAULT_SERVICE_ID, 11, 1, 2);
+            let mut subs = Subscriptions::new();
+            let sub_id = subs.subscribe(key, &address, 1);
+            assert!(subs.subscriber_exists(&address));
+
+            let mut recovered = Subscriptions::new();
+            recovered.recover(subs.snapshot());
+            assert!(recovered.subscriber_exists(&address));
+            let (resub_id, _, _) = recovered.resubscribe(key, &address, 1, Some(sub_id), None);
+            assert_eq!(resub_id, sub_id);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn push_subscriber() {
+            let _ = env_logger::try_init();
+            let raft_addr = String::from("127.0.0.1:2036");
+            let raft_service = start_node(&raft_addr).await;
+            raft_service.bootstrap().await;
+            async_wait(Duration::from_secs(1)).await;
+
+            // No server for the subscriber, notifications come back by push
+            let sub_service = SubscriptionService::initialize_push();
+            assert!(sub_service.server_address.starts_with(PUSH_ADDRESS_PREFIX));
+            sub_service
+                .add_cluster(DEFAULT_SERVICE_ID, vec![raft_addr.clone()])
+                .await;
+            let (fn_id, _, pattern_data) = commands::on_incr::new().encode();
+            let key = (DEFAULT_SERVICE_ID, 11, fn_id, hash_bytes(&pattern_data));
+            let counter = Arc::new(AtomicU64::new(0));
+            let counter_clone = counter.clone();
+            sub_service.subs.write().await.insert(
+                key,
+                vec![(
+                    Box::new(move |data: Vec<u8>| {
+                        let count = commands::on_incr::decode_return(&data).unwrap();
+                        counter_clone.store(count, Ordering::Relaxed);
+                        future::ready(()).boxed()
+                    }),
+                    0,
+                )],
+            );
+            let raft_client = RaftClient::new(&vec![raft_addr], DEFAULT_SERVICE_ID)
+                .await
+                .unwrap();
+            raft_client
+                .execute(
+                    CONFIG_SM_ID,
+                    conf_subscribe::new(&key, &sub_service.server_address, &sub_service.session_id),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            let sm_client = client::SMClient::new(11, &raft_client);
+            sm_client.incr().await.unwrap();
+            sm_client.incr().await.unwrap();
+            async_wait(Duration::from_secs(1)).await;
+            assert_eq!(counter.load(Ordering::Relaxed), 2);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn push_subscriber_over_connection() {
+            let _ = env_logger::try_init();
+            let raft_addr = String::from("127.0.0.1:2066");
+            let raft_service = start_node(&raft_addr).await;
+            raft_service.bootstrap().await;
+            async_wait(Duration::from_secs(1)).await;
+
+            // Subscriber in another process, only known by the connection it made to the server
+            let secret = rand::random::<u64>();
+            let socket = TcpStream::connect(&raft_addr).await.unwrap();
+            let mut transport = Framed::new(socket, LengthDelimitedCodec::new());
+            let mut handshake = BytesMut::new();
+            handshake.put_u64_le(HANDSHAKE_MSG_ID);
+            handshake.put_u64_le(secret);
+            transport.send(handshake.freeze()).await.unwrap();
+            async_wait(Duration::from_millis(500)).await;
+
+            let (fn_id, _, pattern_data) = commands::on_incr::new().encode();
+            let key = (DEFAULT_SERVICE_ID, 11, fn_id, hash_bytes(&pattern_data));
+            let raft_client = RaftClient::new(&vec![raft_addr], DEFAULT_SERVICE_ID)
+                .await
+                .unwrap();
+            raft_client
+                .execute(
+                    CONFIG_SM_ID,
+                    conf_subscribe::new(&key, &push_address(push_id_of(secret)), &0),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            let sm_client = client::SMClient::new(11, &raft_client);
+            sm_client.incr().await.unwrap();
+
+            let mut pushed = timeout(Duration::from_secs(5), transport.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(pushed.get_u64_le(), PUSH_MSG_ID);
+            assert_eq!(pushed.get_u64_le(), CALLBACK_SERVICE_ID);
+            let _func_id = pushed.get_u64_le();
+            let (pushed_key, items): (SubKey, Notifications) =
+                crate::utils::serde::deserialize(pushed.as_ref()).unwrap();
+            assert_eq!(pushed_key, key);
+            assert_eq!(items.len(), 1);
+            assert_eq!(commands::on_incr::decode_return(&items[0].1).unwrap(), 1);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn pattern_subscriptions() {
+            let _ = env_logger::try_init();
+            let raft_addr = String::from("127.0.0.1:2037");
+            let subscriber_addr = String::from("127.0.0.1:2038");
+            let raft_service = start_node(&raft_addr).await;
+            raft_service.bootstrap().await;
+            async_wait(Duration::from_secs(1)).await;
+
+            let subscriber = Server::new(&subscriber_addr);
+            Server::listen_and_resume(&subscriber).await;
+            let sub_service = SubscriptionService::initialize(&subscriber).await;
+            let raft_client = RaftClient::new(&vec![raft_addr], DEFAULT_SERVICE_ID)
+                .await
+                .unwrap();
+            let patterns = vec![
+                commands::on_group_incr::pattern(&ArgFilter::In(vec![1, 2])),
+                commands::on_group_incr::pattern(&ArgFilter::Not(2)),
+                commands::on_group_incr::pattern(&ArgFilter::Any),
+            ];
+            let mut received = vec![];
+            for msg in patterns {
+                let pattern = msg.pattern().unwrap();
+                let (fn_id, _, pattern_data) = msg.encode();
+                let key = (DEFAULT_SERVICE_ID, 11, fn_id, hash_bytes(&pattern_data));
+                let groups = Arc::new(parking_lot::Mutex::new(vec![]));
+                let groups_clone = groups.clone();
+                sub_service.subs.write().await.insert(
+                    key,
+                    vec![(
+                        Box::new(move |data: Vec<u8>| {
+                            let group = commands::on_group_incr::decode_return(&data).unwrap();
+                            groups_clone.lock().push(group);
+                            future::ready(()).boxed()
+                        }),
+                        0,
+                    )],
+                );
+                raft_client
+                    .execute(
+                        CONFIG_SM_ID,
+                        conf_resubscribe_pattern::new(
+                            &key,
+                            &pattern,
+                            &subscriber_addr,
+                            &sub_service.session_id,
+                            &None,
+                            &None,
+                        ),
+                    )
+                    .await
+                    .unwrap()
+                    .unwrap();
+                received.push(groups);
+            }
+            let sm_client = client::SMClient::new(11, &raft_client);
+            for group in 1..=3 {
+                sm_client.incr_group(&group).await.unwrap();
+                async_wait(Duration::from_millis(200)).await;
+            }
+            async_wait(Duration::from_secs(1)).await;
+            let received: Vec<Vec<u64>> = received
+                .iter()
+                .map(|groups| {
+                    let mut groups = groups.lock().clone();
+                    groups.sort();
+                    groups
+                })
+                .collect();
+            assert_eq!(received, vec![vec![1, 2], vec![1, 3], vec![1, 2, 3]]);
+        }
     }
 }
@@ -0,0 +1,120 @@
+// Subscription patterns match notifications by each argument instead of all arguments exactly.
+// Arguments are compared in serialized form, so filters are limited to equality.
+
+use crate::raft::state_machine::OpType;
+use crate::raft::RaftMsg;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ArgMatcher {
+    Any,
+    Eq(Vec<u8>),
+    In(Vec<Vec<u8>>),
+    Not(Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SubPattern {
+    pub fn_id: u64,
+    pub args: Vec<ArgMatcher>,
+}
+
+// Typed filter for an argument of `def sub` functions
+pub enum ArgFilter<T> {
+    Any,
+    Eq(T),
+    In(Vec<T>),
+    Not(T),
+}
+
+// Subscription message for a pattern, used in place of the message with exact arguments
+pub struct PatternMsg<R> {
+    pattern: SubPattern,
+    _ret: PhantomData<fn() -> R>,
+}
+
+impl ArgMatcher {
+    pub fn matches(&self, arg: &Vec<u8>) -> bool {
+        match self {
+            ArgMatcher::Any => true,
+            ArgMatcher::Eq(expected) => expected == arg,
+            ArgMatcher::In(candidates) => candidates.contains(arg),
+            ArgMatcher::Not(excluded) => excluded != arg,
+        }
+    }
+}
+
+impl SubPattern {
+    pub fn matches(&self, args: &Vec<Vec<u8>>) -> bool {
+        self.args.len() == args.len()
+            && self
+                .args
+                .iter()
+                .zip(args.iter())
+                .all(|(matcher, arg)| matcher.matches(arg))
+    }
+}
+
+impl<T: Serialize> ArgFilter<T> {
+    pub fn matcher(&self) -> ArgMatcher {
+        use crate::utils::serde::serialize;
+        match self {
+            ArgFilter::Any => ArgMatcher::Any,
+            ArgFilter::Eq(v) => ArgMatcher::Eq(serialize(v)),
+            ArgFilter::In(vs) => ArgMatcher::In(vs.iter().map(serialize).collect()),
+            ArgFilter::Not(v) => ArgMatcher::Not(serialize(v)),
+        }
+    }
+}
+
+impl<R> PatternMsg<R> {
+    pub fn new(fn_id: u64, args: Vec<ArgMatcher>) -> Self {
+        Self {
+            pattern: SubPattern { fn_id, args },
+            _ret: PhantomData,
+        }
+    }
+}
+
+impl<R: DeserializeOwned> RaftMsg<R> for PatternMsg<R> {
+    fn encode(self) -> (u64, OpType, Vec<u8>) {
+        (
+            self.pattern.fn_id,
+            OpType::SUBSCRIBE,
+            crate::utils::serde::serialize(&self.pattern),
+        )
+    }
+    fn decode_return(data: &Vec<u8>) -> Option<R> {
+        crate::utils::serde::deserialize(data)
+    }
+    fn pattern(&self) -> Option<SubPattern> {
+        Some(self.pattern.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::serde::serialize;
+
+    #[test]
+    fn matching() {
+        let pattern = PatternMsg::<()>::new(
+            1,
+            vec![
+                ArgFilter::<u64>::Any.matcher(),
+                ArgFilter::In(vec![String::from("a"), String::from("b")]).matcher(),
+                ArgFilter::Not(false).matcher(),
+            ],
+        )
+        .pattern;
+        let args = |n: u64, s: &str, b: bool| vec![serialize(&n), serialize(&s), serialize(&b)];
+        assert!(pattern.matches(&args(1, "a", true)));
+        assert!(pattern.matches(&args(2, "b", true)));
+        assert!(!pattern.matches(&args(2, "c", true)));
+        assert!(!pattern.matches(&args(2, "a", false)));
+        assert!(!pattern.matches(&vec![serialize(&1u64)]));
+    }
+}
@@ -1,28 +1,157 @@
 use super::super::OpType;
+use super::pattern::SubPattern;
 use super::*;
 use crate::raft::{RaftMsg, RaftService};
 use crate::rpc;
+use crate::utils::time::get_time;
 use async_std::sync::*;
 use bifrost_hasher::{hash_bytes, hash_str};
+use bytes::BytesMut;
 use futures::stream::FuturesUnordered;
 use serde;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
 use std::sync::Arc;
 
+// Subscribers are replicated state, every node keeps them whether it can reach them or not.
+// The client is connected when the leader first delivers to the subscriber
 pub struct Subscriber {
     pub session_id: u64,
-    pub client: Arc<AsyncServiceClient>,
+    pub address: String,
+    client: Mutex<Option<SubscriberClient>>,
+}
+
+#[derive(Clone)]
+pub enum SubscriberClient {
+    Rpc(Arc<AsyncServiceClient>),
+    // Subscriber without a server, notified through the connection it made to us
+    Push(u64),
 }
 
 pub struct Subscriptions {
     next_id: u64,
-    subscribers: HashMap<u64, Subscriber>,
+    subscribers: HashMap<u64, Arc<Subscriber>>,
     suber_subs: HashMap<u64, HashSet<u64>>, //suber_id -> sub_id
     subscriptions: HashMap<SubKey, HashSet<u64>>, // key -> sub_id
     sub_suber: HashMap<u64, u64>,
     sub_to_key: HashMap<u64, SubKey>, //sub_id -> sub_key
+    buffers: HashMap<SubKey, NotificationBuffer>,
+    sub_from: HashMap<u64, u64>, // sub_id -> sequence of the key when subscribed
+    acked: HashMap<u64, u64>,    // sub_id -> last delivered sequence, only tracked by leader
+    leases: HashMap<u64, i64>,   // suber_id -> last renewed time, only tracked by leader
+    patterns: HashMap<SubKey, SubPattern>,
+    fn_patterns: HashMap<(u64, u64, u64), HashSet<SubKey>>, // (raft_sid, sm_id, fn_id) -> keys
+}
+
+// Recent notifications of a key. Every node records the same notifications in the same
+// order when applying commands, so sequences are consistent across the cluster
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct NotificationBuffer {
+    seq: u64,
+    items: VecDeque<(u64, Vec<u8>)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SubscriptionsSnapshot {
+    next_id: u64,
+    subscribers: Vec<(String, u64)>,           // address, session_id
+    subscriptions: Vec<(u64, SubKey, String)>, // sub_id, key, subscriber address
+    #[serde(default)]
+    buffers: Vec<(SubKey, NotificationBuffer)>,
+    #[serde(default)]
+    sub_from: Vec<(u64, u64)>,
+    #[serde(default)]
+    patterns: Vec<(SubKey, SubPattern)>,
+}
+
+impl NotificationBuffer {
+    fn push(&mut self, data: Vec<u8>) -> u64 {
+        self.seq += 1;
+        self.items.push_back((self.seq, data));
+        if self.items.len() > NOTIFICATION_BUFFER_SIZE {
+            self.items.pop_front();
+        }
+        self.seq
+    }
+    fn since(&self, seq: u64) -> Notifications {
+        if let Some((first, _)) = self.items.front() {
+            if *first > seq + 1 {
+                warn!(
+                    "Notifications from {} to {} are no longer buffered",
+                    seq + 1,
+                    first - 1
+                );
+            }
+        }
+        self.items
+            .iter()
+            .filter(|(s, _)| *s > seq)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Subscriber {
+    fn new(address: String, session_id: u64) -> Self {
+        Subscriber {
+            session_id,
+            address,
+            client: Mutex::new(None),
+        }
+    }
+
+    pub async fn client(&self) -> io::Result<SubscriberClient> {
+        let mut client = self.client.lock().await;
+        if let Some(ref client) = *client {
+            return Ok(client.clone());
+        }
+        let connected = SubscriberClient::connect(&self.address).await?;
+        *client = Some(connected.clone());
+        Ok(connected)
+    }
+
+    pub async fn notify(&self, key: SubKey, items: Notifications) -> Result<(), rpc::RPCError> {
+        let res = match self.client().await {
+            Ok(client) => client.notify(key, items).await,
+            Err(e) => Err(rpc::RPCError::IOError(e)),
+        };
+        if res.is_err() {
+            // Connect again on next delivery
+            *self.client.lock().await = None;
+        }
+        res
+    }
+}
+
+impl SubscriberClient {
+    pub async fn connect(address: &String) -> io::Result<Self> {
+        if let Some(client_id) = push_client_id(address) {
+            return Ok(SubscriberClient::Push(client_id));
+        }
+        let client = RPCClient::new_async(address).await?;
+        Ok(SubscriberClient::Rpc(AsyncServiceClient::new(
+            DEFAULT_SERVICE_ID,
+            &client,
+        )))
+    }
+
+    pub async fn notify(&self, key: SubKey, items: Notifications) -> Result<(), rpc::RPCError> {
+        match self {
+            SubscriberClient::Rpc(client) => client.notify(key, items).await,
+            SubscriberClient::Push(client_id) => {
+                let data = crate::utils::serde::serialize(&(key, items));
+                rpc::push(
+                    *client_id,
+                    DEFAULT_SERVICE_ID,
+                    hash_ident!(notify) as u64,
+                    BytesMut::from(data.as_slice()),
+                )
+                .map_err(rpc::RPCError::IOError)
+            }
+        }
+    }
 }
 
 impl Subscriptions {
@@ -34,16 +163,16 @@ impl Subscriptions {
             subscriptions: HashMap::new(),
             sub_suber: HashMap::new(),
             sub_to_key: HashMap::new(),
+            buffers: HashMap::new(),
+            sub_from: HashMap::new(),
+            acked: HashMap::new(),
+            leases: HashMap::new(),
+            patterns: HashMap::new(),
+            fn_patterns: HashMap::new(),
         }
     }
 
-    pub async fn subscribe(
-        &mut self,
-        key: SubKey,
-        address: &String,
-        session_id: u64,
-    ) -> Result<u64, ()> {
-        let sub_service_id = DEFAULT_SERVICE_ID;
+    pub fn subscribe(&mut self, key: SubKey, address: &String, session_id: u64) -> u64 {
         let suber_id = hash_str(address);
         let suber_exists = self.subscribers.contains_key(&suber_id);
         let sub_id = self.next_id;
@@ -67,16 +196,7 @@ impl Subscriptions {
         if !suber_exists || require_reload_suber {
             self.subscribers.insert(
                 suber_id,
-                Subscriber {
-                    session_id,
-                    client: {
-                        if let Ok(client) = RPCClient::new_async(address).await {
-                            AsyncServiceClient::new(sub_service_id, &client)
-                        } else {
-                            return Err(());
-                        }
-                    },
-                },
+                Arc::new(Subscriber::new(address.clone(), session_id)),
             );
         }
         self.suber_subs
@@ -89,9 +209,240 @@ impl Subscriptions {
             .insert(sub_id);
         self.sub_to_key.insert(sub_id, key);
         self.sub_suber.insert(sub_id, suber_id);
+        self.sub_from.insert(sub_id, self.current_seq(&key));
 
         self.next_id += 1;
-        Ok(sub_id)
+        sub_id
+    }
+
+    // Keep the subscription if it still exists for the session, or subscribe again.
+    // Returns the subscription id, current sequence of the key and buffered notifications
+    // after `last_seq`, the subscription will be notified from current sequence.
+    pub fn resubscribe(
+        &mut self,
+        key: SubKey,
+        address: &String,
+        session_id: u64,
+        sub_id: Option<u64>,
+        last_seq: Option<u64>,
+    ) -> (u64, u64, Notifications) {
+        let suber_id = hash_str(address);
+        let session_match = self
+            .subscribers
+            .get(&suber_id)
+            .map(|suber| suber.session_id == session_id)
+            .unwrap_or(false);
+        let existing = sub_id.filter(|id| {
+            session_match
+                && self.sub_suber.get(id) == Some(&suber_id)
+                && self.sub_to_key.get(id) == Some(&key)
+        });
+        let sub_id = match existing {
+            Some(id) => id,
+            None => self.subscribe(key, address, session_id),
+        };
+        let seq = self.current_seq(&key);
+        let missed = match (last_seq, self.buffers.get(&key)) {
+            (Some(last_seq), Some(buffer)) => buffer.since(last_seq),
+            _ => vec![],
+        };
+        self.sub_from.insert(sub_id, seq);
+        self.acked.remove(&sub_id);
+        (sub_id, seq, missed)
+    }
+
+    // Same as resubscribe, for the key of a pattern
+    pub fn resubscribe_pattern(
+        &mut self,
+        key: SubKey,
+        pattern: SubPattern,
+        address: &String,
+        session_id: u64,
+        sub_id: Option<u64>,
+        last_seq: Option<u64>,
+    ) -> Result<(u64, u64, Notifications), ()> {
+        let (raft_sid, sm_id, fn_id, _) = key;
+        if pattern.fn_id != fn_id {
+            warn!(
+                "Pattern for function {} subscribed for {}",
+                pattern.fn_id, fn_id
+            );
+            return Err(());
+        }
+        self.patterns.insert(key, pattern);
+        self.fn_patterns
+            .entry((raft_sid, sm_id, fn_id))
+            .or_insert_with(|| HashSet::new())
+            .insert(key);
+        Ok(self.resubscribe(key, address, session_id, sub_id, last_seq))
+    }
+
+    // The exact key and keys of patterns matching the arguments, if subscribed. Notifications
+    // of keys never subscribed are not recorded. Arguments are only decoded when there are
+    // patterns for the function
+    pub fn matching_keys<F>(&self, key: SubKey, args: F) -> Vec<SubKey>
+    where
+        F: FnOnce() -> Vec<Vec<u8>>,
+    {
+        let (raft_sid, sm_id, fn_id, _) = key;
+        let mut keys = vec![];
+        if self.subscriptions.contains_key(&key) {
+            keys.push(key);
+        }
+        if let Some(pattern_keys) = self.fn_patterns.get(&(raft_sid, sm_id, fn_id)) {
+            let args = args();
+            keys.extend(pattern_keys.iter().filter(|pattern_key| {
+                self.patterns
+                    .get(pattern_key)
+                    .map(|pattern| pattern.matches(&args))
+                    .unwrap_or(false)
+            }));
+        }
+        keys
+    }
+
+    pub fn current_seq(&self, key: &SubKey) -> u64 {
+        self.buffers.get(key).map(|buffer| buffer.seq).unwrap_or(0)
+    }
+
+    pub fn record(&mut self, key: SubKey, data: Vec<u8>) -> u64 {
+        self.buffers.entry(key).or_default().push(data)
+    }
+
+    // Notifications the subscription has not received yet
+    fn pending(&self, sub_id: u64, key: &SubKey) -> Notifications {
+        let from = self
+            .acked
+            .get(&sub_id)
+            .or_else(|| self.sub_from.get(&sub_id))
+            .cloned()
+            .unwrap_or(0);
+        match self.buffers.get(key) {
+            Some(buffer) => buffer.since(from),
+            None => vec![],
+        }
+    }
+
+    fn ack(&mut self, sub_id: u64, seq: u64) {
+        if self.sub_to_key.contains_key(&sub_id) {
+            let acked = self.acked.entry(sub_id).or_insert(0);
+            *acked = (*acked).max(seq);
+        }
+    }
+
+    pub fn snapshot(&self) -> SubscriptionsSnapshot {
+        SubscriptionsSnapshot {
+            next_id: self.next_id,
+            subscribers: self
+                .subscribers
+                .values()
+                .map(|suber| (suber.address.clone(), suber.session_id))
+                .collect(),
+            subscriptions: self
+                .sub_to_key
+                .iter()
+                .filter_map(|(sub_id, key)| {
+                    let suber_id = self.sub_suber.get(sub_id)?;
+                    let suber = self.subscribers.get(suber_id)?;
+                    Some((*sub_id, *key, suber.address.clone()))
+                })
+                .collect(),
+            buffers: self
+                .buffers
+                .iter()
+                .map(|(key, buffer)| (*key, buffer.clone()))
+                .collect(),
+            sub_from: self
+                .sub_from
+                .iter()
+                .map(|(sub_id, seq)| (*sub_id, *seq))
+                .collect(),
+            patterns: self
+                .patterns
+                .iter()
+                .map(|(key, pattern)| (*key, pattern.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn recover(&mut self, snapshot: SubscriptionsSnapshot) {
+        *self = Subscriptions::new();
+        self.next_id = snapshot.next_id;
+        self.buffers = snapshot.buffers.into_iter().collect();
+        for (address, session_id) in snapshot.subscribers {
+            self.subscribers.insert(
+                hash_str(&address),
+                Arc::new(Subscriber::new(address, session_id)),
+            );
+        }
+        for (sub_id, key, address) in snapshot.subscriptions {
+            let suber_id = hash_str(&address);
+            if !self.subscribers.contains_key(&suber_id) {
+                continue;
+            }
+            self.suber_subs
+                .entry(suber_id)
+                .or_insert_with(|| HashSet::new())
+                .insert(sub_id);
+            self.subscriptions
+                .entry(key)
+                .or_insert_with(|| HashSet::new())
+                .insert(sub_id);
+            self.sub_to_key.insert(sub_id, key);
+            self.sub_suber.insert(sub_id, suber_id);
+        }
+        for (sub_id, seq) in snapshot.sub_from {
+            if self.sub_to_key.contains_key(&sub_id) {
+                self.sub_from.insert(sub_id, seq);
+            }
+        }
+        for (key, pattern) in snapshot.patterns {
+            if self.subscriptions.contains_key(&key) {
+                let (raft_sid, sm_id, fn_id, _) = key;
+                self.fn_patterns
+                    .entry((raft_sid, sm_id, fn_id))
+                    .or_insert_with(|| HashSet::new())
+                    .insert(key);
+                self.patterns.insert(key, pattern);
+            }
+        }
+        debug!(
+            "Recovered {} subscriptions from {} subscribers",
+            self.sub_to_key.len(),
+            self.subscribers.len()
+        );
+    }
+
+    pub fn subscriber_exists(&self, address: &String) -> bool {
+        self.subscribers.contains_key(&hash_str(address))
+    }
+
+    pub fn renew(&mut self, address: &String, session_id: u64) -> bool {
+        let suber_id = hash_str(address);
+        match self.subscribers.get(&suber_id) {
+            Some(suber) if suber.session_id == session_id => {
+                self.leases.insert(suber_id, get_time());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Leases are local to the leader, new leader gives every subscriber a full lease
+    pub fn reset_leases(&mut self) {
+        self.leases.clear();
+    }
+
+    pub fn expired_subscribers(&mut self, now: i64) -> Vec<u64> {
+        let leases = &mut self.leases;
+        self.subscribers
+            .keys()
+            .filter(|suber_id| {
+                let renewed = *leases.entry(**suber_id).or_insert(now);
+                now - renewed > SUBSCRIBER_LEASE_MS
+            })
+            .cloned()
+            .collect()
     }
 
     pub fn remove_subscriber(&mut self, suber_id: u64) {
@@ -106,6 +457,7 @@ impl Subscriptions {
         }
         self.subscribers.remove(&suber_id);
         self.suber_subs.remove(&suber_id);
+        self.leases.remove(&suber_id);
     }
 
     pub fn remove_subscription(&mut self, id: u64) {
@@ -115,6 +467,24 @@ impl Subscriptions {
             if let Some(ref mut sub_subers) = self.subscriptions.get_mut(&sub_key) {
                 sub_subers.remove(&id);
                 self.sub_suber.remove(&id);
+                if sub_subers.is_empty() {
+                    self.remove_pattern(&sub_key);
+                }
+            }
+        }
+        self.sub_from.remove(&id);
+        self.acked.remove(&id);
+    }
+
+    fn remove_pattern(&mut self, key: &SubKey) {
+        if self.patterns.remove(key).is_some() {
+            let (raft_sid, sm_id, fn_id, _) = *key;
+            let fn_key = (raft_sid, sm_id, fn_id);
+            if let Some(keys) = self.fn_patterns.get_mut(&fn_key) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.fn_patterns.remove(&fn_key);
+                }
             }
         }
     }
@@ -126,7 +496,7 @@ pub struct InternalSubscription {
 }
 
 pub struct SMCallback {
-    pub subscriptions: Arc<RwLock<Subscriptions>>,
+    pub subscriptions: Arc<parking_lot::RwLock<Subscriptions>>,
     pub raft_service: Arc<RaftService>,
     pub internal_subs: RwLock<HashMap<u64, Vec<InternalSubscription>>>,
     pub sm_id: u64,
@@ -164,6 +534,25 @@ impl SMCallback {
         R: serde::Serialize + Send + Sync + Clone + Any + Unpin + 'static,
         M: RaftMsg<R> + 'static,
     {
+        let (fn_id, op_type, pattern_data) = msg.encode();
+        match op_type {
+            OpType::SUBSCRIBE => {}
+            _ => return Err(NotifyError::OpTypeNotSubscribe),
+        }
+        let pattern_id = hash_bytes(&pattern_data.as_slice());
+        let raft_sid = self.raft_service.options.service_id;
+        let sm_id = self.sm_id;
+        let key = (raft_sid, sm_id, fn_id, pattern_id);
+        let data = crate::utils::serde::serialize(&message);
+        // All nodes number and buffer the notification for replay, only leader sends them out
+        let keys: Vec<(SubKey, u64)> = {
+            let mut svr_subs = self.subscriptions.write();
+            svr_subs
+                .matching_keys(key, || M::args(&pattern_data))
+                .into_iter()
+                .map(|key| (key, svr_subs.record(key, data.clone())))
+                .collect()
+        };
         if !self.raft_service.is_leader() {
             debug!(
                 "Will not send notification from {} because this node is not a leader",
@@ -171,73 +560,77 @@ impl SMCallback {
             );
             return Err(NotifyError::IsNotLeader);
         }
-        let (fn_id, op_type, pattern_data) = msg.encode();
-        return match op_type {
-            OpType::SUBSCRIBE => {
-                let pattern_id = hash_bytes(&pattern_data.as_slice());
-                let raft_sid = self.raft_service.options.service_id;
-                let sm_id = self.sm_id;
-                let key = (raft_sid, sm_id, fn_id, pattern_id);
-                let internal_subs = self.internal_subs.read().await;
-                let svr_subs = self.subscriptions.read().await;
+        let internal_subs = self.internal_subs.read().await;
+        debug!(
+            "Sending notification, func {}, op: {:?}, pattern_id {}, keys {:?}",
+            fn_id, op_type, pattern_id, keys
+        );
+        if let Some(internal_subs) = internal_subs.get(&pattern_id) {
+            for is in internal_subs {
+                (is.action)(&message)
+            }
+        } else {
+            trace!("Cannot found internal subs {}", pattern_id);
+        }
+        // Subscriptions are never locked across awaits, deliveries are collected before sending
+        let deliveries: Vec<Result<_, NotifyError>> = {
+            let svr_subs = self.subscriptions.read();
+            let key_subs: Vec<_> = keys
+                .iter()
+                .filter_map(|(key, seq)| Some((svr_subs.subscriptions.get(key)?, *key, *seq)))
+                .collect();
+            if key_subs.is_empty() {
+                return Err(NotifyError::CannotFindSubscription);
+            }
+            key_subs
+                .into_iter()
+                .flat_map(|(sub_ids, key, seq)| sub_ids.iter().map(move |id| (*id, key, seq)))
+                .map(|(sub_id, key, seq)| {
+                    let subscriber_id = svr_subs
+                        .sub_suber
+                        .get(&sub_id)
+                        .ok_or(NotifyError::CannotFindSubscribers)?;
+                    let subscriber = svr_subs
+                        .subscribers
+                        .get(subscriber_id)
+                        .ok_or(NotifyError::CannotFindSubscriber)?;
+                    // Also resend what the subscriber have missed
+                    let items = svr_subs.pending(sub_id, &key);
+                    Ok((sub_id, key, seq, subscriber.clone(), items))
+                })
+                .collect()
+        };
+        let num_subs = deliveries.len();
+        let sub_result = deliveries
+            .into_iter()
+            .map(|delivery| async move {
+                let (sub_id, key, seq, subscriber, items) = delivery?;
                 debug!(
-                    "Sending notification, func {}, op: {:?}, pattern_id {}",
-                    fn_id, op_type, pattern_id
+                    "Sending out {} callback notifications to sub id {}",
+                    items.len(),
+                    sub_id
                 );
-                if let Some(internal_subs) = internal_subs.get(&pattern_id) {
-                    for is in internal_subs {
-                        (is.action)(&message)
+                let client_result = subscriber.notify(key, items).await;
+                Ok((sub_id, seq, client_result))
+            })
+            .collect::<FuturesUnordered<_>>()
+            .collect::<Vec<_>>()
+            .await;
+        let mut svr_subs = self.subscriptions.write();
+        let mut errors = vec![];
+        let mut response = vec![];
+        for res in sub_result {
+            match res {
+                Ok((sub_id, seq, client_result)) => {
+                    if client_result.is_ok() {
+                        svr_subs.ack(sub_id, seq);
                     }
-                } else {
-                    trace!("Cannot found internal subs {}", pattern_id);
-                }
-                if let Some(sub_ids) = svr_subs.subscriptions.get(&key) {
-                    let sub_result_futs: FuturesUnordered<_> = sub_ids
-                        .iter()
-                        .map(|sub_id| {
-                            let message = Pin::new(&message);
-                            async move {
-                                let svr_subs = self.subscriptions.read().await;
-                                if let Some(subscriber_id) = svr_subs.sub_suber.get(&sub_id) {
-                                    if let Some(subscriber) =
-                                        svr_subs.subscribers.get(&subscriber_id)
-                                    {
-                                        let data = crate::utils::serde::serialize(&*message);
-                                        let client = &subscriber.client;
-                                        debug!(
-                                            "Sending out callback notification to sub id {}",
-                                            sub_id
-                                        );
-                                        let client_result = client.notify(key, data).await;
-                                        Ok(client_result)
-                                    } else {
-                                        Err(NotifyError::CannotFindSubscriber)
-                                    }
-                                } else {
-                                    Err(NotifyError::CannotFindSubscribers)
-                                }
-                            }
-                        })
-                        .collect();
-                    let sub_result: Vec<_> = sub_result_futs.collect().await;
-                    let errors = sub_result
-                        .iter()
-                        .filter(|r| r.is_err())
-                        .map(|r| if let &Err(e) = r { Some(e) } else { None })
-                        .map(|o| o.unwrap())
-                        .collect::<Vec<NotifyError>>();
-                    let response = sub_result
-                        .into_iter()
-                        .filter(|r| r.is_ok())
-                        .map(|r| r.unwrap())
-                        .collect::<Vec<_>>();
-                    Ok((sub_ids.len(), errors, response))
-                } else {
-                    Err(NotifyError::CannotFindSubscription)
+                    response.push(client_result);
                 }
+                Err(e) => errors.push(e),
             }
-            _ => Err(NotifyError::OpTypeNotSubscribe),
-        };
+        }
+        Ok((num_subs, errors, response))
     }
     pub async fn internal_subscribe<R, F, M>(&self, msg: M, trigger: F) -> Result<(), NotifyError>
     where
@@ -274,7 +667,7 @@ where
 {
     if let Some(ref callback) = *callback {
         match callback.notify(msg, data()).await {
-            Ok(_) | Err(NotifyError::IsNotLeader) => {}
+            Ok(_) | Err(NotifyError::IsNotLeader) | Err(NotifyError::CannotFindSubscription) => {}
             Err(e) => warn!(
                 "Cannot send nofication, failed after called due to: {:?}",
                 e
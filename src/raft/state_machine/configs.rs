@@ -1,11 +1,12 @@
-use crate::raft::state_machine::callback::server::Subscriptions;
-use crate::raft::state_machine::callback::SubKey;
+use crate::raft::state_machine::callback::pattern::SubPattern;
+use crate::raft::state_machine::callback::server::{Subscriptions, SubscriptionsSnapshot};
+use crate::raft::state_machine::callback::{Notifications, SubKey};
 use crate::raft::state_machine::StateMachineCtl;
 use crate::raft::AsyncServiceClient;
 use crate::rpc;
-use async_std::sync::*;
 use bifrost_hasher::hash_str;
 use futures::FutureExt;
+use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
@@ -20,7 +21,8 @@ pub struct RaftMember {
 
 pub struct Configures {
     pub members: HashMap<u64, RaftMember>,
-    // keep it in arc lock for reference in callback server.rs
+    // keep it in arc lock for reference in callback server.rs. Never held across awaits, so
+    // snapshots can read it synchronously
     pub subscriptions: Arc<RwLock<Subscriptions>>,
     service_id: u64,
 }
@@ -30,16 +32,21 @@ pub type MemberConfigSnapshot = HashSet<String>;
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfigSnapshot {
     members: MemberConfigSnapshot,
-    //TODO: snapshot for subscriptions
+    #[serde(default)]
+    subscriptions: SubscriptionsSnapshot,
 }
 
 raft_state_machine! {
     def cmd new_member_(address: String) -> bool;
     def cmd del_member_(address: String);
+    def cmd force_members_(addresses: Vec<String>);
     def qry member_address() -> Vec<String>;
 
     def cmd subscribe(key: SubKey, address: String, session_id: u64) -> Result<u64, ()>;
     def cmd unsubscribe(sub_id: u64);
+    def cmd expire_subscribers_(suber_ids: Vec<u64>);
+    def cmd resubscribe(key: SubKey, address: String, session_id: u64, sub_id: Option<u64>, last_seq: Option<u64>) -> Result<(u64, u64, Notifications), ()>;
+    def cmd resubscribe_pattern(key: SubKey, pattern: SubPattern, address: String, session_id: u64, sub_id: Option<u64>, last_seq: Option<u64>) -> Result<(u64, u64, Notifications), ()>;
 }
 
 impl StateMachineCmds for Configures {
@@ -72,6 +79,11 @@ impl StateMachineCmds for Configures {
         self.members.remove(&hash);
         future::ready(()).boxed()
     }
+    fn force_members_(&mut self, addresses: Vec<String>) -> BoxFuture<()> {
+        warn!("Raft members are forced to be {:?}", addresses);
+        self.recover_members(addresses.into_iter().collect())
+            .boxed()
+    }
     fn member_address(&self) -> BoxFuture<Vec<String>> {
         future::ready(self.members.values().map(|m| m.address.clone()).collect()).boxed()
     }
@@ -82,14 +94,52 @@ impl StateMachineCmds for Configures {
         session_id: u64,
     ) -> BoxFuture<Result<u64, ()>> {
         async move {
-            let mut subs = self.subscriptions.write().await;
-            subs.subscribe(key, &address, session_id).await
+            let mut subs = self.subscriptions.write();
+            Ok(subs.subscribe(key, &address, session_id))
+        }
+        .boxed()
+    }
+    fn resubscribe(
+        &mut self,
+        key: SubKey,
+        address: String,
+        session_id: u64,
+        sub_id: Option<u64>,
+        last_seq: Option<u64>,
+    ) -> BoxFuture<Result<(u64, u64, Notifications), ()>> {
+        async move {
+            let mut subs = self.subscriptions.write();
+            Ok(subs.resubscribe(key, &address, session_id, sub_id, last_seq))
+        }
+        .boxed()
+    }
+    fn resubscribe_pattern(
+        &mut self,
+        key: SubKey,
+        pattern: SubPattern,
+        address: String,
+        session_id: u64,
+        sub_id: Option<u64>,
+        last_seq: Option<u64>,
+    ) -> BoxFuture<Result<(u64, u64, Notifications), ()>> {
+        async move {
+            let mut subs = self.subscriptions.write();
+            subs.resubscribe_pattern(key, pattern, &address, session_id, sub_id, last_seq)
+        }
+        .boxed()
+    }
+    fn expire_subscribers_(&mut self, suber_ids: Vec<u64>) -> BoxFuture<()> {
+        async move {
+            let mut subs = self.subscriptions.write();
+            for suber_id in suber_ids {
+                subs.remove_subscriber(suber_id);
+            }
         }
         .boxed()
     }
     fn unsubscribe(&mut self, sub_id: u64) -> BoxFuture<()> {
         async move {
-            let mut subs = self.subscriptions.write().await;
+            let mut subs = self.subscriptions.write();
             subs.remove_subscription(sub_id);
         }
         .boxed()
@@ -102,8 +152,10 @@ impl StateMachineCtl for Configures {
         CONFIG_SM_ID
     }
     fn snapshot(&self) -> Option<Vec<u8>> {
+        let subscriptions = self.subscriptions.read().snapshot();
         let mut snapshot = ConfigSnapshot {
             members: HashSet::with_capacity(self.members.len()),
+            subscriptions,
         };
         for (_, member) in self.members.iter() {
             snapshot.members.insert(member.address.clone());
@@ -112,7 +164,12 @@ impl StateMachineCtl for Configures {
     }
     fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
         let snapshot: ConfigSnapshot = crate::utils::serde::deserialize(&data).unwrap();
-        self.recover_members(snapshot.members).boxed()
+        async move {
+            self.recover_members(snapshot.members).await;
+            let mut subs = self.subscriptions.write();
+            subs.recover(snapshot.subscriptions);
+        }
+        .boxed()
     }
 }
 
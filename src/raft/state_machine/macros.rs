@@ -3,18 +3,38 @@
 
 #[macro_export]
 macro_rules! raft_trait_fn {
-    (qry $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty) => {
-        fn $fn_name<'a>(&'a self, $($arg:$in_),*) -> ::futures::future::BoxFuture<$out>;
+    (qry $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty $(| $err:ty)?) => {
+        fn $fn_name<'a>(&'a self, $($arg:$in_),*) -> ::futures::future::BoxFuture<raft_fn_ret!($out $(| $err)?)>;
     };
-    (cmd $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty) => {
-        fn $fn_name<'a>(&'a mut self, $($arg:$in_),*) -> ::futures::future::BoxFuture<$out>;
+    (cmd $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty $(| $err:ty)?) => {
+        fn $fn_name<'a>(&'a mut self, $($arg:$in_),*) -> ::futures::future::BoxFuture<raft_fn_ret!($out $(| $err)?)>;
+    };
+    (sub $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty $(| $err:ty)?) => {}
+}
+
+// Functions declared as `-> T | E` can fail with E, ExecError should implement From<E>
+#[macro_export]
+macro_rules! raft_fn_ret {
+    ($out:ty) => { $out };
+    ($out:ty | $err:ty) => { Result<$out, $err> };
+}
+
+#[macro_export]
+macro_rules! raft_fn_output {
+    ($res:ident) => {
+        Ok($crate::utils::serde::serialize(&$res))
+    };
+    ($res:ident | $err:ty) => {
+        match $res {
+            Ok(res) => Ok($crate::utils::serde::serialize(&res)),
+            Err(e) => Err(From::from(e)),
+        }
     };
-    (sub $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty) => {}
 }
 
 #[macro_export]
 macro_rules! raft_client_fn {
-    (sub $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty) => {
+    (sub $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty $(| $err:ty)?) => {
         pub fn $fn_name<F>(&self, f: F, $($arg:$in_),* )
             -> BoxFuture<Result<Result<$crate::raft::client::SubscriptionReceipt, $crate::raft::client::SubscriptionError>, $crate::raft::state_machine::master::ExecError>>
         where F: Fn($out) -> BoxFuture<'static, ()> + 'static + Send + Sync
@@ -26,7 +46,7 @@ macro_rules! raft_client_fn {
             ).boxed()
         }
     };
-    ($others:ident $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty) => {
+    ($others:ident $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty $(| $err:ty)?) => {
         pub async fn $fn_name(&self, $($arg:$in_),*) -> Result<$out, $crate::raft::state_machine::master::ExecError> {
             self.client.execute(
                 self.sm_id,
@@ -36,6 +56,39 @@ macro_rules! raft_client_fn {
     };
 }
 
+#[macro_export]
+macro_rules! raft_stream_fn {
+    (sub $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty $(| $err:ty)?) => {
+        pub async fn $fn_name(&self, $($arg:$in_),*)
+            -> Result<Result<$crate::raft::client::SubscriptionStream<$out>, $crate::raft::client::SubscriptionError>, $crate::raft::state_machine::master::ExecError>
+        {
+            RaftClient::subscribe_stream(
+                &self.sm.client,
+                self.sm.sm_id,
+                $fn_name::new($($arg,)*),
+                self.capacity
+            ).await
+        }
+    };
+    ($others:ident $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty $(| $err:ty)?) => {};
+}
+
+#[macro_export]
+macro_rules! raft_pattern_fn {
+    (sub $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty $(| $err:ty)?) => {
+        // Subscribe by filters on each argument instead of exact arguments
+        pub fn pattern($($arg: &$crate::raft::state_machine::callback::pattern::ArgFilter<$in_>),*)
+            -> $crate::raft::state_machine::callback::pattern::PatternMsg<$out>
+        {
+            $crate::raft::state_machine::callback::pattern::PatternMsg::new(
+                ::bifrost_plugins::hash_ident!($fn_name) as u64,
+                vec![$($arg.matcher()),*]
+            )
+        }
+    };
+    ($others:ident $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty $(| $err:ty)?) => {};
+}
+
 #[macro_export]
 macro_rules! raft_fn_op_type {
     (qry) => {
@@ -51,28 +104,38 @@ macro_rules! raft_fn_op_type {
 
 #[macro_export]
 macro_rules! raft_dispatch_fn {
-    ($fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {{
-        let decoded: ($($in_,)*) = $crate::utils::serde::deserialize($d).unwrap();
-        let ($($arg,)*) = decoded;
-        let f_result = $s.$fn_name($($arg),*).await;
-        Some($crate::utils::serde::serialize(&f_result))
+    ($fn_name:ident $s: ident $d: ident $fn_id: ident ( $( $arg:ident : $in_:ty ),* ) $(| $err:ty)?) => {{
+        match $crate::utils::serde::deserialize::<($($in_,)*)>($d) {
+            Some(($($arg,)*)) => {
+                let f_result = $s.$fn_name($($arg),*).await;
+                raft_fn_output!(f_result $(| $err)?)
+            }
+            None => Err($crate::raft::state_machine::master::ExecError::CannotDecode {
+                sm_id: $s.id(),
+                fn_id: $fn_id,
+            }),
+        }
     }};
 }
 
 #[macro_export]
 macro_rules! raft_dispatch_cmd {
-    (cmd $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {
-        raft_dispatch_fn!($fn_name $s $d( $( $arg : $in_ ),* ))
+    (cmd $fn_name:ident $s: ident $d: ident $fn_id: ident ( $( $arg:ident : $in_:ty ),* ) $(| $err:ty)?) => {
+        raft_dispatch_fn!($fn_name $s $d $fn_id ( $( $arg : $in_ ),* ) $(| $err)?)
+    };
+    ($others:ident $fn_name:ident $s: ident $d: ident $fn_id: ident ( $( $arg:ident : $in_:ty ),* ) $(| $err:ty)?) => {
+        Err($crate::raft::state_machine::master::ExecError::FnNotFound)
     };
-    ($others:ident $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
 }
 
 #[macro_export]
 macro_rules! raft_dispatch_qry {
-    (qry $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {
-        raft_dispatch_fn!($fn_name $s $d( $( $arg : $in_ ),* ))
+    (qry $fn_name:ident $s: ident $d: ident $fn_id: ident ( $( $arg:ident : $in_:ty ),* ) $(| $err:ty)?) => {
+        raft_dispatch_fn!($fn_name $s $d $fn_id ( $( $arg : $in_ ),* ) $(| $err)?)
+    };
+    ($others:ident $fn_name:ident $s: ident $d: ident $fn_id: ident ( $( $arg:ident : $in_:ty ),* ) $(| $err:ty)?) => {
+        Err($crate::raft::state_machine::master::ExecError::FnNotFound)
     };
-    ($others:ident $fn_name:ident $s: ident $d: ident ( $( $arg:ident : $in_:ty ),* )) => {None};
 }
 
 #[macro_export]
@@ -82,14 +145,14 @@ macro_rules! raft_sm_complete {
             &'a mut self,
             fn_id: u64,
             data: &'a Vec<u8>,
-        ) -> ::futures::future::BoxFuture<'a, Option<Vec<u8>>> {
+        ) -> ::futures::future::BoxFuture<'a, $crate::raft::state_machine::master::ExecResult> {
             self.dispatch_cmd_(fn_id, data)
         }
         fn fn_dispatch_qry<'a>(
             &'a self,
             fn_id: u64,
             data: &'a Vec<u8>,
-        ) -> ::futures::future::BoxFuture<'a, Option<Vec<u8>>> {
+        ) -> ::futures::future::BoxFuture<'a, $crate::raft::state_machine::master::ExecResult> {
             self.dispatch_qry_(fn_id, data)
         }
         fn op_type(&mut self, fn_id: u64) -> Option<$crate::raft::state_machine::OpType> {
@@ -103,13 +166,13 @@ macro_rules! raft_state_machine {
     (
         $(
             $(#[$attr:meta])*
-            def $smt:ident $fn_name:ident( $( $arg:ident : $in_:ty ),* ) $(-> $out:ty)* ;
+            def $smt:ident $fn_name:ident( $( $arg:ident : $in_:ty ),* ) $(-> $out:ty $(| $err:ty)?)? ;
         )*
     ) => {
         raft_state_machine! {{
             $(
                 $(#[$attr])*
-                def $smt $fn_name( $( $arg : $in_ ),* ) $(-> $out)*;
+                def $smt $fn_name( $( $arg : $in_ ),* ) $(-> $out $(| $err)?)?;
             )*
         }}
     };
@@ -134,7 +197,7 @@ macro_rules! raft_state_machine {
     (
         {
             $(#[$attr:meta])*
-            def $smt:ident $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
+            def $smt:ident $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty $(| $err:ty)?;
 
             $( $unexpanded:tt )*
         }
@@ -146,14 +209,14 @@ macro_rules! raft_state_machine {
             $( $expanded )*
 
             $(#[$attr])*
-            def $smt $fn_name( $( $arg : $in_ ),* ) -> $out;
+            def $smt $fn_name( $( $arg : $in_ ),* ) -> $out $(| $err)?;
         }
     };
     (
         {} // all expanded
         $(
             $(#[$attr:meta])*
-            def $smt:ident $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
+            def $smt:ident $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty $(| $err:ty)?;
         )*
     ) => {
         #[allow(unused_imports)]
@@ -180,8 +243,14 @@ macro_rules! raft_state_machine {
                             self.data
                         )
                     }
-                    fn decode_return(data: &Vec<u8>) -> $out {
-                        $crate::utils::serde::deserialize(data).unwrap()
+                    fn decode_return(data: &Vec<u8>) -> Option<$out> {
+                        $crate::utils::serde::deserialize(data)
+                    }
+                    fn args(data: &Vec<u8>) -> Vec<Vec<u8>> {
+                        match $crate::utils::serde::deserialize::<($($in_,)*)>(data) {
+                            Some(($($arg,)*)) => vec![$($crate::utils::serde::serialize(&$arg)),*],
+                            None => vec![]
+                        }
                     }
                 }
                 impl $fn_name {
@@ -191,6 +260,7 @@ macro_rules! raft_state_machine {
                             data: $crate::utils::serde::serialize(&req_data)
                         }
                     }
+                    raft_pattern_fn!($smt $fn_name( $( $arg : $in_ ),* ) -> $out $(| $err)?);
                 }
             )*
         }
@@ -200,7 +270,7 @@ macro_rules! raft_state_machine {
         pub trait StateMachineCmds: $crate::raft::state_machine::StateMachineCtl {
            $(
                 $(#[$attr])*
-                raft_trait_fn!($smt $fn_name( $( $arg : $in_ ),* ) -> $out);
+                raft_trait_fn!($smt $fn_name( $( $arg : $in_ ),* ) -> $out $(| $err)?);
            )*
            fn op_type_(&self, fn_id: u64) -> Option<$crate::raft::state_machine::OpType> {
                 match fn_id as usize {
@@ -213,28 +283,28 @@ macro_rules! raft_state_machine {
                    }
                 }
            }
-           fn dispatch_cmd_<'a>(&'a mut self, fn_id: u64, data: &'a Vec<u8>) -> BoxFuture<Option<Vec<u8>>> {
+           fn dispatch_cmd_<'a>(&'a mut self, fn_id: u64, data: &'a Vec<u8>) -> BoxFuture<$crate::raft::state_machine::master::ExecResult> {
                async move {
                     match fn_id as usize {
                         $(::bifrost_plugins::hash_ident!($fn_name) => {
-                            raft_dispatch_cmd!($smt $fn_name self data( $( $arg : $in_ ),* ))
+                            raft_dispatch_cmd!($smt $fn_name self data fn_id ( $( $arg : $in_ ),* ) $(| $err)?)
                         }),*
                         _ => {
                             debug!("Undefined function id: {}. We have {}", fn_id, concat!(stringify!($($fn_name),*)));
-                            None
+                            Err($crate::raft::state_machine::master::ExecError::FnNotFound)
                         }
                     }
                }.boxed()
            }
-           fn dispatch_qry_<'a>(&'a self, fn_id: u64, data: &'a Vec<u8>) -> BoxFuture<Option<Vec<u8>>> {
+           fn dispatch_qry_<'a>(&'a self, fn_id: u64, data: &'a Vec<u8>) -> BoxFuture<$crate::raft::state_machine::master::ExecResult> {
                async move {
                     match fn_id as usize {
                         $(::bifrost_plugins::hash_ident!($fn_name) => {
-                            raft_dispatch_qry!($smt $fn_name self data( $( $arg : $in_ ),* ))
+                            raft_dispatch_qry!($smt $fn_name self data fn_id ( $( $arg : $in_ ),* ) $(| $err)?)
                         }),*
                         _ => {
                             debug!("Undefined function id: {}", fn_id);
-                            None
+                            Err($crate::raft::state_machine::master::ExecError::FnNotFound)
                         }
                     }
                }.boxed()
@@ -251,6 +321,7 @@ macro_rules! raft_state_machine {
             use $crate::raft::state_machine::master::ExecError;
             use $crate::raft::state_machine::StateMachineClient;
             use $crate::raft::client::{RaftClient, SubscriptionError, SubscriptionReceipt};
+            use $crate::raft::state_machine::callback::pattern::PatternMsg;
 
             pub struct SMClient {
                 client: Arc<RaftClient>,
@@ -259,7 +330,7 @@ macro_rules! raft_state_machine {
             impl SMClient {
                $(
                   $(#[$attr])*
-                  raft_client_fn!($smt $fn_name( $( $arg : &$in_ ),* ) -> $out);
+                  raft_client_fn!($smt $fn_name( $( $arg : &$in_ ),* ) -> $out $(| $err)?);
                )*
                pub fn new(sm_id: u64, client: &Arc<RaftClient>) -> Self {
                     Self {
@@ -267,6 +338,38 @@ macro_rules! raft_state_machine {
                         sm_id: sm_id
                     }
                }
+               pub fn subscribe_pattern<R, F>(&self, pattern: PatternMsg<R>, f: F)
+                    -> BoxFuture<Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>>
+               where
+                    R: ::serde::de::DeserializeOwned + Send + 'static,
+                    F: Fn(R) -> BoxFuture<'static, ()> + 'static + Send + Sync
+               {
+                    self.client.subscribe(self.sm_id, pattern, f).boxed()
+               }
+               // Subscriptions as streams buffering at most `capacity` notifications
+               pub fn streams(&self, capacity: usize) -> SMStreams {
+                    SMStreams {
+                        sm: self,
+                        capacity
+                    }
+               }
+            }
+            pub struct SMStreams<'a> {
+                sm: &'a SMClient,
+                capacity: usize
+            }
+            impl <'a> SMStreams<'a> {
+               pub async fn subscribe_pattern<R>(&self, pattern: PatternMsg<R>)
+                    -> Result<Result<SubscriptionStream<R>, SubscriptionError>, ExecError>
+               where
+                    R: ::serde::de::DeserializeOwned + Send + 'static
+               {
+                    RaftClient::subscribe_stream(&self.sm.client, self.sm.sm_id, pattern, self.capacity).await
+               }
+               $(
+                  $(#[$attr])*
+                  raft_stream_fn!($smt $fn_name( $( $arg : &$in_ ),* ) -> $out $(| $err)?);
+               )*
             }
             impl StateMachineClient for SMClient {
                fn new_instance (sm_id: u64, client: &Arc<RaftClient>) -> Self {
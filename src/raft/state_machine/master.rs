@@ -6,6 +6,7 @@ use std::error::Error;
 use std::fmt;
 use std::fmt::Display;
 use std::fmt::Formatter;
+use std::panic::AssertUnwindSafe;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ExecError {
@@ -16,6 +17,35 @@ pub enum ExecError {
     NotCommitted,
     Unknown,
     TooManyRetry,
+    CannotMigrate(u32, u32), // from version, to version
+    CannotDecode {
+        sm_id: u64,
+        fn_id: u64,
+    },
+    Panicked {
+        sm_id: u64,
+        fn_id: u64,
+        message: String,
+    },
+    // Raised by state machine functions declared as `-> T | E`, with the function filled in
+    // when applied
+    StateMachineError {
+        sm_id: u64,
+        fn_id: u64,
+        code: u32,
+        message: String,
+    },
+}
+
+impl ExecError {
+    pub fn state_machine(code: u32, message: String) -> Self {
+        ExecError::StateMachineError {
+            sm_id: 0,
+            fn_id: 0,
+            code,
+            message,
+        }
+    }
 }
 
 pub enum RegisterResult {
@@ -27,50 +57,213 @@ pub enum RegisterResult {
 pub type ExecOk = Vec<u8>;
 pub type ExecResult = Result<ExecOk, ExecError>;
 pub type SubStateMachine = Box<dyn StateMachineCtl>;
-pub type SnapshotDataItem = (u64, Vec<u8>);
 pub type SnapshotDataItems = Vec<SnapshotDataItem>;
 
-raft_state_machine! {}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotDataItem {
+    pub sm_id: u64,
+    pub version: u32, // schema version of the state machine when the snapshot was taken
+    pub data: Vec<u8>,
+}
+
+// Command to a sub state machine, as a part of a master command
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SubCommand {
+    pub sm_id: u64,
+    pub fn_id: u64,
+    pub data: Vec<u8>,
+}
+
+impl SubCommand {
+    pub fn new<R, M: RaftMsg<R>>(sm_id: u64, msg: M) -> Self {
+        let (fn_id, _, data) = msg.encode();
+        Self { sm_id, fn_id, data }
+    }
+    // Commands to the master and config state machines are not allowed as sub commands
+    pub fn is_internal(&self) -> bool {
+        self.sm_id == MASTER_SM_ID || self.sm_id == CONFIG_SM_ID
+    }
+}
+
+// Master snapshots taken before schema versions carry items as (sm_id, data) pairs,
+// recovered as version 0 of the state machines
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MasterSnapshot {
+    Current(SnapshotDataItems),
+    Legacy(Vec<(u64, Vec<u8>)>),
+}
+
+pub const MASTER_SM_ID: u64 = 0;
+
+raft_state_machine! {
+    def cmd restore_(items: SnapshotDataItems);
+    def cmd cascade_(guard: SubCommand, then: Vec<SubCommand>) -> bool;
+}
 
 pub struct MasterStateMachine {
     subs: HashMap<u64, SubStateMachine>,
-    snapshots: HashMap<u64, Vec<u8>>,
+    snapshots: HashMap<u64, SnapshotDataItem>,
     pub configs: Configures,
 }
 
-impl StateMachineCmds for MasterStateMachine {}
+impl StateMachineCmds for MasterStateMachine {
+    fn restore_(&mut self, items: SnapshotDataItems) -> BoxFuture<()> {
+        async move {
+            for item in items {
+                if item.sm_id == CONFIG_SM_ID {
+                    // cluster configuration belongs to the cluster, not the data
+                    continue;
+                }
+                self.recover_item(item).await;
+            }
+        }
+        .boxed()
+    }
+    // Commands to other state machines in one log entry, applied only when the guard command
+    // returns true. Failures of those commands are logged and do not stop the others
+    fn cascade_(&mut self, guard: SubCommand, then: Vec<SubCommand>) -> BoxFuture<bool> {
+        async move {
+            let passed = match self.commit_sub(&guard).await {
+                Ok(data) => crate::utils::serde::deserialize(&data).unwrap_or(false),
+                Err(e) => {
+                    debug!("Guard of cascade failed, {:?}", e);
+                    false
+                }
+            };
+            if !passed {
+                return false;
+            }
+            for cmd in &then {
+                if let Err(e) = self.commit_sub(cmd).await {
+                    warn!(
+                        "Cascaded command {} to state machine {} failed, {:?}",
+                        cmd.fn_id, cmd.sm_id, e
+                    );
+                }
+            }
+            true
+        }
+        .boxed()
+    }
+}
 
 impl StateMachineCtl for MasterStateMachine {
     raft_sm_complete!();
     fn id(&self) -> u64 {
-        0
+        MASTER_SM_ID
     }
     fn snapshot(&self) -> Option<Vec<u8>> {
-        let mut sms: SnapshotDataItems = Vec::with_capacity(self.subs.len());
-        for (sm_id, smc) in self.subs.iter() {
-            let sub_snapshot = smc.snapshot();
-            if let Some(snapshot) = sub_snapshot {
-                sms.push((*sm_id, snapshot));
-            }
-        }
-        sms.push((self.configs.id(), self.configs.snapshot().unwrap()));
+        let mut sms = self.data_snapshot();
+        sms.push(SnapshotDataItem {
+            sm_id: self.configs.id(),
+            version: self.configs.schema_version(),
+            data: self.configs.snapshot().unwrap(),
+        });
         let data = crate::utils::serde::serialize(&sms);
         Some(data)
     }
     fn recover(&mut self, data: Vec<u8>) -> BoxFuture<()> {
-        let sms: SnapshotDataItems = crate::utils::serde::deserialize(data.as_slice()).unwrap();
-        for (sm_id, snapshot) in sms {
-            self.snapshots.insert(sm_id, snapshot);
+        async move {
+            let sms = match crate::utils::serde::deserialize::<MasterSnapshot>(data.as_slice()) {
+                Some(MasterSnapshot::Current(sms)) => sms,
+                Some(MasterSnapshot::Legacy(items)) => {
+                    info!("Recovering master snapshot taken before schema versions");
+                    items
+                        .into_iter()
+                        .map(|(sm_id, data)| SnapshotDataItem {
+                            sm_id,
+                            version: 0,
+                            data,
+                        })
+                        .collect()
+                }
+                None => {
+                    error!("Cannot decode master snapshot, recover skipped");
+                    return;
+                }
+            };
+            for item in sms {
+                self.recover_item(item).await;
+            }
         }
-        future::ready(()).boxed()
+        .boxed()
     }
 }
 
-fn parse_output(r: Option<Vec<u8>>) -> ExecResult {
-    if let Some(d) = r {
-        Ok(d)
+// Upgrade snapshot to the current schema version of the state machine before recover
+async fn recover_sm(sm: &mut dyn StateMachineCtl, item: SnapshotDataItem) {
+    let version = sm.schema_version();
+    let data = if item.version == version {
+        Some(item.data)
     } else {
-        Err(ExecError::FnNotFound)
+        info!(
+            "Migrating snapshot of state machine {} from version {} to {}",
+            item.sm_id, item.version, version
+        );
+        sm.migrate_snapshot(item.version, item.data)
+    };
+    match data {
+        Some(data) => sm.recover(data).await,
+        None => error!(
+            "Cannot migrate snapshot of state machine {} from version {} to {}",
+            item.sm_id, item.version, version
+        ),
+    }
+}
+
+// Upgrade command data to the current schema version of the state machine,
+// None if the entry is already at that version
+fn migrate_cmd(sm: &dyn StateMachineCtl, entry: &LogEntry) -> Result<Option<Vec<u8>>, ExecError> {
+    let version = sm.schema_version();
+    if entry.sm_version == version {
+        return Ok(None);
+    }
+    debug!(
+        "Migrating command {} of state machine {} from version {} to {}",
+        entry.fn_id, entry.sm_id, entry.sm_version, version
+    );
+    sm.migrate_command(entry.sm_version, entry.fn_id, entry.data.clone())
+        .map(Some)
+        .ok_or(ExecError::CannotMigrate(entry.sm_version, version))
+}
+
+// Panics in state machine functions fail the call only, the apply loop keeps going.
+// Errors raised by the functions are tagged with the state machine and function
+async fn guarded<F>(entry: &LogEntry, f: F) -> ExecResult
+where
+    F: Future<Output = ExecResult>,
+{
+    let (sm_id, fn_id) = (entry.sm_id, entry.fn_id);
+    let applied = APPLIED_TIME.scope(entry.time, AssertUnwindSafe(f).catch_unwind());
+    match applied.await {
+        Ok(Err(ExecError::StateMachineError { code, message, .. })) => {
+            Err(ExecError::StateMachineError {
+                sm_id,
+                fn_id,
+                code,
+                message,
+            })
+        }
+        Ok(res) => res,
+        Err(payload) => {
+            let message = if let Some(msg) = payload.downcast_ref::<&str>() {
+                msg.to_string()
+            } else if let Some(msg) = payload.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                String::from("unknown panic")
+            };
+            error!(
+                "Function {} of state machine {} panicked, {}",
+                fn_id, sm_id, message
+            );
+            Err(ExecError::Panicked {
+                sm_id,
+                fn_id,
+                message,
+            })
+        }
     }
 }
 
@@ -84,7 +277,7 @@ impl MasterStateMachine {
         msm
     }
 
-    pub fn register(&mut self, mut smc: SubStateMachine) -> RegisterResult {
+    pub async fn register(&mut self, mut smc: SubStateMachine) -> RegisterResult {
         let id = smc.id();
         if id < 2 {
             return RegisterResult::RESERVED;
@@ -93,24 +286,61 @@ impl MasterStateMachine {
             return RegisterResult::EXISTED;
         };
         if let Some(snapshot) = self.snapshots.remove(&id) {
-            smc.recover(snapshot);
+            recover_sm(smc.as_mut(), snapshot).await;
         }
         self.subs.insert(id, smc);
         RegisterResult::OK
     }
 
+    // Snapshot of all sub state machines, without cluster configuration
+    pub fn data_snapshot(&self) -> SnapshotDataItems {
+        self.subs
+            .iter()
+            .filter_map(|(sm_id, smc)| {
+                smc.snapshot().map(|data| SnapshotDataItem {
+                    sm_id: *sm_id,
+                    version: smc.schema_version(),
+                    data,
+                })
+            })
+            .collect()
+    }
+
+    async fn recover_item(&mut self, item: SnapshotDataItem) {
+        if item.sm_id == CONFIG_SM_ID {
+            recover_sm(&mut self.configs, item).await;
+        } else if let Some(sm) = self.subs.get_mut(&item.sm_id) {
+            recover_sm(sm.as_mut(), item).await;
+        } else {
+            debug!(
+                "State machine {} not registered, recover when it does",
+                item.sm_id
+            );
+            self.snapshots.insert(item.sm_id, item);
+        }
+    }
+
     pub fn members(&self) -> &HashMap<u64, RaftMember> {
         &self.configs.members
     }
 
     pub async fn commit_cmd(&mut self, entry: &LogEntry) -> ExecResult {
         match entry.sm_id {
+            MASTER_SM_ID => {
+                let data = migrate_cmd(self, entry)?;
+                let data = data.as_ref().unwrap_or(&entry.data);
+                guarded(entry, self.fn_dispatch_cmd(entry.fn_id, data)).await
+            }
             CONFIG_SM_ID => {
-                parse_output(self.configs.fn_dispatch_cmd(entry.fn_id, &entry.data).await)
+                let data = migrate_cmd(&self.configs, entry)?;
+                let data = data.as_ref().unwrap_or(&entry.data);
+                guarded(entry, self.configs.fn_dispatch_cmd(entry.fn_id, data)).await
             }
             _ => {
                 if let Some(sm) = self.subs.get_mut(&entry.sm_id) {
-                    parse_output(sm.as_mut().fn_dispatch_cmd(entry.fn_id, &entry.data).await)
+                    let data = migrate_cmd(sm.as_ref(), entry)?;
+                    let data = data.as_ref().unwrap_or(&entry.data);
+                    guarded(entry, sm.as_mut().fn_dispatch_cmd(entry.fn_id, data)).await
                 } else {
                     debug!(
                         "Cannot find state machine {} for command, we have {:?}",
@@ -122,14 +352,37 @@ impl MasterStateMachine {
             }
         }
     }
+    fn commit_sub<'a>(&'a mut self, cmd: &SubCommand) -> BoxFuture<'a, ExecResult> {
+        let internal = cmd.is_internal();
+        let entry = LogEntry {
+            id: 0,
+            term: 0,
+            sm_id: cmd.sm_id,
+            fn_id: cmd.fn_id,
+            data: cmd.data.clone(),
+            sm_version: self.schema_version_of(cmd.sm_id),
+            time: applied_time(),
+        };
+        async move {
+            if internal {
+                return Err(ExecError::FnNotFound);
+            }
+            self.commit_cmd(&entry).await
+        }
+        .boxed()
+    }
     pub async fn exec_qry(&self, entry: &LogEntry) -> ExecResult {
         match entry.sm_id {
             CONFIG_SM_ID => {
-                parse_output(self.configs.fn_dispatch_qry(entry.fn_id, &entry.data).await)
+                guarded(
+                    entry,
+                    self.configs.fn_dispatch_qry(entry.fn_id, &entry.data),
+                )
+                .await
             }
             _ => {
                 if let Some(sm) = self.subs.get(&entry.sm_id) {
-                    parse_output(sm.fn_dispatch_qry(entry.fn_id, &entry.data).await)
+                    guarded(entry, sm.fn_dispatch_qry(entry.fn_id, &entry.data)).await
                 } else {
                     debug!(
                         "Cannot find state machine {} for query, we have {:?}",
@@ -147,6 +400,17 @@ impl MasterStateMachine {
     pub fn has_sub(&self, id: &u64) -> bool {
         self.subs.contains_key(&id)
     }
+    pub fn schema_version_of(&self, id: u64) -> u32 {
+        match id {
+            MASTER_SM_ID => self.schema_version(),
+            CONFIG_SM_ID => self.configs.schema_version(),
+            _ => self
+                .subs
+                .get(&id)
+                .map(|sm| sm.schema_version())
+                .unwrap_or(0),
+        }
+    }
 }
 
 impl Error for ExecError {}
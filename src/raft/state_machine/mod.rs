@@ -7,6 +7,16 @@ pub enum Storage {
     DISK(String),
 }
 
+tokio::task_local! {
+    static APPLIED_TIME: i64;
+}
+
+// Leader clock when the command being applied was appended, the same on every replica so state
+// machines can depend on time. 0 in queries and for entries logged before the time was recorded
+pub fn applied_time() -> i64 {
+    APPLIED_TIME.try_with(|time| *time).unwrap_or(0)
+}
+
 #[derive(Debug)]
 pub enum OpType {
     COMMAND,
@@ -22,13 +32,26 @@ pub trait StateMachineCtl: Sync + Send + Any {
         &'a self,
         fn_id: u64,
         data: &'a Vec<u8>,
-    ) -> ::futures::future::BoxFuture<'a, Option<Vec<u8>>>;
+    ) -> ::futures::future::BoxFuture<'a, self::master::ExecResult>;
     fn fn_dispatch_cmd<'a>(
         &'a mut self,
         fn_id: u64,
         data: &'a Vec<u8>,
-    ) -> ::futures::future::BoxFuture<'a, Option<Vec<u8>>>;
+    ) -> ::futures::future::BoxFuture<'a, self::master::ExecResult>;
     fn op_type(&mut self, fn_id: u64) -> Option<OpType>;
+    // Version of the layout of snapshot and command data.
+    // Bump it when the layout changed and implement the migration functions below
+    fn schema_version(&self) -> u32 {
+        0
+    }
+    // Upgrade snapshot taken at an older schema version, None if it cannot be migrated
+    fn migrate_snapshot(&self, _from_version: u32, data: Vec<u8>) -> Option<Vec<u8>> {
+        Some(data)
+    }
+    // Upgrade command data from log entries appended at an older schema version
+    fn migrate_command(&self, _from_version: u32, _fn_id: u64, data: Vec<u8>) -> Option<Vec<u8>> {
+        Some(data)
+    }
 }
 
 pub trait OpTypes {
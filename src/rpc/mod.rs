@@ -160,6 +160,32 @@ impl Server {
     }
 }
 
+// Services on this process for servers to push requests to, without listening on any port.
+// Requests pushed have no response.
+pub fn register_push_service<T>(service_id: u64, service: &Arc<T>)
+where
+    T: RPCService + Sized + 'static,
+{
+    let service = service.clone();
+    tcp::push::on_push(service_id, move |data| {
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = service.dispatch(data).await {
+                warn!("Cannot dispatch pushed request, {:?}", e);
+            }
+        });
+    });
+}
+
+pub fn push_client_id() -> u64 {
+    *tcp::push::PUSH_CLIENT_ID
+}
+
+// Push a request to the service on a client, through the connection the client made to us
+pub fn push(client_id: u64, service_id: u64, func_id: u64, data: BytesMut) -> io::Result<()> {
+    tcp::push::push(client_id, prepend_u64(service_id, prepend_u64(func_id, data)))
+}
+
 pub struct RPCClient {
     client: tcp::client::Client,
     pub server_id: u64,
@@ -184,6 +210,10 @@ impl RPCClient {
         let res = client.send_msg(payload).await;
         decode_res(res)
     }
+    // Receive pushes from the server through this connection
+    pub async fn handshake_push(&self) -> io::Result<()> {
+        self.client.handshake_push().await
+    }
     pub async fn new_async(addr: &String) -> io::Result<Arc<RPCClient>> {
         let client = tcp::client::Client::connect(addr).await?;
         Ok(Arc::new(RPCClient {
@@ -1,6 +1,7 @@
 use std::sync::Arc;
 use std::time::Duration;
 
+use crate::tcp::push::{self, HANDSHAKE_MSG_ID, PUSH_MSG_ID, PUSH_SECRET};
 use crate::tcp::{shortcut, STANDALONE_ADDRESS};
 use crate::DISABLE_SHORTCUT;
 use bifrost_hasher::hash_str;
@@ -13,8 +14,8 @@ use futures::stream::SplitSink;
 use futures::SinkExt;
 use parking_lot::Mutex as SyncMutex;
 use std::collections::HashMap;
-use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use tokio::io;
 use tokio::net::TcpStream;
 use tokio::sync::oneshot;
@@ -27,6 +28,7 @@ pub struct Client {
     msg_counter: AtomicU64,
     senders: Arc<SyncMutex<HashMap<u64, oneshot::Sender<BytesMut>>>>,
     timeout: Duration,
+    push_ready: AtomicBool,
     pub server_id: u64,
 }
 
@@ -62,10 +64,20 @@ impl Client {
                     while let Some(res) = reader.next().await {
                         if let Ok(mut data) = res {
                             let res_msg_id = data.get_u64_le();
+                            if res_msg_id == PUSH_MSG_ID {
+                                trace!("Received pushed msg, size {}", data.len());
+                                push::handle(data);
+                                continue;
+                            }
                             trace!("Received msg for {}, size {}", res_msg_id, data.len());
-                            let sender: oneshot::Sender<BytesMut> =
-                                cloned_senders.lock().remove(&res_msg_id).unwrap();
-                            sender.send(data).unwrap();
+                            let sender: Option<oneshot::Sender<BytesMut>> =
+                                cloned_senders.lock().remove(&res_msg_id);
+                            match sender {
+                                Some(sender) => {
+                                    let _ = sender.send(data);
+                                }
+                                None => warn!("Received unknown msg {}", res_msg_id),
+                            }
                         }
                     }
                     debug!("Stream from TCP server {} broken", address);
@@ -78,9 +90,32 @@ impl Client {
             server_id,
             senders,
             timeout,
+            push_ready: AtomicBool::new(false),
             msg_counter: AtomicU64::new(0),
         })
     }
+    // Introduce this process for the server to push messages through this connection, only
+    // for subscribers. Local servers push without connections
+    pub async fn handshake_push(&self) -> io::Result<()> {
+        if let Some(ref transport) = self.client {
+            if self.push_ready.swap(true, Relaxed) {
+                return Ok(());
+            }
+            let mut handshake = BytesMut::with_capacity(16);
+            handshake.put_u64_le(HANDSHAKE_MSG_ID);
+            handshake.put_u64_le(*PUSH_SECRET);
+            let sent = time::timeout(
+                self.timeout,
+                transport.lock().await.send(handshake.freeze()),
+            )
+            .await;
+            if !matches!(sent, Ok(Ok(()))) {
+                self.push_ready.store(false, Relaxed);
+            }
+            sent??;
+        }
+        Ok(())
+    }
     pub async fn connect(address: &String) -> io::Result<Self> {
         Client::connect_with_timeout(address, Duration::from_secs(2)).await
     }
@@ -1,6 +1,7 @@
 use bifrost_hasher::hash_str;
 
 pub mod client;
+pub mod push;
 pub mod server;
 pub mod shortcut;
 
@@ -0,0 +1,176 @@
+// Server to client push over connections made by the client, for clients cannot be dialed back.
+// Subscribers introduce themselves with a handshake frame carrying the process wide push secret
+// on connections to the cluster, servers keep the connection under the push id derived from the
+// secret and write push frames to it. Push ids are public in subscriber addresses, the secret
+// is not, so clients cannot claim pushes of others.
+// Push payload starts with a u64 channel id to find the handler on the client.
+
+use bifrost_hasher::hash_bytes;
+use bytes::{Buf, BytesMut};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+pub const PUSH_MSG_ID: u64 = std::u64::MAX;
+pub const HANDSHAKE_MSG_ID: u64 = std::u64::MAX - 1;
+// Pushes waiting to be written to each connection, more are rejected for slow clients
+pub const PUSH_BUFFER_SIZE: usize = 1024;
+
+pub type PushSender = mpsc::Sender<BytesMut>;
+type PushHandler = Arc<dyn Fn(BytesMut) + Send + Sync>;
+
+lazy_static! {
+    pub static ref PUSH_SECRET: u64 = rand::random();
+    pub static ref PUSH_CLIENT_ID: u64 = push_id_of(*PUSH_SECRET);
+    static ref CONNECTIONS: RwLock<HashMap<u64, PushSender>> = RwLock::new(HashMap::new());
+    static ref HANDLERS: RwLock<HashMap<u64, PushHandler>> = RwLock::new(HashMap::new());
+}
+
+pub fn push_id_of(secret: u64) -> u64 {
+    hash_bytes(&secret.to_le_bytes())
+}
+
+pub fn on_push<F>(channel: u64, handler: F)
+where
+    F: Fn(BytesMut) + Send + Sync + 'static,
+{
+    HANDLERS.write().insert(channel, Arc::new(handler));
+}
+
+// Received push frame, or loopback in the same process
+pub fn handle(mut data: BytesMut) {
+    if data.len() < 8 {
+        warn!("Push message too short, size {}", data.len());
+        return;
+    }
+    let channel = data.get_u64_le();
+    let handler = HANDLERS.read().get(&channel).cloned();
+    match handler {
+        Some(handler) => handler(data),
+        None => warn!("No handler for pushed message on channel {}", channel),
+    }
+}
+
+pub fn register_connection(client_id: u64, sender: &PushSender) {
+    debug!("Client {} connected for push", client_id);
+    CONNECTIONS.write().insert(client_id, sender.clone());
+}
+
+pub fn remove_connection(client_id: u64, sender: &PushSender) {
+    let mut conns = CONNECTIONS.write();
+    if conns
+        .get(&client_id)
+        .map(|conn| conn.same_channel(sender))
+        .unwrap_or(false)
+    {
+        conns.remove(&client_id);
+    }
+}
+
+pub fn push(client_id: u64, data: BytesMut) -> io::Result<()> {
+    if client_id == *PUSH_CLIENT_ID {
+        handle(data);
+        return Ok(());
+    }
+    let conn = CONNECTIONS.read().get(&client_id).cloned();
+    match conn {
+        Some(conn) => conn.try_send(data).map_err(|e| match e {
+            TrySendError::Full(_) => io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("Push buffer of client {} is full", client_id),
+            ),
+            TrySendError::Closed(_) => {
+                io::Error::new(io::ErrorKind::BrokenPipe, "Push connection closed")
+            }
+        }),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            format!("Client {} have no connection for push", client_id),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tcp::client::Client;
+    use crate::tcp::server::Server;
+    use bytes::BufMut;
+    use futures::{FutureExt, SinkExt, StreamExt};
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::oneshot;
+    use tokio::time::{sleep, timeout};
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    fn frame(msg_id: u64, data: &[u8]) -> bytes::Bytes {
+        let mut frame = BytesMut::with_capacity(8 + data.len());
+        frame.put_u64_le(msg_id);
+        frame.extend_from_slice(data);
+        frame.freeze()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn push_over_client_connection() {
+        let _ = env_logger::try_init();
+        // Client side, handshake is only sent when asked for and push frames reach the handler
+        let listener = TcpListener::bind("127.0.0.1:2034").await.unwrap();
+        let (tx, rx) = oneshot::channel();
+        let tx = parking_lot::Mutex::new(Some(tx));
+        on_push(42, move |data| {
+            if let Some(tx) = tx.lock().take() {
+                tx.send(data).unwrap();
+            }
+        });
+        let client = tokio::spawn(async { Client::connect(&String::from("127.0.0.1:2034")).await });
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut transport = Framed::new(socket, LengthDelimitedCodec::new());
+        let client = client.await.unwrap().unwrap();
+        assert!(timeout(Duration::from_millis(500), transport.next())
+            .await
+            .is_err());
+        client.handshake_push().await.unwrap();
+        client.handshake_push().await.unwrap();
+        let mut handshake = transport.next().await.unwrap().unwrap();
+        assert_eq!(handshake.get_u64_le(), HANDSHAKE_MSG_ID);
+        assert_eq!(handshake.get_u64_le(), *PUSH_SECRET);
+        let mut payload = BytesMut::new();
+        payload.put_u64_le(42);
+        payload.extend_from_slice(b"hello");
+        transport
+            .send(frame(PUSH_MSG_ID, payload.as_ref()))
+            .await
+            .unwrap();
+        assert_eq!(rx.await.unwrap().as_ref(), b"hello");
+
+        // Server side, connection is registered by handshake and used for push
+        let addr = String::from("127.0.0.1:2035");
+        let server_addr = addr.clone();
+        tokio::spawn(async move {
+            Server::new(
+                &server_addr,
+                Arc::new(|data| futures::future::ready(data).boxed()),
+            )
+            .await
+            .unwrap();
+        });
+        sleep(Duration::from_secs(1)).await;
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let mut transport = Framed::new(socket, LengthDelimitedCodec::new());
+        transport
+            .send(frame(HANDSHAKE_MSG_ID, &7u64.to_le_bytes()))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(500)).await;
+        push(push_id_of(7), BytesMut::from(&b"world"[..])).unwrap();
+        let mut pushed = transport.next().await.unwrap().unwrap();
+        assert_eq!(pushed.get_u64_le(), PUSH_MSG_ID);
+        assert_eq!(pushed.as_ref(), b"world");
+        // The secret is not the push id, claiming a push id does not get its pushes
+        assert!(push(7, BytesMut::new()).is_err());
+        assert!(push(push_id_of(8), BytesMut::new()).is_err());
+    }
+}
@@ -1,13 +1,15 @@
 use super::STANDALONE_ADDRESS;
+use crate::tcp::push::{self, HANDSHAKE_MSG_ID, PUSH_MSG_ID};
 use crate::tcp::shortcut;
+use async_std::sync::Mutex;
 use bytes::{Buf, BufMut, BytesMut};
-use futures::SinkExt;
+use futures::{SinkExt, StreamExt};
 use std::error::Error;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 use tokio::net::TcpListener;
-use tokio_stream::StreamExt;
+use tokio::sync::mpsc;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 pub type RPCFuture = dyn Future<Output = TcpRes>;
@@ -33,18 +35,47 @@ impl Server {
                         // here to move ownership of our db handle into the async closure.
                         let callback = callback.clone();
                         tokio::spawn(async move {
-                            let mut transport = Framed::new(socket, LengthDelimitedCodec::new());
-                            while let Some(result) = transport.next().await {
+                            let transport = Framed::new(socket, LengthDelimitedCodec::new());
+                            let (writer, mut reader) = transport.split();
+                            let writer = Arc::new(Mutex::new(writer));
+                            // Messages pushed to the client share the connection with responses
+                            let (push_tx, mut push_rx) =
+                                mpsc::channel::<BytesMut>(push::PUSH_BUFFER_SIZE);
+                            let push_writer = writer.clone();
+                            tokio::spawn(async move {
+                                while let Some(data) = push_rx.recv().await {
+                                    let mut frame = BytesMut::with_capacity(8 + data.len());
+                                    frame.put_u64_le(PUSH_MSG_ID);
+                                    frame.extend_from_slice(data.as_ref());
+                                    if let Err(e) =
+                                        push_writer.lock().await.send(frame.freeze()).await
+                                    {
+                                        debug!("Cannot push to client {:?}", e);
+                                        break;
+                                    }
+                                }
+                            });
+                            let mut client_id = None;
+                            while let Some(result) = reader.next().await {
                                 match result {
                                     Ok(mut data) => {
                                         let msg_id = data.get_u64_le();
+                                        if msg_id == HANDSHAKE_MSG_ID {
+                                            if data.len() >= 8 {
+                                                let id = push::push_id_of(data.get_u64_le());
+                                                push::register_connection(id, &push_tx);
+                                                client_id = Some(id);
+                                            }
+                                            continue;
+                                        }
                                         let call_back_data = callback(data).await;
                                         let mut res =
                                             BytesMut::with_capacity(8 + call_back_data.len());
                                         // debug!("Received TCP message {}", msg_id);
                                         res.put_u64_le(msg_id);
                                         res.extend_from_slice(call_back_data.as_ref());
-                                        if let Err(e) = transport.send(res.freeze()).await {
+                                        if let Err(e) = writer.lock().await.send(res.freeze()).await
+                                        {
                                             error!("Error on TCP callback {:?}", e);
                                         }
                                     }
@@ -54,6 +85,9 @@ impl Server {
                                 }
                             }
                             // The connection will be closed at this point as `lines.next()` has returned `None`.
+                            if let Some(client_id) = client_id {
+                                push::remove_connection(client_id, &push_tx);
+                            }
                         });
                     }
                     Err(e) => error!("error accepting socket; error = {:?}", e),
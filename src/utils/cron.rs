@@ -0,0 +1,174 @@
+// Five field cron expressions in UTC: minute, hour, day of month, month and day of week.
+// Fields take `*`, numbers, ranges `a-b`, steps `*/n`, `a/n` or `a-b/n`, and lists of them.
+// Day of week is 0 to 7, both 0 and 7 are Sunday. When both day fields are restricted, days
+// matching either of them match, like Vixie cron.
+
+static MINUTE_MS: i64 = 60_000;
+static DAY_MINUTES: i64 = 24 * 60;
+// Expressions like `0 0 30 2 *` never fire
+static MAX_SEARCH_DAYS: i64 = 366 * 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn number(s: &str, min: u32, max: u32) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(n) if n >= min && n <= max => Ok(n),
+        _ => Err(format!("{} is not a number in {}-{}", s, min, max)),
+    }
+}
+
+fn field(s: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in s.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(number(step, 1, max)?)),
+            None => (part, None),
+        };
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else if let Some((lo, hi)) = range.split_once('-') {
+            (number(lo, min, max)?, number(hi, min, max)?)
+        } else {
+            let n = number(range, min, max)?;
+            // `a/n` runs from a to the end
+            (n, if step.is_some() { max } else { n })
+        };
+        if lo > hi {
+            return Err(format!("{} is not a valid range", range));
+        }
+        for n in (lo..=hi).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+// Year, month and day of days since epoch
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let fields: Vec<_> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("{} does not have 5 fields", expr));
+        }
+        let mut weekdays = field(fields[4], 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            minutes: field(fields[0], 0, 59)?,
+            hours: field(fields[1], 0, 23)?,
+            days: field(fields[2], 1, 31)?,
+            months: field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+    fn day_matches(&self, days: i64) -> bool {
+        let (_, month, day) = civil_from_days(days);
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        // Epoch was on Thursday
+        let weekday = (days + 4).rem_euclid(7);
+        let day_match = self.days & (1 << day) != 0;
+        let weekday_match = self.weekdays & (1 << weekday) != 0;
+        match (self.any_day, self.any_weekday) {
+            (true, _) => weekday_match,
+            (false, true) => day_match,
+            (false, false) => day_match || weekday_match,
+        }
+    }
+    // First time matched after `time`, both in milliseconds since epoch
+    pub fn next_after(&self, time: i64) -> Option<i64> {
+        let mut minute = time.div_euclid(MINUTE_MS) + 1;
+        let limit = minute + MAX_SEARCH_DAYS * DAY_MINUTES;
+        while minute < limit {
+            let days = minute.div_euclid(DAY_MINUTES);
+            if !self.day_matches(days) {
+                minute = (days + 1) * DAY_MINUTES;
+                continue;
+            }
+            let hour = minute.rem_euclid(DAY_MINUTES) / 60;
+            if self.hours & (1 << hour) == 0 {
+                minute = days * DAY_MINUTES + (hour + 1) * 60;
+                continue;
+            }
+            if self.minutes & (1 << minute.rem_euclid(60)) == 0 {
+                minute += 1;
+                continue;
+            }
+            return Some(minute * MINUTE_MS);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    static HOUR_MS: i64 = 3_600_000;
+    static DAY_MS: i64 = 24 * HOUR_MS;
+
+    #[test]
+    fn next_after() {
+        let every_15 = Cron::parse("*/15 * * * *").unwrap();
+        assert_eq!(every_15.next_after(0), Some(15 * MINUTE_MS));
+        assert_eq!(
+            every_15.next_after(15 * MINUTE_MS - 1),
+            Some(15 * MINUTE_MS)
+        );
+        assert_eq!(every_15.next_after(50 * MINUTE_MS), Some(HOUR_MS));
+        // 1970-01-01 was Thursday
+        let monday = Cron::parse("30 9 * * 1").unwrap();
+        assert_eq!(
+            monday.next_after(0),
+            Some(4 * DAY_MS + 9 * HOUR_MS + 30 * MINUTE_MS)
+        );
+        let sunday = Cron::parse("0 0 * * 7").unwrap();
+        assert_eq!(sunday.next_after(0), Some(3 * DAY_MS));
+        // 2000-03-01, after the leap day
+        let march = Cron::parse("0 0 1 3 *").unwrap();
+        assert_eq!(march.next_after(951_782_400_000), Some(951_868_800_000));
+        // Either day field matches when both are restricted
+        let either = Cron::parse("0 12 10 * 6").unwrap();
+        assert_eq!(either.next_after(0), Some(2 * DAY_MS + 12 * HOUR_MS));
+        let list = Cron::parse("5,10-12/2 0 * * *").unwrap();
+        assert_eq!(list.next_after(5 * MINUTE_MS), Some(10 * MINUTE_MS));
+        assert_eq!(list.next_after(10 * MINUTE_MS), Some(12 * MINUTE_MS));
+        assert_eq!(Cron::parse("0 0 30 2 *").unwrap().next_after(0), None);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Cron::parse("* * * *").is_err());
+        assert!(Cron::parse("60 * * * *").is_err());
+        assert!(Cron::parse("* * 0 * *").is_err());
+        assert!(Cron::parse("5-1 * * * *").is_err());
+        assert!(Cron::parse("*/0 * * * *").is_err());
+        assert!(Cron::parse("a * * * *").is_err());
+    }
+}
@@ -1,3 +1,4 @@
+pub mod cron;
 pub mod time;
 #[macro_use]
 pub mod bindings;